@@ -5,7 +5,7 @@ use axum::{
 };
 use std::sync::Arc;
 use crate::engine::channel_manager::ChannelManager;
-use crate::storage::messages::{MessageRecord, MessageStatus};
+use crate::storage::messages::{MessageRecord, MessageDestinationRecord};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -28,6 +28,27 @@ pub async fn list_messages(
     }
 }
 
+#[derive(Serialize)]
+pub struct MessageDetail {
+    #[serde(flatten)]
+    pub message: MessageRecord,
+    pub destinations: Vec<MessageDestinationRecord>,
+}
+
+pub async fn get_message(
+    State(manager): State<Arc<ChannelManager>>,
+    Path(id): Path<String>,
+) -> Result<Json<MessageDetail>, StatusCode> {
+    match manager.get_message(&id).await {
+        Ok(Some((message, destinations))) => Ok(Json(MessageDetail { message, destinations })),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to get message {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 pub async fn retry_message(
     State(manager): State<Arc<ChannelManager>>,
     Path(id): Path<String>,
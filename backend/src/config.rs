@@ -1,10 +1,16 @@
 use serde::Deserialize;
+use crate::storage::models::ClientAuth;
 use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::{aws_lc_rs, CryptoProvider};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TlsConfig {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// Client certificate authentication (listeners only)
+    #[serde(default)]
+    pub client_auth: Option<ClientAuth>,
 }
 
 impl TlsConfig {
@@ -12,6 +18,18 @@ impl TlsConfig {
         Self {
             cert_path: cert,
             key_path: key,
+            client_auth: None,
         }
     }
+
+    pub fn with_client_auth(mut self, client_auth: Option<ClientAuth>) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+}
+
+/// Crypto provider of every rustls config we build. Dependencies compile in more than one
+/// provider, so rustls cannot pick a process default by itself.
+pub fn tls_provider() -> Arc<CryptoProvider> {
+    Arc::new(aws_lc_rs::default_provider())
 }
@@ -3,16 +3,15 @@ use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::sync::{broadcast, mpsc};
 use uuid::Uuid;
-use crate::storage::models::{Channel, SourceConfig, ProcessorType};
+use crate::storage::models::{Channel, ClientAuth, SourceConfig, ProcessorType};
+use crate::engine::destinations::ChannelDestinations;
+use crate::engine::destinations::tcp_connection::TcpConnection;
 use crate::engine::listeners::http::HttpListener;
+use crate::engine::listeners::http_response::HttpResponder;
 use crate::engine::listeners::tcp::TcpListener;
 use crate::engine::listeners::database::DatabasePoller;
 use crate::engine::listeners::file::FileReader;
-use crate::engine::destinations::http::HttpSender;
-use crate::engine::destinations::file::FileWriter;
-use crate::engine::destinations::tcp::TcpSender;
-use crate::engine::destinations::database::DatabaseWriter;
-use crate::engine::message::Message;
+use crate::engine::message::{Message, Outcome};
 use std::future::Future;
 use std::pin::Pin;
 use crate::storage::messages::{MessageStore, MessageStatus};
@@ -24,6 +23,8 @@ use chrono::Utc;
 pub struct ChannelManager {
     channels: Arc<Mutex<HashMap<Uuid, tokio::task::JoinHandle<()>>>>,
     senders: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+    // Destinations of each running channel, compiled at deploy; shared with the RetryWorker
+    compiled: Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
     shutdown_tx: broadcast::Sender<()>,
     metrics_tx: broadcast::Sender<crate::storage::models::MetricUpdate>,
 
@@ -46,6 +47,7 @@ impl ChannelManager {
         Self {
             channels: Arc::new(Mutex::new(HashMap::new())),
             senders: Arc::new(Mutex::new(HashMap::new())),
+            compiled: Arc::new(Mutex::new(HashMap::new())),
             shutdown_tx,
             metrics_tx,
             logs,
@@ -101,6 +103,10 @@ impl ChannelManager {
         self.senders.clone()
     }
 
+    pub fn get_compiled_destinations(&self) -> Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>> {
+        self.compiled.clone()
+    }
+
     pub fn get_active_channel_ids(&self) -> Vec<Uuid> {
         self.channels.lock().unwrap().keys().cloned().collect()
     }
@@ -117,6 +123,8 @@ impl ChannelManager {
             
             // Remove sender
             self.senders.lock().unwrap().remove(&channel_id);
+            self.compiled.lock().unwrap().remove(&channel_id);
+            TcpConnection::close_channel(channel_id);
             
             self.add_log("INFO", format!("Channel {} stopped", channel_id), Some(channel_id));
             Ok(())
@@ -146,6 +154,38 @@ impl ChannelManager {
         let channel_id = channel.id;
         tracing::info!("Starting channel: {} ({})", channel.name, channel_id);
 
+        // Destinations in queue mode need the durable store; without it they deliver inline
+        let destination_queues = match &self.db {
+            Some(db) => crate::engine::destination_queue::DestinationQueues::for_channel(&channel, crate::storage::queue::QueueStore::new(db.pool.clone())),
+            None => {
+                if channel.destinations.iter().any(|d| d.queue.is_some()) {
+                    self.add_log("WARN", format!("Channel {} has queued destinations but no database; delivering inline", channel.name), Some(channel_id));
+                }
+                None
+            }
+        };
+
+        // Build the pipeline before touching the running instance: invalid scripts fail the deploy
+        let pipeline = crate::engine::pipeline::processor::PipelineProcessor::new(
+            channel_id,
+            channel.name.clone(),
+            channel.processors.clone(),
+            channel.destinations.clone(),
+            self.message_store.clone(),
+            self.dedup_store.clone(),
+            self.metrics_tx.clone(),
+            self.logs.clone(),
+        )?
+        .with_error_destination(channel.error_destination.clone())?
+        .with_max_retries(channel.max_retries)
+        .with_destination_mode(channel.destination_mode)
+        .with_workers(channel.workers, channel.ordering_key.clone())
+        .with_destination_queues(destination_queues.clone());
+
+        if let SourceConfig::Http { response, .. } = &channel.source {
+            HttpResponder::check_destination(response, &channel)?;
+        }
+
         // Persist to DB if available
         if let Some(db) = &self.db {
              let config = serde_json::to_value(&channel).unwrap_or(serde_json::Value::Null);
@@ -167,6 +207,8 @@ impl ChannelManager {
         
         // Remove old sender to ensure no stale references
         self.senders.lock().unwrap().remove(&channel_id);
+        // Reconnect with the new destination settings
+        TcpConnection::close_channel(channel_id);
 
         // Clear dedup cache for this channel on start/deploy to allow re-testing
         if let Some(dedup) = &self.dedup_store {
@@ -182,24 +224,28 @@ impl ChannelManager {
 
         // Store sender for manual injection AND for listeners to pick up if they need it (though listeners usually take a clone)
         self.senders.lock().unwrap().insert(channel_id, tx.clone());
+        let compiled = pipeline.compiled_destinations();
+        self.compiled.lock().unwrap().insert(channel_id, compiled.clone());
         
         // Prepare store for listeners
         let store_for_listener = self.message_store.clone();
 
+        let queue_channel = Arc::new(channel.clone());
+
         // 2. Start Source Listener (Create Future)
         // We will run this concurrent with the processor
         let listener_fut: Pin<Box<dyn Future<Output = ()> + Send>> = match channel.source {
-            SourceConfig::Http { port, path, cert_path, key_path } => {
+            SourceConfig::Http { port, path, methods, body_encoding, response, cert_path, key_path, client_auth } => {
                 let mut path = path.unwrap_or_else(|| "/".to_string());
                 if !path.starts_with('/') {
                     path = format!("/{}", path);
                 }
+                HttpListener::validate_path(&path)?;
+                HttpListener::parse_methods(&methods)?;
+                let responder = HttpResponder::new(response)
+                    .map_err(|e| anyhow::anyhow!("Invalid response settings: {}", e))?;
                 
-                let tls_config = if let (Some(cert), Some(key)) = (cert_path, key_path) {
-                    Some(crate::config::TlsConfig::new(std::path::PathBuf::from(cert), std::path::PathBuf::from(key)))
-                } else {
-                    None
-                };
+                let tls_config = listener_tls(cert_path, key_path, client_auth)?;
                 
                 let listener = HttpListener::new(
                     port,
@@ -208,7 +254,7 @@ impl ChannelManager {
                     tx.clone(),
                     store_for_listener,
                     tls_config,
-                );
+                ).with_methods(methods).with_body_encoding(body_encoding).with_responder(responder);
                 
                 Box::pin(async move {
                     listener.start().await;
@@ -219,12 +265,11 @@ impl ChannelManager {
                 self.add_log("INFO", format!("Test Channel {} ready for manual injection (Format: {})", channel.name, payload_type), Some(channel_id));
                 Box::pin(std::future::pending())
             },
-            SourceConfig::Tcp { port, cert_path, key_path } => {
-                let tls_config = if let (Some(cert), Some(key)) = (cert_path, key_path) {
-                    Some(crate::config::TlsConfig::new(std::path::PathBuf::from(cert), std::path::PathBuf::from(key)))
-                } else {
-                    None
-                };
+            SourceConfig::Tcp { port, cert_path, key_path, client_auth, ack_mode, ack_script, framing } => {
+                let ack = crate::engine::listeners::ack::AckResponder::new(ack_mode, ack_script)
+                    .map_err(|e| anyhow::anyhow!("Invalid ack_script: {}", e))?;
+                crate::engine::framing::framer(&framing, 0)?;
+                let tls_config = listener_tls(cert_path, key_path, client_auth)?;
 
                 let listener = TcpListener::new(
                     port,
@@ -232,7 +277,7 @@ impl ChannelManager {
                     tx, 
                     store_for_listener,
                     tls_config,
-                );
+                ).with_ack(ack).with_framing(framing);
                 let channel_name_clone = channel.name.clone();
                 let logs_arc_clone = self.logs.clone();
                 
@@ -293,32 +338,21 @@ impl ChannelManager {
 
         };
 
-        // 3. Spawn Processing Loop (Using new PipelineProcessor)
-        let processors = channel.processors.clone();
-        let destinations = channel.destinations.clone();
-        let logs_arc = self.logs.clone(); 
+        // 3. Spawn Processing Loop
         let channel_name = channel.name.clone();
-        let metrics_tx = self.metrics_tx.clone();
-        let store_for_processor = self.message_store.clone();
-        let dedup_store_for_processor = self.dedup_store.clone();
+        let store_for_queues = self.message_store.clone();
 
         let mut shutdown_rx = self.shutdown_tx.subscribe();
         
-        let processor_channel_name = channel_name.clone();
-        
         // 3. Spawn Supervisor Task (runs both listener and processor)
         let handle = tokio::spawn(async move {
+            // Queue workers live in this set and are aborted with the supervisor
+            let mut queue_workers = tokio::task::JoinSet::new();
+            if let (Some(queues), Some(store)) = (&destination_queues, store_for_queues) {
+                queues.spawn_workers(queue_channel, compiled, store, &mut queue_workers).await;
+            }
+
             let processor_fut = async move {
-                 let pipeline = crate::engine::pipeline::processor::PipelineProcessor::new(
-                     channel_id,
-                     processor_channel_name,
-                     processors,
-                     destinations,
-                     store_for_processor,
-                     dedup_store_for_processor,
-                     metrics_tx,
-                     logs_arc,
-                 );
                  pipeline.run(rx).await;
             };
 
@@ -385,9 +419,9 @@ impl ChannelManager {
             self.add_log("INFO", "Manual message injected".to_string(), Some(channel_id));
             
             // Wait for response (Sync wait just like HTTP listener)
-            match tokio::time::timeout(std::time::Duration::from_secs(30), resp_rx).await {
-                Ok(Ok(Ok(response))) => Ok(response),
-                Ok(Ok(Err(proc_err))) => Err(anyhow::anyhow!("{}", proc_err)), // Return purely the error string, don't wrap in "Processing Error"
+            match tokio::time::timeout(std::time::Duration::from_secs(30), resp_rx).await.map(|r| r.map(|reply| reply.outcome)) {
+                Ok(Ok(Outcome::Processed(response) | Outcome::Filtered(response))) => Ok(response),
+                Ok(Ok(Outcome::Failed(proc_err))) => Err(anyhow::anyhow!("{}", proc_err)), // Return purely the error string, don't wrap in "Processing Error"
                  Ok(Err(_)) => Err(anyhow::anyhow!("Response channel closed unexpectedly")),
                 Err(_) => Err(anyhow::anyhow!("Timeout waiting for processing"))
             }
@@ -439,6 +473,8 @@ impl ChannelManager {
 
                                  if let Some(tx) = sender {
                                      let mut msg = Message::new(channel_uuid, msg_record.content, "RECOVERY".to_string());
+                                     msg.metadata = msg_record.metadata;
+                                     msg.redelivery = true;
                                      if let Ok(id) = Uuid::parse_str(&msg_record.id) {
                                          msg.id = id;
                                      }
@@ -472,40 +508,65 @@ impl ChannelManager {
         }
     }
 
+    /// Get a message together with its per-destination delivery records
+    pub async fn get_message(&self, id: &str) -> anyhow::Result<Option<(crate::storage::messages::MessageRecord, Vec<crate::storage::messages::MessageDestinationRecord>)>> {
+        if let Some(store) = &self.message_store {
+            match store.get_message_by_id(id).await? {
+                Some(record) => {
+                    let destinations = store.get_message_destinations(id).await?;
+                    Ok(Some((record, destinations)))
+                },
+                None => Ok(None),
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
     pub async fn retry_message(&self, id: String) -> anyhow::Result<()> {
         if let Some(store) = &self.message_store {
             // 1. Get message
             let msg_record = store.get_message_by_id(&id).await?
                 .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
-                
-            // 2. Increment retry count
+
+            // 2. Channel must be running before the retry is counted
+            let channel_uuid = Uuid::parse_str(&msg_record.channel_id)
+                .map_err(|_| anyhow::anyhow!("Invalid channel ID"))?;
+            let sender = self.senders.lock().unwrap().get(&channel_uuid).cloned()
+                .ok_or_else(|| anyhow::anyhow!("Channel not running"))?;
+
+            // 3. Destinations were already attempted: resend only the failed ones, skipping processors,
+            //    so destinations that succeeded are not written twice
+            if !store.get_message_destinations(&id).await?.is_empty() {
+                let failed_destinations = store.get_failed_destinations(&id).await?;
+                if failed_destinations.is_empty() {
+                    return Err(anyhow::anyhow!("Message has no failed destinations to retry"));
+                }
+                let channel = self.get_channel_by_id(channel_uuid).await?
+                    .ok_or_else(|| anyhow::anyhow!("Channel configuration not found"))?;
+                let compiled = self.compiled.lock().unwrap().get(&channel_uuid).cloned().unwrap_or_default();
+
+                store.increment_retry(&id).await?;
+                let status = crate::engine::retry_worker::retry_destinations(store, &channel, &compiled, &id, failed_destinations, "RETRY_API").await?;
+                self.add_log("INFO", format!("Message {} destinations retried, status {}", id, status), Some(channel_uuid));
+                return Ok(());
+            }
+
+            // 4. Nothing was delivered yet: increment retry count and update status to PENDING
             store.increment_retry(&id).await?;
-            
-            // 3. Update status to PENDING
             store.update_status(&id, MessageStatus::PENDING, None).await?;
-            
-            // 4. Re-inject
-            if let Ok(channel_uuid) = Uuid::parse_str(&msg_record.channel_id) {
-                 let sender = {
-                     let senders = self.senders.lock().unwrap();
-                     senders.get(&channel_uuid).cloned()
-                 };
-
-                 if let Some(tx) = sender {
-                     let mut msg = Message::new(channel_uuid, msg_record.content, "RETRY_API".to_string());
-                     // RESTORE ID
-                     if let Ok(uuid) = Uuid::parse_str(&msg_record.id) {
-                         msg.id = uuid;
-                     }
-                     
-                     tx.send(msg).await.map_err(|_| anyhow::anyhow!("Channel receiver dropped"))?;
-                     Ok(())
-                 } else {
-                     Err(anyhow::anyhow!("Channel not running"))
-                 }
-            } else {
-                Err(anyhow::anyhow!("Invalid channel ID"))
+
+            // 5. Re-inject
+            let mut msg = Message::new(channel_uuid, msg_record.content, "RETRY_API".to_string());
+            msg.metadata = msg_record.metadata;
+            msg.redelivery = true;
+            // RESTORE ID
+            if let Ok(uuid) = Uuid::parse_str(&msg_record.id) {
+                msg.id = uuid;
             }
+
+            sender.send(msg).await.map_err(|_| anyhow::anyhow!("Channel receiver dropped"))?;
+            Ok(())
         } else {
             Err(anyhow::anyhow!("Message store not configured"))
         }
@@ -521,6 +582,7 @@ impl ChannelManager {
             let mut senders = self.senders.lock().unwrap();
             senders.clear();
         }
+        self.compiled.lock().unwrap().clear();
         
         // 3. Join all channel tasks
         let mut handles = Vec::new();
@@ -543,3 +605,13 @@ impl ChannelManager {
         tracing::info!("All channels shutdown gracefully.");
     }
 }
+
+/// TLS settings of a listener; client authentication without a server certificate is refused
+/// rather than silently serving plaintext
+fn listener_tls(cert_path: Option<String>, key_path: Option<String>, client_auth: Option<ClientAuth>) -> anyhow::Result<Option<crate::config::TlsConfig>> {
+    match (cert_path, key_path) {
+        (Some(cert), Some(key)) => Ok(Some(crate::config::TlsConfig::new(std::path::PathBuf::from(cert), std::path::PathBuf::from(key)).with_client_auth(client_auth))),
+        _ if client_auth.is_some() => Err(anyhow::anyhow!("client_auth requires cert_path and key_path")),
+        _ => Ok(None),
+    }
+}
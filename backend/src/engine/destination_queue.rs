@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::engine::destinations::{self, ChannelDestinations};
+use crate::engine::message::Message;
+use crate::engine::pipeline::dead_letter;
+use crate::storage::messages::{MessageStatus, MessageStore};
+use crate::storage::models::{Channel, DestinationConfig, QueueConfig};
+use crate::storage::queue::{QueueEntry, QueueStore};
+
+/// Destination queues of a channel: the pipeline enqueues transformed messages here and
+/// returns, and the queue workers deliver them in the background.
+#[derive(Clone)]
+pub struct DestinationQueues {
+    channel_id: Uuid,
+    store: QueueStore,
+    notifiers: HashMap<String, Arc<Notify>>,
+}
+
+impl DestinationQueues {
+    /// Queues for the destinations of `channel` that have queue mode enabled (`None` if there are none)
+    pub fn for_channel(channel: &Channel, store: QueueStore) -> Option<Self> {
+        let notifiers: HashMap<String, Arc<Notify>> = channel.destinations.iter()
+            .filter(|d| d.queue.is_some())
+            .map(|d| (d.id.clone(), Arc::new(Notify::new())))
+            .collect();
+
+        if notifiers.is_empty() {
+            return None;
+        }
+        Some(Self { channel_id: channel.id, store, notifiers })
+    }
+
+    pub fn is_queued(&self, destination_id: &str) -> bool {
+        self.notifiers.contains_key(destination_id)
+    }
+
+    pub async fn enqueue(&self, destination_id: &str, message_id: &str, content: &str, metadata: &HashMap<String, String>) -> Result<i64, sqlx::Error> {
+        self.store.enqueue(&self.channel_id.to_string(), destination_id, message_id, content, metadata).await
+    }
+
+    /// Wake the workers of a destination queue
+    pub fn notify(&self, destination_id: &str) {
+        if let Some(notify) = self.notifiers.get(destination_id) {
+            notify.notify_waiters();
+        }
+    }
+
+    /// Spawn the delivery workers of every queue into `tasks` (they stop when the set is dropped).
+    /// Entries left in flight by a previous run are requeued first, before any worker can claim.
+    pub async fn spawn_workers(&self, channel: Arc<Channel>, compiled: Arc<ChannelDestinations>, message_store: MessageStore, tasks: &mut JoinSet<()>) {
+        let channel_id = channel.id.to_string();
+        for destination in &channel.destinations {
+            let (Some(queue), Some(notify)) = (&destination.queue, self.notifiers.get(&destination.id)) else {
+                continue;
+            };
+            if !queue.ordered && queue.concurrency == 0 {
+                tracing::warn!("Queue of destination {} has concurrency 0, using 1 worker", destination.name);
+            }
+            match self.store.release_in_flight(&channel_id, &destination.id).await {
+                Ok(count) if count > 0 => tracing::info!("Queue {} resumed {} in-flight message(s)", destination.name, count),
+                Ok(_) => {},
+                Err(e) => tracing::error!("Queue {} failed to resume in-flight messages: {}", destination.name, e),
+            }
+
+            for _ in 0..queue.workers() {
+                let worker = QueueWorker {
+                    channel: channel.clone(),
+                    compiled: compiled.clone(),
+                    destination: destination.clone(),
+                    queue: queue.clone(),
+                    store: self.store.clone(),
+                    message_store: message_store.clone(),
+                    notify: notify.clone(),
+                    poll_interval: Duration::from_secs(1),
+                };
+                tasks.spawn(worker.run());
+            }
+        }
+    }
+}
+
+/// Drains one destination queue: delivers entries in `seq` order and retries failures with
+/// the queue's (or channel's) retry policy until `max_retries` runs out.
+pub struct QueueWorker {
+    channel: Arc<Channel>,
+    compiled: Arc<ChannelDestinations>,
+    destination: DestinationConfig,
+    queue: QueueConfig,
+    store: QueueStore,
+    message_store: MessageStore,
+    notify: Arc<Notify>,
+    poll_interval: Duration,
+}
+
+impl QueueWorker {
+    pub async fn run(self) {
+        loop {
+            match self.deliver_next().await {
+                Ok(true) => continue,
+                Ok(false) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {},
+                        _ = sleep(self.poll_interval) => {},
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Queue {} of channel {} failed: {}", self.destination.name, self.channel.name, e);
+                    sleep(self.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    /// Deliver the next due entry. Returns false when nothing was due.
+    pub async fn deliver_next(&self) -> anyhow::Result<bool> {
+        let channel_id = self.channel.id.to_string();
+        let Some(entry) = self.store.claim_next(&channel_id, &self.destination.id, self.queue.ordered).await? else {
+            return Ok(false);
+        };
+
+        let mut msg = Message::new(self.channel.id, entry.content.clone(), "destination_queue".to_string());
+        msg.id = Uuid::parse_str(&entry.message_id).unwrap_or_default();
+        msg.metadata = entry.metadata.clone();
+
+        match destinations::dispatch(&self.destination, &msg, &self.channel.name, &self.compiled).await {
+            Ok(response) => {
+                self.message_store.record_destination_attempt(&entry.message_id, &self.destination, &entry.content, &entry.metadata, MessageStatus::SENT, None, response.map(|r| r.to_string())).await?;
+                self.store.remove(entry.seq).await?;
+                tracing::info!("Queue {} delivered message {}", self.destination.name, entry.message_id);
+            },
+            Err(e) => self.handle_failure(&entry, &msg, e.to_string()).await?,
+        }
+
+        self.message_store.refresh_status_from_destinations(&entry.message_id).await?;
+        Ok(true)
+    }
+
+    async fn handle_failure(&self, entry: &QueueEntry, msg: &Message, error: String) -> anyhow::Result<()> {
+        // `attempts` counts the failed attempts before this one, i.e. the retries already done
+        if self.channel.can_retry(entry.attempts) {
+            let policy = self.queue.retry_policy.as_ref().unwrap_or(&self.channel.retry_policy);
+            let seed = format!("{}:{}", entry.message_id, self.destination.id);
+            let delay = chrono::Duration::from_std(policy.delay(entry.attempts as u32, &seed)).unwrap_or(chrono::Duration::MAX);
+            let next_attempt_at = Utc::now().checked_add_signed(delay).unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+
+            tracing::warn!("Queue {} failed to deliver message {} (retry at {}): {}", self.destination.name, entry.message_id, next_attempt_at, error);
+            self.message_store.record_destination_attempt(&entry.message_id, &self.destination, &entry.content, &entry.metadata, MessageStatus::QUEUED, Some(error.clone()), None).await?;
+            self.store.reschedule(entry.seq, &error, next_attempt_at).await?;
+            return Ok(());
+        }
+
+        tracing::warn!("Queue {} exhausted {} retries for message {}, marking FAILED", self.destination.name, entry.attempts, entry.message_id);
+        self.message_store.record_destination_attempt(&entry.message_id, &self.destination, &entry.content, &entry.metadata, MessageStatus::FAILED, Some(error.clone()), None).await?;
+        self.store.remove(entry.seq).await?;
+
+        if let Some(error_dest) = &self.channel.error_destination {
+            let original_content = self.message_store.get_message_by_id(&entry.message_id).await?
+                .map(|m| m.content)
+                .unwrap_or_else(|| entry.content.clone());
+            let error = format!("Retries exhausted: {}", error);
+            let failure = dead_letter::Failure { failed_step: &self.destination.name, error: &error, original_content: &original_content };
+            if let Err(e) = dead_letter::send_to_error_destination(error_dest, Some(&self.message_store), &self.channel.name, &self.compiled, msg, &failure).await {
+                tracing::error!("Error destination {} failed for message {}: {}", error_dest.name, entry.message_id, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::models::{DestinationType, Framing, SourceConfig};
+
+    fn queued_destination(id: &str, kind: DestinationType, queue: QueueConfig) -> DestinationConfig {
+        DestinationConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            routes: vec![],
+            queue: Some(queue),
+            timeout_ms: None,
+            kind,
+        }
+    }
+
+    fn channel(destinations: Vec<DestinationConfig>, max_retries: Option<i32>) -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: "Queue Test".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations,
+            error_destination: None,
+            max_retries,
+            retry_policy: crate::storage::models::RetryPolicy { initial_delay_ms: 0, ..Default::default() },
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        }
+    }
+
+    fn worker(channel: &Arc<Channel>, db: &crate::storage::db::Database) -> QueueWorker {
+        let destination = channel.destinations[0].clone();
+        QueueWorker {
+            channel: channel.clone(),
+            compiled: Default::default(),
+            queue: destination.queue.clone().unwrap(),
+            destination,
+            store: QueueStore::new(db.pool.clone()),
+            message_store: MessageStore::new(db.pool.clone()),
+            notify: Arc::new(Notify::new()),
+            poll_interval: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_delivers_in_order() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_queue_out_{}", Uuid::new_v4()));
+
+        let file = DestinationType::File {
+            path: out_dir.to_string_lossy().to_string(),
+            filename: Some("out.txt".to_string()),
+            append: Some(true),
+            encoding: None,
+        };
+        let channel = Arc::new(channel(vec![queued_destination("file", file, QueueConfig::default())], Some(3)));
+        let queues = DestinationQueues::for_channel(&channel, QueueStore::new(db.pool.clone())).unwrap();
+
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+            store.set_destination_status(&id, "file", "file", MessageStatus::QUEUED).await.unwrap();
+            queues.enqueue("file", &id, &format!("line-{}", i), &HashMap::new()).await.unwrap();
+            ids.push(id);
+        }
+        assert_eq!(store.refresh_status_from_destinations(&ids[0]).await.unwrap(), MessageStatus::QUEUED);
+
+        let worker = worker(&channel, &db);
+        while worker.deliver_next().await.unwrap() {}
+
+        let written = tokio::fs::read_to_string(out_dir.join("out.txt")).await.unwrap();
+        assert_eq!(written.lines().collect::<Vec<_>>(), vec!["line-0", "line-1", "line-2"]);
+        for id in &ids {
+            assert_eq!(store.get_message_by_id(id).await.unwrap().unwrap().status, "SENT");
+        }
+        assert_eq!(QueueStore::new(db.pool.clone()).depth(&channel.id.to_string(), "file").await.unwrap(), 0);
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_ordered_queue_retries_head_until_exhausted() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+
+        let tcp = DestinationType::Tcp { host: "127.0.0.1".to_string(), port: 1, framing: Framing::default(), keep_connection_open: false, tls: None, timeouts: Default::default(), response: Default::default() };
+        let channel = Arc::new(channel(vec![queued_destination("tcp-down", tcp, QueueConfig::default())], Some(1)));
+        let queues = DestinationQueues::for_channel(&channel, QueueStore::new(db.pool.clone())).unwrap();
+
+        let first = store.save_message(&channel.id.to_string(), "first").await.unwrap();
+        let second = store.save_message(&channel.id.to_string(), "second").await.unwrap();
+        queues.enqueue("tcp-down", &first, "first", &HashMap::new()).await.unwrap();
+        queues.enqueue("tcp-down", &second, "second", &HashMap::new()).await.unwrap();
+
+        // First attempt and the single retry both go to the head of the queue
+        let worker = worker(&channel, &db);
+        assert!(worker.deliver_next().await.unwrap());
+        let head = store.get_message_destinations(&first).await.unwrap();
+        assert_eq!(head[0].status, "QUEUED");
+        assert!(store.get_message_destinations(&second).await.unwrap().is_empty());
+
+        assert!(worker.deliver_next().await.unwrap());
+        let head = store.get_message_destinations(&first).await.unwrap();
+        assert_eq!(head[0].status, "FAILED");
+        assert_eq!(head[0].attempts, 2);
+        assert_eq!(store.get_message_by_id(&first).await.unwrap().unwrap().status, "FAILED");
+
+        // Only now does the next message get its turn
+        assert!(worker.deliver_next().await.unwrap());
+        assert_eq!(store.get_message_destinations(&second).await.unwrap()[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_queued_delivery_keeps_metadata() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+
+        let lua = DestinationType::Lua { code: "if msg.metadata.mrn ~= '42' then error('mrn missing') end".to_string(), limits: Default::default() };
+        let channel = Arc::new(channel(vec![queued_destination("lookup", lua, QueueConfig::default())], Some(0)));
+        let queues = DestinationQueues::for_channel(&channel, QueueStore::new(db.pool.clone())).unwrap();
+
+        let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+        let metadata = HashMap::from([("mrn".to_string(), "42".to_string())]);
+        queues.enqueue("lookup", &id, "payload", &metadata).await.unwrap();
+
+        assert!(worker(&channel, &db).deliver_next().await.unwrap());
+        let delivered = store.get_message_destinations(&id).await.unwrap();
+        assert_eq!(delivered[0].status, "SENT", "{:?}", delivered[0].last_error);
+        assert_eq!(delivered[0].metadata, metadata);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_workers_resume_in_flight_once() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let queue_store = QueueStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_queue_out_{}", Uuid::new_v4()));
+
+        let file = DestinationType::File {
+            path: out_dir.to_string_lossy().to_string(),
+            filename: Some("out.txt".to_string()),
+            append: Some(true),
+            encoding: None,
+        };
+        let queue = QueueConfig { concurrency: 3, ordered: false, retry_policy: None };
+        let channel = Arc::new(channel(vec![queued_destination("file", file, queue)], Some(3)));
+        let queues = DestinationQueues::for_channel(&channel, queue_store.clone()).unwrap();
+
+        for i in 0..6 {
+            let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+            queues.enqueue("file", &id, &format!("line-{}", i), &HashMap::new()).await.unwrap();
+        }
+        // Left in flight by a previous run
+        queue_store.claim_next(&channel.id.to_string(), "file", false).await.unwrap().unwrap();
+
+        let mut tasks = JoinSet::new();
+        queues.spawn_workers(channel.clone(), Default::default(), store.clone(), &mut tasks).await;
+        for _ in 0..200 {
+            if queue_store.depth(&channel.id.to_string(), "file").await.unwrap() == 0 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        tasks.abort_all();
+
+        let written = tokio::fs::read_to_string(out_dir.join("out.txt")).await.unwrap();
+        let mut lines: Vec<_> = written.lines().collect();
+        lines.sort();
+        assert_eq!(lines, vec!["line-0", "line-1", "line-2", "line-3", "line-4", "line-5"]);
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+}
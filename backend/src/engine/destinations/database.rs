@@ -1,4 +1,5 @@
 use sqlx::{AnyPool};
+use crate::engine::egress;
 use crate::engine::message::Message;
 use uuid::Uuid;
 use anyhow::anyhow;
@@ -33,6 +34,7 @@ impl DatabaseWriter {
         tracing::debug!("🔌 Database Writer (Channel {}) connecting to {}", channel_id, self.url);
 
         // 1. Connect
+        egress::check_database_url(&self.url).await?;
         let pool = AnyPool::connect(&self.url).await.map_err(|e| {
             tracing::error!("❌ Failed to connect to database for Channel {}: {}", channel_id, e);
             e
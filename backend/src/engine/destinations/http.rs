@@ -1,52 +1,100 @@
+use crate::engine::destinations::template;
+use crate::engine::egress;
+use crate::engine::destinations::Response;
 use crate::engine::message::Message;
-use reqwest::Client;
-use std::net::{IpAddr, ToSocketAddrs};
-use std::time::Duration;
+use crate::storage::models::{ApiKeyLocation, HttpAuth, HttpParam};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use url::Url;
 
-/// Blocked hostnames that could be used for SSRF attacks
-const BLOCKED_HOSTS: &[&str] = &[
-    "localhost",
-    "127.0.0.1",
-    "0.0.0.0",
-    "::1",
-    "169.254.169.254",           // AWS metadata
-    "metadata.google.internal",   // GCP metadata
-    "metadata.azure.com",         // Azure metadata
-    "100.100.100.200",           // Alibaba metadata
-];
-
 /// HTTP request timeout
 const REQUEST_TIMEOUT_SECS: u64 = 30;
 
+/// Redirects followed per request, each checked against the egress policy
+const MAX_REDIRECTS: usize = 5;
+
+/// OAuth2 tokens are refreshed this long before they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Lifetime assumed for OAuth2 tokens issued without `expires_in`
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+/// Most characters of an error response body quoted in the failure
+const ERROR_BODY_CHARS: usize = 200;
+
+/// OAuth2 token cache key: token URL, client id and scope
+type TokenKey = (String, String, Option<String>);
+
+struct CachedToken {
+    token: String,
+    expires: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Resolves host names through the egress policy, so every connection (redirects included)
+/// goes only to addresses the policy allowed, without a second DNS lookup
+struct EgressResolver;
+
+impl Resolve for EgressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            // The connector sets the request's port on the returned addresses
+            let addrs = egress::resolve(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client shared by every HTTP destination. Redirect targets are checked by name here; the
+/// addresses they resolve to are checked by the resolver when connecting.
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .dns_resolver(Arc::new(EgressResolver))
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() > MAX_REDIRECTS {
+                    return attempt.error("too many redirects");
+                }
+                let checked = match attempt.url().host_str() {
+                    Some(host) => egress::policy().check(host, &[]),
+                    None => Err(anyhow::anyhow!("URL must have a valid host")),
+                };
+                match checked {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e.to_string()),
+                }
+            }))
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
 pub struct HttpSender {
     url: String,
     method: String,
-    client: Client,
     channel_name: String,
+    headers: Vec<HttpParam>,
+    query: Vec<HttpParam>,
+    auth: Option<HttpAuth>,
+    content_type: String,
 }
 
 impl HttpSender {
-    /// Check if an IP address is in a private/internal range
-    fn is_private_ip(ip: &IpAddr) -> bool {
-        match ip {
-            IpAddr::V4(ipv4) => {
-                ipv4.is_private() ||          // 10.x, 172.16-31.x, 192.168.x
-                ipv4.is_loopback() ||         // 127.x.x.x
-                ipv4.is_link_local() ||       // 169.254.x.x
-                ipv4.is_broadcast() ||        // 255.255.255.255
-                ipv4.is_unspecified() ||      // 0.0.0.0
-                ipv4.octets()[0] == 100 && ipv4.octets()[1] >= 64 && ipv4.octets()[1] <= 127  // Carrier-grade NAT
-            }
-            IpAddr::V6(ipv6) => {
-                ipv6.is_loopback() ||         // ::1
-                ipv6.is_unspecified()         // ::
-                // Note: is_private() not stable for IPv6 in older Rust versions
-            }
-        }
-    }
-
-    /// Validate a URL to prevent SSRF attacks
+    /// Validate a URL's scheme and host name; the addresses it resolves to are checked
+    /// against the egress policy when connecting
     fn validate_url(url_str: &str) -> anyhow::Result<Url> {
         let url = Url::parse(url_str)
             .map_err(|e| anyhow::anyhow!("Invalid URL format: {}", e))?;
@@ -62,32 +110,9 @@ impl HttpSender {
             }
         }
 
-        // Get the host
         let host = url.host_str()
             .ok_or_else(|| anyhow::anyhow!("URL must have a valid host"))?;
-
-        // Check against blocked hostnames
-        let host_lower = host.to_lowercase();
-        for blocked in BLOCKED_HOSTS {
-            if host_lower == *blocked || host_lower.ends_with(&format!(".{}", blocked)) {
-                return Err(anyhow::anyhow!(
-                    "Access to internal host '{}' is blocked for security reasons",
-                    host
-                ));
-            }
-        }
-
-        // Try to resolve the hostname and check for private IPs
-        let port = url.port().unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
-        if let Ok(addrs) = (host, port).to_socket_addrs() {
-            for addr in addrs {
-                if Self::is_private_ip(&addr.ip()) {
-                    return Err(anyhow::anyhow!(
-                        "URL resolves to private/internal IP address. Access blocked for security."
-                    ));
-                }
-            }
-        }
+        egress::policy().check(host, &[])?;
 
         // Check for suspicious patterns in the URL
         if url_str.contains("@") && !url.username().is_empty() {
@@ -100,9 +125,11 @@ impl HttpSender {
     /// Create a new HttpSender with URL validation
     pub fn new(url: String, method: String, channel_name: String) -> Self {
         // Validate URL on construction - log warning but don't fail
-        // (validation will be enforced on send)
-        if let Err(e) = Self::validate_url(&url) {
-            tracing::warn!("HttpSender created with potentially unsafe URL: {}", e);
+        // (validation will be enforced on send, templated URLs are only known then)
+        if !url.contains("${") {
+            if let Err(e) = Self::validate_url(&url) {
+                tracing::warn!("HttpSender created with potentially unsafe URL: {}", e);
+            }
         }
 
         // Validate HTTP method
@@ -117,65 +144,185 @@ impl HttpSender {
         Self {
             url,
             method,
-            client: Client::builder()
-                .redirect(reqwest::redirect::Policy::limited(5)) // Limit redirects
-                .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
-                .build()
-                .unwrap_or_else(|_| Client::new()),
             channel_name,
+            headers: Vec::new(),
+            query: Vec::new(),
+            auth: None,
+            content_type: "application/json".to_string(),
         }
     }
 
-    pub async fn send(&self, msg: &Message) -> anyhow::Result<()> {
-        // Validate URL before each request (in case of DNS rebinding attacks)
-        let validated_url = Self::validate_url(&self.url)?;
+    pub fn with_headers(mut self, headers: Vec<HttpParam>) -> Self {
+        self.headers = headers;
+        self
+    }
 
-        let req = match self.method.as_str() {
-            "GET" => self.client.get(validated_url.as_str()),
-            "POST" => self.client.post(validated_url.as_str()),
-            "PUT" => self.client.put(validated_url.as_str()),
-            "PATCH" => self.client.patch(validated_url.as_str()),
-            "DELETE" => self.client.delete(validated_url.as_str()),
-            _ => self.client.post(validated_url.as_str()),
+    pub fn with_query(mut self, query: Vec<HttpParam>) -> Self {
+        self.query = query;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Option<HttpAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: String) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Send the message and return the response. Non-2xx responses are failures.
+    pub async fn send(&self, msg: &Message) -> anyhow::Result<Response> {
+        let token = match &self.auth {
+            Some(HttpAuth::Oauth2 { token_url, client_id, client_secret, scope }) => {
+                Some(self.oauth2_token(token_url, client_id, client_secret, scope).await?)
+            },
+            _ => None,
         };
+        let request = self.request(msg, token.as_deref())?;
+        let host = request.url().host_str().unwrap_or("unknown").to_string();
 
-        let res = req
-            .header("Content-Type", "application/json")
-            .body(msg.content.clone())
-            .send()
-            .await?;
-        
+        let res = client().execute(request).await?;
         let status = res.status();
+        let body = res.text().await?;
+
         if !status.is_success() {
             tracing::warn!(
                 channel = %self.channel_name,
-                url = %validated_url.host_str().unwrap_or("unknown"),
+                url = %host,
                 method = %self.method,
                 status = %status,
                 "HTTP destination returned non-success status"
             );
-        } else {
-            tracing::info!(
-                channel = %self.channel_name,
-                url = %validated_url.host_str().unwrap_or("unknown"),
-                method = %self.method,
-                status = %status,
-                "HTTP destination success"
-            );
+            if status == StatusCode::UNAUTHORIZED {
+                // The token may have been revoked; fetch a new one next time
+                if let Some(HttpAuth::Oauth2 { token_url, client_id, scope, .. }) = &self.auth {
+                    tokens().lock().unwrap().remove(&(token_url.clone(), client_id.clone(), scope.clone()));
+                }
+            }
+            let excerpt: String = body.chars().take(ERROR_BODY_CHARS).collect();
+            return Err(anyhow::anyhow!("HTTP {} from {}: {}", status, host, excerpt));
+        }
+
+        tracing::info!(
+            channel = %self.channel_name,
+            url = %host,
+            method = %self.method,
+            status = %status,
+            "HTTP destination success"
+        );
+        Ok(Response { status: Some(status.as_u16()), body })
+    }
+
+    /// Build the request for `msg`: templated URL, query and headers, content type and auth
+    fn request(&self, msg: &Message, token: Option<&str>) -> anyhow::Result<reqwest::Request> {
+        let rendered = template::render(&self.url, msg, &self.channel_name, template::url_encoded);
+        // Validate URL before each request (in case of DNS rebinding attacks)
+        let mut url = Self::validate_url(&rendered)?;
+
+        let api_key_in_query = match &self.auth {
+            Some(HttpAuth::ApiKey { name, value, location: ApiKeyLocation::Query }) => Some((name, value)),
+            _ => None,
+        };
+        if !self.query.is_empty() || api_key_in_query.is_some() {
+            let mut pairs = url.query_pairs_mut();
+            for param in &self.query {
+                pairs.append_pair(&param.name, &template::render(&param.value, msg, &self.channel_name, template::raw));
+            }
+            if let Some((name, value)) = api_key_in_query {
+                pairs.append_pair(name, value);
+            }
+        }
+
+        let req = match self.method.as_str() {
+            "GET" => client().get(url),
+            "POST" => client().post(url),
+            "PUT" => client().put(url),
+            "PATCH" => client().patch(url),
+            "DELETE" => client().delete(url),
+            _ => client().post(url),
+        };
+
+        let mut req = req.header("Content-Type", &self.content_type);
+        for header in &self.headers {
+            req = req.header(&header.name, template::render(&header.value, msg, &self.channel_name, template::raw));
+        }
+        req = match &self.auth {
+            Some(HttpAuth::Basic { username, password }) => req.basic_auth(username, Some(password)),
+            Some(HttpAuth::Bearer { token }) => req.bearer_auth(token),
+            Some(HttpAuth::ApiKey { name, value, location: ApiKeyLocation::Header }) => req.header(name, value),
+            Some(HttpAuth::Oauth2 { .. }) => match token {
+                Some(token) => req.bearer_auth(token),
+                None => req,
+            },
+            _ => req,
+        };
+
+        req.body(msg.content.clone())
+            .build()
+            .map_err(|e| anyhow::anyhow!("Invalid HTTP request: {}", e))
+    }
+
+    /// Access token from the OAuth2 client credentials grant, cached per token URL, client and scope
+    async fn oauth2_token(&self, token_url: &str, client_id: &str, client_secret: &str, scope: &Option<String>) -> anyhow::Result<String> {
+        let key = (token_url.to_string(), client_id.to_string(), scope.clone());
+        if let Some(token) = cached_token(&key) {
+            return Ok(token);
         }
 
-        Ok(())
+        let url = Self::validate_url(token_url)?;
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = scope {
+            form.push(("scope", scope.as_str()));
+        }
+        let request = client().post(url)
+            .basic_auth(client_id, Some(client_secret))
+            .form(&form)
+            .build()?;
+        let res = client().execute(request).await?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("OAuth2 token request failed with HTTP {}", status));
+        }
+        let token: TokenResponse = res.json().await
+            .map_err(|e| anyhow::anyhow!("Invalid OAuth2 token response: {}", e))?;
+
+        store_token(key, token.access_token.clone(), token.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS));
+        Ok(token.access_token)
     }
 }
 
+fn tokens() -> &'static Mutex<HashMap<TokenKey, CachedToken>> {
+    static TOKENS: OnceLock<Mutex<HashMap<TokenKey, CachedToken>>> = OnceLock::new();
+    TOKENS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn cached_token(key: &TokenKey) -> Option<String> {
+    let tokens = tokens().lock().unwrap();
+    tokens.get(key)
+        .filter(|cached| cached.expires > Instant::now())
+        .map(|cached| cached.token.clone())
+}
+
+/// Cache a token until `TOKEN_EXPIRY_MARGIN` before it expires (half its lifetime when shorter)
+fn store_token(key: TokenKey, token: String, expires_in: u64) {
+    let lifetime = Duration::from_secs(expires_in);
+    let usable = lifetime.checked_sub(TOKEN_EXPIRY_MARGIN)
+        .filter(|usable| !usable.is_zero())
+        .unwrap_or(lifetime / 2);
+    tokens().lock().unwrap().insert(key, CachedToken { token, expires: Instant::now() + usable });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_validate_url_blocks_localhost() {
-        assert!(HttpSender::validate_url("http://localhost:8080/api").is_err());
-        assert!(HttpSender::validate_url("http://127.0.0.1:8080/api").is_err());
+    fn test_validate_url_blocks_private_addresses() {
+        // Not on the (test) egress allow list
+        assert!(HttpSender::validate_url("http://10.1.2.3:8080/api").is_err());
+        assert!(HttpSender::validate_url("http://192.168.0.10/api").is_err());
     }
 
     #[test]
@@ -196,5 +343,99 @@ mod tests {
     fn test_validate_url_blocks_file_scheme() {
         assert!(HttpSender::validate_url("file:///etc/passwd").is_err());
     }
+
+    fn param(name: &str, value: &str) -> HttpParam {
+        HttpParam { name: name.to_string(), value: value.to_string() }
+    }
+
+    fn message() -> Message {
+        let mut msg = Message::new(uuid::Uuid::new_v4(), "MSH|^~\\&|".to_string(), "test".to_string());
+        msg.metadata.insert("mrn".to_string(), "12 34".to_string());
+        msg
+    }
+
+    #[test]
+    fn test_request_templates_url_query_and_headers() {
+        let sender = HttpSender::new("https://api.example.com/patients/${mrn}".to_string(), "put".to_string(), "ADT".to_string())
+            .with_query(vec![param("source", "${channel}")])
+            .with_headers(vec![param("X-Message-Id", "${id}"), param("X-Static", "yes")])
+            .with_content_type("application/hl7-v2".to_string());
+        let msg = message();
+        let request = sender.request(&msg, None).unwrap();
+
+        assert_eq!(request.method(), "PUT");
+        assert_eq!(request.url().as_str(), "https://api.example.com/patients/12%2034?source=ADT");
+        assert_eq!(request.headers()["content-type"], "application/hl7-v2");
+        assert_eq!(request.headers()["x-message-id"], msg.id.to_string().as_str());
+        assert_eq!(request.headers()["x-static"], "yes");
+    }
+
+    #[test]
+    fn test_request_auth_schemes() {
+        let sender = |auth: HttpAuth| HttpSender::new("https://api.example.com/in".to_string(), "POST".to_string(), "ADT".to_string())
+            .with_auth(Some(auth));
+        let msg = message();
+
+        let request = sender(HttpAuth::Basic { username: "user".to_string(), password: "pass".to_string() }).request(&msg, None).unwrap();
+        assert_eq!(request.headers()["authorization"], "Basic dXNlcjpwYXNz");
+
+        let request = sender(HttpAuth::Bearer { token: "abc".to_string() }).request(&msg, None).unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer abc");
+
+        let api_key = |location| HttpAuth::ApiKey { name: "X-Api-Key".to_string(), value: "k1".to_string(), location };
+        let request = sender(api_key(ApiKeyLocation::Header)).request(&msg, None).unwrap();
+        assert_eq!(request.headers()["x-api-key"], "k1");
+        let request = sender(api_key(ApiKeyLocation::Query)).request(&msg, None).unwrap();
+        assert_eq!(request.url().query(), Some("X-Api-Key=k1"));
+        assert!(!request.headers().contains_key("x-api-key"));
+
+        let oauth2 = HttpAuth::Oauth2 {
+            token_url: "https://auth.example.com/token".to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            scope: None,
+        };
+        // Channel configs are logged with `{:?}`
+        let logged = format!("{:?}", oauth2);
+        assert!(logged.contains("client_id: \"id\"") && !logged.contains("secret\""));
+        let logged = format!("{:?} {:?}", HttpAuth::Basic { username: "user".to_string(), password: "pass".to_string() }, api_key(ApiKeyLocation::Header));
+        assert!(logged.contains("user") && !logged.contains("\"pass\"") && !logged.contains("\"k1\""));
+
+        let request = sender(oauth2).request(&msg, Some("tok")).unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer tok");
+    }
+
+    #[tokio::test]
+    async fn test_connections_and_redirects_follow_egress_policy() {
+        // Every host name the client connects to goes through the policy
+        let resolve = |host: &str| EgressResolver.resolve(host.parse().unwrap());
+        assert!(resolve("127.0.0.1").await.is_ok());
+        assert!(resolve("10.1.2.3").await.is_err());
+
+        let app = axum::Router::new()
+            .route("/ok", axum::routing::post(|| async { "done" }))
+            .route("/away", axum::routing::post(|| async { axum::response::Redirect::temporary("http://10.1.2.3/in") }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let sender = |path: &str| HttpSender::new(format!("http://127.0.0.1:{}{}", port, path), "POST".to_string(), "ADT".to_string());
+        assert_eq!(sender("/ok").send(&message()).await.unwrap().body, "done");
+        let err = sender("/away").send(&message()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("egress policy"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_token_cache_expiry() {
+        let key = ("https://auth.example.com/token".to_string(), "cache-test".to_string(), Some("read".to_string()));
+        assert_eq!(cached_token(&key), None);
+        store_token(key.clone(), "t1".to_string(), 3600);
+        assert_eq!(cached_token(&key).as_deref(), Some("t1"));
+        // Same client with another scope has its own token
+        assert_eq!(cached_token(&(key.0.clone(), key.1.clone(), None)), None);
+        // Tokens within the expiry margin are not reused
+        store_token(key.clone(), "t2".to_string(), 0);
+        assert_eq!(cached_token(&key), None);
+    }
 }
 
@@ -1,48 +1,35 @@
 use crate::engine::message::Message;
-use mlua::{Lua, StdLib, LuaOptions};
-use crate::lua_helpers;
+use crate::engine::lua_pool::LuaPool;
+use crate::lua_helpers::sandbox;
+use crate::storage::models::LuaLimits;
+use std::sync::Arc;
 
 pub struct LuaDestination {
-    code: String,
+    pool: Arc<LuaPool>,
 }
 
 impl LuaDestination {
-    pub fn new(code: String) -> Self {
-        Self { code }
+    pub fn new(code: String, limits: LuaLimits) -> anyhow::Result<Self> {
+        // Wrapped in a function same as processor
+        let pool = sandbox::build_pool("destination", &code, true, limits)?;
+        Ok(Self { pool: Arc::new(pool) })
     }
 
-    pub fn send(&self, msg: &Message) -> anyhow::Result<()> {
+    pub async fn send(&self, msg: &Message) -> anyhow::Result<()> {
         // Similar to LuaProcessor, but for side-effects (sending)
-        
-        let lua = Lua::new_with(
-            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8 | StdLib::COROUTINE | StdLib::PACKAGE,
-            LuaOptions::default()
-        ).map_err(|e| anyhow::anyhow!("Lua init error: {}", e))?;
-
-        // Helpers
-        lua_helpers::logging::register_logging(&lua)?;
-        lua_helpers::hl7::register_hl7(&lua)?;
-        lua_helpers::json::register_json(&lua)?;
-        
-        // Mock OS table for date/time (safe) - Reuse from LuaProcessor logic ideally, or duplicate for now
-        let globals = lua.globals();
-
-        // Msg object
-        let msg_table = lua.create_table()?;
-        msg_table.set("id", msg.id.to_string())?;
-        msg_table.set("content", msg.content.clone())?;
-        msg_table.set("origin", msg.origin.clone())?;
-        globals.set("msg", msg_table)?;
-
-        // Execute
-        // Wrapped in a function same as processor
-        let script = format!(
-            "local function run(msg)\n{}\nend\nreturn run(msg)", 
-            self.code
-        );
+        let (id, content, origin) = (msg.id.to_string(), msg.content.clone(), msg.origin.clone());
+        let metadata = msg.metadata.clone();
+        self.pool.execute(move |lua, script| {
+            // Msg object
+            let msg_table = lua.create_table()?;
+            msg_table.set("id", id)?;
+            msg_table.set("content", content)?;
+            msg_table.set("origin", origin)?;
+            // Includes the responses of earlier destinations (`response.<name>.status` / `.body`)
+            msg_table.set("metadata", lua.create_table_from(metadata)?)?;
+            lua.globals().set("msg", msg_table)?;
 
-        let _: () = lua.load(&script).eval()?; // Expect no return or verify success?
-        
-        Ok(())
+            script.call::<_, mlua::Value>(()).map(|_| ()) // Expect no return or verify success?
+        }).await
     }
 }
@@ -1,5 +1,121 @@
 pub mod http;
 pub mod file;
 pub mod tcp;
+pub mod tcp_connection;
 pub mod database;
 pub mod lua;
+pub mod template;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::engine::message::Message;
+use crate::storage::models::{DestinationConfig, DestinationType, LuaLimits};
+
+/// What the remote side answered: an HTTP response or an MLLP ACK
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    /// HTTP status code; absent for TCP senders
+    pub status: Option<u16>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ack(body: String) -> Self {
+        Self { status: None, body }
+    }
+}
+
+/// Stored as the destination response: the status line followed by the body
+impl std::fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) if self.body.is_empty() => write!(f, "HTTP {}", status),
+            Some(status) => write!(f, "HTTP {}\n\n{}", status, self.body),
+            None => f.write_str(&self.body),
+        }
+    }
+}
+
+/// The destinations of one channel with their Lua scripts compiled once, at deploy. Shared by
+/// the pipeline, its destination queues and retries, and dropped with the channel.
+#[derive(Clone, Default)]
+pub struct ChannelDestinations {
+    lua: HashMap<(String, LuaLimits), Arc<lua::LuaDestination>>,
+}
+
+impl ChannelDestinations {
+    pub fn build<'a>(destinations: impl IntoIterator<Item = &'a DestinationConfig>) -> anyhow::Result<Self> {
+        let mut compiled = Self::default();
+        for dest in destinations {
+            compiled.add(dest)?;
+        }
+        Ok(compiled)
+    }
+
+    /// Compile `dest` if it runs a script
+    pub fn add(&mut self, dest: &DestinationConfig) -> anyhow::Result<()> {
+        if let DestinationType::Lua { code, limits } = &dest.kind {
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.lua.entry((code.clone(), *limits)) {
+                let destination = lua::LuaDestination::new(code.clone(), *limits)
+                    .map_err(|e| anyhow::anyhow!("Destination {}: {}", dest.name, e))?;
+                entry.insert(Arc::new(destination));
+            }
+        }
+        Ok(())
+    }
+
+    /// The compiled script; one outside the deployed set (e.g. a test send) is compiled for this
+    /// use only
+    fn lua(&self, code: &str, limits: LuaLimits) -> anyhow::Result<Arc<lua::LuaDestination>> {
+        match self.lua.get(&(code.to_string(), limits)) {
+            Some(destination) => Ok(destination.clone()),
+            None => Ok(Arc::new(lua::LuaDestination::new(code.to_string(), limits)?)),
+        }
+    }
+}
+
+/// Send a message to a single configured destination, bounded by its `timeout_ms`.
+/// Returns the remote response when the destination produces one (HTTP response, MLLP ACK).
+pub async fn dispatch(dest: &DestinationConfig, msg: &Message, channel_name: &str, compiled: &ChannelDestinations) -> anyhow::Result<Option<Response>> {
+    match dest.timeout_ms {
+        Some(ms) => tokio::time::timeout(std::time::Duration::from_millis(ms), send(dest, msg, channel_name, compiled))
+            .await
+            .map_err(|_| anyhow::anyhow!("Destination {} timed out after {} ms", dest.name, ms))?,
+        None => send(dest, msg, channel_name, compiled).await,
+    }
+}
+
+async fn send(dest: &DestinationConfig, msg: &Message, channel_name: &str, compiled: &ChannelDestinations) -> anyhow::Result<Option<Response>> {
+    match &dest.kind {
+        DestinationType::File { path, filename, append, encoding } => {
+            let writer = file::FileWriter::new(path.clone(), filename.clone(), *append, encoding.clone(), channel_name.to_string());
+            writer.send(msg).await.map(|_| None)
+        },
+        DestinationType::Http { url, method, headers, query, auth, content_type } => {
+            let sender = http::HttpSender::new(url.clone(), method.clone(), channel_name.to_string())
+                .with_headers(headers.clone())
+                .with_query(query.clone())
+                .with_auth(auth.clone())
+                .with_content_type(content_type.clone());
+            sender.send(msg).await.map(Some)
+        },
+        DestinationType::Tcp { host, port, framing, keep_connection_open, tls, timeouts, response } => {
+            let sender = tcp::TcpSender::new(host.clone(), *port, channel_name.to_string())
+                .with_destination_id(&dest.id)
+                .with_framing(framing.clone())
+                .with_keep_connection_open(*keep_connection_open)
+                .with_tls(tls.clone())
+                .with_timeouts(*timeouts)
+                .with_response(*response);
+            sender.send(msg).await.map(|ack| ack.map(Response::ack))
+        },
+        DestinationType::Database { url, table, mode, query } => {
+            let writer = database::DatabaseWriter::new(url.clone(), table.clone(), mode.clone(), query.clone(), channel_name.to_string());
+            writer.send(msg).await.map(|_| None)
+        },
+        DestinationType::Lua { code, limits } => {
+            let destination = compiled.lua(code, *limits)?;
+            destination.send(msg).await.map(|_| None)
+        },
+    }
+}
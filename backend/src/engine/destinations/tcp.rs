@@ -1,90 +1,76 @@
 use crate::engine::message::Message;
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::time::Duration;
-use crate::engine::listeners::mllp::MllpFrameAccumulator;
+use crate::engine::destinations::tcp_connection::{ConnectionSettings, TcpConnection};
+use crate::storage::models::{Framing, TcpClientTls, TcpResponseMode, TcpTimeouts};
 
 pub struct TcpSender {
-    host: String,
-    port: u16,
+    destination_id: String,
     channel_name: String,
+    settings: ConnectionSettings,
 }
 
 impl TcpSender {
     pub fn new(host: String, port: u16, channel_name: String) -> Self {
         Self {
-            host,
-            port,
+            destination_id: String::new(),
             channel_name,
+            settings: ConnectionSettings {
+                host,
+                port,
+                framing: Framing::default(),
+                keep_open: false,
+                tls: None,
+                timeouts: TcpTimeouts::default(),
+                response: TcpResponseMode::default(),
+            },
         }
     }
 
-    pub async fn send(&self, msg: &Message) -> anyhow::Result<()> {
-        let addr = format!("{}:{}", self.host, self.port);
-        tracing::info!("Connecting to TCP destination {} for channel {}", addr, self.channel_name);
+    /// Destination whose connection this sender uses (one per channel and destination id)
+    pub fn with_destination_id(mut self, id: &str) -> Self {
+        self.destination_id = id.to_string();
+        self
+    }
 
-        // 1. Connect with Timeout
-        let mut stream = tokio::time::timeout(
-            Duration::from_secs(5),
-            TcpStream::connect(&addr)
-        ).await.map_err(|_| anyhow::anyhow!("Connection timeout to {}", addr))??;
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.settings.framing = framing;
+        self
+    }
 
-        // 2. Wrap in MLLP
-        // <SB>Data<EB><CR>
-        let mllp_frame = format!("\x0B{}\x1C\x0D", msg.content);
-        
-        // 3. Send
-        stream.write_all(mllp_frame.as_bytes()).await?;
-        
-        // 4. Wait for ACK with Timeout
-        let mut buffer = [0u8; 1024];
-        let read_result = tokio::time::timeout(
-            Duration::from_secs(10), // 10s wait for ACK
-            stream.read(&mut buffer)
-        ).await;
-
-        match read_result {
-            Ok(Ok(n)) => {
-                if n == 0 {
-                    return Err(anyhow::anyhow!("Connection closed by remote host before ACK"));
-                }
-                
-                // Parse ACK using MLLP Accumulator logic?
-                // For simplified sender, we just expect one ACK frame.
-                // But let's verify it starts with SB and ends with EB+CR?
-                // Or just assume it's the ACK.
-                
-                let data = &buffer[0..n];
-                // Simple validation
-                if data[0] != 0x0B {
-                     tracing::warn!("Response did not start with SB (0x0B). Raw: {:?}", data);
-                }
-                
-                let response = String::from_utf8_lossy(data);
-                if response.contains("|AA|") || response.contains("|CA|") {
-                    tracing::info!("Received positive ACK from {}", addr);
-                    Ok(())
-                } else if response.contains("|AE|") || response.contains("|CE|") || response.contains("|AR|") || response.contains("|CR|") {
-                     Err(anyhow::anyhow!("Received NACK/Reject from remote: {}", response))
-                } else {
-                    tracing::warn!("Received unknown response format: {}", response);
-                    // Assume OK if it's an ACK message?
-                    if response.contains("MSA|") {
-                         Ok(())
-                    } else {
-                         Err(anyhow::anyhow!("Invalid ACK response: {}", response))
-                    }
-                }
-            },
-            Ok(Err(e)) => Err(anyhow::anyhow!("Failed to read ACK: {}", e)),
-            Err(_) => Err(anyhow::anyhow!("Timeout waiting for ACK from {}", addr)),
-        }
+    pub fn with_keep_connection_open(mut self, keep_open: bool) -> Self {
+        self.settings.keep_open = keep_open;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: Option<TcpClientTls>) -> Self {
+        self.settings.tls = tls;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: TcpTimeouts) -> Self {
+        self.settings.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_response(mut self, response: TcpResponseMode) -> Self {
+        self.settings.response = response;
+        self
+    }
+
+    /// Send the framed message over the destination's connection and return the reply received
+    /// from the remote host, if the response mode expects one. ASTM transfers are acknowledged
+    /// frame by frame and return no ACK message.
+    pub async fn send(&self, msg: &Message) -> anyhow::Result<Option<String>> {
+        tracing::info!("Sending to TCP destination {}:{} for channel {}", self.settings.host, self.settings.port, self.channel_name);
+        let connection = TcpConnection::shared((msg.channel_id, self.destination_id.clone()), self.settings.clone())?;
+        connection.send(&msg.content).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
     use uuid::Uuid;
 
@@ -116,10 +102,40 @@ mod tests {
             metadata: std::collections::HashMap::new(),
             timestamp: chrono::Utc::now(),
             origin: None,
+            redelivery: false,
             response_tx: None,
         };
 
         let result = sender.send(&msg).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_tcp_send_length_prefixed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut len = [0u8; 2];
+            socket.read_exact(&mut len).await.unwrap();
+            let mut content = vec![0u8; u16::from_be_bytes(len) as usize];
+            socket.read_exact(&mut content).await.unwrap();
+            assert_eq!(content, b"MSH|^~\\&|Test");
+
+            // ACK split over two writes
+            let ack = b"MSH|^~\\&|||||||ACK||P|2.3\rMSA|AA|123";
+            socket.write_all(&(ack.len() as u16).to_be_bytes()).await.unwrap();
+            socket.write_all(&ack[..10]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            socket.write_all(&ack[10..]).await.unwrap();
+        });
+
+        let sender = TcpSender::new("127.0.0.1".to_string(), port, "TestChannel".to_string())
+            .with_framing(Framing::LengthPrefixed { width: 2 });
+        let msg = Message::new(Uuid::new_v4(), "MSH|^~\\&|Test".to_string(), "test".to_string());
+
+        let ack = sender.send(&msg).await.unwrap().unwrap();
+        assert!(ack.ends_with("MSA|AA|123"));
+    }
 }
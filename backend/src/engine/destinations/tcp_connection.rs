@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+use uuid::Uuid;
+use crate::engine::egress;
+use crate::engine::framing::{self, astm, Framer};
+use crate::storage::models::{Framing, TcpClientTls, TcpResponseMode, TcpTimeouts};
+
+/// First reconnect delay after a failed connection attempt; doubles up to `MAX_BACKOFF`
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A plain or TLS socket
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Everything that decides how a destination connects; a change replaces its connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSettings {
+    pub host: String,
+    pub port: u16,
+    pub framing: Framing,
+    pub keep_open: bool,
+    pub tls: Option<TcpClientTls>,
+    pub timeouts: TcpTimeouts,
+    pub response: TcpResponseMode,
+}
+
+struct Open {
+    stream: Box<dyn Stream>,
+    framer: Box<dyn Framer>,
+}
+
+#[derive(Default)]
+struct State {
+    open: Option<Open>,
+    /// Connection attempts that failed in a row
+    failures: u32,
+    /// No new attempt before this
+    retry_at: Option<Instant>,
+}
+
+/// The connection of one TCP destination. Messages go through it one at a time (each waits
+/// for its reply, unless the response mode is `none`); with `keep_open` the socket stays open
+/// between messages and is re-established when it drops, with exponential backoff after
+/// failed attempts.
+pub struct TcpConnection {
+    settings: ConnectionSettings,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    state: tokio::sync::Mutex<State>,
+}
+
+impl TcpConnection {
+    pub fn new(settings: ConnectionSettings) -> anyhow::Result<Self> {
+        framing::framer(&settings.framing, settings.timeouts.ack_ms)?;
+        let tls = match &settings.tls {
+            Some(tls) => Some(tls_connector(tls, &settings.host)?),
+            None => None,
+        };
+        Ok(Self { settings, tls, state: tokio::sync::Mutex::new(State::default()) })
+    }
+
+    /// Shared connection of the destination `key` (channel and destination id), rebuilt when
+    /// its settings change
+    pub fn shared(key: (Uuid, String), settings: ConnectionSettings) -> anyhow::Result<Arc<Self>> {
+        let registry = registry();
+        if let Some(connection) = registry.lock().unwrap().get(&key) {
+            if connection.settings == settings {
+                return Ok(connection.clone());
+            }
+        }
+        let connection = Arc::new(Self::new(settings)?);
+        registry.lock().unwrap().insert(key, connection.clone());
+        Ok(connection)
+    }
+
+    /// Drop the connections of a stopped channel; kept-open sockets close once no message is
+    /// using them any more
+    pub fn close_channel(channel_id: Uuid) {
+        registry().lock().unwrap().retain(|(channel, _), _| *channel != channel_id);
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.settings.host, self.settings.port)
+    }
+
+    /// Send one message and return the reply (none for ASTM, which acknowledges each frame, and
+    /// in `none` response mode)
+    pub async fn send(&self, content: &str) -> anyhow::Result<Option<String>> {
+        let mut state = self.state.lock().await;
+
+        // A kept-open socket the peer has closed in the meantime is replaced before writing
+        if state.open.as_mut().is_some_and(|open| is_closed(open.stream.as_mut())) {
+            tracing::info!("TCP connection to {} was closed by the peer, reconnecting", self.addr());
+            state.open = None;
+        }
+        let (mut open, reused) = match state.open.take() {
+            Some(open) => (open, true),
+            None => (self.connect(&mut state).await?, false),
+        };
+
+        let mut result = self.exchange(&mut open, content).await;
+        if let (true, Err(Failure::Write(e))) = (reused, &result) {
+            // The kept-open socket went stale without us noticing; nothing was delivered
+            tracing::warn!("Write to {} failed on a reused connection ({}), reconnecting", self.addr(), e);
+            open = self.connect(&mut state).await?;
+            result = self.exchange(&mut open, content).await;
+        }
+        self.finish(&mut state, open, &result);
+        result.map_err(Failure::into_error)
+    }
+
+    /// Keep the socket for the next message only if it is still usable and wanted
+    fn finish(&self, state: &mut State, open: Open, result: &Result<Option<String>, Failure>) {
+        if self.settings.keep_open && !matches!(result, Err(Failure::Write(_) | Failure::Read(_))) {
+            state.open = Some(open);
+        }
+    }
+
+    async fn connect(&self, state: &mut State) -> anyhow::Result<Open> {
+        if let Some(retry_at) = state.retry_at {
+            tokio::time::sleep_until(retry_at.into()).await;
+        }
+        match self.open().await {
+            Ok(open) => {
+                state.failures = 0;
+                state.retry_at = None;
+                Ok(open)
+            },
+            Err(e) => {
+                let backoff = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(state.failures)).min(MAX_BACKOFF);
+                state.failures += 1;
+                state.retry_at = Some(Instant::now() + backoff);
+                Err(e)
+            },
+        }
+    }
+
+    async fn open(&self) -> anyhow::Result<Open> {
+        let addr = self.addr();
+        let timeout = Duration::from_millis(self.settings.timeouts.connect_ms);
+        let connect = async {
+            let addrs = egress::resolve(&self.settings.host, self.settings.port).await?;
+            let socket = TcpStream::connect(&addrs[..]).await?;
+            let stream: Box<dyn Stream> = match &self.tls {
+                Some((connector, server_name)) => Box::new(connector.connect(server_name.clone(), socket).await?),
+                None => Box::new(socket),
+            };
+            anyhow::Ok(stream)
+        };
+        let stream = tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| anyhow::anyhow!("Connection timeout to {}", addr))?
+            .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", addr, e))?;
+        tracing::info!("Connected to TCP destination {}{}", addr, if self.tls.is_some() { " (TLS)" } else { "" });
+        let framer = framing::framer(&self.settings.framing, self.settings.timeouts.ack_ms)?;
+        Ok(Open { stream, framer })
+    }
+
+    async fn exchange(&self, open: &mut Open, content: &str) -> Result<Option<String>, Failure> {
+        let addr = self.addr();
+        let timeouts = &self.settings.timeouts;
+
+        if self.settings.framing == Framing::Astm {
+            let transfer = astm::send(&mut open.stream, content);
+            return match tokio::time::timeout(Duration::from_millis(timeouts.send_ms + timeouts.ack_ms), transfer).await {
+                Ok(Ok(())) => Ok(None),
+                Ok(Err(e)) => Err(Failure::Read(e)),
+                Err(_) => Err(Failure::Read(anyhow::anyhow!("Timeout sending ASTM transfer to {}", addr))),
+            };
+        }
+
+        let bytes = open.framer.encode(content).map_err(Failure::Rejected)?;
+        match tokio::time::timeout(Duration::from_millis(timeouts.send_ms), open.stream.write_all(&bytes)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => return Err(Failure::Write(e.into())),
+            Err(_) => return Err(Failure::Read(anyhow::anyhow!("Timeout sending to {}", addr))),
+        }
+        if self.settings.response == TcpResponseMode::None {
+            return Ok(None);
+        }
+
+        // Read until the framer yields one complete reply
+        let read_ack = async {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let n = open.stream.read(&mut buffer).await?;
+                if n == 0 {
+                    return Err(anyhow::anyhow!("Connection closed by remote host before ACK"));
+                }
+                if let Some(ack) = open.framer.feed(&buffer[..n]).into_iter().next() {
+                    return Ok(ack);
+                }
+            }
+        };
+        let ack = tokio::time::timeout(Duration::from_millis(timeouts.ack_ms), read_ack)
+            .await
+            .map_err(|_| Failure::Read(anyhow::anyhow!("Timeout waiting for ACK from {}", addr)))?
+            .map_err(|e| Failure::Read(anyhow::anyhow!("Failed to read ACK: {}", e)))?;
+        match self.settings.response {
+            TcpResponseMode::Hl7Ack => check_ack(&addr, ack).map(Some).map_err(Failure::Rejected),
+            _ => Ok(Some(ack)),
+        }
+    }
+}
+
+type Registry = Mutex<HashMap<(Uuid, String), Arc<TcpConnection>>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// How an exchange failed, which decides what happens to the socket
+enum Failure {
+    /// Writing failed: nothing was delivered
+    Write(anyhow::Error),
+    /// The exchange broke off after writing (read error, timeout): the socket is out of step
+    Read(anyhow::Error),
+    /// A complete exchange with a negative or invalid answer: the socket is fine
+    Rejected(anyhow::Error),
+}
+
+impl Failure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Self::Write(e) | Self::Read(e) | Self::Rejected(e) => e,
+        }
+    }
+}
+
+/// Whether the peer has closed an idle socket (stray bytes, such as a late ACK, are dropped)
+fn is_closed(stream: &mut dyn Stream) -> bool {
+    let mut buffer = [0u8; 1024];
+    loop {
+        match stream.read(&mut buffer).now_or_never() {
+            None => return false,
+            Some(Ok(0)) | Some(Err(_)) => return true,
+            Some(Ok(_)) => continue,
+        }
+    }
+}
+
+fn check_ack(addr: &str, ack: String) -> anyhow::Result<String> {
+    if ack.contains("|AA|") || ack.contains("|CA|") {
+        tracing::info!("Received positive ACK from {}", addr);
+        Ok(ack)
+    } else if ack.contains("|AE|") || ack.contains("|CE|") || ack.contains("|AR|") || ack.contains("|CR|") {
+        Err(anyhow::anyhow!("Received NACK/Reject from remote: {}", ack))
+    } else {
+        tracing::warn!("Received unknown response format: {}", ack);
+        // Assume OK if it's an ACK message?
+        if ack.contains("MSA|") {
+            Ok(ack)
+        } else {
+            Err(anyhow::anyhow!("Invalid ACK response: {}", ack))
+        }
+    }
+}
+
+fn tls_connector(tls: &TcpClientTls, host: &str) -> anyhow::Result<(TlsConnector, ServerName<'static>)> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_path {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| anyhow::anyhow!("Cannot read CA file {}: {}", path, e))?;
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                roots.add(cert?)?;
+            }
+            if roots.is_empty() {
+                return Err(anyhow::anyhow!("No certificate found in CA file {}", path));
+            }
+        },
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder = ClientConfig::builder_with_provider(crate::config::tls_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match (&tls.cert_path, &tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let file = File::open(cert_path).map_err(|e| anyhow::anyhow!("Cannot read client certificate {}: {}", cert_path, e))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+            let file = File::open(key_path).map_err(|e| anyhow::anyhow!("Cannot read client key {}: {}", key_path, e))?;
+            let key = rustls_pemfile::private_key(&mut BufReader::new(file))?
+                .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path))?;
+            builder.with_client_auth_cert(certs, key)?
+        },
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(anyhow::anyhow!("TLS cert_path and key_path must be set together")),
+    };
+
+    let name = tls.server_name.clone().unwrap_or_else(|| host.to_string());
+    let server_name = ServerName::try_from(name.clone())
+        .map_err(|_| anyhow::anyhow!("Invalid TLS server name '{}'", name))?;
+    Ok((TlsConnector::from(Arc::new(config)), server_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::listeners::mllp::{frame, generate_ack, MllpFrameAccumulator};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::{ServerConfig, server::WebPkiClientVerifier};
+
+    const HL7: &str = "MSH|^~\\&|A|B|C|D|20240101||ADT^A01|MSG1|P|2.5";
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn settings(port: u16, keep_open: bool) -> ConnectionSettings {
+        ConnectionSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            framing: Framing::Mllp,
+            keep_open,
+            tls: None,
+            timeouts: TcpTimeouts { connect_ms: 1000, send_ms: 1000, ack_ms: 1000 },
+            response: TcpResponseMode::Hl7Ack,
+        }
+    }
+
+    /// Answers every MLLP message with an AA and hangs up after `per_connection` messages
+    async fn serve<S>(mut socket: S, per_connection: usize)
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut acc = MllpFrameAccumulator::new(1000);
+        let mut buf = [0u8; 1024];
+        let mut answered = 0;
+        while answered < per_connection {
+            let n = socket.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            for message in acc.feed(&buf[..n]) {
+                socket.write_all(generate_ack(&message).as_bytes()).await.unwrap();
+                answered += 1;
+            }
+        }
+    }
+
+    /// MLLP server on a free port, with its count of accepted connections
+    async fn mllp_server(per_connection: usize) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(serve(socket, per_connection));
+            }
+        });
+        (port, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_keep_open_reuses_one_connection() {
+        let (port, accepted) = mllp_server(usize::MAX).await;
+        let connection = TcpConnection::new(settings(port, true)).unwrap();
+        for _ in 0..3 {
+            let ack = connection.send(HL7).await.unwrap().unwrap();
+            assert!(ack.contains("MSA|AA|MSG1"));
+        }
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let (port, accepted) = mllp_server(usize::MAX).await;
+        let connection = TcpConnection::new(settings(port, false)).unwrap();
+        for _ in 0..3 {
+            connection.send(HL7).await.unwrap();
+        }
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_peer_closes() {
+        // The server hangs up after every ACK
+        let (port, accepted) = mllp_server(1).await;
+        let connection = TcpConnection::new(settings(port, true)).unwrap();
+        for _ in 0..3 {
+            connection.send(HL7).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_ack_split_across_reads_and_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await.unwrap();
+            let ack = frame("MSH|^~\\&|||||||ACK||P|2.5\rMSA|AA|MSG1");
+            let (first, rest) = ack.as_bytes().split_at(12);
+            socket.write_all(first).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            socket.write_all(rest).await.unwrap();
+        });
+        let connection = TcpConnection::new(settings(port, false)).unwrap();
+        assert!(connection.send(HL7).await.unwrap().unwrap().ends_with("MSA|AA|MSG1"));
+
+        // Nothing listens any more: failures push the next attempt back
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let connection = TcpConnection::new(settings(closed, true)).unwrap();
+        assert!(connection.send(HL7).await.is_err());
+        let started = Instant::now();
+        assert!(connection.send(HL7).await.is_err());
+        assert!(started.elapsed() >= INITIAL_BACKOFF - Duration::from_millis(50));
+        assert_eq!(connection.state.lock().await.failures, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_channel_releases_its_connection() {
+        let (port, accepted) = mllp_server(usize::MAX).await;
+        let key = (Uuid::new_v4(), "his".to_string());
+        let connection = TcpConnection::shared(key.clone(), settings(port, true)).unwrap();
+        connection.send(HL7).await.unwrap();
+        assert!(Arc::ptr_eq(&connection, &TcpConnection::shared(key.clone(), settings(port, true)).unwrap()));
+
+        TcpConnection::close_channel(key.0);
+        assert!(!registry().lock().unwrap().contains_key(&key));
+        drop(connection);
+        TcpConnection::shared(key.clone(), settings(port, true)).unwrap().send(HL7).await.unwrap();
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 2);
+        TcpConnection::close_channel(key.0);
+    }
+
+    #[tokio::test]
+    async fn test_response_modes_for_raw_streams() {
+        // A delimited peer that reads everything and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = socket.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                received_tx.send(buf[..n].to_vec()).unwrap();
+            }
+        });
+        let silent = ConnectionSettings {
+            framing: Framing::Delimited { start: vec![], end: vec![b'\n'] },
+            keep_open: true,
+            response: TcpResponseMode::None,
+            ..settings(port, true)
+        };
+        let connection = TcpConnection::new(silent).unwrap();
+        let started = Instant::now();
+        assert_eq!(connection.send("reading=42").await.unwrap(), None);
+        assert_eq!(connection.send("reading=43").await.unwrap(), None);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        let mut received = Vec::new();
+        while received.len() < 22 {
+            received.extend(received_rx.recv().await.unwrap());
+        }
+        assert_eq!(received, b"reading=42\nreading=43\n");
+
+        // A peer answering with something that is not an HL7 ACK
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await.unwrap();
+                socket.write_all(b"OK 42\n").await.unwrap();
+            }
+        });
+        let raw = |response| ConnectionSettings {
+            framing: Framing::Delimited { start: vec![], end: vec![b'\n'] },
+            response,
+            ..settings(port, false)
+        };
+        let connection = TcpConnection::new(raw(TcpResponseMode::Raw)).unwrap();
+        assert_eq!(connection.send("reading=42").await.unwrap().as_deref(), Some("OK 42"));
+        let connection = TcpConnection::new(raw(TcpResponseMode::Hl7Ack)).unwrap();
+        assert!(connection.send("reading=42").await.unwrap_err().to_string().contains("Invalid ACK"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_with_client_certificate() {
+        let load_certs = |name: &str| rustls_pemfile::certs(&mut BufReader::new(File::open(fixture(name)).unwrap()))
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(load_certs("ca.pem").remove(0)).unwrap();
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(client_roots), crate::config::tls_provider()).build().unwrap();
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(fixture("server.key")).unwrap())).unwrap().unwrap();
+        let config = ServerConfig::builder_with_provider(crate::config::tls_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs("server.pem"), key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                if let Ok(stream) = acceptor.accept(socket).await {
+                    tokio::spawn(serve(stream, usize::MAX));
+                }
+            }
+        });
+
+        let mut with_cert = settings(port, true);
+        with_cert.tls = Some(TcpClientTls {
+            ca_path: Some(fixture("ca.pem")),
+            cert_path: Some(fixture("client.pem")),
+            key_path: Some(fixture("client.key")),
+            server_name: Some("localhost".to_string()),
+        });
+        let connection = TcpConnection::new(with_cert.clone()).unwrap();
+        assert!(connection.send(HL7).await.unwrap().unwrap().contains("MSA|AA|MSG1"));
+        assert!(connection.send(HL7).await.is_ok());
+
+        // The server requires a client certificate
+        let mut without_cert = with_cert.clone();
+        without_cert.tls.as_mut().unwrap().cert_path = None;
+        without_cert.tls.as_mut().unwrap().key_path = None;
+        assert!(TcpConnection::new(without_cert).unwrap().send(HL7).await.is_err());
+
+        let mut half = with_cert;
+        half.tls.as_mut().unwrap().key_path = None;
+        assert!(TcpConnection::new(half).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use chrono::Utc;
+use crate::engine::message::Message;
+
+/// Replace `${name}` variables in `template`, passing each value through `encode`.
+///
+/// Variables are `id`, `channel`, `channel_id`, `origin`, `timestamp`, `date` and any key of
+/// the message metadata (e.g. `${response.Lookup.body}`). Unknown variables are left as they are.
+pub fn render(template: &str, msg: &Message, channel_name: &str, encode: fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &after[..end];
+        match value(name, msg, channel_name) {
+            Some(value) => out.push_str(&encode(&value)),
+            None => out.push_str(&rest[start..start + end + 3]),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Leave values unchanged (headers)
+pub fn raw(value: &str) -> String {
+    value.to_string()
+}
+
+/// Percent-encode values placed in a URL path or query
+pub fn url_encoded(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>().replace('+', "%20")
+}
+
+fn value(name: &str, msg: &Message, channel_name: &str) -> Option<String> {
+    match name {
+        "id" => Some(msg.id.to_string()),
+        "channel" => Some(channel_name.to_string()),
+        "channel_id" => Some(msg.channel_id.to_string()),
+        "origin" => Some(msg.origin.clone().unwrap_or_default()),
+        "timestamp" => Some(Utc::now().format("%Y%m%d-%H%M%S").to_string()),
+        "date" => Some(Utc::now().format("%Y-%m-%d").to_string()),
+        _ => msg.metadata.get(name).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_render_variables() {
+        let mut msg = Message::new(Uuid::new_v4(), "{}".to_string(), "api".to_string());
+        msg.metadata.insert("patient".to_string(), "A&B 1".to_string());
+
+        let rendered = render("/p/${patient}?from=${origin}&c=${channel}", &msg, "ADT", url_encoded);
+        assert_eq!(rendered, "/p/A%26B%201?from=api&c=ADT");
+        assert_eq!(render("${id}", &msg, "ADT", raw), msg.id.to_string());
+        assert_eq!(render("x ${unknown} ${patient", &msg, "ADT", raw), "x ${unknown} ${patient");
+    }
+}
@@ -0,0 +1,280 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use url::Url;
+
+/// Cloud metadata endpoints; blocked whatever the allow list says
+const METADATA_HOSTS: &[&str] = &[
+    "metadata.google.internal",  // GCP
+    "metadata.azure.com",        // Azure
+];
+const METADATA_IPS: &[IpAddr] = &[
+    IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),               // AWS, GCP, Azure
+    IpAddr::V4(Ipv4Addr::new(100, 100, 100, 200)),               // Alibaba
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)), // AWS IPv6
+];
+
+/// What happens to destinations neither list matches
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DefaultRule {
+    /// Public addresses are allowed; private, loopback and link-local ones are blocked
+    #[default]
+    Public,
+    /// Only destinations on the allow list are reachable
+    Deny,
+}
+
+/// An allow or deny entry: a CIDR range (a bare IP is a single address) or a hostname,
+/// where `*.example.org` matches every subdomain of example.org
+#[derive(Debug, Clone, PartialEq)]
+enum Rule {
+    Net(IpNet),
+    Host(String),
+}
+
+impl Rule {
+    fn parse(entry: &str) -> anyhow::Result<Self> {
+        if let Ok(net) = entry.parse::<IpNet>() {
+            return Ok(Rule::Net(net));
+        }
+        if let Ok(ip) = entry.parse::<IpAddr>() {
+            return Ok(Rule::Net(IpNet::from(ip)));
+        }
+        let host = entry.to_ascii_lowercase();
+        let name = host.strip_prefix("*.").unwrap_or(&host);
+        if name.is_empty() || name.contains(['/', '*', ':']) {
+            return Err(anyhow::anyhow!("Invalid egress rule '{}': expected a CIDR range, an IP or a hostname", entry));
+        }
+        Ok(Rule::Host(host))
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Rule::Host(pattern) => match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+                None => host == pattern,
+            },
+            Rule::Net(_) => false,
+        }
+    }
+
+    fn matches_ip(&self, ip: &IpAddr) -> bool {
+        matches!(self, Rule::Net(net) if net.contains(ip))
+    }
+}
+
+/// Server-wide policy for outbound connections of destinations (HTTP, TCP, database).
+/// Set with `EGRESS_ALLOW` and `EGRESS_DENY` (comma-separated CIDR ranges, IPs and hostnames)
+/// and `EGRESS_DEFAULT` (`public` or `deny`). Cloud metadata endpoints are always blocked,
+/// then the deny list wins over the allow list, then `default` decides.
+#[derive(Debug, Clone, Default)]
+pub struct EgressPolicy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    default: DefaultRule,
+}
+
+impl EgressPolicy {
+    pub fn new(allow: &str, deny: &str, default: DefaultRule) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: Self::rules(allow)?,
+            deny: Self::rules(deny)?,
+            default,
+        })
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let default = match std::env::var("EGRESS_DEFAULT").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "public" => DefaultRule::Public,
+            "deny" => DefaultRule::Deny,
+            other => return Err(anyhow::anyhow!("Invalid EGRESS_DEFAULT '{}': expected 'public' or 'deny'", other)),
+        };
+        Self::new(
+            &std::env::var("EGRESS_ALLOW").unwrap_or_default(),
+            &std::env::var("EGRESS_DENY").unwrap_or_default(),
+            default,
+        )
+    }
+
+    fn rules(list: &str) -> anyhow::Result<Vec<Rule>> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Rule::parse)
+            .collect()
+    }
+
+    /// Check a destination host and the addresses it resolved to
+    pub fn check(&self, host: &str, addrs: &[IpAddr]) -> anyhow::Result<()> {
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+        let mut addrs = addrs.to_vec();
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            addrs.push(ip);
+        }
+        if host == "localhost" || host.ends_with(".localhost") {
+            addrs.push(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        let addrs: Vec<IpAddr> = addrs.into_iter().map(canonical).collect();
+
+        if METADATA_HOSTS.iter().any(|blocked| host == *blocked || host.ends_with(&format!(".{}", blocked)))
+            || addrs.iter().any(|ip| METADATA_IPS.contains(ip))
+        {
+            return Err(anyhow::anyhow!("Access to cloud metadata endpoint '{}' is blocked", host));
+        }
+        if let Some(ip) = addrs.iter().find(|ip| self.deny.iter().any(|rule| rule.matches_ip(ip))) {
+            return Err(anyhow::anyhow!("Access to '{}' ({}) is denied by the egress policy", host, ip));
+        }
+        if self.deny.iter().any(|rule| rule.matches_host(&host)) {
+            return Err(anyhow::anyhow!("Access to '{}' is denied by the egress policy", host));
+        }
+        if self.allow.iter().any(|rule| rule.matches_host(&host)) {
+            return Ok(());
+        }
+
+        for ip in &addrs {
+            let allowed = self.allow.iter().any(|rule| rule.matches_ip(ip))
+                || (self.default == DefaultRule::Public && is_public(ip));
+            if !allowed {
+                return Err(anyhow::anyhow!(
+                    "Access to '{}' ({}) is blocked by the egress policy; add it to EGRESS_ALLOW to permit it",
+                    host, ip
+                ));
+            }
+        }
+        if addrs.is_empty() && self.default == DefaultRule::Deny {
+            return Err(anyhow::anyhow!("Access to '{}' is blocked by the egress policy; add it to EGRESS_ALLOW to permit it", host));
+        }
+        Ok(())
+    }
+}
+
+static POLICY: OnceLock<EgressPolicy> = OnceLock::new();
+
+/// Install the server's policy; done once at startup, before any destination runs
+pub fn install(policy: EgressPolicy) -> anyhow::Result<()> {
+    POLICY.set(policy).map_err(|_| anyhow::anyhow!("Egress policy already installed"))
+}
+
+pub fn policy() -> &'static EgressPolicy {
+    POLICY.get_or_init(|| {
+        // Unit tests run their mock servers on 127.0.0.1
+        if cfg!(test) {
+            return EgressPolicy::new("127.0.0.0/8", "", DefaultRule::Public).unwrap_or_default();
+        }
+        EgressPolicy::default()
+    })
+}
+
+/// Resolve `host` and check it against the policy. Connect only to the returned addresses,
+/// so a DNS answer that changes after the check cannot redirect the connection.
+pub async fn resolve(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((bare, port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    let ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+    policy().check(host, &ips)?;
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("{} did not resolve to any address", host));
+    }
+    Ok(addrs)
+}
+
+/// Check the server of a database connection URL; file databases (SQLite) have none
+pub async fn check_database_url(url: &str) -> anyhow::Result<()> {
+    let Ok(parsed) = Url::parse(url) else {
+        return Ok(());
+    };
+    match parsed.host_str() {
+        Some(host) if !host.is_empty() => resolve(host, parsed.port().unwrap_or(0)).await.map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are checked as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// Not private, loopback, link-local, carrier-grade NAT, broadcast, multicast or unspecified
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => {
+            !(ipv4.is_private()
+                || ipv4.is_loopback()
+                || ipv4.is_link_local()
+                || ipv4.is_broadcast()
+                || ipv4.is_multicast()
+                || ipv4.is_unspecified()
+                || (ipv4.octets()[0] == 100 && (64..=127).contains(&ipv4.octets()[1])))
+        },
+        IpAddr::V6(ipv6) => {
+            let first = ipv6.segments()[0];
+            !(ipv6.is_loopback()
+                || ipv6.is_unspecified()
+                || ipv6.is_multicast()
+                || (first & 0xfe00) == 0xfc00  // unique local
+                || (first & 0xffc0) == 0xfe80) // link-local
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_default_blocks_private_addresses() {
+        let policy = EgressPolicy::default();
+        assert!(policy.check("localhost", &[]).is_err());
+        assert!(policy.check("127.0.0.1", &[]).is_err());
+        assert!(policy.check("his.hospital.local", &[ip("10.1.2.3")]).is_err());
+        assert!(policy.check("[::1]", &[]).is_err());
+        assert!(policy.check("mapped", &[ip("::ffff:192.168.0.1")]).is_err());
+        assert!(policy.check("api.example.com", &[ip("93.184.216.34")]).is_ok());
+        // Unresolvable names are left to the connection attempt
+        assert!(policy.check("api.example.com", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let policy = EgressPolicy::new("10.0.0.0/8, *.hospital.local", "10.9.0.0/16, lab.hospital.local", DefaultRule::Public).unwrap();
+        assert!(policy.check("his", &[ip("10.1.2.3")]).is_ok());
+        assert!(policy.check("pacs.hospital.local", &[ip("192.168.1.5")]).is_ok());
+        assert!(policy.check("hospital.local", &[ip("192.168.1.5")]).is_err());
+        // Deny wins over allow
+        assert!(policy.check("old", &[ip("10.9.1.1")]).is_err());
+        assert!(policy.check("lab.hospital.local", &[ip("10.1.1.1")]).is_err());
+        assert!(policy.check("api.example.com", &[ip("93.184.216.34")]).is_ok());
+
+        let strict = EgressPolicy::new("10.0.0.0/8", "", DefaultRule::Deny).unwrap();
+        assert!(strict.check("his", &[ip("10.1.2.3")]).is_ok());
+        assert!(strict.check("api.example.com", &[ip("93.184.216.34")]).is_err());
+        assert!(strict.check("api.example.com", &[]).is_err());
+    }
+
+    #[test]
+    fn test_metadata_cannot_be_allowed() {
+        let policy = EgressPolicy::new("0.0.0.0/0, ::/0, metadata.google.internal", "", DefaultRule::Public).unwrap();
+        assert!(policy.check("169.254.169.254", &[]).is_err());
+        assert!(policy.check("metadata.google.internal", &[]).is_err());
+        assert!(policy.check("rebind.example.com", &[ip("169.254.169.254")]).is_err());
+        assert!(policy.check("[fd00:ec2::254]", &[]).is_err());
+        assert!(policy.check("10.0.0.1", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(EgressPolicy::new("10.0.0.0/33", "", DefaultRule::Public).is_err());
+        assert!(EgressPolicy::new("*.", "", DefaultRule::Public).is_err());
+        assert!(EgressPolicy::new(" , 10.0.0.1 ,", "", DefaultRule::Public).is_ok());
+    }
+}
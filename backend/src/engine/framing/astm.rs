@@ -0,0 +1,313 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::{Activity, Framer, MAX_FRAME_BYTES};
+
+pub const ENQ: u8 = 0x05;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const EOT: u8 = 0x04;
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+const ETB: u8 = 0x17;
+const CR: u8 = 0x0D;
+const LF: u8 = 0x0A;
+
+/// Most text bytes in one frame (E1381 allows 247 bytes per frame, overhead included)
+const MAX_FRAME_TEXT: usize = 240;
+/// Times a frame is retransmitted after a NAK
+const MAX_RETRANSMITS: usize = 6;
+/// How long the sender waits for each ACK (E1381 timer: 15s)
+const ACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Waiting for ENQ
+    Idle,
+    /// Established, waiting for STX or EOT
+    Linked,
+    /// Inside a frame
+    Frame,
+}
+
+/// Receiver side of the ASTM E1381 low-level protocol: ENQ is answered with ACK, each frame
+/// (`STX FN text ETB|ETX C1 C2 CR LF`) with ACK or NAK (bad checksum or frame number), and EOT
+/// completes the message made of all frame texts.
+pub struct AstmFramer {
+    state: State,
+    frame: Vec<u8>,
+    message: Vec<u8>,
+    /// Frame number (0-7) the next frame must carry
+    expected: u8,
+    reply: Vec<u8>,
+    activity: Activity,
+}
+
+impl AstmFramer {
+    pub fn new(timeout_ms: u64) -> Self {
+        Self {
+            state: State::Idle,
+            frame: Vec::new(),
+            message: Vec::new(),
+            expected: 1,
+            reply: Vec::new(),
+            activity: Activity::new(timeout_ms),
+        }
+    }
+
+    /// Frame complete once the two checksum characters and CR LF follow ETB/ETX
+    fn frame_complete(&self) -> bool {
+        let n = self.frame.len();
+        n >= 6 && self.frame[n - 2] == CR && self.frame[n - 1] == LF && matches!(self.frame[n - 5], ETB | ETX)
+    }
+
+    /// Check a complete frame (without STX) and keep its text
+    fn accept_frame(&mut self) -> u8 {
+        let frame = std::mem::take(&mut self.frame);
+        let body = &frame[..frame.len() - 4];
+        let checksum = &frame[frame.len() - 4..frame.len() - 2];
+        if checksum != format!("{:02X}", sum(body)).as_bytes() {
+            tracing::warn!("ASTM frame with bad checksum, sending NAK");
+            return NAK;
+        }
+        let number = body[0].wrapping_sub(b'0');
+        if number == (self.expected + 7) % 8 {
+            // Retransmission of a frame we already have
+            return ACK;
+        }
+        if number != self.expected {
+            tracing::warn!("ASTM frame number {} out of sequence (expected {}), sending NAK", number, self.expected);
+            return NAK;
+        }
+        self.message.extend_from_slice(&body[1..body.len() - 1]);
+        self.expected = (self.expected + 1) % 8;
+        ACK
+    }
+
+    fn reset_link(&mut self) {
+        self.state = State::Idle;
+        self.frame.clear();
+        self.message.clear();
+        self.expected = 1;
+    }
+}
+
+impl Framer for AstmFramer {
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        self.activity.touch();
+        let mut messages = Vec::new();
+        for &byte in data {
+            match self.state {
+                State::Idle => {
+                    if byte == ENQ {
+                        self.reset_link();
+                        self.state = State::Linked;
+                        self.reply.push(ACK);
+                    }
+                },
+                State::Linked => match byte {
+                    STX => self.state = State::Frame,
+                    EOT => {
+                        if !self.message.is_empty() {
+                            messages.push(String::from_utf8_lossy(&self.message).into_owned());
+                        }
+                        self.reset_link();
+                    },
+                    ENQ => {
+                        // The sender restarted the link
+                        self.reset_link();
+                        self.state = State::Linked;
+                        self.reply.push(ACK);
+                    },
+                    _ => {},
+                },
+                State::Frame => {
+                    self.frame.push(byte);
+                    if self.frame_complete() {
+                        let answer = self.accept_frame();
+                        self.reply.push(answer);
+                        self.state = State::Linked;
+                    } else if self.frame.len() > MAX_FRAME_BYTES || self.message.len() > MAX_FRAME_BYTES {
+                        tracing::warn!("Dropping ASTM message larger than {} bytes", MAX_FRAME_BYTES);
+                        self.reset_link();
+                    }
+                },
+            }
+        }
+        messages
+    }
+
+    fn take_reply(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.reply)
+    }
+
+    /// The whole exchange without waiting for ACKs; senders use `send`
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>> {
+        let mut out = vec![ENQ];
+        for frame in frames(content) {
+            out.extend(frame);
+        }
+        out.push(EOT);
+        Ok(out)
+    }
+
+    fn check_timeout(&mut self) -> bool {
+        if self.state != State::Idle && self.activity.expired() {
+            self.reset_link();
+            return true;
+        }
+        false
+    }
+
+    fn link_acknowledged(&self) -> bool {
+        true
+    }
+}
+
+/// Modulo-256 sum of the frame number, text and ETB/ETX
+fn sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Frames for `content`: one record per line, each ending in CR, split into frames of at most
+/// 240 text bytes (ETB) with the last one closed by ETX; frame numbers run 1-7, 0, 1...
+pub fn frames(content: &str) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut number = 1u8;
+    for record in content.split(['\r', '\n']).filter(|r| !r.is_empty()) {
+        let mut text = record.as_bytes().to_vec();
+        text.push(CR);
+        let chunks: Vec<&[u8]> = text.chunks(MAX_FRAME_TEXT).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut body = vec![b'0' + number];
+            body.extend_from_slice(chunk);
+            body.push(if i + 1 == chunks.len() { ETX } else { ETB });
+            let mut frame = vec![STX];
+            frame.extend_from_slice(&body);
+            frame.extend_from_slice(format!("{:02X}", sum(&body)).as_bytes());
+            frame.extend_from_slice(&[CR, LF]);
+            frames.push(frame);
+            number = (number + 1) % 8;
+        }
+    }
+    frames
+}
+
+/// Sender side: establish the link with ENQ, send each frame until it is ACKed (retransmitting
+/// after a NAK) and release the link with EOT
+pub async fn send<S>(stream: &mut S, content: &str) -> anyhow::Result<()>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.write_all(&[ENQ]).await?;
+    match read_answer(stream).await? {
+        ACK => {},
+        NAK => return Err(anyhow::anyhow!("ASTM receiver is busy (NAK to ENQ)")),
+        other => return Err(anyhow::anyhow!("Unexpected ASTM answer 0x{:02X} to ENQ", other)),
+    }
+
+    for (n, frame) in frames(content).iter().enumerate() {
+        let mut attempts = 0;
+        loop {
+            stream.write_all(frame).await?;
+            match read_answer(stream).await? {
+                ACK => break,
+                NAK if attempts < MAX_RETRANSMITS => attempts += 1,
+                NAK => {
+                    stream.write_all(&[EOT]).await?;
+                    return Err(anyhow::anyhow!("ASTM frame {} rejected {} times", n + 1, attempts + 1));
+                },
+                EOT => return Err(anyhow::anyhow!("ASTM receiver interrupted the transfer")),
+                other => return Err(anyhow::anyhow!("Unexpected ASTM answer 0x{:02X}", other)),
+            }
+        }
+    }
+    stream.write_all(&[EOT]).await?;
+    Ok(())
+}
+
+async fn read_answer<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<u8> {
+    let mut byte = [0u8; 1];
+    match tokio::time::timeout(ACK_TIMEOUT, stream.read(&mut byte)).await {
+        Ok(Ok(0)) => Err(anyhow::anyhow!("ASTM receiver closed the connection")),
+        Ok(Ok(_)) => Ok(byte[0]),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(anyhow::anyhow!("Timeout waiting for ASTM ACK")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESULT: &str = "H|\\^&|||Analyzer\rR|1|^^^GLU|5.4|mmol/L\rL|1|N";
+
+    #[test]
+    fn test_frames_and_checksum() {
+        let frames = frames("H|\\^&\r");
+        assert_eq!(frames.len(), 1);
+        // "1H|\^&<CR><ETX>" sums to 0x1E5
+        assert_eq!(frames[0], b"\x021H|\\^&\r\x03E5\r\n");
+
+        let long = "R|".to_string() + &"x".repeat(500);
+        let frames = super::frames(&long);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0][frames[0].len() - 5], ETB);
+        assert_eq!(frames[2][frames[2].len() - 5], ETX);
+        assert_eq!(frames[2][1], b'3');
+    }
+
+    #[test]
+    fn test_receiver_acks_and_assembles() {
+        let mut framer = AstmFramer::new(1000);
+        let encoded = framer.encode(RESULT).unwrap();
+        let messages = framer.feed(&encoded);
+        assert_eq!(messages, vec![format!("{}\r", RESULT)]);
+        // ENQ + 3 frames
+        assert_eq!(framer.take_reply(), vec![ACK; 4]);
+    }
+
+    #[test]
+    fn test_receiver_naks_bad_checksum() {
+        let mut framer = AstmFramer::new(1000);
+        let mut frame = frames("H|x\r").remove(0);
+        let good = frame.clone();
+        let n = frame.len();
+        frame[n - 3] = b'0';
+        framer.feed(&[ENQ]);
+        framer.feed(&frame);
+        assert_eq!(framer.take_reply(), vec![ACK, NAK]);
+        framer.feed(&good);
+        // Retransmitted frame
+        framer.feed(&good);
+        assert_eq!(framer.take_reply(), vec![ACK, ACK]);
+        assert_eq!(framer.feed(&[EOT]), vec!["H|x\r"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let receiver = tokio::spawn(async move {
+            let mut framer = AstmFramer::new(1000);
+            let mut buf = [0u8; 512];
+            let mut first_frame = true;
+            loop {
+                let n = server.read(&mut buf).await.unwrap();
+                let mut messages = framer.feed(&buf[..n]);
+                let mut reply = framer.take_reply();
+                // NAK the first frame once to force a retransmission
+                if buf[0] == STX && first_frame {
+                    first_frame = false;
+                    reply = vec![NAK];
+                    framer = AstmFramer::new(1000);
+                    framer.feed(&[ENQ]);
+                    framer.take_reply();
+                }
+                server.write_all(&reply).await.unwrap();
+                if let Some(message) = messages.pop() {
+                    return message;
+                }
+            }
+        });
+        send(&mut client, RESULT).await.unwrap();
+        assert_eq!(receiver.await.unwrap(), format!("{}\r", RESULT));
+    }
+}
@@ -0,0 +1,336 @@
+pub mod astm;
+
+use std::time::Instant;
+use crate::engine::listeners::mllp::{self, MllpFrameAccumulator};
+use crate::storage::models::Framing;
+
+/// Largest message the non-MLLP framers accept (16 MB)
+pub const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+/// Splits a received byte stream into messages and wraps outgoing ones, for one connection
+pub trait Framer: Send + Sync {
+    /// Feed received bytes; returns the messages they complete
+    fn feed(&mut self, data: &[u8]) -> Vec<String>;
+
+    /// Bytes to answer right away (ASTM link-level ACK/NAK)
+    fn take_reply(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Bytes carrying one outgoing message
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Drop a partial message that has been pending for longer than the timeout; returns
+    /// whether one was dropped
+    fn check_timeout(&mut self) -> bool;
+
+    /// Whether the framing acknowledges messages itself, so no HL7 ACK is sent back
+    fn link_acknowledged(&self) -> bool {
+        false
+    }
+}
+
+/// Framer for a connection; fails on settings that cannot work (empty end sequence, ...)
+pub fn framer(framing: &Framing, timeout_ms: u64) -> anyhow::Result<Box<dyn Framer>> {
+    Ok(match framing {
+        Framing::Mllp => Box::new(MllpFrameAccumulator::new(timeout_ms)),
+        Framing::Delimited { start, end } => {
+            if end.is_empty() {
+                return Err(anyhow::anyhow!("Delimited framing needs an end sequence"));
+            }
+            Box::new(DelimitedFramer::new(start.clone(), end.clone(), timeout_ms))
+        },
+        Framing::LengthPrefixed { width } => {
+            if *width != 2 && *width != 4 {
+                return Err(anyhow::anyhow!("Length prefix width must be 2 or 4 bytes, got {}", width));
+            }
+            Box::new(LengthPrefixedFramer::new(*width as usize, timeout_ms))
+        },
+        Framing::Astm => Box::new(astm::AstmFramer::new(timeout_ms)),
+        Framing::Fixed { size, padding } => {
+            if *size == 0 || *size > MAX_FRAME_BYTES {
+                return Err(anyhow::anyhow!("Fixed record size must be between 1 and {} bytes", MAX_FRAME_BYTES));
+            }
+            Box::new(FixedFramer::new(*size, *padding, timeout_ms))
+        },
+    })
+}
+
+/// Time of the last received bytes, for partial-message timeouts
+struct Activity {
+    last: Instant,
+    timeout_ms: u64,
+}
+
+impl Activity {
+    fn new(timeout_ms: u64) -> Self {
+        Self { last: Instant::now(), timeout_ms }
+    }
+
+    fn touch(&mut self) {
+        self.last = Instant::now();
+    }
+
+    fn expired(&self) -> bool {
+        self.last.elapsed().as_millis() as u64 > self.timeout_ms
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl Framer for MllpFrameAccumulator {
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        MllpFrameAccumulator::feed(self, data)
+    }
+
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(mllp::frame(content).into_bytes())
+    }
+
+    fn check_timeout(&mut self) -> bool {
+        MllpFrameAccumulator::check_timeout(self)
+    }
+}
+
+/// `start` (optional) + message + `end`
+pub struct DelimitedFramer {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    buffer: Vec<u8>,
+    in_frame: bool,
+    activity: Activity,
+}
+
+impl DelimitedFramer {
+    pub fn new(start: Vec<u8>, end: Vec<u8>, timeout_ms: u64) -> Self {
+        Self { start, end, buffer: Vec::new(), in_frame: false, activity: Activity::new(timeout_ms) }
+    }
+}
+
+impl Framer for DelimitedFramer {
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        self.activity.touch();
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+        loop {
+            if !self.in_frame {
+                if self.start.is_empty() {
+                    self.in_frame = true;
+                } else if let Some(pos) = find(&self.buffer, &self.start) {
+                    self.buffer.drain(..pos + self.start.len());
+                    self.in_frame = true;
+                } else {
+                    // Bytes outside a frame are dropped, except a possible partial start sequence
+                    let keep = self.buffer.len().min(self.start.len() - 1);
+                    self.buffer.drain(..self.buffer.len() - keep);
+                    break;
+                }
+            }
+            match find(&self.buffer, &self.end) {
+                Some(pos) => {
+                    messages.push(String::from_utf8_lossy(&self.buffer[..pos]).into_owned());
+                    self.buffer.drain(..pos + self.end.len());
+                    self.in_frame = false;
+                },
+                None => {
+                    if self.buffer.len() > MAX_FRAME_BYTES {
+                        tracing::warn!("Dropping delimited frame larger than {} bytes", MAX_FRAME_BYTES);
+                        self.buffer.clear();
+                        self.in_frame = false;
+                    }
+                    break;
+                },
+            }
+        }
+        messages
+    }
+
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>> {
+        let mut out = self.start.clone();
+        out.extend_from_slice(content.as_bytes());
+        out.extend_from_slice(&self.end);
+        Ok(out)
+    }
+
+    fn check_timeout(&mut self) -> bool {
+        let partial = if self.start.is_empty() { !self.buffer.is_empty() } else { self.in_frame };
+        if partial && self.activity.expired() {
+            self.buffer.clear();
+            self.in_frame = false;
+            return true;
+        }
+        false
+    }
+}
+
+/// Big-endian length (`width` bytes) + message
+pub struct LengthPrefixedFramer {
+    width: usize,
+    buffer: Vec<u8>,
+    activity: Activity,
+}
+
+impl LengthPrefixedFramer {
+    pub fn new(width: usize, timeout_ms: u64) -> Self {
+        Self { width, buffer: Vec::new(), activity: Activity::new(timeout_ms) }
+    }
+}
+
+impl Framer for LengthPrefixedFramer {
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        self.activity.touch();
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+        while self.buffer.len() >= self.width {
+            let length = self.buffer[..self.width].iter().fold(0usize, |n, b| (n << 8) | *b as usize);
+            if length > MAX_FRAME_BYTES {
+                // The stream cannot be resynchronised after a bogus length
+                tracing::warn!("Dropping buffered data: length prefix {} exceeds {} bytes", length, MAX_FRAME_BYTES);
+                self.buffer.clear();
+                break;
+            }
+            if self.buffer.len() < self.width + length {
+                break;
+            }
+            messages.push(String::from_utf8_lossy(&self.buffer[self.width..self.width + length]).into_owned());
+            self.buffer.drain(..self.width + length);
+        }
+        messages
+    }
+
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>> {
+        let length = content.len();
+        let max = if self.width == 2 { u16::MAX as usize } else { (u32::MAX as usize).min(MAX_FRAME_BYTES) };
+        if length > max {
+            return Err(anyhow::anyhow!("Message of {} bytes does not fit a {}-byte length prefix", length, self.width));
+        }
+        let mut out = (length as u32).to_be_bytes()[4 - self.width..].to_vec();
+        out.extend_from_slice(content.as_bytes());
+        Ok(out)
+    }
+
+    fn check_timeout(&mut self) -> bool {
+        if !self.buffer.is_empty() && self.activity.expired() {
+            self.buffer.clear();
+            return true;
+        }
+        false
+    }
+}
+
+/// Records of exactly `size` bytes; trailing `padding` is stripped on receipt
+pub struct FixedFramer {
+    size: usize,
+    padding: u8,
+    buffer: Vec<u8>,
+    activity: Activity,
+}
+
+impl FixedFramer {
+    pub fn new(size: usize, padding: u8, timeout_ms: u64) -> Self {
+        Self { size, padding, buffer: Vec::new(), activity: Activity::new(timeout_ms) }
+    }
+}
+
+impl Framer for FixedFramer {
+    fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        self.activity.touch();
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+        while self.buffer.len() >= self.size {
+            let record: Vec<u8> = self.buffer.drain(..self.size).collect();
+            let end = record.iter().rposition(|b| *b != self.padding).map_or(0, |i| i + 1);
+            messages.push(String::from_utf8_lossy(&record[..end]).into_owned());
+        }
+        messages
+    }
+
+    fn encode(&self, content: &str) -> anyhow::Result<Vec<u8>> {
+        if content.len() > self.size {
+            return Err(anyhow::anyhow!("Message of {} bytes exceeds the {}-byte record size", content.len(), self.size));
+        }
+        let mut out = content.as_bytes().to_vec();
+        out.resize(self.size, self.padding);
+        Ok(out)
+    }
+
+    fn check_timeout(&mut self) -> bool {
+        if !self.buffer.is_empty() && self.activity.expired() {
+            self.buffer.clear();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_bytewise(framer: &mut dyn Framer, data: &[u8]) -> Vec<String> {
+        data.iter().flat_map(|b| framer.feed(&[*b])).collect()
+    }
+
+    #[test]
+    fn test_framers_round_trip() {
+        let framings = [
+            Framing::Mllp,
+            Framing::Delimited { start: vec![0x02], end: vec![0x03] },
+            Framing::Delimited { start: vec![], end: vec![b'\r', b'\n'] },
+            Framing::LengthPrefixed { width: 2 },
+            Framing::LengthPrefixed { width: 4 },
+            Framing::Fixed { size: 64, padding: b' ' },
+        ];
+        let messages = ["MSH|^~\\&|A|B\rPID|1", "second"];
+        for framing in framings {
+            let mut framer = framer(&framing, 1000).unwrap();
+            let mut stream = Vec::new();
+            for m in messages {
+                stream.extend(framer.encode(m).unwrap());
+            }
+            assert_eq!(framer.feed(&stream), messages, "{:?} in one read", framing);
+            assert_eq!(feed_bytewise(framer.as_mut(), &stream), messages, "{:?} byte by byte", framing);
+        }
+    }
+
+    #[test]
+    fn test_delimited_drops_bytes_between_frames() {
+        let mut framer = DelimitedFramer::new(b"<<".to_vec(), b">>".to_vec(), 1000);
+        assert_eq!(framer.feed(b"noise<"), Vec::<String>::new());
+        assert_eq!(framer.feed(b"<one>>junk<<two>"), vec!["one"]);
+        assert_eq!(framer.feed(b">"), vec!["two"]);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(framer(&Framing::Delimited { start: vec![2], end: vec![] }, 1000).is_err());
+        assert!(framer(&Framing::LengthPrefixed { width: 3 }, 1000).is_err());
+        assert!(framer(&Framing::Fixed { size: 0, padding: 0 }, 1000).is_err());
+
+        let fixed = framer(&Framing::Fixed { size: 4, padding: 0 }, 1000).unwrap();
+        assert!(fixed.encode("too long").is_err());
+        let short = framer(&Framing::LengthPrefixed { width: 2 }, 1000).unwrap();
+        assert!(short.encode(&"x".repeat(70_000)).is_err());
+    }
+
+    #[test]
+    fn test_partial_frame_timeout() {
+        let mut framer = LengthPrefixedFramer::new(4, 10);
+        assert!(framer.feed(&[0, 0, 0, 5, b'a']).is_empty());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(framer.check_timeout());
+        assert_eq!(framer.feed(&[0, 0, 0, 1, b'b']), vec!["b"]);
+    }
+
+    #[test]
+    fn test_framing_config() {
+        let framing: Framing = serde_json::from_str(r#"{"mode": "length_prefixed"}"#).unwrap();
+        assert_eq!(framing, Framing::LengthPrefixed { width: 4 });
+        let framing: Framing = serde_json::from_str(r#"{"mode": "delimited", "end": [13, 10]}"#).unwrap();
+        assert_eq!(framing, Framing::Delimited { start: vec![], end: vec![13, 10] });
+        let framing: Framing = serde_json::from_str(r#"{"mode": "fixed", "size": 80}"#).unwrap();
+        assert_eq!(framing, Framing::Fixed { size: 80, padding: b' ' });
+    }
+}
@@ -25,15 +25,20 @@ pub async fn ensure_default_channels(channel_manager: Arc<ChannelManager>) {
                 source: SourceConfig::Http { 
                     port: 8090, 
                     path: None, 
+                    methods: vec!["POST".to_string()],
+                    body_encoding: Default::default(),
+                    response: Default::default(),
                     cert_path: None, 
-                    key_path: None 
+                    key_path: None,
+                    client_auth: None,
                 },
                 processors: vec![
                     ProcessorConfig {
                         id: "proc-1".to_string(),
                         name: "Uppercaser".to_string(),
                         kind: ProcessorType::Lua { 
-                            code: "return msg.content:upper()".to_string() 
+                            code: "return msg.content:upper()".to_string(),
+                            limits: Default::default(),
                         }
                     }
                 ],
@@ -41,6 +46,9 @@ pub async fn ensure_default_channels(channel_manager: Arc<ChannelManager>) {
                     DestinationConfig {
                         id: "dest-1".to_string(),
                         name: "File Out".to_string(),
+                        routes: vec![],
+                        queue: None,
+                        timeout_ms: None,
                         kind: DestinationType::File { 
                             path: "./output".to_string(), 
                             filename: None, 
@@ -51,6 +59,10 @@ pub async fn ensure_default_channels(channel_manager: Arc<ChannelManager>) {
                 ],
                 error_destination: None,
                 max_retries: Some(3),
+                retry_policy: Default::default(),
+                destination_mode: Default::default(),
+                workers: 1,
+                ordering_key: None,
             };
 
             // Pass None for frontend_schema as this is auto-deployed
@@ -0,0 +1,138 @@
+use mlua::prelude::*;
+use crate::engine::listeners::mllp::{AckCode, AckRequest};
+use crate::engine::listeners::reply_script::ReplyScript;
+use crate::storage::models::AckMode;
+
+/// When a TCP source ACKs (`mode`) and builds the (unframed) ACK, optionally through an `ack_script`.
+/// Senders in enhanced mode (MSH-15/MSH-16) get commit ACKs (CA/CE) on receipt and only the
+/// ACKs their MSH asks for.
+///
+/// The script sees `msg.content` and an `ack` table with `code` ("AA", "AE", "AR", "CA", "CE"),
+/// `error` (nil for AA) and `content` (the default ACK), and returns the replacement ACK.
+pub struct AckResponder {
+    pub mode: AckMode,
+    script: Option<ReplyScript>,
+}
+
+impl AckResponder {
+    pub fn new(mode: AckMode, script: Option<String>) -> anyhow::Result<Self> {
+        Ok(Self { mode, script: ReplyScript::new("ack", script.as_deref())? })
+    }
+
+    /// ACK once the message is persisted: AA in immediate mode, plus the enhanced-mode commit
+    /// ACK (CA) in after_processing mode
+    pub async fn receipt(&self, content: &str) -> Option<String> {
+        let request = AckRequest::parse(content);
+        let code = match self.mode {
+            AckMode::None => return None,
+            _ if request.is_enhanced() => AckCode::CA,
+            AckMode::Immediate => AckCode::AA,
+            AckMode::AfterProcessing => return None,
+        };
+        self.respond(content, &request, code, None).await
+    }
+
+    /// ACK for a message that could not be persisted (and is not processed)
+    pub async fn persist_failed(&self, content: &str, error: &str) -> Option<String> {
+        let request = AckRequest::parse(content);
+        let code = match self.mode {
+            AckMode::None => return None,
+            _ if request.is_enhanced() => AckCode::CE,
+            _ => AckCode::AE,
+        };
+        self.respond(content, &request, code, Some(error)).await
+    }
+
+    /// Application ACK (AA/AE/AR) once the pipeline is done, in after_processing mode
+    pub async fn processed(&self, content: &str, code: AckCode, error: Option<&str>) -> Option<String> {
+        if self.mode != AckMode::AfterProcessing {
+            return None;
+        }
+        let request = AckRequest::parse(content);
+        self.respond(content, &request, code, error).await
+    }
+
+    async fn respond(&self, content: &str, request: &AckRequest, code: AckCode, error: Option<&str>) -> Option<String> {
+        if !request.wants(code) {
+            return None;
+        }
+        Some(self.ack(content, request, code, error).await)
+    }
+
+    async fn ack(&self, content: &str, request: &AckRequest, code: AckCode, error: Option<&str>) -> String {
+        let default = request.build(code, error);
+        let Some(script) = &self.script else {
+            return default;
+        };
+
+        let content = content.to_string();
+        let error = error.map(str::to_string);
+        let default_content = default.clone();
+        script.run(
+            default,
+            move |_, msg_table, ack_table| {
+                msg_table.set("content", content)?;
+                ack_table.set("code", code.as_str())?;
+                ack_table.set("error", error)?;
+                ack_table.set("content", default_content)
+            },
+            |lua, value, _| String::from_lua(value, lua),
+        ).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HL7: &str = "MSH|^~\\&|HIS|HOSP|LAB|LAB|20240101120000||ADT^A01|MSG1|P|2.3\rPID|1";
+
+    const ENHANCED: &str = "MSH|^~\\&|HIS|HOSP|LAB|LAB|20240101120000||ADT^A01|MSG1|P|2.5|||AL|ER\rPID|1";
+
+    #[tokio::test]
+    async fn test_default_ack() {
+        let responder = AckResponder::new(AckMode::AfterProcessing, None).unwrap();
+        let ack = responder.processed(HL7, AckCode::AE, Some("boom")).await.unwrap();
+        assert!(ack.starts_with("MSH|"));
+        assert!(ack.contains("\rMSA|AE|MSG1|boom\rERR|"));
+        assert!(responder.receipt(HL7).await.is_none());
+
+        let responder = AckResponder::new(AckMode::Immediate, None).unwrap();
+        assert!(responder.receipt(HL7).await.unwrap().ends_with("\rMSA|AA|MSG1"));
+        assert!(responder.persist_failed(HL7, "disk full").await.unwrap().contains("\rMSA|AE|MSG1|disk full\r"));
+        assert!(responder.processed(HL7, AckCode::AA, None).await.is_none());
+
+        let responder = AckResponder::new(AckMode::None, None).unwrap();
+        assert!(responder.receipt(HL7).await.is_none());
+        assert!(responder.persist_failed(HL7, "disk full").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_enhanced_mode_acks() {
+        let responder = AckResponder::new(AckMode::AfterProcessing, None).unwrap();
+        assert!(responder.receipt(ENHANCED).await.unwrap().ends_with("\rMSA|CA|MSG1"));
+        // MSH-16 is ER: only errors get an application ACK
+        assert!(responder.processed(ENHANCED, AckCode::AA, None).await.is_none());
+        assert!(responder.processed(ENHANCED, AckCode::AR, Some("Message Filtered")).await.unwrap().contains("\rMSA|AR|MSG1|"));
+
+        let responder = AckResponder::new(AckMode::Immediate, None).unwrap();
+        assert!(responder.receipt(ENHANCED).await.unwrap().ends_with("\rMSA|CA|MSG1"));
+        assert!(responder.persist_failed(ENHANCED, "disk full").await.unwrap().contains("\rMSA|CE|MSG1|disk full\r"));
+    }
+
+    #[tokio::test]
+    async fn test_script_customises_ack() {
+        let script = r#"
+            if ack.code == "AA" then return nil end
+            return ack.content:gsub("MSA|" .. ack.code, "MSA|CE") .. "\rZER|" .. msg.content:sub(1, 3)
+        "#;
+        let responder = AckResponder::new(AckMode::AfterProcessing, Some(script.to_string())).unwrap();
+
+        let ack = responder.processed(HL7, AckCode::AR, Some("Message Filtered")).await.unwrap();
+        assert!(ack.contains("\rMSA|CE|MSG1|Message Filtered\r"));
+        assert!(ack.ends_with("\rZER|MSH"));
+
+        let ack = responder.processed(HL7, AckCode::AA, None).await.unwrap();
+        assert!(ack.ends_with("\rMSA|AA|MSG1"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, ServerConnection};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use crate::config::TlsConfig;
+use crate::engine::message::Message;
+use crate::storage::models::{ClientAuth, ClientAuthMode};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::objects::{oid2abbrev, oid_registry};
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::AttributeTypeAndValue;
+
+/// Server config of a TLS listener; with `client_auth`, client certificates must chain to its CA
+/// (and are required unless the mode is optional)
+pub fn server_config(tls: &TlsConfig) -> anyhow::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&tls.key_path)?))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found"))?;
+
+    let builder = ServerConfig::builder_with_provider(crate::config::tls_provider())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &tls.client_auth {
+        Some(client_auth) => {
+            let mut roots = RootCertStore::empty();
+            let ca_certs = rustls_pemfile::certs(&mut BufReader::new(
+                File::open(&client_auth.ca_path)
+                    .map_err(|e| anyhow::anyhow!("Cannot read client CA bundle {}: {}", client_auth.ca_path, e))?,
+            )).collect::<Result<Vec<_>, _>>()?;
+            let (added, _) = roots.add_parsable_certificates(ca_certs);
+            if added == 0 {
+                return Err(anyhow::anyhow!("No CA certificates found in {}", client_auth.ca_path));
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crate::config::tls_provider());
+            let verifier = match client_auth.mode {
+                ClientAuthMode::Required => verifier.build()?,
+                ClientAuthMode::Optional => verifier.allow_unauthenticated().build()?,
+            };
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+/// Identity of the (already verified) client certificate of a connection. Fails when the
+/// certificate is not one of `allowed_subjects`; `None` when the client sent no certificate.
+pub fn authorize(conn: &ServerConnection, client_auth: Option<&ClientAuth>) -> anyhow::Result<Option<PeerIdentity>> {
+    let Some(client_auth) = client_auth else {
+        return Ok(None);
+    };
+    let Some(cert) = conn.peer_certificates().and_then(|certs| certs.first()) else {
+        return Ok(None);
+    };
+    let identity = PeerIdentity::from_der(cert)?;
+    if !identity.matches(&client_auth.allowed_subjects) {
+        return Err(anyhow::anyhow!("Client certificate '{}' is not in allowed_subjects", identity.subject));
+    }
+    Ok(Some(identity))
+}
+
+/// Subject and subject alternative names of a client certificate
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerIdentity {
+    /// Attributes in certificate order, as `openssl x509 -subject` prints them: `O=Acme, CN=hl7-gw`
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// `DNS:...`, `email:...`, `URI:...` and `IP:...` entries
+    pub san: Vec<String>,
+    attributes: Vec<String>,
+}
+
+impl PeerIdentity {
+    pub fn from_der(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| anyhow::anyhow!("Invalid client certificate: {}", e))?;
+
+        let attributes: Vec<(String, String)> = cert.subject().iter_attributes()
+            .map(|attr| (attribute_name(attr), attribute_value(attr)))
+            .collect();
+        let san = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                GeneralName::IPAddress(bytes) => ip(bytes).map(|ip| format!("IP:{}", ip)),
+                _ => None,
+            }).collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => return Err(anyhow::anyhow!("Invalid client certificate: {}", e)),
+        };
+
+        let common_name = attributes.iter()
+            .find_map(|(key, value)| (key == "CN").then(|| value.clone()));
+        let attributes: Vec<String> = attributes.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        Ok(Self {
+            subject: attributes.join(", "),
+            common_name,
+            san,
+            attributes,
+        })
+    }
+
+    /// Whether a pattern matches one of the subject attributes or a SAN (any when empty).
+    /// Patterns are matched against single entries, so `*` cannot span several attributes.
+    pub fn matches(&self, patterns: &[String]) -> bool {
+        if patterns.is_empty() {
+            return true;
+        }
+        let options = glob::MatchOptions { case_sensitive: false, ..Default::default() };
+        let candidates: Vec<&String> = self.attributes.iter().chain(&self.san).collect();
+        patterns.iter().any(|pattern| match glob::Pattern::new(pattern) {
+            Ok(pattern) => candidates.iter().any(|candidate| pattern.matches_with(candidate, options)),
+            Err(_) => candidates.iter().any(|candidate| candidate.eq_ignore_ascii_case(pattern)),
+        })
+    }
+
+    /// Expose the identity as `client_cert.subject`, `client_cert.cn` and `client_cert.san`
+    /// (comma-separated) message metadata
+    pub fn apply(&self, msg: &mut Message) {
+        msg.metadata.insert("client_cert.subject".to_string(), self.subject.clone());
+        if let Some(cn) = &self.common_name {
+            msg.metadata.insert("client_cert.cn".to_string(), cn.clone());
+        }
+        if !self.san.is_empty() {
+            msg.metadata.insert("client_cert.san".to_string(), self.san.join(", "));
+        }
+    }
+}
+
+/// Short name of an attribute (`CN`, `O`, ...), or its dotted OID when it has none
+fn attribute_name(attr: &AttributeTypeAndValue) -> String {
+    match oid2abbrev(attr.attr_type(), oid_registry()) {
+        Ok(name) => name.to_string(),
+        Err(_) => attr.attr_type().to_id_string(),
+    }
+}
+
+fn attribute_value(attr: &AttributeTypeAndValue) -> String {
+    match attr.as_str() {
+        Ok(value) => value.to_string(),
+        Err(_) => match attr.attr_value().as_bmpstring() {
+            Ok(value) => value.string(),
+            Err(_) => String::from_utf8_lossy(attr.as_slice()).into_owned(),
+        },
+    }
+}
+
+fn ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    fn client_identity() -> PeerIdentity {
+        let cert = rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("client.pem")).unwrap()))
+            .next().unwrap().unwrap();
+        PeerIdentity::from_der(&cert).unwrap()
+    }
+
+    #[test]
+    fn test_identity_from_certificate() {
+        let identity = client_identity();
+        assert_eq!(identity.subject, "O=MirthBR Test, CN=mirthbr-client");
+        assert_eq!(identity.common_name.as_deref(), Some("mirthbr-client"));
+        assert_eq!(identity.san, vec!["DNS:partner.example.org", "email:interfaces@partner.example.org"]);
+
+        let mut msg = Message::new(uuid::Uuid::new_v4(), String::new(), "test".to_string());
+        identity.apply(&mut msg);
+        assert_eq!(msg.metadata["client_cert.cn"], "mirthbr-client");
+        assert_eq!(msg.metadata["client_cert.san"], "DNS:partner.example.org, email:interfaces@partner.example.org");
+    }
+
+    #[test]
+    fn test_allowed_subject_patterns() {
+        let identity = client_identity();
+        let allowed = |patterns: &[&str]| identity.matches(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert!(allowed(&[]));
+        assert!(allowed(&["cn=mirthbr-*"]));
+        assert!(allowed(&["O=MirthBR Test"]));
+        // Patterns match single attributes, not the whole subject
+        assert!(!allowed(&["O=MirthBR Test, CN=mirthbr-client"]));
+        assert!(!allowed(&["O=*client"]));
+        assert!(allowed(&["DNS:*.example.org"]));
+        assert!(allowed(&["CN=other", "email:interfaces@*"]));
+        assert!(!allowed(&["CN=other", "DNS:*.example.com"]));
+    }
+
+    #[test]
+    fn test_server_config_requires_ca() {
+        let tls = TlsConfig::new(fixture("server.pem"), fixture("server.key"));
+        assert!(server_config(&tls).is_ok());
+
+        let client_auth = |ca_path: PathBuf| Some(ClientAuth {
+            ca_path: ca_path.to_string_lossy().into_owned(),
+            mode: ClientAuthMode::Required,
+            allowed_subjects: vec![],
+        });
+        assert!(server_config(&tls.clone().with_client_auth(client_auth(fixture("ca.pem")))).is_ok());
+        // A private key is not a CA bundle
+        assert!(server_config(&tls.with_client_auth(client_auth(fixture("client.key")))).is_err());
+    }
+}
@@ -1,23 +1,40 @@
 use axum::{
-    routing::post,
+    routing::{on, MethodFilter},
     Router,
-    extract::{State, ConnectInfo},
-    http::{header::{CONTENT_TYPE, AUTHORIZATION}, HeaderValue, Method, StatusCode},
+    Extension,
+    body::Bytes,
+    extract::{State, ConnectInfo, Path},
+    http::{header::{CONTENT_TYPE, AUTHORIZATION}, request::Parts, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::AddExtension,
 };
+use base64::Engine;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
 use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use crate::engine::message::Message;
+use crate::engine::message::{Message, Outcome};
 use crate::storage::messages::MessageStore;
 use uuid::Uuid;
 use tower_http::cors::CorsLayer;
 use tower_http::limit::RequestBodyLimitLayer;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 /// Maximum request body size (1MB)
 const MAX_BODY_SIZE: usize = 1024 * 1024;
 
+/// Request headers never copied into message metadata
+const SECRET_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
 use crate::config::TlsConfig;
+use crate::engine::listeners::client_auth::{self, PeerIdentity};
+use crate::engine::listeners::http_response::{Ending, HttpReply, HttpResponder};
+use crate::storage::models::{BodyEncoding, ClientAuth};
 
 pub struct HttpListener {
     pub port: u16,
@@ -27,6 +44,9 @@ pub struct HttpListener {
     pub allowed_origins: Option<Vec<String>>,
     pub store: Option<MessageStore>,
     pub tls_config: Option<TlsConfig>,
+    pub methods: Vec<String>,
+    pub body_encoding: BodyEncoding,
+    pub responder: Arc<HttpResponder>,
 }
 
 impl HttpListener {
@@ -39,7 +59,75 @@ impl HttpListener {
             allowed_origins: None,
             store,
             tls_config,
+            methods: vec!["POST".to_string()],
+            body_encoding: BodyEncoding::Text,
+            responder: Arc::new(HttpResponder::default()),
+        }
+    }
+
+    pub fn with_methods(mut self, methods: Vec<String>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    pub fn with_body_encoding(mut self, body_encoding: BodyEncoding) -> Self {
+        self.body_encoding = body_encoding;
+        self
+    }
+
+    pub fn with_responder(mut self, responder: HttpResponder) -> Self {
+        self.responder = Arc::new(responder);
+        self
+    }
+
+    /// Parse the configured methods (e.g. `["GET", "post"]`)
+    pub fn parse_methods(methods: &[String]) -> anyhow::Result<Vec<Method>> {
+        if methods.is_empty() {
+            return Err(anyhow::anyhow!("At least one HTTP method is required"));
+        }
+        methods.iter()
+            .map(|m| {
+                let method = Method::from_bytes(m.trim().to_uppercase().as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Invalid HTTP method '{}'", m))?;
+                MethodFilter::try_from(method.clone())
+                    .map_err(|_| anyhow::anyhow!("Unsupported HTTP method '{}'", m))?;
+                Ok(method)
+            })
+            .collect()
+    }
+
+    /// Check a path pattern: `/` segments, `:name` parameters and a final `*name` wildcard
+    pub fn validate_path(path: &str) -> anyhow::Result<()> {
+        if !path.starts_with('/') {
+            return Err(anyhow::anyhow!("Path '{}' must start with '/'", path));
+        }
+        let segments: Vec<&str> = path[1..].split('/').collect();
+        let mut names = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let Some(name) = segment.strip_prefix(':').or_else(|| segment.strip_prefix('*')) else {
+                continue;
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(anyhow::anyhow!("Invalid parameter '{}' in path '{}'", segment, path));
+            }
+            if segment.starts_with('*') && i + 1 != segments.len() {
+                return Err(anyhow::anyhow!("Wildcard '{}' must be the last segment of '{}'", segment, path));
+            }
+            if names.contains(&name) {
+                return Err(anyhow::anyhow!("Parameter '{}' appears twice in path '{}'", name, path));
+            }
+            names.push(name);
         }
+        Ok(())
+    }
+
+    /// Methods allowed cross-origin: the configured ones (plus OPTIONS for preflight requests)
+    fn cors_methods(&self, preflight: bool) -> Vec<Method> {
+        let mut methods = Self::parse_methods(&self.methods).unwrap_or_else(|_| vec![Method::POST]);
+        if preflight && !methods.contains(&Method::OPTIONS) {
+            methods.push(Method::OPTIONS);
+        }
+        methods
     }
 
     /// Build a secure CORS layer based on allowed origins configuration
@@ -55,7 +143,7 @@ impl HttpListener {
             );
             CorsLayer::new()
                 .allow_origin(tower_http::cors::Any)
-                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS])
                 .allow_headers([CONTENT_TYPE, AUTHORIZATION])
                 .max_age(Duration::from_secs(3600))
         } else {
@@ -74,7 +162,7 @@ impl HttpListener {
                             self.channel_id
                         );
                         CorsLayer::new()
-                            .allow_methods([Method::POST])
+                            .allow_methods(self.cors_methods(false))
                             .allow_headers([CONTENT_TYPE])
                             .max_age(Duration::from_secs(3600))
                     } else {
@@ -85,7 +173,7 @@ impl HttpListener {
                         );
                         CorsLayer::new()
                             .allow_origin(parsed_origins)
-                            .allow_methods([Method::POST, Method::OPTIONS])
+                            .allow_methods(self.cors_methods(true))
                             .allow_headers([CONTENT_TYPE, AUTHORIZATION])
                             .max_age(Duration::from_secs(3600))
                     }
@@ -98,7 +186,7 @@ impl HttpListener {
                         self.channel_id
                     );
                     CorsLayer::new()
-                        .allow_methods([Method::POST])
+                        .allow_methods(self.cors_methods(false))
                         .allow_headers([CONTENT_TYPE])
                         .max_age(Duration::from_secs(3600))
                 }
@@ -108,9 +196,21 @@ impl HttpListener {
 
     pub async fn start(&self) {
         let cors = self.build_cors_layer();
+        let methods = match Self::parse_methods(&self.methods).and_then(|methods| {
+            methods.into_iter()
+                .map(|method| MethodFilter::try_from(method).map_err(anyhow::Error::from))
+                .reduce(|a, b| Ok(a?.or(b?)))
+                .unwrap_or_else(|| Err(anyhow::anyhow!("At least one HTTP method is required")))
+        }) {
+            Ok(methods) => methods,
+            Err(e) => {
+                tracing::error!("❌ Invalid methods for channel {}: {}", self.channel_id, e);
+                return;
+            }
+        };
 
         let app = Router::new()
-            .route(&self.path, post(handler))
+            .route(&self.path, on(methods, handler))
             .layer(cors)
             .layer(RequestBodyLimitLayer::new(MAX_BODY_SIZE)) // Limit body size
             .with_state(AppState {
@@ -119,6 +219,8 @@ impl HttpListener {
                 port: self.port,
                 path: self.path.clone(),
                 store: self.store.clone(),
+                body_encoding: self.body_encoding,
+                responder: self.responder.clone(),
             });
 
         // Configurable bind address for listeners
@@ -133,20 +235,23 @@ impl HttpListener {
             tracing::info!("🔒 Channel {} listening on HTTPS {}:{}", self.channel_id, bind_addr, self.port);
             
             // Load TLS Config
-            let rustls_config = match axum_server::tls_rustls::RustlsConfig::from_pem_file(
-                &tls_config.cert_path,
-                &tls_config.key_path
-            ).await {
+            let mut server_config = match client_auth::server_config(tls_config) {
                 Ok(c) => c,
                 Err(e) => {
                     tracing::error!("❌ Failed to load TLS certificates for channel {}: {}", self.channel_id, e);
                     return;
                 }
             };
+            server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            let acceptor = ClientCertAcceptor {
+                inner: RustlsAcceptor::new(RustlsConfig::from_config(Arc::new(server_config))),
+                client_auth: tls_config.client_auth.clone().map(Arc::new),
+            };
 
             // Spawn HTTPS server
             tokio::spawn(async move {
-                if let Err(e) = axum_server::bind_rustls(addr, rustls_config)
+                if let Err(e) = axum_server::bind(addr)
+                    .acceptor(acceptor)
                     .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                     .await 
                 {
@@ -178,6 +283,41 @@ impl HttpListener {
     }
 }
 
+/// Verified client certificate of an HTTPS connection (mutual TLS)
+#[derive(Clone)]
+struct ClientCert(Option<PeerIdentity>);
+
+/// TLS acceptor that refuses client certificates outside `allowed_subjects` and hands the
+/// peer identity to the handler
+#[derive(Clone)]
+struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+    client_auth: Option<Arc<ClientAuth>>,
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCert>;
+    type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        let client_auth = self.client_auth.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let peer = client_auth::authorize(stream.get_ref().1, client_auth.as_deref()).map_err(|e| {
+                tracing::warn!("Refused HTTPS client: {}", e);
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, e.to_string())
+            })?;
+            Ok((stream, Extension(ClientCert(peer)).layer(service)))
+        })
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     channel_id: Uuid,
@@ -185,14 +325,22 @@ struct AppState {
     port: u16,
     path: String,
     store: Option<MessageStore>,
+    body_encoding: BodyEncoding,
+    responder: Arc<HttpResponder>,
 }
 
 async fn handler(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    body: String
-) -> impl axum::response::IntoResponse {
+    client_cert: Option<Extension<ClientCert>>,
+    path_params: Option<Path<HashMap<String, String>>>,
+    request: Parts,
+    body: Bytes
+) -> HttpReply {
     let origin = format!("HTTP :{}{} from {}", state.port, state.path, addr.ip());
+    let Some((body, encoding)) = decode_body(&body, state.body_encoding) else {
+        return HttpReply::text(StatusCode::BAD_REQUEST, "Body is not valid UTF-8; set body_encoding to base64 or auto for binary payloads");
+    };
     
     // 1. Persist Message
     let mut msg_id_str = Uuid::new_v4().to_string(); // Default if not using store
@@ -205,7 +353,7 @@ async fn handler(
             },
             Err(e) => {
                  tracing::error!("CRITICAL: Failed to persist HTTP message: {}", e);
-                 return (StatusCode::INTERNAL_SERVER_ERROR, "Persistence Failed".to_string());
+                 return HttpReply::text(StatusCode::INTERNAL_SERVER_ERROR, "Persistence Failed");
             }
         }
     }
@@ -216,34 +364,218 @@ async fn handler(
     if let Ok(uuid) = Uuid::parse_str(&msg_id_str) {
         msg.id = uuid;
     }
+    let path_params = path_params.map(|Path(params)| params).unwrap_or_default();
+    msg.metadata.extend(request_metadata(&request.method, &request.uri, &request.headers, &path_params, addr, encoding));
+    if let Some(Extension(ClientCert(Some(peer)))) = &client_cert {
+        peer.apply(&mut msg);
+    }
     
-    // 3. Prepare Response Channel (Sync Wait)
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    msg.response_tx = Some(Arc::new(Mutex::new(Some(tx))));
+    // 3. Prepare Response Channel (sync mode)
+    let responder = state.responder.clone();
+    let id = msg.id;
+    let content = msg.content.clone();
+    let metadata = msg.metadata.clone();
+    let rx = if responder.is_async() {
+        None
+    } else {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        msg.response_tx = Some(Arc::new(Mutex::new(Some(tx))));
+        Some(rx)
+    };
 
     // 4. Send to channel processing loop
     if let Err(e) = state.sender.send(msg).await {
         tracing::error!("Failed to send message to channel pipeline: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error".to_string());
-    }
-
-    // 5. Wait for Result (Sync Mode)
-    // Wait up to 30 seconds for processing
-    match tokio::time::timeout(Duration::from_secs(30), rx).await {
-        Ok(result) => {
-            match result {
-                Ok(Ok(msg)) => (StatusCode::OK, msg),
-                Ok(Err(error_msg)) => {
-                    tracing::warn!("Request failed validation/processing: {}", error_msg);
-                    (StatusCode::BAD_REQUEST, error_msg) 
-                },
-                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Channel Dropped Response".to_string()),
+        return HttpReply::text(StatusCode::INTERNAL_SERVER_ERROR, "Internal Error");
+    }
+
+    // 5. Reply now (async mode) or once the pipeline answers
+    let Some(rx) = rx else {
+        return responder.reply(id, content, metadata, Ending::Accepted).await;
+    };
+    match tokio::time::timeout(responder.timeout(), rx).await {
+        Ok(Ok(reply)) => {
+            if let Outcome::Failed(error_msg) = &reply.outcome {
+                tracing::warn!("Request failed validation/processing: {}", error_msg);
             }
+            responder.reply(id, reply.content, reply.metadata, Ending::Finished(reply.outcome)).await
         },
+        Ok(Err(_)) => responder.reply(id, content, metadata, Ending::Dropped).await,
         Err(_) => {
-             tracing::error!("Request timed out waiting for processing");
-             (StatusCode::GATEWAY_TIMEOUT, "Processing Timeout".to_string())
+            tracing::error!("Request timed out waiting for processing");
+            responder.reply(id, content, metadata, Ending::TimedOut).await
         }
     }
 }
 
+
+/// Message content for a request body, and whether it is `text` or `base64`;
+/// `None` when a text body is not UTF-8
+fn decode_body(body: &[u8], encoding: BodyEncoding) -> Option<(String, &'static str)> {
+    let base64 = || (base64::engine::general_purpose::STANDARD.encode(body), "base64");
+    match encoding {
+        BodyEncoding::Base64 => Some(base64()),
+        BodyEncoding::Text => String::from_utf8(body.to_vec()).ok().map(|text| (text, "text")),
+        BodyEncoding::Auto => Some(String::from_utf8(body.to_vec()).map(|text| (text, "text")).unwrap_or_else(|_| base64())),
+    }
+}
+
+/// `http.*` metadata describing a request: method, path, query string and parameters,
+/// path parameters, headers (except credentials), content type, remote IP and body encoding
+fn request_metadata(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    path_params: &HashMap<String, String>,
+    addr: SocketAddr,
+    body_encoding: &str,
+) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    metadata.insert("http.method".to_string(), method.to_string());
+    metadata.insert("http.path".to_string(), uri.path().to_string());
+    metadata.insert("http.remote_ip".to_string(), addr.ip().to_string());
+    metadata.insert("http.body_encoding".to_string(), body_encoding.to_string());
+    if let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        metadata.insert("http.content_type".to_string(), content_type.to_string());
+    }
+
+    if let Some(query) = uri.query() {
+        metadata.insert("http.query".to_string(), query.to_string());
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            // Repeated parameters keep every value, comma-separated
+            metadata.entry(format!("http.query.{}", name))
+                .and_modify(|existing: &mut String| {
+                    existing.push(',');
+                    existing.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+    }
+    for (name, value) in path_params {
+        metadata.insert(format!("http.path.{}", name), value.clone());
+    }
+    for name in headers.keys() {
+        if SECRET_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        let values: Vec<String> = headers.get_all(name).iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .collect();
+        metadata.insert(format!("http.header.{}", name), values.join(", "));
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::message::Reply;
+    use crate::storage::models::{HttpResponseConfig, HttpResponseMode};
+
+    #[test]
+    fn test_methods_and_paths() {
+        let methods = HttpListener::parse_methods(&["get".to_string(), "POST".to_string()]).unwrap();
+        assert_eq!(methods, vec![Method::GET, Method::POST]);
+        assert!(HttpListener::parse_methods(&[]).is_err());
+        assert!(HttpListener::parse_methods(&["FETCH".to_string()]).is_err());
+
+        assert!(HttpListener::validate_path("/fhir/Patient/:id").is_ok());
+        assert!(HttpListener::validate_path("/files/*rest").is_ok());
+        assert!(HttpListener::validate_path("fhir").is_err());
+        assert!(HttpListener::validate_path("/a/:").is_err());
+        assert!(HttpListener::validate_path("/a/*rest/b").is_err());
+        assert!(HttpListener::validate_path("/a/:id/b/:id").is_err());
+    }
+
+    #[test]
+    fn test_decode_body() {
+        assert_eq!(decode_body(b"MSH|", BodyEncoding::Text), Some(("MSH|".to_string(), "text")));
+        assert_eq!(decode_body(&[0xFF, 0x00], BodyEncoding::Text), None);
+        assert_eq!(decode_body(&[0xFF, 0x00], BodyEncoding::Auto), Some(("/wA=".to_string(), "base64")));
+        assert_eq!(decode_body(b"hi", BodyEncoding::Auto), Some(("hi".to_string(), "text")));
+        assert_eq!(decode_body(b"hi", BodyEncoding::Base64), Some(("aGk=".to_string(), "base64")));
+    }
+
+    #[test]
+    fn test_request_metadata() {
+        let uri: Uri = "/fhir/Patient/42?_format=json&tag=a&tag=b%20c".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/fhir+json"));
+        headers.insert("x-tenant", HeaderValue::from_static("north"));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        let params = HashMap::from([("id".to_string(), "42".to_string())]);
+        let addr: SocketAddr = "10.0.0.7:50000".parse().unwrap();
+
+        let metadata = request_metadata(&Method::PUT, &uri, &headers, &params, addr, "text");
+        assert_eq!(metadata["http.method"], "PUT");
+        assert_eq!(metadata["http.path"], "/fhir/Patient/42");
+        assert_eq!(metadata["http.path.id"], "42");
+        assert_eq!(metadata["http.query"], "_format=json&tag=a&tag=b%20c");
+        assert_eq!(metadata["http.query._format"], "json");
+        assert_eq!(metadata["http.query.tag"], "a,b c");
+        assert_eq!(metadata["http.content_type"], "application/fhir+json");
+        assert_eq!(metadata["http.header.x-tenant"], "north");
+        assert_eq!(metadata["http.remote_ip"], "10.0.0.7");
+        assert!(!metadata.contains_key("http.header.authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_listener_routes_methods_and_params() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, mut rx) = mpsc::channel::<Message>(4);
+        let listener = HttpListener::new(port, "/fhir/Patient/:id".to_string(), Uuid::new_v4(), tx, None, None)
+            .with_methods(vec!["GET".to_string(), "PUT".to_string()])
+            .with_body_encoding(BodyEncoding::Auto);
+        listener.start().await;
+        let pipeline = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(msg) = rx.recv().await {
+                if let Some(tx) = msg.response_tx.as_ref().and_then(|tx| tx.lock().unwrap().take()) {
+                    let _ = tx.send(Reply { outcome: Outcome::Processed("ok".to_string()), content: msg.content.clone(), metadata: msg.metadata.clone() });
+                }
+                received.push(msg);
+                if received.len() == 2 {
+                    return received;
+                }
+            }
+            received
+        });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://127.0.0.1:{}/fhir/Patient/42?_format=json", port);
+        assert_eq!(client.get(&url).send().await.unwrap().status(), 200);
+        assert_eq!(client.put(&url).body(vec![0xFFu8, 0x00]).send().await.unwrap().status(), 200);
+        assert_eq!(client.post(&url).send().await.unwrap().status(), 405);
+
+        let received = pipeline.await.unwrap();
+        assert_eq!(received[0].metadata["http.method"], "GET");
+        assert_eq!(received[0].metadata["http.path.id"], "42");
+        assert_eq!(received[0].metadata["http.query._format"], "json");
+        assert_eq!(received[1].content, "/wA=");
+        assert_eq!(received[1].metadata["http.body_encoding"], "base64");
+    }
+
+    #[tokio::test]
+    async fn test_async_mode_replies_accepted() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, mut rx) = mpsc::channel::<Message>(4);
+        let responder = HttpResponder::new(HttpResponseConfig {
+            mode: HttpResponseMode::Async,
+            ..Default::default()
+        }).unwrap();
+        HttpListener::new(port, "/".to_string(), Uuid::new_v4(), tx, None, None)
+            .with_responder(responder)
+            .start().await;
+
+        // Nothing answers for the pipeline: the reply must not wait for it
+        let res = reqwest::Client::new().post(format!("http://127.0.0.1:{}/", port)).body("MSH|").send().await.unwrap();
+        assert_eq!(res.status(), 202);
+        let header_id = res.headers()["x-message-id"].to_str().unwrap().to_string();
+        let body = res.text().await.unwrap();
+
+        let msg = rx.recv().await.unwrap();
+        assert!(msg.response_tx.is_none());
+        assert_eq!(body, msg.id.to_string());
+        assert_eq!(header_id, body);
+    }
+}
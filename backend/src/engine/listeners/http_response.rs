@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use axum::http::{header::CONTENT_TYPE, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use mlua::prelude::*;
+use uuid::Uuid;
+use crate::engine::listeners::reply_script::ReplyScript;
+use crate::engine::message::Outcome;
+use crate::storage::models::{Channel, DestinationMode, HttpResponseConfig, HttpResponseMode};
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+/// How a request ended, as seen by the HTTP listener
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ending {
+    /// Persisted and handed to the pipeline (async mode)
+    Accepted,
+    Finished(Outcome),
+    TimedOut,
+    /// The pipeline went away without answering
+    Dropped,
+}
+
+impl Ending {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Accepted => "accepted",
+            Self::Finished(Outcome::Processed(_)) => "processed",
+            Self::Finished(Outcome::Filtered(_)) => "filtered",
+            Self::Finished(Outcome::Failed(_)) => "failed",
+            Self::TimedOut => "timeout",
+            Self::Dropped => "dropped",
+        }
+    }
+}
+
+/// Reply of an HTTP listener
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpReply {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpReply {
+    pub fn text(status: StatusCode, body: impl Into<String>) -> Self {
+        Self { status, headers: vec![(CONTENT_TYPE.to_string(), TEXT_PLAIN.to_string())], body: body.into() }
+    }
+}
+
+impl IntoResponse for HttpReply {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.body).into_response();
+        for (name, value) in self.headers {
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+                (Ok(name), Ok(value)) => {
+                    response.headers_mut().insert(name, value);
+                },
+                _ => tracing::warn!("Skipping invalid response header '{}'", name),
+            }
+        }
+        response
+    }
+}
+
+/// Builds the replies of an HTTP listener from its `response` settings.
+///
+/// Processed messages get 200, filtered ones `filtered_status` (204 by default), failures 500
+/// and timeouts 504; async mode answers 202 with the message ID. Every reply carries an
+/// `X-Message-Id` header.
+///
+/// The script sees `msg` (`id`, `content`, `metadata` as the pipeline left them) and a
+/// `response` table with `outcome` ("accepted", "processed", "filtered", "failed", "timeout",
+/// "dropped"), `status`, `headers` and `body` (the default reply). A returned table replaces
+/// the status and body it sets and adds its headers, a string replaces the body.
+pub struct HttpResponder {
+    config: HttpResponseConfig,
+    filtered_status: StatusCode,
+    script: Option<ReplyScript>,
+}
+
+impl Default for HttpResponder {
+    fn default() -> Self {
+        Self { config: HttpResponseConfig::default(), filtered_status: StatusCode::NO_CONTENT, script: None }
+    }
+}
+
+impl HttpResponder {
+    pub fn new(config: HttpResponseConfig) -> anyhow::Result<Self> {
+        let filtered_status = StatusCode::from_u16(config.filtered_status)
+            .map_err(|_| anyhow::anyhow!("Invalid filtered_status {}", config.filtered_status))?;
+        if config.timeout_ms == 0 {
+            return Err(anyhow::anyhow!("Response timeout_ms must be greater than 0"));
+        }
+        let script = ReplyScript::new("response", config.script.as_deref())?;
+        Ok(Self { config, filtered_status, script })
+    }
+
+    /// Check that the `destination` reply source answers before the listener replies: it must
+    /// exist, not be queued, and the channel must not use `first_success` or `fire_and_forget`
+    pub fn check_destination(config: &HttpResponseConfig, channel: &Channel) -> anyhow::Result<()> {
+        let Some(name) = &config.destination else {
+            return Ok(());
+        };
+        let Some(destination) = channel.destinations.iter().find(|d| &d.name == name) else {
+            return Err(anyhow::anyhow!("Response destination '{}' is not a destination of this channel", name));
+        };
+        if destination.queue.is_some() {
+            return Err(anyhow::anyhow!("Response destination '{}' is queued; its response arrives after the reply", name));
+        }
+        match channel.destination_mode {
+            DestinationMode::FirstSuccess => {
+                return Err(anyhow::anyhow!("Response destination '{}' cannot be used with the first_success destination mode", name));
+            }
+            DestinationMode::FireAndForget => {
+                return Err(anyhow::anyhow!("Response destination '{}' cannot be used with the fire_and_forget destination mode", name));
+            }
+            DestinationMode::Sequential | DestinationMode::WaitAll => {}
+        }
+        Ok(())
+    }
+
+    pub fn is_async(&self) -> bool {
+        self.config.mode == HttpResponseMode::Async
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.config.timeout_ms)
+    }
+
+    /// Reply for a message, given its content and metadata as the pipeline left them
+    pub async fn reply(&self, id: Uuid, content: String, metadata: HashMap<String, String>, ending: Ending) -> HttpReply {
+        let mut reply = self.default_reply(id, &metadata, &ending);
+        reply.headers.push(("x-message-id".to_string(), id.to_string()));
+        let Some(script) = &self.script else {
+            return reply;
+        };
+
+        let outcome = ending.name();
+        let default = reply.clone();
+        script.run(
+            reply,
+            move |lua, msg_table, response_table| {
+                msg_table.set("id", id.to_string())?;
+                msg_table.set("content", content)?;
+                msg_table.set("metadata", lua.create_table_from(metadata)?)?;
+                response_table.set("outcome", outcome)?;
+                response_table.set("status", default.status.as_u16())?;
+                response_table.set("headers", lua.create_table_from(default.headers)?)?;
+                response_table.set("body", default.body)
+            },
+            |lua, value, mut reply| match value {
+                LuaValue::Table(custom) => {
+                    if let Some(status) = custom.get::<_, Option<u16>>("status")? {
+                        reply.status = StatusCode::from_u16(status)
+                            .map_err(|_| LuaError::RuntimeError(format!("invalid status {}", status)))?;
+                    }
+                    for (name, value) in custom.get::<_, Option<HashMap<String, String>>>("headers")?.unwrap_or_default() {
+                        reply.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(&name));
+                        reply.headers.push((name, value));
+                    }
+                    if let Some(body) = custom.get::<_, Option<String>>("body")? {
+                        reply.body = body;
+                    }
+                    Ok(reply)
+                },
+                other => {
+                    reply.body = String::from_lua(other, lua)?;
+                    Ok(reply)
+                },
+            },
+        ).await
+    }
+
+    fn default_reply(&self, id: Uuid, metadata: &HashMap<String, String>, ending: &Ending) -> HttpReply {
+        match ending {
+            Ending::Accepted => HttpReply::text(StatusCode::ACCEPTED, id.to_string()),
+            Ending::Finished(Outcome::Processed(text)) => {
+                let destination_body = self.config.destination.as_ref()
+                    .and_then(|name| metadata.get(&format!("response.{}.body", name)));
+                match destination_body {
+                    Some(body) => {
+                        let content_type = self.config.content_type.clone().unwrap_or_else(|| TEXT_PLAIN.to_string());
+                        HttpReply { status: StatusCode::OK, headers: vec![(CONTENT_TYPE.to_string(), content_type)], body: body.clone() }
+                    },
+                    None => HttpReply::text(StatusCode::OK, text.clone()),
+                }
+            },
+            Ending::Finished(Outcome::Filtered(text)) => {
+                // 204 and 304 replies cannot have a body
+                let body = if matches!(self.filtered_status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) { String::new() } else { text.clone() };
+                HttpReply::text(self.filtered_status, body)
+            },
+            Ending::Finished(Outcome::Failed(text)) => HttpReply::text(StatusCode::INTERNAL_SERVER_ERROR, text.clone()),
+            Ending::TimedOut => HttpReply::text(StatusCode::GATEWAY_TIMEOUT, "Processing Timeout"),
+            Ending::Dropped => HttpReply::text(StatusCode::INTERNAL_SERVER_ERROR, "Channel Dropped Response"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::models::{DestinationConfig, DestinationType, LuaLimits, QueueConfig, SourceConfig};
+
+    fn responder(config: HttpResponseConfig) -> HttpResponder {
+        HttpResponder::new(config).unwrap()
+    }
+
+    fn header<'a>(reply: &'a HttpReply, name: &str) -> Option<&'a str> {
+        reply.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    #[tokio::test]
+    async fn test_default_status_mapping() {
+        let responder = responder(HttpResponseConfig::default());
+        let id = Uuid::new_v4();
+        let reply = |ending| responder.reply(id, String::new(), HashMap::new(), ending);
+
+        let processed = reply(Ending::Finished(Outcome::Processed("ok".to_string()))).await;
+        assert_eq!((processed.status, processed.body.as_str()), (StatusCode::OK, "ok"));
+        assert_eq!(header(&processed, "x-message-id"), Some(id.to_string().as_str()));
+
+        let filtered = reply(Ending::Finished(Outcome::Filtered("Message Filtered".to_string()))).await;
+        assert_eq!((filtered.status, filtered.body.as_str()), (StatusCode::NO_CONTENT, ""));
+        assert_eq!(reply(Ending::Finished(Outcome::Failed("boom".to_string()))).await.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(reply(Ending::TimedOut).await.status, StatusCode::GATEWAY_TIMEOUT);
+
+        let accepted = reply(Ending::Accepted).await;
+        assert_eq!((accepted.status, accepted.body), (StatusCode::ACCEPTED, id.to_string()));
+
+        let responder = HttpResponder::new(HttpResponseConfig { filtered_status: 422, ..Default::default() }).unwrap();
+        let filtered = responder.reply(id, String::new(), HashMap::new(), Ending::Finished(Outcome::Filtered("Message Filtered".to_string()))).await;
+        assert_eq!((filtered.status.as_u16(), filtered.body.as_str()), (422, "Message Filtered"));
+
+        assert!(HttpResponder::new(HttpResponseConfig { filtered_status: 1000, ..Default::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reply_with_destination_response() {
+        let responder = responder(HttpResponseConfig {
+            destination: Some("FHIR".to_string()),
+            content_type: Some("application/fhir+json".to_string()),
+            ..Default::default()
+        });
+        let metadata = HashMap::from([("response.FHIR.body".to_string(), "{\"id\":\"7\"}".to_string())]);
+        let processed = Ending::Finished(Outcome::Processed("Message Processed Successfully".to_string()));
+
+        let reply = responder.reply(Uuid::new_v4(), String::new(), metadata, processed.clone()).await;
+        assert_eq!(reply.body, "{\"id\":\"7\"}");
+        assert_eq!(header(&reply, "content-type"), Some("application/fhir+json"));
+
+        // No response recorded (e.g. queued destination): the default text
+        let reply = responder.reply(Uuid::new_v4(), String::new(), HashMap::new(), processed).await;
+        assert_eq!(reply.body, "Message Processed Successfully");
+    }
+
+    #[test]
+    fn test_check_destination() {
+        let destination = |name: &str, queue| DestinationConfig {
+            id: name.to_string(),
+            name: name.to_string(),
+            routes: vec![],
+            queue,
+            timeout_ms: None,
+            kind: DestinationType::Lua { code: "return true".to_string(), limits: LuaLimits::default() },
+        };
+        let mut channel = Channel {
+            id: Uuid::new_v4(),
+            name: "Replies".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations: vec![destination("FHIR", None), destination("Archive", Some(QueueConfig::default()))],
+            error_destination: None,
+            max_retries: None,
+            retry_policy: Default::default(),
+            destination_mode: DestinationMode::Sequential,
+            workers: 1,
+            ordering_key: None,
+        };
+        let config = |name: &str| HttpResponseConfig { destination: Some(name.to_string()), ..Default::default() };
+
+        assert!(HttpResponder::check_destination(&HttpResponseConfig::default(), &channel).is_ok());
+        assert!(HttpResponder::check_destination(&config("FHIR"), &channel).is_ok());
+        assert!(HttpResponder::check_destination(&config("Missing"), &channel).is_err());
+        assert!(HttpResponder::check_destination(&config("Archive"), &channel).is_err());
+        channel.destination_mode = DestinationMode::WaitAll;
+        assert!(HttpResponder::check_destination(&config("FHIR"), &channel).is_ok());
+        // The reply may go out before the response destination has answered
+        channel.destination_mode = DestinationMode::FirstSuccess;
+        assert!(HttpResponder::check_destination(&config("FHIR"), &channel).is_err());
+        channel.destination_mode = DestinationMode::FireAndForget;
+        assert!(HttpResponder::check_destination(&config("FHIR"), &channel).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_script_builds_reply() {
+        let script = r#"
+            if response.outcome == "failed" then return "sorry" end
+            if response.outcome ~= "processed" then return nil end
+            return { status = 201, headers = { ["content-type"] = "application/json", location = "/p/" .. msg.metadata["http.path.id"] },
+                     body = '{"content":"' .. msg.content .. '"}' }
+        "#;
+        let responder = responder(HttpResponseConfig { script: Some(script.to_string()), ..Default::default() });
+        let metadata = HashMap::from([("http.path.id".to_string(), "42".to_string())]);
+
+        let reply = responder.reply(Uuid::new_v4(), "ADT".to_string(), metadata, Ending::Finished(Outcome::Processed("ok".to_string()))).await;
+        assert_eq!(reply.status, StatusCode::CREATED);
+        assert_eq!(reply.body, "{\"content\":\"ADT\"}");
+        assert_eq!(header(&reply, "location"), Some("/p/42"));
+        assert_eq!(header(&reply, "content-type"), Some("application/json"));
+        assert!(header(&reply, "x-message-id").is_some());
+
+        let reply = responder.reply(Uuid::new_v4(), String::new(), HashMap::new(), Ending::Finished(Outcome::Failed("boom".to_string()))).await;
+        assert_eq!((reply.status, reply.body.as_str()), (StatusCode::INTERNAL_SERVER_ERROR, "sorry"));
+        assert_eq!(responder.reply(Uuid::new_v4(), String::new(), HashMap::new(), Ending::TimedOut).await.status, StatusCode::GATEWAY_TIMEOUT);
+
+        // An invalid status is rejected and the default reply sent
+        let invalid = HttpResponder::new(HttpResponseConfig { script: Some("return { status = 99 }".to_string()), ..Default::default() }).unwrap();
+        let reply = invalid.reply(Uuid::new_v4(), String::new(), HashMap::new(), Ending::Finished(Outcome::Processed("ok".to_string()))).await;
+        assert_eq!((reply.status, reply.body.as_str()), (StatusCode::OK, "ok"));
+    }
+}
@@ -1,4 +1,5 @@
 use std::time::Instant;
+use crate::hl7::{Delimiters, Message};
 
 const SB: u8 = 0x0B;
 const EB: u8 = 0x1C;
@@ -97,46 +98,218 @@ impl MllpFrameAccumulator {
     }
 }
 
+/// MSA-1 acknowledgment code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckCode {
+    /// Application accept
+    AA,
+    /// Application error
+    AE,
+    /// Application reject
+    AR,
+    /// Commit accept (enhanced mode)
+    CA,
+    /// Commit error (enhanced mode)
+    CE,
+    /// Commit reject (enhanced mode)
+    CR,
+}
+
+impl AckCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AA => "AA",
+            Self::AE => "AE",
+            Self::AR => "AR",
+            Self::CA => "CA",
+            Self::CE => "CE",
+            Self::CR => "CR",
+        }
+    }
+
+    /// Accept (commit) acknowledgement rather than application acknowledgement
+    pub fn is_commit(&self) -> bool {
+        matches!(self, Self::CA | Self::CE | Self::CR)
+    }
+
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Self::AA | Self::CA)
+    }
+}
+
+/// Longest MSA-3 text message (v2.3)
+const MAX_ACK_TEXT: usize = 80;
+/// Longest MSH-10 message control ID (v2.3)
+const MAX_CONTROL_ID: usize = 20;
+/// MSH-12 used when the message does not carry one
+const DEFAULT_VERSION: &str = "2.3";
+
+/// Wrap a message in an MLLP frame
+pub fn frame(content: &str) -> String {
+    format!("\x0B{}\x1C\x0D", content)
+}
+
+/// MLLP-framed AA ACK for `hl7_msg`
 pub fn generate_ack(hl7_msg: &str) -> String {
-    // 1. Parse MSH
-    // MSH|^~\&|SendingApp|SendingFac|ReceivingApp|ReceivingFac|Timestamp||MSG_TYPE|MSG_CTRL_ID|P|2.3
-    
-    let segments: Vec<&str> = hl7_msg.split('\r').collect();
-    if segments.is_empty() {
-        return "".to_string(); // Invalid
+    frame(&build_ack(hl7_msg, AckCode::AA, None))
+}
+
+/// Unframed ACK for `hl7_msg`. For error codes, `error` goes to MSA-3 and an ERR segment.
+pub fn build_ack(hl7_msg: &str, code: AckCode, error: Option<&str>) -> String {
+    AckRequest::parse(hl7_msg).build(code, error)
+}
+
+/// What an incoming message's MSH says about acknowledging it. Values are kept raw (escaped)
+/// and echoed with the message's own delimiters.
+#[derive(Debug, Clone, Default)]
+pub struct AckRequest {
+    pub delimiters: Delimiters,
+    pub sending_application: String,
+    pub sending_facility: String,
+    pub receiving_application: String,
+    pub receiving_facility: String,
+    /// MSH-9.2
+    pub trigger_event: String,
+    pub control_id: String,
+    pub processing_id: String,
+    pub version: String,
+    /// MSH-15
+    pub accept_ack_type: String,
+    /// MSH-16
+    pub application_ack_type: String,
+}
+
+impl AckRequest {
+    /// Read the MSH of `hl7_msg`. Without a usable MSH every field is left empty.
+    pub fn parse(hl7_msg: &str) -> Self {
+        let header = hl7_msg.trim_start_matches(['\r', '\n']).split(['\r', '\n']).next().unwrap_or_default();
+        let Ok(message) = Message::parse(header) else {
+            return Self::default();
+        };
+        let Some(msh) = message.segment("MSH") else {
+            return Self::default();
+        };
+        let delimiters = message.delimiters.clone();
+        let field = |n: usize| msh.field(n).map(|f| f.encode(&delimiters)).unwrap_or_default();
+        let component = |n: usize, c: usize| msh.field(n).and_then(|f| f.get(1, c, 1)).unwrap_or_default().to_string();
+
+        Self {
+            sending_application: field(3),
+            sending_facility: field(4),
+            receiving_application: field(5),
+            receiving_facility: field(6),
+            trigger_event: component(9, 2),
+            control_id: field(10),
+            processing_id: field(11),
+            version: component(12, 1),
+            accept_ack_type: component(15, 1).to_ascii_uppercase(),
+            application_ack_type: component(16, 1).to_ascii_uppercase(),
+            delimiters,
+        }
     }
-    
-    let msh_fields: Vec<&str> = segments[0].split('|').collect();
-    if msh_fields.len() < 10 {
-         // Fallback basic ACK
-         let now = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
-         return format!("\x0BMSA|AA|Unknown|{}\x1C\x0D", now);
+
+    /// Enhanced acknowledgement mode: the sender filled MSH-15 or MSH-16
+    pub fn is_enhanced(&self) -> bool {
+        !self.accept_ack_type.is_empty() || !self.application_ack_type.is_empty()
+    }
+
+    /// Whether the sender asked for an ACK with this code. Original mode always gets
+    /// application ACKs and never commit ACKs; enhanced mode follows MSH-15 (commit) and
+    /// MSH-16 (application): AL, NE, ER (errors only) or SU (successes only), empty meaning AL.
+    pub fn wants(&self, code: AckCode) -> bool {
+        if !self.is_enhanced() {
+            return !code.is_commit();
+        }
+        let condition = if code.is_commit() { &self.accept_ack_type } else { &self.application_ack_type };
+        match condition.as_str() {
+            "NE" => false,
+            "ER" => code.is_error(),
+            "SU" => !code.is_error(),
+            _ => true,
+        }
+    }
+
+    /// Unframed ACK: MSH (sender and receiver swapped, MSH-9 trigger, MSH-11 and MSH-12
+    /// echoed), MSA and, for error codes, an ERR segment in the message's version
+    pub fn build(&self, code: AckCode, error: Option<&str>) -> String {
+        let d = &self.delimiters;
+        let field = d.field.to_string();
+        let component = d.component.to_string();
+        let version = if self.version.is_empty() { DEFAULT_VERSION } else { &self.version };
+
+        // MSH-9.3 (message structure) exists since v2.3.1
+        let mut message_type = String::from("ACK");
+        if !self.trigger_event.is_empty() {
+            message_type.push_str(&component);
+            message_type.push_str(&self.trigger_event);
+            if version_at_least(version, &[2, 3, 1]) {
+                message_type.push_str(&component);
+                message_type.push_str("ACK");
+            }
+        }
+        let mut control_id = uuid::Uuid::new_v4().simple().to_string();
+        control_id.truncate(MAX_CONTROL_ID);
+        let processing_id = if self.processing_id.is_empty() { "P" } else { &self.processing_id };
+
+        let mut msh = vec![
+            format!("MSH{}{}", d.field, d.encoding_characters()),
+            self.receiving_application.clone(),
+            self.receiving_facility.clone(),
+            self.sending_application.clone(),
+            self.sending_facility.clone(),
+            chrono::Utc::now().format("%Y%m%d%H%M%S").to_string(),
+            String::new(),
+            message_type,
+            control_id,
+            processing_id.to_string(),
+            version.to_string(),
+        ];
+        // An ACK is never acknowledged itself
+        if self.is_enhanced() {
+            msh.extend([String::new(), String::new(), "NE".to_string(), "NE".to_string()]);
+        }
+
+        let mut msa = vec!["MSA".to_string(), code.as_str().to_string(), self.control_id.clone()];
+        let mut segments = vec![msh.join(&field)];
+        match error.filter(|_| code.is_error()) {
+            Some(error) => {
+                let short: String = error.chars().take(MAX_ACK_TEXT).collect();
+                msa.push(d.escape(&short));
+                segments.push(msa.join(&field));
+                segments.push(self.err_segment(version, &d.escape(error)));
+            }
+            None => segments.push(msa.join(&field)),
+        }
+        segments.join("\r")
+    }
+
+    /// ERR with HL7 table 0357 code 207 (application internal error). Before v2.5 the code sits
+    /// in ERR-1 (error location and code); from v2.5 on it is ERR-3, with ERR-4 severity and the
+    /// error text as ERR-8 user message.
+    fn err_segment(&self, version: &str, text: &str) -> String {
+        let d = &self.delimiters;
+        if version_at_least(version, &[2, 5]) {
+            let code = ["207", "Application internal error", "HL70357"].join(&d.component.to_string());
+            ["ERR", "", "", &code, "E", "", "", "", text].join(&d.field.to_string())
+        } else {
+            let sub = d.subcomponent.unwrap_or(d.component).to_string();
+            let code = ["207", "Application internal error", "HL70357"].join(&sub);
+            let location = ["", "", "", &code].join(&d.component.to_string());
+            ["ERR", &location].join(&d.field.to_string())
+        }
     }
-    
-    // MSH-3 (SendingApp) -> becomes ReceivingApp
-    let sending_app = msh_fields.get(2).unwrap_or(&"");
-    let sending_fac = msh_fields.get(3).unwrap_or(&"");
-    // MSH-5 (ReceivingApp) -> becomes SendingApp
-    let receiving_app = msh_fields.get(4).unwrap_or(&"");
-    let receiving_fac = msh_fields.get(5).unwrap_or(&"");
-    
-    let msg_control_id = msh_fields.get(9).unwrap_or(&"");
-    //let _msg_type = msh_fields.get(8).unwrap_or(&""); 
-    
-    let now = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
-    
-    // Construct ACK (MSA needed)
-    // MSH|^~\&|{ReceivingApp}|{ReceivingFac}|{SendingApp}|{SendingFac}|{TIMESTAMP}||ACK|{NEW_ID}|P|2.3
-    // MSA|AA|{MSG_CTRL_ID}
-    
-    let ack_msh = format!(
-        "MSH|^~\\&|{}|{}|{}|{}|{}||ACK|{}|P|2.3",
-        receiving_app, receiving_fac, sending_app, sending_fac, now,  uuid::Uuid::new_v4().to_string()
-    );
-    
-    let ack_msa = format!("MSA|AA|{}", msg_control_id);
-    
-    format!("\x0B{}\r{}\x1C\x0D", ack_msh, ack_msa)
+}
+
+/// Compare a dotted HL7 version (`2.5.1`) with `minimum`; unreadable parts count as 0
+fn version_at_least(version: &str, minimum: &[u32]) -> bool {
+    let parts: Vec<u32> = version.split('.').map(|p| p.trim().parse().unwrap_or(0)).collect();
+    for (i, min) in minimum.iter().enumerate() {
+        let part = parts.get(i).copied().unwrap_or(0);
+        if part != *min {
+            return part > *min;
+        }
+    }
+    true
 }
 
 #[cfg(test)]
@@ -208,8 +381,71 @@ mod tests {
         
         assert!(ack.starts_with("\x0BMSH"));
         assert!(ack.contains("|Mirth|System|HIS|Hospital|")); // Default swapped
-        assert!(ack.contains("|ACK|"));
+        assert!(ack.contains("||ACK^A01|"));
         assert!(ack.contains("MSA|AA|MSG12345"));
         assert!(ack.ends_with("\x1C\x0D"));
     }
+
+    #[test]
+    fn test_ack_echoes_header() {
+        let input_hl7 = "MSH|^~\\&|HIS|Hospital|Mirth|System|20231010120000||ADT^A01^ADT_A01|MSG12345|T|2.5.1\rPID|...";
+        let ack = build_ack(input_hl7, AckCode::AA, None);
+        let msh: Vec<&str> = ack.split('\r').next().unwrap().split('|').collect();
+        assert_eq!(msh[8], "ACK^A01^ACK");
+        assert!(msh[9].len() <= 20 && !msh[9].is_empty());
+        assert_eq!(msh[10], "T");
+        assert_eq!(msh[11], "2.5.1");
+        assert_eq!(msh.len(), 12);
+
+        // No message structure before v2.3.1, custom delimiters are kept
+        let ack = build_ack("MSH#*~\\&#HIS#H#LAB#L#2023##ADT*A04#C1#D#2.2", AckCode::AA, None);
+        assert!(ack.starts_with("MSH#*~\\&#LAB#L#HIS#H#"));
+        assert!(ack.contains("##ACK*A04#"));
+        assert!(ack.ends_with("#D#2.2\rMSA#AA#C1"));
+    }
+
+    #[test]
+    fn test_ack_without_msh() {
+        let ack = build_ack("PID|1||123", AckCode::AA, None);
+        let segments: Vec<&str> = ack.split('\r').collect();
+        let msh: Vec<&str> = segments[0].split('|').collect();
+        assert_eq!(msh[..6], ["MSH", "^~\\&", "", "", "", ""]);
+        assert_eq!(msh[8..], ["ACK", msh[9], "P", "2.3"]);
+        assert_eq!(segments[1], "MSA|AA|");
+    }
+
+    #[test]
+    fn test_error_ack_carries_err_segment() {
+        let input_hl7 = "MSH|^~\\&|HIS|Hospital|Mirth|System|20231010120000||ADT^A01|MSG12345|P|2.3\rPID|...";
+        let ack = build_ack(input_hl7, AckCode::AE, Some("Processor Map: field|missing"));
+        let segments: Vec<&str> = ack.split('\r').collect();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[1], "MSA|AE|MSG12345|Processor Map: field\\F\\missing");
+        assert_eq!(segments[2], "ERR|^^^207&Application internal error&HL70357");
+
+        let v25 = input_hl7.replace("|P|2.3", "|P|2.5");
+        let ack = build_ack(&v25, AckCode::AR, Some("Message Filtered"));
+        assert!(ack.ends_with("\rMSA|AR|MSG12345|Message Filtered\rERR|||207^Application internal error^HL70357|E||||Message Filtered"));
+        assert!(build_ack(input_hl7, AckCode::AA, Some("ignored")).ends_with("MSA|AA|MSG12345"));
+    }
+
+    #[test]
+    fn test_enhanced_mode() {
+        let original = AckRequest::parse("MSH|^~\\&|A|B|C|D|2023||ADT^A01|1|P|2.5");
+        assert!(!original.is_enhanced());
+        assert!(original.wants(AckCode::AA) && original.wants(AckCode::AE));
+        assert!(!original.wants(AckCode::CA));
+
+        let enhanced = AckRequest::parse("MSH|^~\\&|A|B|C|D|2023||ADT^A01|1|P|2.5|||AL|ER");
+        assert!(enhanced.is_enhanced());
+        assert!(enhanced.wants(AckCode::CA) && enhanced.wants(AckCode::CE));
+        assert!(!enhanced.wants(AckCode::AA));
+        assert!(enhanced.wants(AckCode::AE) && enhanced.wants(AckCode::AR));
+
+        let never = AckRequest::parse("MSH|^~\\&|A|B|C|D|2023||ADT^A01|1|P|2.5|||NE|SU");
+        assert!(!never.wants(AckCode::CA) && never.wants(AckCode::AA) && !never.wants(AckCode::AE));
+
+        let ack = enhanced.build(AckCode::CA, None);
+        assert!(ack.contains("|P|2.5|||NE|NE\rMSA|CA|1"));
+    }
 }
@@ -1,5 +1,9 @@
 pub mod http;
 pub mod tcp;
 pub mod mllp;
+pub mod ack;
+pub mod http_response;
+pub mod reply_script;
+pub mod client_auth;
 pub mod database;
 pub mod file;
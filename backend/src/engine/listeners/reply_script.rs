@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use mlua::prelude::*;
+use crate::engine::lua_pool::LuaPool;
+use crate::lua_helpers::sandbox;
+use crate::storage::models::LuaLimits;
+
+/// Lua hook that customises the reply of a source (`ack_script`, the HTTP `response.script`).
+///
+/// The script sees a `msg` table and a table named after the reply (`ack`, `response`) holding
+/// the default reply. Returning nil keeps the default; any other value is read by the source. If
+/// the script fails, or returns something the source cannot use, the error is logged and the
+/// default is sent, so a broken script never leaves the sender without a reply.
+pub struct ReplyScript {
+    reply: &'static str,
+    pool: Arc<LuaPool>,
+}
+
+impl ReplyScript {
+    /// `None` when no script is configured
+    pub fn new(reply: &'static str, code: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let Some(code) = code.filter(|code| !code.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let pool = sandbox::build_pool(reply, code, true, LuaLimits::default())?;
+        Ok(Some(Self { reply, pool: Arc::new(pool) }))
+    }
+
+    /// Run the script: `fill` sets the fields of the `msg` and reply tables, `read` turns a
+    /// non-nil return value into the reply
+    pub async fn run<T, F, R>(&self, default: T, fill: F, read: R) -> T
+    where
+        T: Clone + Send + 'static,
+        F: FnOnce(&Lua, &LuaTable, &LuaTable) -> LuaResult<()> + Send + 'static,
+        R: FnOnce(&Lua, LuaValue, T) -> LuaResult<T> + Send + 'static,
+    {
+        let reply = self.reply;
+        let fallback = default.clone();
+        let result = self.pool.execute(move |lua, script| {
+            let msg_table = lua.create_table()?;
+            let reply_table = lua.create_table()?;
+            fill(lua, &msg_table, &reply_table)?;
+            lua.globals().set("msg", msg_table)?;
+            lua.globals().set(reply, reply_table)?;
+
+            match script.call::<_, LuaValue>(())? {
+                LuaValue::Nil => Ok(default),
+                value => read(lua, value, default),
+            }
+        }).await;
+
+        match result {
+            Ok(custom) => custom,
+            Err(e) => {
+                tracing::error!("The {} script failed, sending the default {}: {}", reply, reply, e);
+                fallback
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(code: &str) -> String {
+        let script = ReplyScript::new("reply", Some(code)).unwrap().unwrap();
+        script.run(
+            "default".to_string(),
+            |_, msg, reply| {
+                msg.set("content", "ADT")?;
+                reply.set("content", "default")
+            },
+            |lua, value, _| {
+                let custom = String::from_lua(value, lua)?;
+                if custom.is_empty() {
+                    return Err(LuaError::RuntimeError("empty reply".to_string()));
+                }
+                Ok(custom)
+            },
+        ).await
+    }
+
+    #[tokio::test]
+    async fn test_script_replaces_or_keeps_default() {
+        assert_eq!(run("return reply.content .. ':' .. msg.content").await, "default:ADT");
+        assert_eq!(run("return nil").await, "default");
+        assert!(ReplyScript::new("reply", Some("  ")).unwrap().is_none());
+        assert!(ReplyScript::new("reply", Some("this is not lua")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failing_script_falls_back_to_default() {
+        assert_eq!(run("error('nope')").await, "default");
+        // Rejected by the source's reader
+        assert_eq!(run("return ''").await, "default");
+    }
+}
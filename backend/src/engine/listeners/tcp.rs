@@ -2,18 +2,19 @@ use std::net::SocketAddr;
 use tokio::net::{TcpListener as TokioTcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
-use crate::engine::message::Message;
+use crate::engine::message::{Message, Outcome};
 use crate::storage::messages::MessageStore;
-use crate::engine::listeners::mllp::{MllpFrameAccumulator, generate_ack};
+use crate::engine::listeners::mllp::AckCode;
+use crate::engine::framing::{self, Framer};
+use crate::engine::listeners::ack::AckResponder;
+use crate::storage::models::{AckMode, Framing};
 use uuid::Uuid;
 use std::time::Duration;
 
 use crate::config::TlsConfig;
+use crate::engine::listeners::client_auth::{self, PeerIdentity};
 use tokio_rustls::TlsAcceptor;
-use tokio_rustls::rustls::{ServerConfig, pki_types::{CertificateDer, PrivateKeyDer}};
 use std::sync::Arc;
-use std::io::BufReader;
-use std::fs::File;
 
 pub struct TcpListener {
     pub port: u16,
@@ -21,6 +22,18 @@ pub struct TcpListener {
     pub sender: mpsc::Sender<Message>,
     pub store: Option<MessageStore>,
     pub tls_config: Option<TlsConfig>,
+    pub ack: Arc<AckResponder>,
+    pub framing: Framing,
+}
+
+/// What every connection of a listener shares
+struct Connection {
+    channel_id: Uuid,
+    sender: mpsc::Sender<Message>,
+    store: Option<MessageStore>,
+    local_port: u16,
+    ack: Arc<AckResponder>,
+    framing: Framing,
 }
 
 impl TcpListener {
@@ -31,9 +44,21 @@ impl TcpListener {
             sender,
             store,
             tls_config,
+            ack: Arc::new(AckResponder::new(AckMode::Immediate, None).expect("default ACK responder")),
+            framing: Framing::default(),
         }
     }
 
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn with_ack(mut self, ack: AckResponder) -> Self {
+        self.ack = Arc::new(ack);
+        self
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         let port = self.port;
         let channel_id = self.channel_id;
@@ -41,20 +66,12 @@ impl TcpListener {
         // TLS Setup
         let tls_acceptor = if let Some(cfg) = &self.tls_config {
             tracing::info!("🔒 Configuring TLS for Channel {}", channel_id);
-             // Load certs
-            let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cfg.cert_path)?))
-                .collect::<Result<Vec<_>, _>>()?;
-            let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&cfg.key_path)?))?
-                .ok_or_else(|| anyhow::anyhow!("No private key found"))?;
-
-            let config = ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(certs, key)?;
-            
+            let config = client_auth::server_config(cfg)?;
             Some(TlsAcceptor::from(Arc::new(config)))
         } else {
             None
         };
+        let peer_auth = Arc::new(self.tls_config.as_ref().and_then(|cfg| cfg.client_auth.clone()));
 
         let bind_addr: std::net::IpAddr = std::env::var("LISTENER_BIND_ADDRESS")
             .unwrap_or_else(|_| "0.0.0.0".to_string())
@@ -69,25 +86,35 @@ impl TcpListener {
             e
         })?;
 
+        let connection = Arc::new(Connection {
+            channel_id,
+            sender: self.sender.clone(),
+            store: self.store.clone(),
+            local_port: port,
+            ack: self.ack.clone(),
+            framing: self.framing.clone(),
+        });
+
         loop {
             match listener.accept().await {
                 Ok((socket, addr)) => {
-                    let sender_clone = self.sender.clone();
-                    let store_clone = self.store.clone();
                     let acceptor = tls_acceptor.clone();
+                    let connection = connection.clone();
 
                     if let Some(acceptor) = acceptor {
+                        let peer_auth = peer_auth.clone();
                         tokio::spawn(async move {
                             match acceptor.accept(socket).await {
-                                Ok(stream) => {
-                                    handle_connection(stream, addr, channel_id, sender_clone, store_clone, port).await;
+                                Ok(stream) => match client_auth::authorize(stream.get_ref().1, peer_auth.as_ref().as_ref()) {
+                                    Ok(peer) => handle_connection(stream, addr, peer.as_ref(), &connection).await,
+                                    Err(e) => tracing::warn!("Refused TLS client {}: {}", addr, e),
                                 },
                                 Err(e) => tracing::error!("TLS Handshake failed from {}: {}", addr, e),
                             }
                         });
                     } else {
                         tokio::spawn(async move {
-                            handle_connection(socket, addr, channel_id, sender_clone, store_clone, port).await;
+                            handle_connection(socket, addr, None, &connection).await;
                         });
                     }
                 }
@@ -99,15 +126,26 @@ impl TcpListener {
     }
 }
 
-async fn handle_connection<S>(mut socket: S, addr: SocketAddr, channel_id: Uuid, sender: mpsc::Sender<Message>, store: Option<MessageStore>, local_port: u16) 
+/// Receive messages from one client; `peer` is its verified client certificate (mutual TLS)
+async fn handle_connection<S>(mut socket: S, addr: SocketAddr, peer: Option<&PeerIdentity>, connection: &Connection)
 where S: AsyncReadExt + AsyncWriteExt + Unpin
 {
+    let Connection { channel_id, sender, store, local_port, ack, framing } = connection;
+    let channel_id = *channel_id;
     tracing::info!("Accepted connection from {}", addr);
     let mut buffer = [0u8; 4096];
     
-    // MLLP Accumulator with 30s timeout logic (checked manually or via read timeout)
+    // Framer with 30s timeout logic (checked manually or via read timeout)
     const TIMEOUT_SECS: u64 = 30;
-    let mut accumulator = MllpFrameAccumulator::new(TIMEOUT_SECS * 1000);
+    let mut accumulator = match framing::framer(framing, TIMEOUT_SECS * 1000) {
+        Ok(framer) => framer,
+        Err(e) => {
+            tracing::error!("Invalid framing for channel {}: {}", channel_id, e);
+            return;
+        }
+    };
+    // Link-level acknowledgements (ASTM) replace HL7 ACKs
+    let hl7_acks = !accumulator.link_acknowledged();
 
     loop {
         // Read with timeout
@@ -126,14 +164,22 @@ where S: AsyncReadExt + AsyncWriteExt + Unpin
                 tracing::debug!("Received {} bytes from {}", n, addr);
                 
                 let messages = accumulator.feed(data);
+                let reply = accumulator.take_reply();
+                if !reply.is_empty() {
+                    if let Err(e) = socket.write_all(&reply).await {
+                        tracing::error!("Failed to answer {}: {}", addr, e);
+                        break;
+                    }
+                }
                 
                 for content in messages {
                     let origin = format!("TCP :{} from {}", local_port, addr);
-                    tracing::info!("Received complete MLLP message from {}", origin);
+                    tracing::info!("Received complete message from {}", origin);
                     
                     // 2. Persist
                     let mut persistence_id = Uuid::new_v4().to_string();
                     let mut persisted = false;
+                    let mut persist_error = String::new();
                     
                     if let Some(s) = &store {
                          match s.save_message(&channel_id.to_string(), &content).await {
@@ -144,6 +190,7 @@ where S: AsyncReadExt + AsyncWriteExt + Unpin
                             },
                             Err(e) => {
                                 tracing::error!("CRITICAL: Failed to persist message: {}", e);
+                                persist_error = format!("Failed to persist message: {}", e);
                             }
                         }
                     } else {
@@ -151,25 +198,56 @@ where S: AsyncReadExt + AsyncWriteExt + Unpin
                     }
                     
                     if persisted {
-                        // 3. Send ACK
-                        let ack = generate_ack(&content);
-                        if let Err(e) = socket.write_all(ack.as_bytes()).await {
-                            tracing::error!("Failed to send ACK to {}: {}", addr, e);
-                            break; 
-                        } else {
-                            tracing::info!("ACK sent to {}", addr);
+                        // 3. Send ACK (immediate mode, or enhanced-mode commit ACK)
+                        let reply = if hl7_acks { ack.receipt(&content).await } else { None };
+                        if let Some(reply) = reply {
+                            if !write_ack(&mut socket, accumulator.as_ref(), &reply, addr).await {
+                                break;
+                            }
                         }
-                        
+
                         // 4. Dispatch to Pipeline
-                        let mut msg = Message::new(channel_id, content, origin);
+                        let mut msg = Message::new(channel_id, content.clone(), origin);
                         if let Ok(uuid) = Uuid::parse_str(&persistence_id) {
                             msg.id = uuid;
                         }
-                        
+                        if let Some(peer) = peer {
+                            peer.apply(&mut msg);
+                        }
+                        let response_rx = if hl7_acks && ack.mode == AckMode::AfterProcessing {
+                            let (tx, rx) = tokio::sync::oneshot::channel();
+                            msg.response_tx = Some(Arc::new(std::sync::Mutex::new(Some(tx))));
+                            Some(rx)
+                        } else {
+                            None
+                        };
+
                         if let Err(e) = sender.send(msg).await {
                              tracing::error!("Failed to send message to pipeline: {}", e);
                              break;
                         }
+
+                        // 5. Send ACK (after-processing mode)
+                        if let Some(rx) = response_rx {
+                            let (code, error) = match tokio::time::timeout(Duration::from_secs(TIMEOUT_SECS), rx).await.map(|r| r.map(|reply| reply.outcome)) {
+                                Ok(Ok(Outcome::Processed(_))) => (AckCode::AA, None),
+                                Ok(Ok(Outcome::Filtered(text))) => (AckCode::AR, Some(text)),
+                                Ok(Ok(Outcome::Failed(text))) => (AckCode::AE, Some(text)),
+                                Ok(Err(_)) => (AckCode::AE, Some("Pipeline closed without a response".to_string())),
+                                Err(_) => (AckCode::AE, Some("Processing timeout".to_string())),
+                            };
+                            if let Some(reply) = ack.processed(&content, code, error.as_deref()).await {
+                                if !write_ack(&mut socket, accumulator.as_ref(), &reply, addr).await {
+                                    break;
+                                }
+                            }
+                        }
+                    } else if hl7_acks {
+                        if let Some(reply) = ack.persist_failed(&content, &persist_error).await {
+                            if !write_ack(&mut socket, accumulator.as_ref(), &reply, addr).await {
+                                break;
+                            }
+                        }
                     }
                 }
             },
@@ -188,3 +266,183 @@ where S: AsyncReadExt + AsyncWriteExt + Unpin
         }
     }
 }
+
+/// Frame and write an ACK; false when the connection can no longer be used
+async fn write_ack<S>(socket: &mut S, framer: &dyn Framer, ack: &str, addr: SocketAddr) -> bool
+where S: AsyncWriteExt + Unpin
+{
+    let bytes = match framer.encode(ack) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Cannot frame ACK for {}: {}", addr, e);
+            return true;
+        }
+    };
+    match socket.write_all(&bytes).await {
+        Ok(()) => {
+            tracing::info!("ACK sent to {}", addr);
+            true
+        },
+        Err(e) => {
+            tracing::error!("Failed to send ACK to {}: {}", addr, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::framing::astm;
+    use crate::engine::listeners::mllp::frame;
+    use crate::engine::message::Reply;
+
+    const HL7: &str = "MSH|^~\\&|HIS|HOSP|LAB|LAB|20240101120000||ADT^A01|MSG1|P|2.3\rPID|1";
+
+    /// Runs one connection over an in-memory stream; the fake pipeline answers with `outcome`.
+    /// Returns what the listener wrote back and the messages it dispatched.
+    async fn exchange(mode: AckMode, framing: Framing, input: &[u8], outcome: Option<Outcome>) -> (Vec<u8>, Vec<String>) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let (tx, mut rx) = mpsc::channel::<Message>(1);
+        let pipeline = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(msg) = rx.recv().await {
+                received.push(msg.content.clone());
+                if let (Some(outcome), Some(response_tx)) = (outcome.clone(), msg.response_tx) {
+                    let reply = Reply { outcome, content: msg.content, metadata: msg.metadata };
+                    let _ = response_tx.lock().unwrap().take().unwrap().send(reply);
+                }
+            }
+            received
+        });
+        let connection = Connection {
+            channel_id: Uuid::new_v4(),
+            sender: tx,
+            store: None,
+            local_port: 2575,
+            ack: Arc::new(AckResponder::new(mode, None).unwrap()),
+            framing,
+        };
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let server = tokio::spawn(async move { handle_connection(server, addr, None, &connection).await });
+
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        server.await.unwrap();
+        (reply, pipeline.await.unwrap())
+    }
+
+    async fn mllp_exchange(mode: AckMode, outcome: Option<Outcome>) -> String {
+        let (reply, _) = exchange(mode, Framing::Mllp, frame(HL7).as_bytes(), outcome).await;
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ack_modes() {
+        let reply = mllp_exchange(AckMode::Immediate, None).await;
+        assert!(reply.contains("\rMSA|AA|MSG1\x1C"));
+
+        let reply = mllp_exchange(AckMode::AfterProcessing, Some(Outcome::Filtered("Message Filtered".to_string()))).await;
+        assert!(reply.contains("\rMSA|AR|MSG1|Message Filtered\rERR|"));
+
+        let reply = mllp_exchange(AckMode::AfterProcessing, Some(Outcome::Failed("Processor Map failed".to_string()))).await;
+        assert!(reply.contains("\rMSA|AE|MSG1|Processor Map failed\rERR|"));
+
+        let reply = mllp_exchange(AckMode::AfterProcessing, Some(Outcome::Processed("ok".to_string()))).await;
+        assert!(reply.contains("\rMSA|AA|MSG1\x1C"));
+
+        assert_eq!(mllp_exchange(AckMode::None, None).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_listener() {
+        let framing = Framing::LengthPrefixed { width: 4 };
+        let mut input = (HL7.len() as u32).to_be_bytes().to_vec();
+        input.extend_from_slice(HL7.as_bytes());
+        let (reply, received) = exchange(AckMode::Immediate, framing, &input, None).await;
+        assert_eq!(received, vec![HL7]);
+        let length = u32::from_be_bytes(reply[..4].try_into().unwrap()) as usize;
+        assert_eq!(length, reply.len() - 4);
+        assert!(String::from_utf8_lossy(&reply[4..]).ends_with("\rMSA|AA|MSG1"));
+    }
+
+    #[tokio::test]
+    async fn test_astm_listener_sends_link_acks_only() {
+        let record = "H|\\^&|||Analyzer\rL|1|N";
+        let mut input = vec![astm::ENQ];
+        for frame in astm::frames(record) {
+            input.extend(frame);
+        }
+        input.push(astm::EOT);
+        let (reply, received) = exchange(AckMode::Immediate, Framing::Astm, &input, None).await;
+        assert_eq!(received, vec![format!("{}\r", record)]);
+        assert_eq!(reply, vec![astm::ACK; 3]);
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// TLS client trusting the test CA, presenting the test client certificate when `with_cert`
+    fn tls_client(with_cert: bool) -> tokio_rustls::TlsConnector {
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(fixture("ca.pem")).unwrap())) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(crate::config::tls_provider())
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots);
+        let config = if with_cert {
+            let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(fixture("client.pem")).unwrap()))
+                .collect::<Result<Vec<_>, _>>().unwrap();
+            let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(fixture("client.key")).unwrap()))
+                .unwrap().unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+    }
+
+    /// TLS handshake over an in-memory stream; the server's verdict on the client certificate
+    async fn mtls_handshake(with_cert: bool, allowed_subjects: &[&str]) -> anyhow::Result<Option<PeerIdentity>> {
+        let client_auth = crate::storage::models::ClientAuth {
+            ca_path: fixture("ca.pem"),
+            mode: crate::storage::models::ClientAuthMode::Required,
+            allowed_subjects: allowed_subjects.iter().map(|s| s.to_string()).collect(),
+        };
+        let tls = TlsConfig::new(fixture("server.pem").into(), fixture("server.key").into())
+            .with_client_auth(Some(client_auth.clone()));
+        let acceptor = TlsAcceptor::from(Arc::new(client_auth::server_config(&tls).unwrap()));
+
+        let (client, server) = tokio::io::duplex(16384);
+        let connector = tls_client(with_cert);
+        let client = tokio::spawn(async move {
+            let name = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(name, client).await?;
+            // TLS 1.3 client certificates are checked after the client's handshake completes
+            stream.write_all(b"x").await?;
+            let mut buf = [0u8; 1];
+            let _ = stream.read(&mut buf).await;
+            anyhow::Ok(())
+        });
+        let stream = acceptor.accept(server).await?;
+        let verdict = client_auth::authorize(stream.get_ref().1, Some(&client_auth));
+        drop(stream);
+        let _ = client.await;
+        verdict
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_client_identity() {
+        let peer = mtls_handshake(true, &["CN=mirthbr-*"]).await.unwrap().unwrap();
+        assert_eq!(peer.common_name.as_deref(), Some("mirthbr-client"));
+
+        assert!(mtls_handshake(true, &["CN=someone-else"]).await.is_err());
+        // Required client auth refuses clients without a certificate
+        assert!(mtls_handshake(false, &[]).await.is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use mlua::{Function, HookTriggers, Lua, RegistryKey, Table, Value};
+use crate::storage::models::LuaLimits;
+
+/// Idle states kept per pool; more are created on demand when several workers run the same script
+const DEFAULT_POOL_SIZE: usize = 4;
+/// VM instructions between two limit checks
+const HOOK_INTERVAL: u32 = 1_000;
+/// Extra time given to the hook before the caller stops waiting (a script stuck inside a single
+/// C function, e.g. a huge `string.rep`, cannot be interrupted by the hook)
+const ABANDON_GRACE: Duration = Duration::from_secs(1);
+
+/// Prepares a fresh state (libraries, helpers, limits) before the script is compiled into it
+pub type StateInit = fn(&Lua) -> mlua::Result<()>;
+
+/// A Lua state with the script already compiled and a snapshot of its pristine globals
+struct PooledState {
+    lua: Lua,
+    script: RegistryKey,
+    baseline: RegistryKey,
+}
+
+/// Pool of pre-initialized Lua states running one script. The script is compiled when a state
+/// is created (the first one at deploy time), and globals are restored after every run so no
+/// data leaks from one message to the next.
+pub struct LuaPool {
+    name: String,
+    source: String,
+    init: StateInit,
+    idle: Mutex<Vec<PooledState>>,
+    max_idle: usize,
+    limits: LuaLimits,
+}
+
+impl LuaPool {
+    /// Build the pool and compile the script once, so syntax errors surface at deploy
+    pub fn new(name: &str, source: String, init: StateInit) -> anyhow::Result<Self> {
+        let pool = Self {
+            name: name.to_string(),
+            source,
+            init,
+            idle: Mutex::new(Vec::new()),
+            max_idle: DEFAULT_POOL_SIZE,
+            limits: LuaLimits::default(),
+        };
+        let state = pool.create_state()?;
+        pool.idle.lock().unwrap().push(state);
+        Ok(pool)
+    }
+
+    /// Instruction budget and wall-clock timeout of every run
+    pub fn with_limits(mut self, limits: LuaLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn create_state(&self) -> anyhow::Result<PooledState> {
+        let lua = Lua::new_with(mlua::StdLib::NONE, mlua::LuaOptions::default())
+            .map_err(|e| anyhow::anyhow!("Lua init error: {}", e))?;
+        (self.init)(&lua).map_err(|e| anyhow::anyhow!("Lua init error: {}", e))?;
+
+        let script = {
+            let function = lua.load(&self.source)
+                .set_name(self.name.as_str())
+                .into_function()
+                .map_err(|e| anyhow::anyhow!("Lua compile error in {}: {}", self.name, e))?;
+            lua.create_registry_value(function)?
+        };
+        let baseline = snapshot_globals(&lua)?;
+
+        Ok(PooledState { lua, script, baseline })
+    }
+
+    /// Run the script on the blocking thread pool so a slow script never stalls the async
+    /// executor. Exceeding the limits is reported as an error.
+    pub async fn execute<R, F>(self: &Arc<Self>, call: F) -> anyhow::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Lua, Function) -> mlua::Result<R> + Send + 'static,
+    {
+        let pool = self.clone();
+        let task = tokio::task::spawn_blocking(move || pool.run(call));
+
+        match tokio::time::timeout(self.limits.timeout() + ABANDON_GRACE, task).await {
+            Ok(joined) => joined.map_err(|e| anyhow::anyhow!("Lua {} panicked: {}", self.name, e))?,
+            Err(_) => Err(anyhow::anyhow!("Lua {} did not finish within {} ms", self.name, self.limits.timeout_ms)),
+        }
+    }
+
+    /// Run the compiled script on a pooled state. `call` sets up the inputs (e.g. the `msg`
+    /// global) and invokes the script; the state is reset and returned to the pool afterwards.
+    pub fn run<R>(&self, call: impl FnOnce(&Lua, Function) -> mlua::Result<R>) -> anyhow::Result<R> {
+        let state = self.idle.lock().unwrap().pop();
+        let state = match state {
+            Some(state) => state,
+            None => self.create_state()?,
+        };
+
+        let result = {
+            let script: Function = state.lua.registry_value(&state.script)?;
+            let budget = Budget::new(self.limits);
+            state.lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |lua, _| budget.check(lua));
+            let result = call(&state.lua, script);
+            state.lua.remove_hook();
+            result
+        };
+
+        // A state whose reset fails is dropped rather than reused
+        match restore_globals(&state.lua, &state.baseline) {
+            Ok(()) => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < self.max_idle {
+                    idle.push(state);
+                }
+            },
+            Err(e) => tracing::warn!("Discarding Lua state of {}: reset failed: {}", self.name, e),
+        }
+
+        result.map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+impl LuaLimits {
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Limit accounting of one run, driven by the instruction hook
+struct Budget {
+    executed: AtomicU64,
+    max_instructions: u64,
+    timeout_ms: u64,
+    deadline: Instant,
+}
+
+impl Budget {
+    fn new(limits: LuaLimits) -> Self {
+        Self {
+            executed: AtomicU64::new(0),
+            max_instructions: limits.max_instructions,
+            timeout_ms: limits.timeout_ms,
+            deadline: Instant::now() + limits.timeout(),
+        }
+    }
+
+    fn check(&self, lua: &Lua) -> mlua::Result<()> {
+        let executed = self.executed.fetch_add(HOOK_INTERVAL as u64, Ordering::Relaxed) + HOOK_INTERVAL as u64;
+        let violation = if executed > self.max_instructions {
+            format!("Lua script exceeded the instruction limit of {}", self.max_instructions)
+        } else if Instant::now() >= self.deadline {
+            format!("Lua script timed out after {} ms", self.timeout_ms)
+        } else {
+            return Ok(());
+        };
+
+        // From now on fail on every instruction, so a script cannot swallow the error with
+        // `pcall` and keep running
+        let error = violation.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(1), move |_, _| Err(mlua::Error::RuntimeError(error.clone())));
+        Err(mlua::Error::RuntimeError(violation))
+    }
+}
+
+/// Copy of the globals, and of the fields of every global table (library tables included)
+fn snapshot_globals(lua: &Lua) -> mlua::Result<RegistryKey> {
+    let globals = lua.globals();
+    let snapshot = lua.create_table()?;
+    for pair in globals.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let entry = lua.create_table()?;
+        entry.raw_set(1, value.clone())?;
+        if let Value::Table(table) = &value {
+            if *table != globals {
+                entry.raw_set(2, copy_table(lua, table)?)?;
+            }
+        }
+        snapshot.raw_set(key, entry)?;
+    }
+    lua.create_registry_value(snapshot)
+}
+
+fn restore_globals(lua: &Lua, baseline: &RegistryKey) -> mlua::Result<()> {
+    let snapshot: Table = lua.registry_value(baseline)?;
+    let globals = lua.globals();
+
+    restore_table(&globals, &snapshot, true)?;
+
+    for pair in snapshot.pairs::<Value, Table>() {
+        let (_, entry) = pair?;
+        if let (Value::Table(table), Value::Table(fields)) = (entry.raw_get::<_, Value>(1)?, entry.raw_get::<_, Value>(2)?) {
+            restore_table(&table, &fields, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Make `table` hold exactly the keys of `saved` again. With `entries`, saved values are
+/// snapshot entries (`{value, fields}`) rather than the values themselves.
+fn restore_table(table: &Table, saved: &Table, entries: bool) -> mlua::Result<()> {
+    let mut added = Vec::new();
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        if saved.raw_get::<_, Value>(key.clone())?.is_nil() {
+            added.push(key);
+        }
+    }
+    for key in added {
+        table.raw_set(key, Value::Nil)?;
+    }
+    for pair in saved.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let value = match value {
+            Value::Table(entry) if entries => entry.raw_get(1)?,
+            value => value,
+        };
+        table.raw_set(key, value)?;
+    }
+    Ok(())
+}
+
+fn copy_table<'lua>(lua: &'lua Lua, table: &Table<'lua>) -> mlua::Result<Table<'lua>> {
+    let copy = lua.create_table()?;
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        copy.raw_set(key, value)?;
+    }
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init(lua: &Lua) -> mlua::Result<()> {
+        lua.load_from_std_lib(mlua::StdLib::STRING | mlua::StdLib::TABLE)
+    }
+
+    #[test]
+    fn test_state_is_reset_between_runs() {
+        let pool = LuaPool::new("test", "counter = (counter or 0) + 1; string.upper = nil; return counter".to_string(), init).unwrap();
+
+        for _ in 0..3 {
+            let count: i64 = pool.run(|_, script| script.call(())).unwrap();
+            assert_eq!(count, 1);
+            let upper: String = pool.run(|lua, _| lua.load("return string.upper('a')").eval()).unwrap();
+            assert_eq!(upper, "A");
+        }
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let limits = LuaLimits { max_instructions: 100_000, timeout_ms: 60_000 };
+        let pool = LuaPool::new("test", "while true do end".to_string(), init).unwrap().with_limits(limits);
+
+        let err = pool.run(|_, script| script.call::<_, ()>(())).unwrap_err();
+        assert!(err.to_string().contains("instruction limit of 100000"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_cannot_be_caught() {
+        let limits = LuaLimits { max_instructions: u64::MAX, timeout_ms: 50 };
+        let source = "while true do pcall(function() while true do end end) end".to_string();
+        let pool = Arc::new(LuaPool::new("test", source, init).unwrap().with_limits(limits));
+
+        let err = pool.execute(|_, script| script.call::<_, ()>(())).await.unwrap_err();
+        assert!(err.to_string().contains("timed out after 50 ms"), "{}", err);
+
+        // The state is reset and reusable after an aborted run
+        let value: i64 = pool.execute(|lua, _| lua.load("return 1 + 1").eval()).await.unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[test]
+    fn test_compile_error_at_creation() {
+        assert!(LuaPool::new("broken", "return (".to_string(), init).is_err());
+    }
+}
@@ -14,9 +14,43 @@ pub struct Message {
     pub timestamp: DateTime<Utc>,
     #[serde(default)]
     pub origin: Option<String>,
+    /// Re-sent under its stored id (retry or recovery); not checked for duplicates again
+    #[serde(default)]
+    pub redelivery: bool,
     #[serde(skip)]
     #[serde(default)]
-    pub response_tx: Option<Arc<Mutex<Option<oneshot::Sender<Result<String, String>>>>>>,
+    pub response_tx: Option<ResponseSender>,
+}
+
+/// Answer channel of a source that waits for the pipeline (taken by the first answer)
+pub type ResponseSender = Arc<Mutex<Option<oneshot::Sender<Reply>>>>;
+
+/// Answer to a waiting source: the outcome, plus the message as the pipeline left it
+/// (transformed content, metadata such as `response.<destination>.body`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub outcome: Outcome,
+    pub content: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// What the pipeline did with a message, as reported to a waiting source
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Delivered; the text mentions destinations that failed, if any
+    Processed(String),
+    /// Dropped on purpose: filtered, unrouted or a duplicate
+    Filtered(String),
+    /// A processor failed, or every destination did
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Processed(text) | Self::Filtered(text) | Self::Failed(text) => text,
+        }
+    }
 }
 
 impl Message {
@@ -28,6 +62,7 @@ impl Message {
             metadata: HashMap::new(),
             timestamp: Utc::now(),
             origin: Some(origin),
+            redelivery: false,
             response_tx: None,
         }
     }
@@ -1,9 +1,13 @@
 pub mod channel_manager;
 pub mod listeners;
 pub mod processors;
+pub mod lua_pool;
 pub mod destinations;
 pub mod message;
 pub mod retry_worker;
+pub mod destination_queue;
 pub mod cleanup_worker;
 pub mod pipeline;
+pub mod framing;
+pub mod egress;
 pub mod init;
@@ -0,0 +1,55 @@
+use chrono::Utc;
+use crate::engine::destinations::{self, ChannelDestinations};
+use crate::engine::message::Message;
+use crate::storage::messages::{MessageStore, MessageStatus};
+use crate::storage::models::DestinationConfig;
+
+/// Why a message is being dead-lettered
+pub struct Failure<'a> {
+    pub failed_step: &'a str,
+    pub error: &'a str,
+    pub original_content: &'a str,
+}
+
+/// Send a failed message to the error destination (DLQ) and record the outcome on the message.
+/// The DLQ payload is a JSON envelope carrying the error, the failing step and the original content.
+pub async fn send_to_error_destination(
+    error_dest: &DestinationConfig,
+    store: Option<&MessageStore>,
+    channel_name: &str,
+    compiled: &ChannelDestinations,
+    msg: &Message,
+    failure: &Failure<'_>,
+) -> anyhow::Result<()> {
+    let envelope = serde_json::json!({
+        "message_id": msg.id.to_string(),
+        "channel_id": msg.channel_id.to_string(),
+        "channel_name": channel_name,
+        "failed_step": failure.failed_step,
+        "error": failure.error,
+        "original_content": failure.original_content,
+        "content": msg.content,
+        "origin": msg.origin,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+
+    let mut dlq_msg = msg.clone();
+    dlq_msg.content = envelope.to_string();
+    dlq_msg.response_tx = None;
+
+    let msg_id_str = msg.id.to_string();
+    match destinations::dispatch(error_dest, &dlq_msg, channel_name, compiled).await {
+        Ok(_) => {
+            if let Some(store) = store {
+                let _ = store.update_dlq_status(&msg_id_str, MessageStatus::SENT, None).await;
+            }
+            Ok(())
+        },
+        Err(e) => {
+            if let Some(store) = store {
+                let _ = store.update_dlq_status(&msg_id_str, MessageStatus::ERROR, Some(e.to_string())).await;
+            }
+            Err(e)
+        }
+    }
+}
@@ -1 +1,3 @@
 pub mod processor;
+pub mod dead_letter;
+pub mod ordering;
@@ -0,0 +1,77 @@
+use crate::engine::processors::mapper::MapperProcessor;
+use crate::hl7;
+
+/// Extract the ordering key of a raw message.
+///
+/// `key` is either an HL7 v2 field reference (`PID-3`, `PID-3-1`, `MSH-10`) or a JSON path
+/// (`patient.id`, `$.orders[0].id`). Returns `None` when the message has no such value.
+pub fn extract_key(content: &str, key: &str) -> Option<String> {
+    let key = key.trim();
+    if let Some((segment, field, component)) = parse_hl7_ref(key) {
+        if content.trim_start().starts_with("MSH") {
+            return hl7_value(content, segment, field, component);
+        }
+    }
+
+    let json: serde_json::Value = serde_json::from_str(content).ok()?;
+    let path = key.strip_prefix("$.").unwrap_or(key);
+    match MapperProcessor::get_value_by_path(&json, path)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
+
+/// `SEG-field[-component]`, e.g. `PID-3-1`
+fn parse_hl7_ref(key: &str) -> Option<(&str, usize, Option<usize>)> {
+    let mut parts = key.split('-');
+    let segment = parts.next()?;
+    if segment.len() != 3 || !segment.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        return None;
+    }
+    let field = parts.next()?.parse().ok()?;
+    let component = match parts.next() {
+        Some(c) => Some(c.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((segment, field, component))
+}
+
+fn hl7_value(content: &str, segment: &str, field: usize, component: Option<usize>) -> Option<String> {
+    let message = hl7::Message::parse(content).ok()?;
+    let field = message.segment(segment)?.field(field)?;
+    let value = match component {
+        Some(c) => field.get(1, c, 1)?.to_string(),
+        None => field.encode(&message.delimiters),
+    };
+
+    if value.is_empty() { None } else { Some(value) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADT: &str = "MSH|^~\\&|HIS|HOSP|LAB|HOSP|202401011200||ADT^A01|MSG0001|P|2.5\rPID|1||12345^^^HOSP^MR||DOE^JOHN";
+
+    #[test]
+    fn test_hl7_field_keys() {
+        assert_eq!(extract_key(ADT, "PID-3").as_deref(), Some("12345^^^HOSP^MR"));
+        assert_eq!(extract_key(ADT, "PID-3-1").as_deref(), Some("12345"));
+        assert_eq!(extract_key(ADT, "MSH-10").as_deref(), Some("MSG0001"));
+        assert_eq!(extract_key(ADT, "PV1-19"), None);
+    }
+
+    #[test]
+    fn test_json_path_keys() {
+        let json = r#"{"patient": {"id": "P-1", "mrn": 42}, "orders": [{"id": "O-1"}]}"#;
+        assert_eq!(extract_key(json, "patient.id").as_deref(), Some("P-1"));
+        assert_eq!(extract_key(json, "$.patient.mrn").as_deref(), Some("42"));
+        assert_eq!(extract_key(json, "orders[0].id").as_deref(), Some("O-1"));
+        assert_eq!(extract_key(json, "patient.missing"), None);
+        assert_eq!(extract_key("not json", "patient.id"), None);
+    }
+}
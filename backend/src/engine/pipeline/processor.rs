@@ -1,25 +1,65 @@
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::sync::{mpsc, broadcast};
+use tokio::task::JoinSet;
 use uuid::Uuid;
 use chrono::Utc;
 use std::collections::VecDeque;
 
-use crate::storage::models::{Channel, ProcessorConfig, DestinationConfig, ProcessorType, DestinationType};
+use crate::storage::models::{ProcessorConfig, DestinationConfig, DestinationMode, ProcessorType};
 use crate::storage::messages::{MessageStore, MessageStatus};
 use crate::storage::logs::LogEntry;
-use crate::engine::message::Message;
+use crate::engine::message::{Message, Outcome, Reply};
 use crate::engine::processors::lua::LuaProcessor;
-use crate::engine::destinations::http::HttpSender;
-use crate::engine::destinations::file::FileWriter;
-use crate::engine::destinations::tcp::TcpSender;
-use crate::engine::destinations::database::DatabaseWriter;
+use crate::engine::processors::mapper::MapperProcessor;
+use crate::engine::processors::filter::FilterProcessor;
+use crate::engine::processors::router::RouterProcessor;
+use crate::engine::destinations::{self, ChannelDestinations, Response};
+use crate::engine::pipeline::{dead_letter, ordering};
+use crate::engine::destination_queue::DestinationQueues;
+use crate::hl7;
+
+/// A processor built once when the channel starts (scripts compiled, conditions parsed)
+struct Step {
+    name: String,
+    kind: StepKind,
+}
+
+enum StepKind {
+    Lua(LuaProcessor),
+    Mapper(MapperProcessor),
+    Filter(FilterProcessor),
+    Hl7(hl7::Conversion),
+    Router(RouterProcessor),
+}
+
+impl Step {
+    fn build(config: ProcessorConfig) -> anyhow::Result<Self> {
+        let kind = match config.kind {
+            ProcessorType::Lua { code, limits } => StepKind::Lua(LuaProcessor::new(code, limits)?),
+            ProcessorType::Mapper { mappings } => StepKind::Mapper(MapperProcessor::new(mappings)),
+            ProcessorType::Filter { condition, limits } => StepKind::Filter(FilterProcessor::new(condition, limits)?),
+            ProcessorType::Hl7 { input_format, output_format, fhir_mappings } => StepKind::Hl7(
+                hl7::Conversion::new(&input_format, &output_format)?.with_fhir_mappings(&fhir_mappings)?
+            ),
+            ProcessorType::Router { routes, default_route, limits } => StepKind::Router(RouterProcessor::new(routes, default_route, limits)?),
+        };
+        Ok(Self { name: config.name, kind })
+    }
+}
 
 pub struct PipelineProcessor {
     channel_id: Uuid,
     channel_name: String,
-    processors: Vec<ProcessorConfig>,
+    processors: Vec<Step>,
     destinations: Vec<DestinationConfig>,
+    compiled: Arc<ChannelDestinations>,
+    error_destination: Option<DestinationConfig>,
+    max_retries: Option<i32>,
+    destination_mode: DestinationMode,
+    workers: usize,
+    ordering_key: Option<String>,
+    queues: Option<DestinationQueues>,
     message_store: Option<MessageStore>,
     dedup_store: Option<Arc<crate::storage::deduplication::DeduplicationStore>>,
     metrics_tx: broadcast::Sender<crate::storage::models::MetricUpdate>,
@@ -27,6 +67,8 @@ pub struct PipelineProcessor {
 }
 
 impl PipelineProcessor {
+    /// Builds every processor and destination up front, so invalid scripts fail the deploy instead of each message
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         channel_id: Uuid,
         channel_name: String,
@@ -36,17 +78,71 @@ impl PipelineProcessor {
         dedup_store: Option<Arc<crate::storage::deduplication::DeduplicationStore>>,
         metrics_tx: broadcast::Sender<crate::storage::models::MetricUpdate>,
         logs: Arc<Mutex<VecDeque<LogEntry>>>,
-    ) -> Self {
-        Self {
+    ) -> anyhow::Result<Self> {
+        let processors = processors.into_iter()
+            .map(|config| {
+                let name = config.name.clone();
+                Step::build(config).map_err(|e| anyhow::anyhow!("Processor {}: {}", name, e))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let compiled = Arc::new(ChannelDestinations::build(&destinations)?);
+
+        Ok(Self {
             channel_id,
             channel_name,
             processors,
             destinations,
+            compiled,
+            error_destination: None,
+            max_retries: Some(0),
+            destination_mode: DestinationMode::Sequential,
+            workers: 1,
+            ordering_key: None,
+            queues: None,
             message_store,
             dedup_store,
             metrics_tx,
             logs,
+        })
+    }
+
+    /// Route failed messages to a dead-letter destination
+    pub fn with_error_destination(mut self, error_destination: Option<DestinationConfig>) -> anyhow::Result<Self> {
+        if let Some(dest) = &error_destination {
+            Arc::make_mut(&mut self.compiled).add(dest)?;
         }
+        self.error_destination = error_destination;
+        Ok(self)
+    }
+
+    /// Failures are retried by the RetryWorker up to `max_retries` times (`None` = forever)
+    pub fn with_max_retries(mut self, max_retries: Option<i32>) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Fan-out of the destinations (sequential or concurrent)
+    pub fn with_destination_mode(mut self, destination_mode: DestinationMode) -> Self {
+        self.destination_mode = destination_mode;
+        self
+    }
+
+    /// Process up to `workers` messages at once; messages sharing an `ordering_key` value stay in order
+    pub fn with_workers(mut self, workers: usize, ordering_key: Option<String>) -> Self {
+        self.workers = workers.max(1);
+        self.ordering_key = ordering_key;
+        self
+    }
+
+    /// Destinations in queue mode are enqueued instead of being delivered inline
+    pub fn with_destination_queues(mut self, queues: Option<DestinationQueues>) -> Self {
+        self.queues = queues;
+        self
+    }
+
+    /// The compiled destinations, shared with the channel's queue workers and retries
+    pub fn compiled_destinations(&self) -> Arc<ChannelDestinations> {
+        self.compiled.clone()
     }
 
     fn add_log(&self, level: &str, message: String) {
@@ -63,236 +159,850 @@ impl PipelineProcessor {
         }
     }
 
-    pub async fn run(&self, mut rx: mpsc::Receiver<Message>) {
+    /// Whether failures are left to the RetryWorker (which dead-letters them once retries run out)
+    fn retries_enabled(&self) -> bool {
+        self.message_store.is_some() && self.max_retries != Some(0)
+    }
+
+    /// Send a failed message to the error destination (DLQ), if one is configured
+    async fn send_to_error_destination(&self, msg: &Message, original_content: &str, failed_step: &str, error: &str) {
+        let Some(error_dest) = &self.error_destination else {
+            return;
+        };
+
+        let failure = dead_letter::Failure { failed_step, error, original_content };
+        match dead_letter::send_to_error_destination(error_dest, self.message_store.as_ref(), &self.channel_name, &self.compiled, msg, &failure).await {
+            Ok(_) => {
+                self.add_log("WARN", format!("[Channel: {}] Message {} sent to error destination {}", self.channel_name, msg.id, error_dest.name));
+            },
+            Err(e) => {
+                self.add_log("ERROR", format!("[Channel: {}] Error destination {} failed for message {}: {}", self.channel_name, error_dest.name, msg.id, e));
+            }
+        }
+    }
+
+    pub async fn run(self, mut rx: mpsc::Receiver<Message>) {
         tracing::info!("Channel {} ({}) pipeline started", self.channel_name, self.channel_id);
 
-        while let Some(mut msg) = rx.recv().await {
-            let start_time = Instant::now();
-            let origin = msg.origin.as_deref().unwrap_or("unknown");
-            let msg_id_str = msg.id.to_string();
-
-            // 1. DEDUPLICATION
-            if let Some(dedup) = &self.dedup_store {
-                match dedup.is_duplicate(&self.channel_id.to_string(), &msg.content).await {
-                    Ok(true) => {
-                        tracing::info!(channel = %self.channel_name, message_id = %msg.id, "Message is duplicate, skipping");
-                        if let Some(store) = &self.message_store {
-                            let _ = store.update_status(&msg_id_str, MessageStatus::FILTERED, Some("Duplicate message".to_string())).await;
-                        }
-                        if let Some(tx_arc) = &msg.response_tx {
-                            if let Ok(mut tx_opt) = tx_arc.lock() {
-                                if let Some(tx) = tx_opt.take() {
-                                    let _ = tx.send(Ok("Message skipped: Duplicate detected. Change payload content to process again.".to_string()));
-                                }
+        let this = Arc::new(self);
+        let mut workers = JoinSet::new();
+        match (this.workers, this.ordering_key.clone()) {
+            (1, _) => {
+                while let Some(msg) = rx.recv().await {
+                    this.process_message(msg).await;
+                }
+            },
+            (count, None) => {
+                // Workers take the next message as soon as they are free
+                let rx = Arc::new(tokio::sync::Mutex::new(rx));
+                for _ in 0..count {
+                    let this = this.clone();
+                    let rx = rx.clone();
+                    workers.spawn(async move {
+                        loop {
+                            let next = rx.lock().await.recv().await;
+                            match next {
+                                Some(msg) => this.process_message(msg).await,
+                                None => break,
                             }
                         }
-                        continue;
-                    },
-                    Ok(false) => {
-                        let _ = dedup.mark_processed(&self.channel_id.to_string(), &msg.content).await;
-                    },
-                    Err(e) => {
-                        tracing::warn!("Deduplication check failed: {}, proceeding", e);
+                    });
+                }
+            },
+            (count, Some(key)) => {
+                // One lane per worker: a key always maps to the same lane, so its messages stay in order
+                let mut lanes = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (lane_tx, mut lane_rx) = mpsc::channel::<Message>(100);
+                    lanes.push(lane_tx);
+                    let this = this.clone();
+                    workers.spawn(async move {
+                        while let Some(msg) = lane_rx.recv().await {
+                            this.process_message(msg).await;
+                        }
+                    });
+                }
+
+                let mut next_lane = 0;
+                while let Some(msg) = rx.recv().await {
+                    let lane = match ordering::extract_key(&msg.content, &key) {
+                        Some(value) => {
+                            use std::hash::{Hash, Hasher};
+                            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                            value.hash(&mut hasher);
+                            (hasher.finish() % count as u64) as usize
+                        },
+                        None => {
+                            // Messages without a key have no ordering constraint
+                            next_lane = (next_lane + 1) % count;
+                            next_lane
+                        }
+                    };
+                    if lanes[lane].send(msg).await.is_err() {
+                        break;
                     }
                 }
-            }
+            },
+        }
+
+        // Let the workers drain what they already received
+        while workers.join_next().await.is_some() {}
+    }
+
+    async fn process_message(self: &Arc<Self>, mut msg: Message) {
+        let start_time = Instant::now();
+        let origin = msg.origin.as_deref().unwrap_or("unknown");
+        let msg_id_str = msg.id.to_string();
+        let original_content = msg.content.clone();
 
-            // 2. MARK PROCESSING
-            if let Some(store) = &self.message_store {
-                let _ = store.update_status(&msg_id_str, MessageStatus::PROCESSING, None).await;
+        // 1. DEDUPLICATION (a redelivery was already checked when it first arrived)
+        if let Some(dedup) = self.dedup_store.as_ref().filter(|_| !msg.redelivery) {
+            match dedup.is_duplicate(&self.channel_id.to_string(), &msg.content).await {
+                Ok(true) => {
+                    tracing::info!(channel = %self.channel_name, message_id = %msg.id, "Message is duplicate, skipping");
+                    if let Some(store) = &self.message_store {
+                        let _ = store.update_status(&msg_id_str, MessageStatus::FILTERED, Some("Duplicate message".to_string())).await;
+                    }
+                    Self::respond(&msg, Outcome::Filtered("Message skipped: Duplicate detected. Change payload content to process again.".to_string()));
+                    return;
+                },
+                Ok(false) => {
+                    let _ = dedup.mark_processed(&self.channel_id.to_string(), &msg.content).await;
+                },
+                Err(e) => {
+                    tracing::warn!("Deduplication check failed: {}, proceeding", e);
+                }
             }
-            let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
-                channel_id: self.channel_id.to_string(),
-                message_id: Some(msg.id.to_string()),
-                status: "PROCESSING".to_string(),
-                timestamp: Utc::now(),
-            });
+        }
 
-            self.add_log("INFO", format!("[Channel: {}] Processing message {} (Origin: {})", self.channel_name, msg.id, origin));
-
-            // 3. PROCESSORS
-            let mut failed = false;
-            let mut error_msg = String::new();
-
-            for proc_config in &self.processors {
-                match &proc_config.kind {
-                    ProcessorType::Lua { code } => {
-                        let processor = LuaProcessor::new(code.clone());
-                        match processor.process(msg.clone()) {
-                            Ok(new_msg) => msg = new_msg,
-                            Err(e) => {
-                                error_msg = format!("Processor {} failed: {}", proc_config.name, e);
-                                self.add_log("ERROR", error_msg.clone());
-                                failed = true;
-                                break;
-                            }
+        // 2. MARK PROCESSING
+        if let Some(store) = &self.message_store {
+            let _ = store.update_status(&msg_id_str, MessageStatus::PROCESSING, None).await;
+            let _ = store.save_metadata(&msg_id_str, &msg.metadata).await;
+        }
+        let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
+            channel_id: self.channel_id.to_string(),
+            message_id: Some(msg.id.to_string()),
+            status: "PROCESSING".to_string(),
+            timestamp: Utc::now(),
+        });
+
+        self.add_log("INFO", format!("[Channel: {}] Processing message {} (Origin: {})", self.channel_name, msg.id, origin));
+
+        // 3. PROCESSORS
+        let mut failed = false;
+        let mut error_msg = String::new();
+        let mut failed_step = String::new();
+        // Route names selected by a Content Router (None = no router, every destination receives)
+        let mut matched_routes: Option<Vec<String>> = None;
+
+        for step in &self.processors {
+            failed_step = step.name.clone();
+            match &step.kind {
+                StepKind::Lua(processor) => {
+                    match processor.process(msg.clone()).await {
+                        Ok(new_msg) => msg = new_msg,
+                        Err(e) => {
+                            error_msg = format!("Processor {} failed: {}", step.name, e);
+                            self.add_log("ERROR", error_msg.clone());
+                            failed = true;
+                            break;
                         }
-                    },
-                    ProcessorType::Mapper { mappings } => {
-                        let processor = crate::engine::processors::mapper::MapperProcessor::new(mappings.clone());
-                        match processor.process(msg.clone()) {
-                            Ok(new_msg) => msg = new_msg,
-                            Err(e) => {
-                                error_msg = format!("Mapper failed: {}", e);
-                                self.add_log("ERROR", error_msg.clone());
-                                failed = true;
-                                break;
-                            }
+                    }
+                },
+                StepKind::Mapper(processor) => {
+                    match processor.process(msg.clone()) {
+                        Ok(new_msg) => msg = new_msg,
+                        Err(e) => {
+                            error_msg = format!("Mapper failed: {}", e);
+                            self.add_log("ERROR", error_msg.clone());
+                            failed = true;
+                            break;
                         }
-                    },
-                    ProcessorType::Filter { condition } => {
-                        let processor = crate::engine::processors::filter::FilterProcessor::new(condition.clone());
-                        match processor.process(msg.clone()) {
-                            Ok(true) => {}, // Allowed
-                            Ok(false) => {
-                                self.add_log("INFO", format!("[Channel: {}] Message {} FILTERED", self.channel_name, msg.id));
-                                if let Some(store) = &self.message_store {
-                                    let _ = store.update_status(&msg_id_str, MessageStatus::FILTERED, None).await;
-                                }
-                                if let Some(tx_arc) = &msg.response_tx {
-                                    if let Ok(mut tx_opt) = tx_arc.lock() {
-                                        if let Some(tx) = tx_opt.take() {
-                                            let _ = tx.send(Ok("Message Filtered".to_string()));
-                                        }
-                                    }
-                                }
-                                error_msg = "FILTERED".to_string();
-                                failed = true;
-                                break;
-                            },
-                            Err(e) => {
-                                error_msg = format!("Filter error: {}", e);
-                                self.add_log("ERROR", error_msg.clone());
-                                failed = true;
-                                break;
+                    }
+                },
+                StepKind::Filter(processor) => {
+                    match processor.process(msg.clone()).await {
+                        Ok(true) => {}, // Allowed
+                        Ok(false) => {
+                            self.add_log("INFO", format!("[Channel: {}] Message {} FILTERED", self.channel_name, msg.id));
+                            if let Some(store) = &self.message_store {
+                                let _ = store.update_status(&msg_id_str, MessageStatus::FILTERED, None).await;
                             }
+                            Self::respond(&msg, Outcome::Filtered("Message Filtered".to_string()));
+                            error_msg = "FILTERED".to_string();
+                            failed = true;
+                            break;
+                        },
+                        Err(e) => {
+                            error_msg = format!("Filter error: {}", e);
+                            self.add_log("ERROR", error_msg.clone());
+                            failed = true;
+                            break;
                         }
-                    },
-                     ProcessorType::Hl7 { inputFormat: _, outputFormat: _ } => {
-                        // HL7 Logic (Simplified inline for now as in channel_manager)
-                        const MAX_HL7_SEGMENTS: usize = 1000;
-                        const MAX_HL7_FIELDS: usize = 100;
-                        
-                        let content = msg.content.clone();
-                        let mut map: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
-                        let lines: Vec<&str> = if content.contains('\r') {
-                            content.split('\r').take(MAX_HL7_SEGMENTS).collect()
-                        } else {
-                            content.split('\n').take(MAX_HL7_SEGMENTS).collect()
-                        };
-
-                        for segment in lines {
-                            if segment.trim().is_empty() { continue; }
-                            let fields: Vec<&str> = segment.split('|').take(MAX_HL7_FIELDS).collect();
-                            if fields.is_empty() { continue; }
-                            let segment_name = fields[0].to_string();
-                            let field_values: Vec<String> = fields.iter().map(|s| s.to_string()).collect();
-                            map.insert(segment_name, field_values);
+                    }
+                },
+                StepKind::Hl7(conversion) => {
+                    match conversion.convert(&msg.content) {
+                        Ok(converted) => msg.content = converted,
+                        Err(e) => {
+                            error_msg = format!("HL7 conversion failed: {}", e);
+                            self.add_log("ERROR", error_msg.clone());
+                            failed = true;
+                            break;
                         }
-                        
-                         match serde_json::to_string(&map) {
-                            Ok(json) => {
-                                msg.content = json;
-                            },
-                            Err(e) => {
-                                 error_msg = format!("HL7 Serialization failed: {}", e);
-                                 self.add_log("ERROR", error_msg.clone());
-                                 failed = true;
-                                 break;
+                    }
+                },
+                StepKind::Router(router) => {
+                    match router.process(&msg).await {
+                        Ok(matched) if matched.is_empty() => {
+                            self.add_log("INFO", format!("[Channel: {}] Message {} matched no route, FILTERED", self.channel_name, msg.id));
+                            if let Some(store) = &self.message_store {
+                                let _ = store.update_status(&msg_id_str, MessageStatus::FILTERED, Some("No route matched".to_string())).await;
                             }
+                            Self::respond(&msg, Outcome::Filtered("Message Filtered: no route matched".to_string()));
+                            error_msg = "FILTERED".to_string();
+                            failed = true;
+                            break;
+                        },
+                        Ok(matched) => {
+                            self.add_log("INFO", format!("[Channel: {}] Message {} routed to [{}]", self.channel_name, msg.id, matched.join(", ")));
+                            matched_routes = Some(matched);
+                        },
+                        Err(e) => {
+                            error_msg = format!("Router error: {}", e);
+                            self.add_log("ERROR", error_msg.clone());
+                            failed = true;
+                            break;
                         }
-                    },
-                    _ => {}
+                    }
+                },
+            }
+        }
+
+        if failed {
+            if error_msg != "FILTERED" {
+                 let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
+                    channel_id: self.channel_id.to_string(),
+                    message_id: Some(msg.id.to_string()),
+                    status: "ERROR".to_string(),
+                    timestamp: Utc::now(),
+                });
+                // Retryable failures stay ERROR; without retries the failure is terminal
+                let status = if self.retries_enabled() { MessageStatus::ERROR } else { MessageStatus::FAILED };
+                if let Some(store) = &self.message_store {
+                    let _ = store.update_status(&msg_id_str, status, Some(error_msg.clone())).await;
+                }
+                Self::respond(&msg, Outcome::Failed(error_msg.clone()));
+                if !self.retries_enabled() {
+                    self.send_to_error_destination(&msg, &original_content, &failed_step, &error_msg).await;
                 }
             }
+            return;
+        }
 
-            if failed {
-                if error_msg != "FILTERED" {
-                     let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
-                        channel_id: self.channel_id.to_string(),
-                        message_id: Some(msg.id.to_string()),
-                        status: "ERROR".to_string(),
-                        timestamp: Utc::now(),
-                    });
+        // 4. DESTINATIONS
+        let mut targets = Vec::new();
+        for dest in &self.destinations {
+            if let Some(matched) = &matched_routes {
+                if !RouterProcessor::accepts(&dest.routes, matched) {
                     if let Some(store) = &self.message_store {
-                        let _ = store.update_status(&msg_id_str, MessageStatus::ERROR, Some(error_msg.clone())).await;
-                    }
-                     if let Some(tx_arc) = &msg.response_tx {
-                        if let Ok(mut tx_opt) = tx_arc.lock() {
-                            if let Some(tx) = tx_opt.take() {
-                                let _ = tx.send(Err(error_msg));
-                            }
-                        }
+                        let _ = store.set_destination_status(&msg_id_str, &dest.id, &dest.name, MessageStatus::FILTERED).await;
                     }
-                    // DLQ logic could be here (passing error destination in props)
-                    // For brevity, skipping explicit DLQ implementation in this refactor unless specifically asked,
-                    // but ChannelManager extracted code had it. I should probably keep it if I have access to Channel config.
-                    // The struct has `destinations` but no `error_destination`.
-                    // I should ideally add `error_destination` to `PipelineProcessor`.
-                    // But for this step I'll focus on main path.
+                    continue;
                 }
-                continue;
             }
+            targets.push(dest.clone());
+        }
 
-            // 4. DESTINATIONS
-            for dest in &self.destinations {
-                match &dest.kind {
-                     DestinationType::File { path, filename, append, encoding } => {
-                        let writer = FileWriter::new(path.clone(), filename.clone(), *append, encoding.clone(), self.channel_name.clone());
-                        if let Err(e) = writer.send(&msg).await {
-                             self.add_log("ERROR", format!("[Channel: {}] File Dest failed: {}", self.channel_name, e));
-                        } else {
-                             self.add_log("INFO", format!("[Channel: {}] File written", self.channel_name));
-                        }
-                     },
-                     DestinationType::Http { url, method } => {
-                         let sender = HttpSender::new(url.clone(), method.clone(), self.channel_name.clone());
-                         if let Err(e) = sender.send(&msg).await {
-                             self.add_log("ERROR", format!("[Channel: {}] HTTP Dest failed: {}", self.channel_name, e));
-                         }
-                     },
-                     DestinationType::Tcp { host, port } => {
-                         let sender = TcpSender::new(host.clone(), *port, self.channel_name.clone());
-                         if let Err(e) = sender.send(&msg).await {
-                             self.add_log("ERROR", format!("[Channel: {}] TCP Dest failed: {}", self.channel_name, e));
-                         }
-                     },
-                     DestinationType::Database { url, table, mode, query } => {
-                        let writer = DatabaseWriter::new(url.clone(), table.clone(), mode.clone(), query.clone(), self.channel_name.clone());
-                         if let Err(e) = writer.send(&msg).await {
-                             self.add_log("ERROR", format!("[Channel: {}] DB Dest failed: {}", self.channel_name, e));
-                         }
-                     },
-                      DestinationType::Lua { code } => {
-                        let destination = crate::engine::destinations::lua::LuaDestination::new(code.clone());
-                        if let Err(e) = destination.send(&msg) {
-                            self.add_log("ERROR", format!("[Channel: {}] Lua Dest failed: {}", self.channel_name, e));
+        match self.destination_mode {
+            DestinationMode::Sequential => {
+                let mut outcomes = Vec::new();
+                for dest in &targets {
+                    let outcome = self.deliver(dest, &msg, &original_content).await;
+                    // Later destinations can use the response in templates and scripts
+                    outcome.record_response(&mut msg);
+                    outcomes.push(outcome);
+                }
+                self.finalize(&msg, outcomes, start_time).await;
+            },
+            DestinationMode::WaitAll => {
+                let mut tasks = self.spawn_deliveries(targets, &msg, &original_content);
+                let mut outcomes = Vec::new();
+                while let Some(res) = tasks.join_next().await {
+                    let outcome = Delivery::joined(res);
+                    // Available to the source's reply
+                    outcome.record_response(&mut msg);
+                    outcomes.push(outcome);
+                }
+                self.finalize(&msg, outcomes, start_time).await;
+            },
+            DestinationMode::FirstSuccess => {
+                let mut tasks = self.spawn_deliveries(targets, &msg, &original_content);
+                let mut outcomes = Vec::new();
+                while let Some(res) = tasks.join_next().await {
+                    let outcome = Delivery::joined(res);
+                    let succeeded = !matches!(outcome, Delivery::Failed(_));
+                    // Recorded before the early answer so the source can use it
+                    outcome.record_response(&mut msg);
+                    outcomes.push(outcome);
+                    if succeeded {
+                        break;
+                    }
+                }
+
+                if tasks.is_empty() {
+                    self.finalize(&msg, outcomes, start_time).await;
+                } else {
+                    // Answer the source now; the remaining destinations finish in the background
+                    Self::respond(&msg, Outcome::Processed("Message Processed Successfully".to_string()));
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        while let Some(res) = tasks.join_next().await {
+                            outcomes.push(Delivery::joined(res));
                         }
+                        this.finalize(&msg, outcomes, start_time).await;
+                    });
+                }
+            },
+            DestinationMode::FireAndForget => {
+                let mut tasks = self.spawn_deliveries(targets, &msg, &original_content);
+                Self::respond(&msg, Outcome::Processed("Message Accepted".to_string()));
+                let this = self.clone();
+                tokio::spawn(async move {
+                    let mut outcomes = Vec::new();
+                    while let Some(res) = tasks.join_next().await {
+                        outcomes.push(Delivery::joined(res));
+                    }
+                    this.finalize(&msg, outcomes, start_time).await;
+                });
+            },
+        }
+    }
+
+    /// Deliver to every target destination concurrently, one task each
+    fn spawn_deliveries(self: &Arc<Self>, targets: Vec<DestinationConfig>, msg: &Message, original_content: &str) -> JoinSet<Delivery> {
+        let mut tasks = JoinSet::new();
+        for dest in targets {
+            let this = self.clone();
+            let msg = msg.clone();
+            let original_content = original_content.to_string();
+            tasks.spawn(async move { this.deliver(&dest, &msg, &original_content).await });
+        }
+        tasks
+    }
+
+    /// Hand the message to one destination (or its queue) and record the outcome
+    async fn deliver(&self, dest: &DestinationConfig, msg: &Message, original_content: &str) -> Delivery {
+        let msg_id_str = msg.id.to_string();
+
+        if let (Some(queues), Some(store)) = (&self.queues, &self.message_store) {
+            if queues.is_queued(&dest.id) {
+                // Mark QUEUED before enqueueing so a fast worker's SENT is not overwritten
+                let _ = store.set_destination_status(&msg_id_str, &dest.id, &dest.name, MessageStatus::QUEUED).await;
+                match queues.enqueue(&dest.id, &msg_id_str, &msg.content, &msg.metadata).await {
+                    Ok(_) => {
+                        queues.notify(&dest.id);
+                        return Delivery::Queued;
                     },
+                    Err(e) => {
+                        self.add_log("WARN", format!("[Channel: {}] Could not enqueue for destination {}, delivering inline: {}", self.channel_name, dest.name, e));
+                    }
                 }
             }
+        }
 
-            // 5. FINALIZE
-            if let Some(store) = &self.message_store {
-                let _ = store.update_status(&msg_id_str, MessageStatus::SENT, None).await;
+        match destinations::dispatch(dest, msg, &self.channel_name, &self.compiled).await {
+            Ok(response) => {
+                self.add_log("INFO", format!("[Channel: {}] Sent to destination {}", self.channel_name, dest.name));
+                if let Some(store) = &self.message_store {
+                    let _ = store.record_destination_attempt(&msg_id_str, dest, &msg.content, &msg.metadata, MessageStatus::SENT, None, response.as_ref().map(|r| r.to_string())).await;
+                }
+                Delivery::Sent { destination: dest.name.clone(), response }
+            },
+            Err(e) => {
+                let dest_error = format!("Destination {} failed: {}", dest.name, e);
+                self.add_log("ERROR", format!("[Channel: {}] {}", self.channel_name, dest_error));
+                let status = if self.retries_enabled() { MessageStatus::ERROR } else { MessageStatus::FAILED };
+                if let Some(store) = &self.message_store {
+                    let _ = store.record_destination_attempt(&msg_id_str, dest, &msg.content, &msg.metadata, status, Some(e.to_string()), None).await;
+                }
+                if !self.retries_enabled() {
+                    self.send_to_error_destination(msg, original_content, &dest.name, &dest_error).await;
+                }
+                Delivery::Failed(dest_error)
             }
-            let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
-                channel_id: self.channel_id.to_string(),
-                message_id: Some(msg.id.to_string()),
-                status: "SENT".to_string(),
-                timestamp: Utc::now(),
-            });
+        }
+    }
 
-             if let Some(tx_arc) = &msg.response_tx {
-                if let Ok(mut tx_opt) = tx_arc.lock() {
-                    if let Some(tx) = tx_opt.take() {
-                        let _ = tx.send(Ok("Message Processed Successfully".to_string()));
-                    }
+    /// Set the overall message status from the destination outcomes and answer the source
+    async fn finalize(&self, msg: &Message, outcomes: Vec<Delivery>, start_time: Instant) {
+        let msg_id_str = msg.id.to_string();
+        let queued = outcomes.iter().any(|o| matches!(o, Delivery::Queued));
+        let dest_errors: Vec<String> = outcomes.iter()
+            .filter_map(|o| match o {
+                Delivery::Failed(e) => Some(e.clone()),
+                _ => None,
+            })
+            .collect();
+        let sent_count = outcomes.len() - dest_errors.len();
+
+        // Overall status reflects the destinations: all failed -> ERROR (FAILED without retries),
+        // some failed -> PARTIAL; the failed destinations themselves are already marked
+        let (final_status, final_error) = if dest_errors.is_empty() {
+            (MessageStatus::SENT, None)
+        } else if sent_count == 0 && !self.retries_enabled() {
+            (MessageStatus::FAILED, Some(dest_errors.join("; ")))
+        } else if sent_count == 0 {
+            (MessageStatus::ERROR, Some(dest_errors.join("; ")))
+        } else {
+            (MessageStatus::PARTIAL, Some(dest_errors.join("; ")))
+        };
+
+        let final_status = match &self.message_store {
+            // Queue workers may already be delivering, so derive the status from the destination records
+            Some(store) if queued => store.refresh_status_from_destinations(&msg_id_str).await.unwrap_or(final_status),
+            Some(store) => {
+                let _ = store.update_status(&msg_id_str, final_status.clone(), final_error.clone()).await;
+                final_status
+            },
+            None => final_status,
+        };
+        let _ = self.metrics_tx.send(crate::storage::models::MetricUpdate {
+            channel_id: self.channel_id.to_string(),
+            message_id: Some(msg.id.to_string()),
+            status: final_status.to_string(),
+            timestamp: Utc::now(),
+        });
+
+        let response = match final_error {
+            Some(err) if sent_count == 0 => Outcome::Failed(err),
+            Some(err) => Outcome::Processed(format!("Message Processed with destination errors: {}", err)),
+            None => Outcome::Processed("Message Processed Successfully".to_string()),
+        };
+        Self::respond(msg, response);
+
+        let elapsed = start_time.elapsed();
+        tracing::info!(channel = %self.channel_name, processing_time_ms = elapsed.as_millis(), "Message processed");
+    }
+
+    /// Answer the source, if it is waiting and has not been answered yet
+    fn respond(msg: &Message, response: Outcome) {
+        if let Some(tx_arc) = &msg.response_tx {
+            if let Ok(mut tx_opt) = tx_arc.lock() {
+                if let Some(tx) = tx_opt.take() {
+                    let _ = tx.send(Reply { outcome: response, content: msg.content.clone(), metadata: msg.metadata.clone() });
                 }
             }
-            
-            let elapsed = start_time.elapsed();
-            tracing::info!(channel = %self.channel_name, processing_time_ms = elapsed.as_millis(), "Message processed");
         }
     }
 }
+
+/// Outcome of handing a message to one destination
+enum Delivery {
+    /// Delivered, with the remote response if there was one
+    Sent { destination: String, response: Option<Response> },
+    Queued,
+    Failed(String),
+}
+
+impl Delivery {
+    fn joined(res: Result<Delivery, tokio::task::JoinError>) -> Delivery {
+        res.unwrap_or_else(|e| Delivery::Failed(format!("Destination task failed: {}", e)))
+    }
+
+    /// Put the remote response in the message metadata as `response.<destination>.status`
+    /// and `response.<destination>.body`
+    fn record_response(&self, msg: &mut Message) {
+        if let Delivery::Sent { destination, response: Some(response) } = self {
+            if let Some(status) = response.status {
+                msg.metadata.insert(format!("response.{}.status", destination), status.to_string());
+            }
+            msg.metadata.insert(format!("response.{}.body", destination), response.body.clone());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::models::{DestinationType, Framing};
+
+    fn file_destination(id: &str, path: &str) -> DestinationConfig {
+        DestinationConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            routes: vec![],
+            queue: None,
+            timeout_ms: None,
+            kind: DestinationType::File {
+                path: path.to_string(),
+                filename: Some("${id}.json".to_string()),
+                append: Some(false),
+                encoding: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_processor_failure_goes_to_error_destination() {
+        let dlq_dir = std::env::temp_dir().join(format!("mirthbr_dlq_{}", Uuid::new_v4()));
+        let dlq_path = dlq_dir.to_string_lossy().to_string();
+
+        let processors = vec![ProcessorConfig {
+            id: "proc-1".to_string(),
+            name: "Broken Script".to_string(),
+            kind: ProcessorType::Lua { code: "error('boom')".to_string(), limits: Default::default() },
+        }];
+        let (metrics_tx, _) = broadcast::channel(10);
+        let pipeline = PipelineProcessor::new(
+            Uuid::new_v4(),
+            "DLQ Test".to_string(),
+            processors,
+            vec![],
+            None,
+            None,
+            metrics_tx,
+            Arc::new(Mutex::new(VecDeque::new())),
+        ).unwrap().with_error_destination(Some(file_destination("dlq", &dlq_path))).unwrap();
+
+        let (tx, rx) = mpsc::channel(1);
+        let msg = Message::new(Uuid::new_v4(), "original".to_string(), "test".to_string());
+        let msg_id = msg.id;
+        tx.send(msg).await.unwrap();
+        drop(tx);
+        pipeline.run(rx).await;
+
+        let written = tokio::fs::read_to_string(dlq_dir.join(format!("{}.json", msg_id))).await.unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(written.trim()).unwrap();
+        assert_eq!(envelope["failed_step"], "Broken Script");
+        assert_eq!(envelope["original_content"], "original");
+        assert!(envelope["error"].as_str().unwrap().contains("boom"));
+
+        let _ = tokio::fs::remove_dir_all(&dlq_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_partial_destination_failure_is_tracked() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_out_{}", Uuid::new_v4()));
+
+        // Without retries the failed destination is terminal, but the message stays PARTIAL
+        for (max_retries, failed_status) in [(Some(3), "ERROR"), (Some(0), "FAILED")] {
+            let channel_id = Uuid::new_v4();
+            let destinations = vec![
+                file_destination("file-ok", &out_dir.to_string_lossy()),
+                DestinationConfig {
+                    id: "tcp-down".to_string(),
+                    name: "tcp-down".to_string(),
+                    routes: vec![],
+                    queue: None,
+                    timeout_ms: None,
+                    kind: DestinationType::Tcp { host: "127.0.0.1".to_string(), port: 1, framing: Framing::default(), keep_connection_open: false, tls: None, timeouts: Default::default(), response: Default::default() },
+                },
+            ];
+            let (metrics_tx, _) = broadcast::channel(10);
+            let pipeline = PipelineProcessor::new(
+                channel_id,
+                "Partial Test".to_string(),
+                vec![],
+                destinations,
+                Some(store.clone()),
+                None,
+                metrics_tx,
+                Arc::new(Mutex::new(VecDeque::new())),
+            ).unwrap().with_max_retries(max_retries);
+
+            let id = store.save_message(&channel_id.to_string(), "payload").await.unwrap();
+            let mut msg = Message::new(channel_id, "payload".to_string(), "test".to_string());
+            msg.id = Uuid::parse_str(&id).unwrap();
+
+            let (tx, rx) = mpsc::channel(1);
+            tx.send(msg).await.unwrap();
+            drop(tx);
+            pipeline.run(rx).await;
+
+            let record = store.get_message_by_id(&id).await.unwrap().unwrap();
+            assert_eq!(record.status, "PARTIAL");
+
+            let dests = store.get_message_destinations(&id).await.unwrap();
+            assert_eq!(dests.len(), 2);
+            let ok = dests.iter().find(|d| d.destination_id == "file-ok").unwrap();
+            let down = dests.iter().find(|d| d.destination_id == "tcp-down").unwrap();
+            assert_eq!(ok.status, "SENT");
+            assert_eq!(down.status, failed_status);
+            assert_eq!(down.attempts, 1);
+            assert!(down.last_error.is_some());
+        }
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_queued_destination_returns_before_delivery() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+
+        let channel = crate::storage::models::Channel {
+            id: Uuid::new_v4(),
+            name: "Queued Test".to_string(),
+            enabled: true,
+            source: crate::storage::models::SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations: vec![DestinationConfig {
+                id: "tcp-down".to_string(),
+                name: "tcp-down".to_string(),
+                routes: vec![],
+                queue: Some(Default::default()),
+                timeout_ms: None,
+                kind: DestinationType::Tcp { host: "127.0.0.1".to_string(), port: 1, framing: Framing::default(), keep_connection_open: false, tls: None, timeouts: Default::default(), response: Default::default() },
+            }],
+            error_destination: None,
+            max_retries: Some(3),
+            retry_policy: Default::default(),
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        };
+        let queue_store = crate::storage::queue::QueueStore::new(db.pool.clone());
+        let (metrics_tx, _) = broadcast::channel(10);
+        let pipeline = PipelineProcessor::new(
+            channel.id,
+            channel.name.clone(),
+            vec![],
+            channel.destinations.clone(),
+            Some(store.clone()),
+            None,
+            metrics_tx,
+            Arc::new(Mutex::new(VecDeque::new())),
+        ).unwrap().with_destination_queues(DestinationQueues::for_channel(&channel, queue_store.clone()));
+
+        let id = store.save_message(&channel.id.to_string(), "payload").await.unwrap();
+        let mut msg = Message::new(channel.id, "payload".to_string(), "test".to_string());
+        msg.id = Uuid::parse_str(&id).unwrap();
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        msg.response_tx = Some(Arc::new(Mutex::new(Some(resp_tx))));
+
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(msg).await.unwrap();
+        drop(tx);
+        pipeline.run(rx).await;
+
+        // No worker is running: the source got its answer while the message is still queued
+        assert!(matches!(resp_rx.await.unwrap().outcome, Outcome::Processed(_)));
+        assert_eq!(store.get_message_by_id(&id).await.unwrap().unwrap().status, "QUEUED");
+        assert_eq!(queue_store.depth(&channel.id.to_string(), "tcp-down").await.unwrap(), 1);
+    }
+
+    /// TCP peer that accepts connections but never answers
+    async fn silent_peer() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                open.push(socket);
+            }
+        });
+        port
+    }
+
+    fn slow_destination(id: &str, port: u16, timeout_ms: u64) -> DestinationConfig {
+        DestinationConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            routes: vec![],
+            queue: None,
+            timeout_ms: Some(timeout_ms),
+            kind: DestinationType::Tcp { host: "127.0.0.1".to_string(), port, framing: Framing::default(), keep_connection_open: false, tls: None, timeouts: Default::default(), response: Default::default() },
+        }
+    }
+
+    async fn run_one(pipeline: PipelineProcessor, store: &MessageStore, channel_id: Uuid) -> (String, tokio::sync::oneshot::Receiver<Reply>) {
+        let id = store.save_message(&channel_id.to_string(), "payload").await.unwrap();
+        let mut msg = Message::new(channel_id, "payload".to_string(), "test".to_string());
+        msg.id = Uuid::parse_str(&id).unwrap();
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        msg.response_tx = Some(Arc::new(Mutex::new(Some(resp_tx))));
+
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(msg).await.unwrap();
+        drop(tx);
+        pipeline.run(rx).await;
+        (id, resp_rx)
+    }
+
+    #[tokio::test]
+    async fn test_wait_all_runs_destinations_concurrently() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let port = silent_peer().await;
+
+        let channel_id = Uuid::new_v4();
+        let (metrics_tx, _) = broadcast::channel(10);
+        let pipeline = PipelineProcessor::new(
+            channel_id,
+            "Fan-out Test".to_string(),
+            vec![],
+            vec![slow_destination("slow-1", port, 500), slow_destination("slow-2", port, 500)],
+            Some(store.clone()),
+            None,
+            metrics_tx,
+            Arc::new(Mutex::new(VecDeque::new())),
+        ).unwrap().with_max_retries(Some(3)).with_destination_mode(DestinationMode::WaitAll);
+
+        let started = Instant::now();
+        let (id, resp_rx) = run_one(pipeline, &store, channel_id).await;
+        assert!(started.elapsed() < std::time::Duration::from_millis(950));
+        assert!(matches!(resp_rx.await.unwrap().outcome, Outcome::Failed(e) if e.contains("timed out")));
+
+        assert_eq!(store.get_message_by_id(&id).await.unwrap().unwrap().status, "ERROR");
+        let dests = store.get_message_destinations(&id).await.unwrap();
+        assert_eq!(dests.len(), 2);
+        assert!(dests.iter().all(|d| d.last_error.as_deref().unwrap().contains("timed out after 500 ms")));
+    }
+
+    #[tokio::test]
+    async fn test_first_success_responds_before_slow_destination() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_fanout_out_{}", Uuid::new_v4()));
+        let port = silent_peer().await;
+
+        let channel_id = Uuid::new_v4();
+        let (metrics_tx, _) = broadcast::channel(10);
+        let pipeline = PipelineProcessor::new(
+            channel_id,
+            "Fan-out Test".to_string(),
+            vec![],
+            vec![file_destination("file-ok", &out_dir.to_string_lossy()), slow_destination("slow", port, 1_000)],
+            Some(store.clone()),
+            None,
+            metrics_tx,
+            Arc::new(Mutex::new(VecDeque::new())),
+        ).unwrap().with_max_retries(Some(3)).with_destination_mode(DestinationMode::FirstSuccess);
+
+        let started = Instant::now();
+        let (id, resp_rx) = run_one(pipeline, &store, channel_id).await;
+        assert!(matches!(resp_rx.await.unwrap().outcome, Outcome::Processed(_)));
+        assert!(started.elapsed() < std::time::Duration::from_millis(900));
+
+        // The slow destination keeps going in the background and is recorded when it times out
+        let mut status = String::new();
+        for _ in 0..50 {
+            status = store.get_message_by_id(&id).await.unwrap().unwrap().status;
+            if status != "PROCESSING" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(status, "PARTIAL");
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_ordering_key_keeps_per_key_order_across_workers() {
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_ordering_{}", Uuid::new_v4()));
+        let route = |name: &str| crate::storage::models::Route {
+            name: name.to_string(),
+            condition: format!("return msg.content:find('\"{}\"', 1, true) ~= nil", name),
+        };
+        let keyed_file = |key: &str| DestinationConfig {
+            id: key.to_string(),
+            name: key.to_string(),
+            routes: vec![key.to_string()],
+            queue: None,
+            timeout_ms: None,
+            kind: DestinationType::File {
+                path: out_dir.to_string_lossy().to_string(),
+                filename: Some(format!("{}.txt", key)),
+                append: Some(true),
+                encoding: None,
+            },
+        };
+
+        let processors = vec![ProcessorConfig {
+            id: "router".to_string(),
+            name: "By Patient".to_string(),
+            kind: ProcessorType::Router { routes: vec![route("A"), route("B")], default_route: None, limits: Default::default() },
+        }];
+        let (metrics_tx, _) = broadcast::channel(100);
+        let pipeline = PipelineProcessor::new(
+            Uuid::new_v4(),
+            "Ordering Test".to_string(),
+            processors,
+            vec![keyed_file("A"), keyed_file("B")],
+            None,
+            None,
+            metrics_tx,
+            Arc::new(Mutex::new(VecDeque::new())),
+        ).unwrap().with_workers(4, Some("patient".to_string()));
+
+        let (tx, rx) = mpsc::channel(100);
+        for n in 0..20 {
+            let patient = if n % 2 == 0 { "A" } else { "B" };
+            let content = format!(r#"{{"patient":"{}","n":{}}}"#, patient, n);
+            tx.send(Message::new(Uuid::new_v4(), content, "test".to_string())).await.unwrap();
+        }
+        drop(tx);
+        pipeline.run(rx).await;
+
+        for key in ["A", "B"] {
+            let written = tokio::fs::read_to_string(out_dir.join(format!("{}.txt", key))).await.unwrap();
+            let order: Vec<i64> = written.lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["n"].as_i64().unwrap())
+                .collect();
+            assert_eq!(order.len(), 10);
+            assert!(order.windows(2).all(|w| w[0] < w[1]), "{} out of order: {:?}", key, order);
+        }
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[test]
+    fn test_invalid_hl7_formats_fail_deploy() {
+        let build = |input: &str, output: &str| {
+            let processors = vec![ProcessorConfig {
+                id: "hl7".to_string(),
+                name: "HL7".to_string(),
+                kind: ProcessorType::Hl7 { input_format: input.to_string(), output_format: output.to_string(), fhir_mappings: Default::default() },
+            }];
+            let (metrics_tx, _) = broadcast::channel(10);
+            PipelineProcessor::new(Uuid::new_v4(), "HL7".to_string(), processors, vec![], None, None, metrics_tx, Arc::new(Mutex::new(VecDeque::new())))
+        };
+
+        assert!(build("hl7v2", "xml").is_ok());
+        for (input, output) in [("hl7v2", "fhir_r3"), ("json", "json"), ("json_map", "hl7v2")] {
+            let err = build(input, output).err().expect("deploy should fail").to_string();
+            assert!(err.starts_with("Processor HL7:"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_invalid_lua_destination_fails_deploy() {
+        let lua_destination = |id: &str, code: &str| DestinationConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            routes: vec![],
+            queue: None,
+            timeout_ms: None,
+            kind: DestinationType::Lua { code: code.to_string(), limits: Default::default() },
+        };
+        let build = |destinations: Vec<DestinationConfig>| {
+            let (metrics_tx, _) = broadcast::channel(10);
+            PipelineProcessor::new(Uuid::new_v4(), "Lua".to_string(), vec![], destinations, None, None, metrics_tx, Arc::new(Mutex::new(VecDeque::new())))
+        };
+
+        assert!(build(vec![lua_destination("ok", "log(msg.content)")]).is_ok());
+        let err = build(vec![lua_destination("broken", "this is not lua")]).err().expect("deploy should fail").to_string();
+        assert!(err.starts_with("Destination broken:"), "{}", err);
+
+        let err = build(vec![]).unwrap()
+            .with_error_destination(Some(lua_destination("dlq", "end")))
+            .err().expect("deploy should fail").to_string();
+        assert!(err.starts_with("Destination dlq:"), "{}", err);
+    }
+}
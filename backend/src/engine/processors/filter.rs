@@ -1,44 +1,45 @@
+use std::sync::Arc;
 use crate::engine::message::Message;
+use crate::engine::lua_pool::LuaPool;
+use crate::lua_helpers::sandbox;
+use crate::storage::models::LuaLimits;
 use mlua::prelude::*;
-use std::sync::{Arc, Mutex};
 
 pub struct FilterProcessor {
-    condition: String,
+    pool: Arc<LuaPool>,
 }
 
 impl FilterProcessor {
-    pub fn new(condition: String) -> Self {
-        Self { condition }
+    /// Compile the condition once; evaluation reuses pooled states
+    pub fn new(condition: String, limits: LuaLimits) -> anyhow::Result<Self> {
+        // In Mirth Connect, filter scripts usually 'return true' to pass, 'return false' to filter.
+        // The condition is executed as a chunk, so it must `return` its verdict.
+        let pool = sandbox::build_pool("filter", &condition, false, limits)?;
+        Ok(Self { pool: Arc::new(pool) })
     }
 
-    pub fn process(&self, msg: Message) -> anyhow::Result<bool> {
-        let lua = Lua::new();
-        let globals = lua.globals();
-
-        // 1. Create 'msg' table
-        let msg_table = lua.create_table()?;
-        msg_table.set("id", msg.id.to_string())?;
-        msg_table.set("content", msg.content.clone())?;
-        msg_table.set("origin", msg.origin.clone())?;
+    pub async fn process(&self, msg: Message) -> anyhow::Result<bool> {
+        let result = self.pool.execute(move |lua, condition| {
+            // 'msg' table
+            let msg_table = lua.create_table()?;
+            msg_table.set("id", msg.id.to_string())?;
+            msg_table.set("content", msg.content)?;
+            msg_table.set("origin", msg.origin)?;
+            // Source details such as `client_cert.cn` (mutual TLS)
+            msg_table.set("metadata", lua.create_table_from(msg.metadata)?)?;
+            lua.globals().set("msg", msg_table)?;
 
-        globals.set("msg", msg_table)?;
-
-        // 2. Wrap condition in a function to return value
-        // The condition is likely just an expression like `msg.content == 'foo'`
-        // Or a full script `if ... then return true else return false end`
-        // Let's assume it's valid Lua code that returns a boolean.
-        // If it's a simple expression, we might need to prepend 'return '.
-        // But for flexibility, let's assume the user writes `return ...` or we wrap it?
-        // In Mirth Connect, filter scripts usually 'return true' to pass, 'return false' to filter.
-        // Let's execute it as a chunk.
-        
-        let chunk = lua.load(&self.condition);
-        let result: LuaValue = chunk.eval()?;
+            // `None` for a non-boolean result
+            match condition.call::<_, LuaValue>(())? {
+                LuaValue::Boolean(b) => Ok(Some(b)),
+                LuaValue::Nil => Ok(Some(false)), // treat nil as false/drop? Or strict?
+                _ => Ok(None),
+            }
+        }).await?;
         
         match result {
-            LuaValue::Boolean(b) => Ok(b),
-            LuaValue::Nil => Ok(false), // treat nil as false/drop? Or strict?
-            _ => {
+            Some(pass) => Ok(pass),
+            None => {
                 // Try truthiness? Lua treats everything except false and nil as true.
                 // But explicit return expected.
                 tracing::warn!("Filter returned non-boolean value. Treating as true (Pass).");
@@ -53,17 +54,26 @@ mod tests {
     use super::*;
     use uuid::Uuid;
 
-    #[test]
-    fn test_filter_pass() {
-        let processor = FilterProcessor::new("return msg.content == 'KEEP'".to_string());
+    #[tokio::test]
+    async fn test_filter_pass() {
+        let processor = FilterProcessor::new("return msg.content == 'KEEP'".to_string(), LuaLimits::default()).unwrap();
         let msg = Message::new(Uuid::new_v4(), "KEEP".to_string(), "test".to_string());
-        assert_eq!(processor.process(msg).unwrap(), true);
+        assert_eq!(processor.process(msg).await.unwrap(), true);
     }
 
-    #[test]
-    fn test_filter_drop() {
-        let processor = FilterProcessor::new("return msg.content == 'KEEP'".to_string());
+    #[tokio::test]
+    async fn test_filter_drop() {
+        let processor = FilterProcessor::new("return msg.content == 'KEEP'".to_string(), LuaLimits::default()).unwrap();
         let msg = Message::new(Uuid::new_v4(), "DROP".to_string(), "test".to_string());
-        assert_eq!(processor.process(msg).unwrap(), false);
+        assert_eq!(processor.process(msg).await.unwrap(), false);
+    }
+
+    #[tokio::test]
+    async fn test_filter_on_metadata() {
+        let processor = FilterProcessor::new("return msg.metadata['client_cert.cn'] == 'partner'".to_string(), LuaLimits::default()).unwrap();
+        let mut msg = Message::new(Uuid::new_v4(), "MSH|".to_string(), "test".to_string());
+        assert!(!processor.process(msg.clone()).await.unwrap());
+        msg.metadata.insert("client_cert.cn".to_string(), "partner".to_string());
+        assert!(processor.process(msg).await.unwrap());
     }
 }
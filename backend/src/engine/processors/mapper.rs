@@ -35,7 +35,7 @@ impl MapperProcessor {
         // If target exists, overwrite.
         
         for mapping in &self.mappings {
-            let source_val = self.get_value_by_path(&content_json, &mapping.source);
+            let source_val = Self::get_value_by_path(&content_json, &mapping.source);
             
             if let Some(val) = source_val {
                 self.set_value_by_path(&mut content_json, &mapping.target, val)?;
@@ -51,7 +51,8 @@ impl MapperProcessor {
         Ok(msg)
     }
 
-    fn get_value_by_path(&self, json: &Value, path: &str) -> Option<Value> {
+    /// Value at a dotted path such as `patient.ids[0].value` (`.` or empty for the root)
+    pub fn get_value_by_path(json: &Value, path: &str) -> Option<Value> {
         // Handle root
         if path == "." || path.is_empty() {
              return Some(json.clone());
@@ -1,3 +1,4 @@
 pub mod lua;
 pub mod mapper;
 pub mod filter;
+pub mod router;
@@ -0,0 +1,93 @@
+use crate::engine::message::Message;
+use crate::engine::processors::filter::FilterProcessor;
+use crate::storage::models::{LuaLimits, Route};
+
+/// Content Router: evaluates every route condition and returns the names of the
+/// routes that matched. Destinations subscribe to route names via `DestinationConfig.routes`.
+pub struct RouterProcessor {
+    /// Route names with their compiled conditions
+    routes: Vec<(String, FilterProcessor)>,
+    default_route: Option<String>,
+}
+
+impl RouterProcessor {
+    pub fn new(routes: Vec<Route>, default_route: Option<String>, limits: LuaLimits) -> anyhow::Result<Self> {
+        let routes = routes.into_iter()
+            .map(|route| {
+                // Route conditions share the filter semantics (return true to match)
+                let condition = FilterProcessor::new(route.condition, limits)
+                    .map_err(|e| anyhow::anyhow!("Route '{}' condition is invalid: {}", route.name, e))?;
+                Ok((route.name, condition))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { routes, default_route })
+    }
+
+    /// Returns the matched route names (in declaration order). Falls back to the
+    /// default route when nothing matches; an empty result means the message is unrouted.
+    pub async fn process(&self, msg: &Message) -> anyhow::Result<Vec<String>> {
+        let mut matched = Vec::new();
+
+        for (name, condition) in &self.routes {
+            let is_match = condition
+                .process(msg.clone())
+                .await
+                .map_err(|e| anyhow::anyhow!("Route '{}' condition failed: {}", name, e))?;
+
+            if is_match {
+                matched.push(name.clone());
+            }
+        }
+
+        if matched.is_empty() {
+            if let Some(default_route) = &self.default_route {
+                matched.push(default_route.clone());
+            }
+        }
+
+        Ok(matched)
+    }
+
+    /// Whether a destination subscribed to `dest_routes` should receive a message routed to `matched`.
+    pub fn accepts(dest_routes: &[String], matched: &[String]) -> bool {
+        dest_routes.is_empty() || dest_routes.iter().any(|r| matched.contains(r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn routes() -> Vec<Route> {
+        vec![
+            Route { name: "lab".to_string(), condition: "return msg.content:find('ORU') ~= nil".to_string() },
+            Route { name: "adt".to_string(), condition: "return msg.content:find('ADT') ~= nil".to_string() },
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_router_matches_route() {
+        let router = RouterProcessor::new(routes(), None, LuaLimits::default()).unwrap();
+        let msg = Message::new(Uuid::new_v4(), "MSH|...|ORU^R01".to_string(), "test".to_string());
+        assert_eq!(router.process(&msg).await.unwrap(), vec!["lab".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_router_default_route() {
+        let router = RouterProcessor::new(routes(), Some("other".to_string()), LuaLimits::default()).unwrap();
+        let msg = Message::new(Uuid::new_v4(), "MSH|...|SIU^S12".to_string(), "test".to_string());
+        assert_eq!(router.process(&msg).await.unwrap(), vec!["other".to_string()]);
+
+        let router = RouterProcessor::new(routes(), None, LuaLimits::default()).unwrap();
+        assert!(router.process(&msg).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_router_accepts() {
+        let matched = vec!["lab".to_string()];
+        assert!(RouterProcessor::accepts(&[], &matched));
+        assert!(RouterProcessor::accepts(&["lab".to_string()], &matched));
+        assert!(!RouterProcessor::accepts(&["adt".to_string()], &matched));
+    }
+}
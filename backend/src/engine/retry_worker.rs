@@ -2,48 +2,226 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::time::sleep;
 use tokio::sync::mpsc;
-use crate::storage::messages::MessageStatus;
+use crate::storage::messages::{decode_metadata, MessageStore, MessageStatus, MessageDestinationRecord};
+use crate::storage::models::Channel;
 use crate::engine::message::Message;
+use crate::engine::destinations::{self, ChannelDestinations};
+use crate::engine::pipeline::dead_letter;
 use std::collections::HashMap;
 use uuid::Uuid;
 use chrono::Utc;
-use anyhow::Result;
 
 pub struct RetryWorker {
     pool: sqlx::SqlitePool,
     senders: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+    compiled: Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
     interval: Duration,
 }
 
+/// Resend the stored, already-transformed payload to the given failed destinations of a message.
+/// Processors are not re-run and destinations that already succeeded are not touched.
+pub async fn retry_destinations(
+    store: &MessageStore,
+    channel: &Channel,
+    compiled: &ChannelDestinations,
+    message_id: &str,
+    failed: Vec<MessageDestinationRecord>,
+    origin: &str,
+) -> anyhow::Result<MessageStatus> {
+    let message_uuid = Uuid::parse_str(message_id)?;
+
+    for record in failed {
+        let Some(dest) = channel.destinations.iter().find(|d| d.id == record.destination_id) else {
+            tracing::warn!("Destination {} no longer exists in channel {}, skipping retry of message {}", record.destination_id, channel.name, message_id);
+            continue;
+        };
+        let Some(content) = record.content else {
+            tracing::warn!("No stored payload for destination {} of message {}, skipping retry", record.destination_id, message_id);
+            continue;
+        };
+
+        let mut msg = Message::new(channel.id, content.clone(), origin.to_string());
+        msg.id = message_uuid;
+        msg.metadata = record.metadata;
+
+        tracing::info!("Retrying destination {} for message {} (Attempt {})", dest.name, message_id, record.attempts + 1);
+        match destinations::dispatch(dest, &msg, &channel.name, compiled).await {
+            Ok(response) => {
+                store.record_destination_attempt(message_id, dest, &content, &msg.metadata, MessageStatus::SENT, None, response.map(|r| r.to_string())).await?;
+            },
+            Err(e) => {
+                tracing::warn!("Retry of destination {} for message {} failed: {}", dest.name, message_id, e);
+                store.record_destination_attempt(message_id, dest, &content, &msg.metadata, MessageStatus::ERROR, Some(e.to_string()), None).await?;
+            }
+        }
+    }
+
+    Ok(store.refresh_status_from_destinations(message_id).await?)
+}
+
 impl RetryWorker {
-    pub fn new(pool: sqlx::SqlitePool, senders: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>) -> Self {
+    pub fn new(
+        pool: sqlx::SqlitePool,
+        senders: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        compiled: Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
+    ) -> Self {
         Self {
             pool,
             senders,
+            compiled,
             interval: Duration::from_secs(60), // Check every minute
         }
     }
 
+    pub fn with_interval(
+        pool: sqlx::SqlitePool,
+        senders: Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        compiled: Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
+        interval: Duration,
+    ) -> Self {
+        Self { pool, senders, compiled, interval }
+    }
+
     pub async fn start(self) {
-        tracing::info!("Starting RetryWorker...");
+        tracing::info!("Starting RetryWorker (interval {}s)...", self.interval.as_secs());
         let pool = self.pool.clone();
         let senders = self.senders.clone();
 
         loop {
-            if let Err(e) = Self::process_retries(&pool, &senders).await {
+            if let Err(e) = Self::process_retries(&pool, &senders, &self.compiled).await {
                 tracing::error!("RetryWorker passed error: {}", e);
             }
             sleep(self.interval).await;
         }
     }
 
-    async fn process_retries(pool: &sqlx::SqlitePool, senders: &Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>) -> anyhow::Result<()> {
+    async fn process_retries(
+        pool: &sqlx::SqlitePool,
+        senders: &Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        compiled: &Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
+    ) -> anyhow::Result<()> {
+        let mut channels = ChannelCache { compiled: compiled.clone(), ..Default::default() };
+        Self::retry_failed_destinations(pool, senders, &mut channels).await?;
+        Self::retry_failed_pipelines(pool, senders, &mut channels).await
+    }
+
+    /// Whether retry number `retry` (0-based) is due, given the time of the last attempt
+    fn is_due(channel: &Channel, retry: i32, seed: &str, last_attempt: chrono::DateTime<Utc>) -> bool {
+        let delay = channel.retry_policy.delay(retry.max(0) as u32, seed);
+        let delay = chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::MAX);
+        let next_retry_time = last_attempt.checked_add_signed(delay).unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+        Utc::now() >= next_retry_time
+    }
+
+    /// Dead-letter a message whose retries ran out, if the channel has an error destination
+    async fn send_exhausted_to_error_destination(store: &MessageStore, channel: &Channel, compiled: &ChannelDestinations, msg: &Message, failure: &dead_letter::Failure<'_>) {
+        let Some(error_dest) = &channel.error_destination else {
+            return;
+        };
+        match dead_letter::send_to_error_destination(error_dest, Some(store), &channel.name, compiled, msg, failure).await {
+            Ok(_) => tracing::warn!("Message {} exhausted its retries and was sent to error destination {}", msg.id, error_dest.name),
+            Err(e) => tracing::error!("Error destination {} failed for exhausted message {}: {}", error_dest.name, msg.id, e),
+        }
+    }
+
+    /// Destinations that failed after the message was transformed: resend only to them.
+    async fn retry_failed_destinations(
+        pool: &sqlx::SqlitePool,
+        senders: &Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        channels: &mut ChannelCache,
+    ) -> anyhow::Result<()> {
+        use sqlx::Row;
+        let store = MessageStore::new(pool.clone());
+
+        let rows = sqlx::query(
+            r#"
+            SELECT md.message_id, m.channel_id, m.content AS original_content, md.destination_id, md.destination_name,
+                   md.attempts, md.last_error, md.content, md.metadata, md.updated_at
+            FROM message_destinations md
+            JOIN messages m ON m.id = md.message_id
+            WHERE md.status = 'ERROR'
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // Group due destinations by message
+        let mut due: HashMap<(String, String), Vec<String>> = HashMap::new();
+        for row in rows {
+            let message_id: String = row.get("message_id");
+            let channel_id_str: String = row.get("channel_id");
+            let Some(channel) = channels.get_running(pool, senders, &channel_id_str).await? else {
+                continue;
+            };
+
+            // attempts includes the first delivery, so retries done = attempts - 1
+            let attempts: i32 = row.get("attempts");
+            let retries_done = attempts - 1;
+            let destination_id: String = row.get("destination_id");
+
+            if !channel.can_retry(retries_done) {
+                let destination_name: String = row.get("destination_name");
+                let last_error: Option<String> = row.get("last_error");
+                let original_content: String = row.get("original_content");
+                let content: Option<String> = row.get("content");
+
+                tracing::warn!("Destination {} of message {} exhausted {} retries, marking FAILED", destination_name, message_id, retries_done);
+                store.set_destination_status(&message_id, &destination_id, &destination_name, MessageStatus::FAILED).await?;
+                store.refresh_status_from_destinations(&message_id).await?;
+
+                let mut msg = Message::new(channel.id, content.unwrap_or_else(|| original_content.clone()), "retry_worker".to_string());
+                msg.id = Uuid::parse_str(&message_id).unwrap_or_default();
+                msg.metadata = decode_metadata(row.get("metadata"));
+                let error = format!("Retries exhausted: {}", last_error.as_deref().unwrap_or("unknown error"));
+                let failure = dead_letter::Failure { failed_step: &destination_name, error: &error, original_content: &original_content };
+                Self::send_exhausted_to_error_destination(&store, &channel, &channels.compiled(channel.id), &msg, &failure).await;
+                continue;
+            }
+
+            let updated_at: chrono::DateTime<Utc> = row.get("updated_at");
+            let seed = format!("{}:{}", message_id, destination_id);
+            if Self::is_due(&channel, retries_done, &seed, updated_at) {
+                due.entry((message_id, channel_id_str))
+                    .or_default()
+                    .push(destination_id);
+            }
+        }
+
+        for ((message_id, channel_id_str), destination_ids) in due {
+            let Some(channel) = channels.get_running(pool, senders, &channel_id_str).await? else {
+                continue;
+            };
+
+            let failed: Vec<MessageDestinationRecord> = store.get_failed_destinations(&message_id).await?
+                .into_iter()
+                .filter(|d| destination_ids.contains(&d.destination_id))
+                .collect();
+
+            store.increment_retry(&message_id).await?;
+            match retry_destinations(&store, &channel, &channels.compiled(channel.id), &message_id, failed, "retry_worker").await {
+                Ok(status) => tracing::info!("Destination retry for message {} finished with status {}", message_id, status),
+                Err(e) => tracing::error!("Destination retry for message {} failed: {}", message_id, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Messages that failed before any destination was attempted: re-run the whole pipeline.
+    async fn retry_failed_pipelines(
+        pool: &sqlx::SqlitePool,
+        senders: &Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        channels: &mut ChannelCache,
+    ) -> anyhow::Result<()> {
+        let store = MessageStore::new(pool.clone());
+
         // Query for messages with status ERROR using generic query to avoid build-time DB check
         let messages = sqlx::query(
             r#"
-            SELECT id, channel_id, content, status, retry_count, created_at, updated_at
+            SELECT id, channel_id, content, metadata, status, error_message, retry_count, created_at, updated_at
             FROM messages
             WHERE status = 'ERROR'
+              AND NOT EXISTS (SELECT 1 FROM message_destinations md WHERE md.message_id = messages.id)
             "#
         )
         .fetch_all(pool)
@@ -57,51 +235,378 @@ impl RetryWorker {
             let retry_count: i32 = record.get("retry_count");
             let updated_at: chrono::DateTime<Utc> = record.get("updated_at"); // Ensure type matches DB
 
-            // Check max_retries. 
-            // Backoff: 2^retry_count * 1 minute
-            let backoff_minutes = 2u64.pow(retry_count as u32);
-            let next_retry_time = updated_at.checked_add_signed(chrono::Duration::minutes(backoff_minutes as i64)).unwrap_or(Utc::now());
+            let Some(channel) = channels.get_running(pool, senders, &channel_id_str).await? else {
+                continue;
+            };
 
-            if Utc::now() >= next_retry_time {
-                 let channel_id_uuid = match Uuid::parse_str(&channel_id_str) {
-                     Ok(u) => u,
-                     Err(_) => continue,
-                 };
-                 
+            let mut msg = Message {
+                id: Uuid::parse_str(&id).unwrap_or_default(),
+                channel_id: channel.id,
+                content: content.clone(),
+                metadata: decode_metadata(record.get("metadata")),
+                origin: Some("retry_worker".to_string()),
+                timestamp: Utc::now(),
+                redelivery: true,
+                response_tx: None,
+            };
+
+            if !channel.can_retry(retry_count) {
+                let last_error: Option<String> = record.get("error_message");
+                let error = format!("Retries exhausted: {}", last_error.as_deref().unwrap_or("unknown error"));
+                tracing::warn!("Message {} exhausted {} retries, marking FAILED", id, retry_count);
+                store.update_status(&id, MessageStatus::FAILED, Some(error.clone())).await?;
+
+                let failure = dead_letter::Failure { failed_step: "pipeline", error: &error, original_content: &content };
+                Self::send_exhausted_to_error_destination(&store, &channel, &channels.compiled(channel.id), &msg, &failure).await;
+                continue;
+            }
+
+            if Self::is_due(&channel, retry_count, &id, updated_at) {
                  let sender = {
                      let map = senders.lock().unwrap();
-                     map.get(&channel_id_uuid).cloned()
+                     map.get(&channel.id).cloned()
                  };
-                 
+
                  if let Some(sender) = sender {
                      let new_retry_count = retry_count + 1;
-                     
-                     let mut msg = Message {
-                        id: Uuid::parse_str(&id).unwrap_or_default(),
-                        channel_id: channel_id_uuid,
-                        content: content.clone(),
-                        metadata: HashMap::new(),
-                        origin: Some("retry_worker".to_string()),
-                        timestamp: Utc::now(),
-                        response_tx: None,
-                     };
-                     
+                     msg.timestamp = Utc::now();
+
                      // Update DB to PROCESSING
                      let _ = sqlx::query("UPDATE messages SET status = 'PROCESSING', retry_count = ?, updated_at = ? WHERE id = ?")
                          .bind(new_retry_count)
                          .bind(Utc::now())
                          .bind(&id)
                          .execute(pool).await;
-                     
+
                      tracing::info!("Retrying message {} for channel {} (Attempt {})", id, channel_id_str, new_retry_count);
-                     
+
                      if let Err(e) = sender.send(msg).await {
                          tracing::error!("Failed to re-queue message {}: {}", id, e);
                      }
                  }
             }
         }
-        
+
         Ok(())
     }
 }
+
+/// Channel configurations loaded from the database during one retry pass
+#[derive(Default)]
+struct ChannelCache {
+    channels: HashMap<String, Option<Channel>>,
+    compiled: Arc<Mutex<HashMap<Uuid, Arc<ChannelDestinations>>>>,
+}
+
+impl ChannelCache {
+    /// The destinations compiled when the running channel was deployed
+    fn compiled(&self, channel_id: Uuid) -> Arc<ChannelDestinations> {
+        self.compiled.lock().unwrap().get(&channel_id).cloned().unwrap_or_default()
+    }
+
+    /// The channel's configuration, if it is currently running
+    async fn get_running(
+        &mut self,
+        pool: &sqlx::SqlitePool,
+        senders: &Arc<Mutex<HashMap<Uuid, mpsc::Sender<Message>>>>,
+        channel_id: &str,
+    ) -> anyhow::Result<Option<Channel>> {
+        let running = match Uuid::parse_str(channel_id) {
+            Ok(uuid) => senders.lock().unwrap().contains_key(&uuid),
+            Err(_) => false,
+        };
+        if !running {
+            return Ok(None);
+        }
+
+        if !self.channels.contains_key(channel_id) {
+            let channel = Self::load_channel(pool, channel_id).await?;
+            self.channels.insert(channel_id.to_string(), channel);
+        }
+        Ok(self.channels.get(channel_id).cloned().flatten())
+    }
+
+    async fn load_channel(pool: &sqlx::SqlitePool, channel_id: &str) -> anyhow::Result<Option<Channel>> {
+        use sqlx::Row;
+        let row = sqlx::query("SELECT config FROM channels WHERE id = ?")
+            .bind(channel_id)
+            .fetch_optional(pool)
+            .await?;
+
+        match row {
+            Some(row) => {
+                let config: serde_json::Value = row.get("config");
+                Ok(Some(serde_json::from_value(config)?))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::models::{DestinationConfig, DestinationType, SourceConfig};
+
+    fn file_destination(id: &str, path: &std::path::Path) -> DestinationConfig {
+        DestinationConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            routes: vec![],
+            queue: None,
+            timeout_ms: None,
+            kind: DestinationType::File {
+                path: path.to_string_lossy().to_string(),
+                filename: Some("${id}.txt".to_string()),
+                append: Some(true),
+                encoding: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_only_failed_destinations() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_retry_out_{}", Uuid::new_v4()));
+
+        let delivered = file_destination("delivered", &out_dir.join("delivered"));
+        let failed = file_destination("failed", &out_dir.join("failed"));
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: "Retry Test".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations: vec![delivered.clone(), failed.clone()],
+            error_destination: None,
+            max_retries: Some(3),
+            retry_policy: Default::default(),
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        };
+
+        let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+        store.record_destination_attempt(&id, &delivered, "TRANSFORMED", &HashMap::new(), MessageStatus::SENT, None, None).await.unwrap();
+        // Retries exhausted: a manual retry still picks the destination up
+        store.record_destination_attempt(&id, &failed, "TRANSFORMED", &HashMap::new(), MessageStatus::FAILED, Some("down".to_string()), None).await.unwrap();
+        assert_eq!(store.refresh_status_from_destinations(&id).await.unwrap(), MessageStatus::PARTIAL);
+
+        let to_retry = store.get_failed_destinations(&id).await.unwrap();
+        assert_eq!(to_retry.len(), 1);
+        let status = retry_destinations(&store, &channel, &Default::default(), &id, to_retry, "test").await.unwrap();
+        assert_eq!(status, MessageStatus::SENT);
+
+        // Only the failed destination received the stored (transformed) payload
+        let written = tokio::fs::read_to_string(out_dir.join("failed").join(format!("{}.txt", id))).await.unwrap();
+        assert_eq!(written.trim(), "TRANSFORMED");
+        assert!(!out_dir.join("delivered").exists());
+
+        let records = store.get_message_destinations(&id).await.unwrap();
+        let retried = records.iter().find(|d| d.destination_id == "failed").unwrap();
+        assert_eq!(retried.attempts, 2);
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_retry_restores_delivery_metadata() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+
+        // Fails unless the retry sees the metadata of the original delivery
+        let lookup = DestinationConfig {
+            id: "lookup".to_string(),
+            name: "lookup".to_string(),
+            routes: vec![],
+            queue: None,
+            timeout_ms: None,
+            kind: DestinationType::Lua {
+                code: "if msg.metadata.mrn ~= '42' then error('mrn missing') end".to_string(),
+                limits: Default::default(),
+            },
+        };
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: "Retry Test".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations: vec![lookup.clone()],
+            error_destination: None,
+            max_retries: Some(3),
+            retry_policy: Default::default(),
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        };
+
+        let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+        let metadata = HashMap::from([("mrn".to_string(), "42".to_string())]);
+        store.record_destination_attempt(&id, &lookup, "TRANSFORMED", &metadata, MessageStatus::ERROR, Some("down".to_string()), None).await.unwrap();
+
+        let to_retry = store.get_failed_destinations(&id).await.unwrap();
+        assert_eq!(to_retry[0].metadata, metadata);
+        let status = retry_destinations(&store, &channel, &Default::default(), &id, to_retry, "test").await.unwrap();
+        assert_eq!(status, MessageStatus::SENT);
+        assert_eq!(store.get_message_destinations(&id).await.unwrap()[0].metadata, metadata);
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = crate::storage::models::RetryPolicy { initial_delay_ms: 1_000, multiplier: 2.0, max_delay_ms: 5_000, jitter: 0.0 };
+        assert_eq!(policy.delay(0, "m"), Duration::from_millis(1_000));
+        assert_eq!(policy.delay(2, "m"), Duration::from_millis(4_000));
+        assert_eq!(policy.delay(10, "m"), Duration::from_millis(5_000));
+
+        let jittered = crate::storage::models::RetryPolicy { jitter: 0.5, ..policy };
+        let delay = jittered.delay(1, "m");
+        assert!(delay >= Duration::from_millis(1_000) && delay <= Duration::from_millis(3_000));
+        assert_eq!(delay, jittered.delay(1, "m"));
+        // Jitter never pushes a capped delay past the maximum
+        for seed in ["a", "b", "c", "d", "e", "f"] {
+            assert!(jittered.delay(10, seed) <= Duration::from_millis(5_000));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_destination_goes_to_error_destination() {
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_retry_out_{}", Uuid::new_v4()));
+
+        let failed = file_destination("failed", &out_dir.join("failed"));
+        let dlq = file_destination("dlq", &out_dir.join("dlq"));
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: "Exhaustion Test".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![],
+            destinations: vec![failed.clone()],
+            error_destination: Some(dlq),
+            max_retries: Some(1),
+            retry_policy: Default::default(),
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        };
+        db.save_channel(&channel.id.to_string(), &channel.name, serde_json::to_value(&channel).unwrap(), None).await.unwrap();
+
+        let (tx, _rx) = mpsc::channel(1);
+        let senders = Arc::new(Mutex::new(HashMap::from([(channel.id, tx)])));
+
+        // First delivery plus one retry, both failed
+        let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+        for _ in 0..2 {
+            store.record_destination_attempt(&id, &failed, "TRANSFORMED", &HashMap::new(), MessageStatus::ERROR, Some("down".to_string()), None).await.unwrap();
+        }
+        store.refresh_status_from_destinations(&id).await.unwrap();
+
+        RetryWorker::process_retries(&db.pool, &senders, &Default::default()).await.unwrap();
+
+        let record = store.get_message_by_id(&id).await.unwrap().unwrap();
+        let destinations = store.get_message_destinations(&id).await.unwrap();
+        assert_eq!(record.status, MessageStatus::FAILED.to_string());
+        assert_eq!(destinations[0].status, MessageStatus::FAILED.to_string());
+        assert_eq!(destinations[0].attempts, 2);
+        assert!(!out_dir.join("failed").exists());
+
+        let envelope = tokio::fs::read_to_string(out_dir.join("dlq").join(format!("{}.txt", id))).await.unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(envelope.trim()).unwrap();
+        assert_eq!(envelope["failed_step"], "failed");
+        assert_eq!(envelope["original_content"], "raw");
+        assert_eq!(envelope["content"], "TRANSFORMED");
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_processor_failure_retries_with_dedup_then_dead_letters() {
+        use crate::engine::pipeline::processor::PipelineProcessor;
+        use crate::storage::deduplication::DeduplicationStore;
+        use crate::storage::models::{ProcessorConfig, ProcessorType};
+
+        let (db, _db_file) = crate::storage::db::temp_database().await;
+        let store = MessageStore::new(db.pool.clone());
+        let out_dir = std::env::temp_dir().join(format!("mirthbr_retry_out_{}", Uuid::new_v4()));
+
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: "Dedup Retry Test".to_string(),
+            enabled: true,
+            source: SourceConfig::Test { payload_type: "text".to_string(), payload: String::new() },
+            processors: vec![ProcessorConfig {
+                id: "boom".to_string(),
+                name: "boom".to_string(),
+                kind: ProcessorType::Lua { code: "error('boom')".to_string(), limits: Default::default() },
+            }],
+            destinations: vec![file_destination("out", &out_dir.join("out"))],
+            error_destination: Some(file_destination("dlq", &out_dir.join("dlq"))),
+            max_retries: Some(1),
+            retry_policy: crate::storage::models::RetryPolicy { initial_delay_ms: 0, ..Default::default() },
+            destination_mode: Default::default(),
+            workers: 1,
+            ordering_key: None,
+        };
+        db.save_channel(&channel.id.to_string(), &channel.name, serde_json::to_value(&channel).unwrap(), None).await.unwrap();
+
+        let (metrics_tx, _) = tokio::sync::broadcast::channel(10);
+        let pipeline = PipelineProcessor::new(
+            channel.id,
+            channel.name.clone(),
+            channel.processors.clone(),
+            channel.destinations.clone(),
+            Some(store.clone()),
+            Some(Arc::new(DeduplicationStore::new(db.pool.clone()))),
+            metrics_tx,
+            Arc::new(Mutex::new(std::collections::VecDeque::new())),
+        )
+        .unwrap()
+        .with_error_destination(channel.error_destination.clone())
+        .unwrap()
+        .with_max_retries(channel.max_retries);
+        let (tx, rx) = mpsc::channel(10);
+        tokio::spawn(pipeline.run(rx));
+        let senders = Arc::new(Mutex::new(HashMap::from([(channel.id, tx.clone())])));
+
+        let id = store.save_message(&channel.id.to_string(), "raw").await.unwrap();
+        let mut msg = Message::new(channel.id, "raw".to_string(), "test".to_string());
+        msg.id = Uuid::parse_str(&id).unwrap();
+        tx.send(msg).await.unwrap();
+
+        let settled = |retries: i32| {
+            let store = store.clone();
+            let id = id.clone();
+            async move {
+                for _ in 0..100 {
+                    let record = store.get_message_by_id(&id).await.unwrap().unwrap();
+                    if record.status != "PENDING" && record.status != "PROCESSING" && record.retry_count == retries {
+                        return record;
+                    }
+                    sleep(Duration::from_millis(20)).await;
+                }
+                panic!("message {} did not settle", id);
+            }
+        };
+        assert_eq!(settled(0).await.status, "ERROR");
+
+        // The retry re-runs the processors instead of being dropped as a duplicate
+        RetryWorker::process_retries(&db.pool, &senders, &Default::default()).await.unwrap();
+        let record = settled(1).await;
+        assert_eq!(record.status, "ERROR", "{:?}", record.error_message);
+
+        // Retries exhausted: the message is FAILED and dead-lettered
+        RetryWorker::process_retries(&db.pool, &senders, &Default::default()).await.unwrap();
+        let record = store.get_message_by_id(&id).await.unwrap().unwrap();
+        assert_eq!(record.status, "FAILED");
+        assert_eq!(record.dlq_status.as_deref(), Some("SENT"));
+        let envelope = tokio::fs::read_to_string(out_dir.join("dlq").join(format!("{}.txt", id))).await.unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(envelope.trim()).unwrap();
+        assert_eq!(envelope["failed_step"], "pipeline");
+        assert_eq!(envelope["original_content"], "raw");
+
+        let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    }
+}
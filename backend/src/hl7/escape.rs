@@ -0,0 +1,120 @@
+use super::Delimiters;
+
+/// Decode HL7 escape sequences (`\F\`, `\S\`, `\T\`, `\R\`, `\E\`, `\Xhh..\`). Formatting and
+/// character-set sequences (`\.br\`, `\H\`, `\C..\`, ...) have no plain-text meaning and are
+/// kept as they are.
+pub fn unescape(text: &str, delimiters: &Delimiters) -> String {
+    let Some(escape) = delimiters.escape else {
+        return text.to_string();
+    };
+    if !text.contains(escape) {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(escape) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + escape.len_utf8()..];
+        let Some(end) = after.find(escape) else {
+            // Unterminated sequence: keep the remainder literally
+            out.push_str(&rest[start..]);
+            return out;
+        };
+
+        let sequence = &after[..end];
+        match decode_sequence(sequence, delimiters) {
+            Some(decoded) => out.push_str(&decoded),
+            None => {
+                out.push(escape);
+                out.push_str(sequence);
+                out.push(escape);
+            }
+        }
+        rest = &after[end + escape.len_utf8()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn decode_sequence(sequence: &str, delimiters: &Delimiters) -> Option<String> {
+    let single = |c: Option<char>| c.map(String::from);
+    match sequence {
+        "F" => single(Some(delimiters.field)),
+        "S" => single(Some(delimiters.component)),
+        "R" => single(Some(delimiters.repetition)),
+        "T" => single(delimiters.subcomponent),
+        "E" => single(delimiters.escape),
+        "P" => single(delimiters.truncation),
+        _ => {
+            let hex = sequence.strip_prefix('X')?;
+            if hex.is_empty() || hex.len() % 2 != 0 {
+                return None;
+            }
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+}
+
+/// Escape delimiters (and line breaks, which would end the segment) so `text` can be stored as
+/// a single value
+pub fn escape(text: &str, delimiters: &Delimiters) -> String {
+    let Some(escape) = delimiters.escape else {
+        return text.to_string();
+    };
+
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let sequence = if c == escape {
+            "E"
+        } else if c == delimiters.field {
+            "F"
+        } else if c == delimiters.component {
+            "S"
+        } else if c == delimiters.repetition {
+            "R"
+        } else if Some(c) == delimiters.subcomponent {
+            "T"
+        } else if Some(c) == delimiters.truncation {
+            "P"
+        } else if c == '\r' {
+            "X0D"
+        } else if c == '\n' {
+            "X0A"
+        } else {
+            out.push(c);
+            continue;
+        };
+        out.push(escape);
+        out.push_str(sequence);
+        out.push(escape);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape() {
+        let d = Delimiters::default();
+        assert_eq!(unescape(r"A\F\B\S\C\T\D\R\E\E\", &d), r"A|B^C&D~E\");
+        assert_eq!(unescape(r"line\X0D0A\end", &d), "line\r\nend");
+        assert_eq!(unescape(r"keep\.br\this", &d), r"keep\.br\this");
+        assert_eq!(unescape(r"open\F", &d), r"open\F");
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        let d = Delimiters::default();
+        let text = "a|b^c&d~e\\f\rg";
+        let escaped = escape(text, &d);
+        assert_eq!(escaped, r"a\F\b\S\c\T\d\R\e\E\f\X0D\g");
+        assert_eq!(unescape(&escaped, &d), text);
+    }
+}
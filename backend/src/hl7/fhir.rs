@@ -0,0 +1,710 @@
+//! HL7 v2 -> FHIR R4 transaction Bundle. ADT^A01/A04/A08 become Patient and Encounter,
+//! ORU^R01 becomes Patient, DiagnosticReport (one per OBR) and Observation (one per OBX).
+//!
+//! Patient, Encounter and DiagnosticReport are conditional updates on their first identifier, so
+//! replaying a message updates the same resources. `fullUrl`s are name-based UUIDs of the
+//! message, which keeps the output of a given message stable.
+
+use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use crate::storage::models::FhirMappings;
+use super::{FieldPath, Message, Segment};
+
+const V2_0203: &str = "http://terminology.hl7.org/CodeSystem/v2-0203";
+const V2_0004: &str = "http://terminology.hl7.org/CodeSystem/v2-0004";
+const V2_0069: &str = "http://terminology.hl7.org/CodeSystem/v2-0069";
+const V2_0074: &str = "http://terminology.hl7.org/CodeSystem/v2-0074";
+const ACT_CODE: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+const NULL_FLAVOR: &str = "http://terminology.hl7.org/CodeSystem/v3-NullFlavor";
+const PARTICIPATION_TYPE: &str = "http://terminology.hl7.org/CodeSystem/v3-ParticipationType";
+const INTERPRETATION: &str = "http://terminology.hl7.org/CodeSystem/v3-ObservationInterpretation";
+const OBSERVATION_CATEGORY: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+
+/// Built-in tables (HL7 v2 code -> FHIR code); `FhirMappings` entries are layered on top
+const GENDER: &[(&str, &str)] = &[("M", "male"), ("F", "female"), ("O", "other"), ("A", "other"), ("U", "unknown"), ("N", "unknown")];
+const ENCOUNTER_CLASS: &[(&str, &str)] = &[("I", "IMP"), ("O", "AMB"), ("E", "EMER"), ("P", "PRENC"), ("R", "AMB")];
+const OBSERVATION_STATUS: &[(&str, &str)] = &[
+    ("F", "final"), ("U", "final"), ("C", "corrected"), ("P", "preliminary"), ("R", "preliminary"), ("S", "preliminary"),
+    ("I", "registered"), ("D", "entered-in-error"), ("W", "entered-in-error"), ("X", "cancelled"),
+];
+const REPORT_STATUS: &[(&str, &str)] = &[
+    ("F", "final"), ("C", "corrected"), ("P", "preliminary"), ("A", "partial"), ("R", "partial"), ("S", "partial"),
+    ("I", "registered"), ("O", "registered"), ("X", "cancelled"),
+];
+const INTERPRETATION_CODE: &[(&str, &str)] = &[("N", "N"), ("H", "H"), ("L", "L"), ("HH", "HH"), ("LL", "LL"), ("A", "A"), ("AA", "AA")];
+const CODING_SYSTEMS: &[(&str, &str)] = &[
+    ("LN", "http://loinc.org"), ("SCT", "http://snomed.info/sct"), ("UCUM", "http://unitsofmeasure.org"),
+    ("I9C", "http://hl7.org/fhir/sid/icd-9-cm"), ("I10", "http://hl7.org/fhir/sid/icd-10"),
+];
+
+/// Displays of the FHIR codes produced by the tables above
+const DISPLAYS: &[(&str, &str)] = &[
+    ("IMP", "inpatient encounter"), ("AMB", "ambulatory"), ("EMER", "emergency"), ("PRENC", "pre-admission"),
+    ("OBSENC", "observation encounter"), ("HH", "home health"), ("VR", "virtual"), ("SS", "short stay"),
+    ("N", "Normal"), ("H", "High"), ("L", "Low"), ("A", "Abnormal"), ("AA", "Critical abnormal"),
+];
+const INTERPRETATION_DISPLAYS: &[(&str, &str)] = &[("HH", "Critical high"), ("LL", "Critical low")];
+
+/// Value sets the status and gender tables must stay within
+const GENDERS: &[&str] = &["male", "female", "other", "unknown"];
+const OBSERVATION_STATUSES: &[&str] = &["registered", "preliminary", "final", "amended", "corrected", "cancelled", "entered-in-error", "unknown"];
+const REPORT_STATUSES: &[&str] = &["registered", "partial", "preliminary", "final", "amended", "corrected", "appended", "cancelled", "entered-in-error", "unknown"];
+
+/// Built-in tables merged with a channel's `FhirMappings`, resolved once at deploy
+#[derive(Debug, Clone)]
+pub struct Mapper {
+    gender: HashMap<String, String>,
+    encounter_class: HashMap<String, String>,
+    observation_status: HashMap<String, String>,
+    report_status: HashMap<String, String>,
+    interpretation: HashMap<String, String>,
+    identifier_systems: HashMap<String, String>,
+    coding_systems: HashMap<String, String>,
+    timezone: Option<String>,
+}
+
+impl Mapper {
+    pub fn new(mappings: &FhirMappings) -> anyhow::Result<Self> {
+        let mapper = Self {
+            gender: table(GENDER, &mappings.gender),
+            encounter_class: table(ENCOUNTER_CLASS, &mappings.encounter_class),
+            observation_status: table(OBSERVATION_STATUS, &mappings.observation_status),
+            report_status: table(REPORT_STATUS, &mappings.report_status),
+            interpretation: table(INTERPRETATION_CODE, &mappings.interpretation),
+            identifier_systems: table(&[], &mappings.identifier_systems),
+            coding_systems: table(CODING_SYSTEMS, &mappings.coding_systems),
+            timezone: mappings.timezone.clone(),
+        };
+
+        for (name, table, allowed) in [
+            ("gender", &mapper.gender, GENDERS),
+            ("observation_status", &mapper.observation_status, OBSERVATION_STATUSES),
+            ("report_status", &mapper.report_status, REPORT_STATUSES),
+        ] {
+            if let Some((code, value)) = table.iter().find(|(_, v)| !allowed.contains(&v.as_str())) {
+                return Err(anyhow::anyhow!("fhirMappings.{}: '{}' maps to '{}', expected one of {}", name, code, value, allowed.join(", ")));
+            }
+        }
+        if let Some(tz) = &mapper.timezone {
+            if !is_offset(tz) {
+                return Err(anyhow::anyhow!("fhirMappings.timezone: '{}' is not an offset such as -03:00 or Z", tz));
+            }
+        }
+        Ok(mapper)
+    }
+
+    /// The transaction Bundle for a supported message type
+    pub fn bundle(&self, message: &Message) -> anyhow::Result<Value> {
+        let msh = message.segment("MSH")
+            .ok_or_else(|| anyhow::anyhow!("FHIR conversion needs an MSH segment"))?;
+        let mut builder = Builder { message, mapper: self, source: message.encode(), entries: Vec::new() };
+        let kind = builder.get(msh, 9, 1, 1).unwrap_or_default();
+        let trigger = builder.get(msh, 9, 1, 2).unwrap_or_default();
+
+        match (kind.as_str(), trigger.as_str()) {
+            ("ADT", "A01" | "A04" | "A08") => builder.adt(&trigger)?,
+            ("ORU", "R01") => builder.oru()?,
+            _ => return Err(anyhow::anyhow!(
+                "FHIR conversion supports ADT^A01, ADT^A04, ADT^A08 and ORU^R01, not {}^{}", kind, trigger
+            )),
+        }
+
+        let mut bundle = Map::new();
+        bundle.insert("resourceType".into(), json!("Bundle"));
+        if let Some(control_id) = builder.get(msh, 10, 1, 1) {
+            bundle.insert("identifier".into(), json!({ "value": control_id }));
+        }
+        bundle.insert("type".into(), json!("transaction"));
+        bundle.insert("entry".into(), Value::Array(builder.entries));
+        Ok(Value::Object(bundle))
+    }
+}
+
+fn table(defaults: &[(&str, &str)], overrides: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut table: HashMap<String, String> = defaults.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    table.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+    table
+}
+
+fn display<'a>(code: &str, displays: &'a [(&'a str, &'a str)]) -> Option<&'a str> {
+    displays.iter().chain(DISPLAYS).find(|(c, _)| *c == code).map(|(_, d)| *d)
+}
+
+struct Builder<'a> {
+    message: &'a Message,
+    mapper: &'a Mapper,
+    /// Name of the fullUrl UUIDs
+    source: String,
+    entries: Vec<Value>,
+}
+
+impl<'a> Builder<'a> {
+    /// Decoded, non-empty value of the first subcomponent
+    fn get(&self, segment: &Segment, field: usize, repetition: usize, component: usize) -> Option<String> {
+        let path = FieldPath { field, repetition, component, subcomponent: 1 };
+        segment.get(&path, &self.message.delimiters).filter(|v| !v.is_empty())
+    }
+
+    fn repetitions(segment: &Segment, field: usize) -> usize {
+        segment.field(field).map_or(0, |f| f.repetitions.len())
+    }
+
+    /// Reserve the next entry and return its fullUrl
+    fn reserve(&mut self) -> (usize, String) {
+        let index = self.entries.len();
+        let name = format!("{}\n{}", self.source, index);
+        self.entries.push(Value::Null);
+        (index, format!("urn:uuid:{}", Uuid::new_v3(&Uuid::NAMESPACE_OID, name.as_bytes())))
+    }
+
+    /// Fill a reserved entry. Resources with an identifier of a known system are conditional
+    /// updates on it; without a system the value alone could match another assigner's record.
+    fn fill(&mut self, (index, full_url): (usize, String), resource: Map<String, Value>) -> String {
+        let kind = resource["resourceType"].as_str().unwrap_or_default().to_string();
+        let identifier = resource.get("identifier").and_then(Value::as_array)
+            .and_then(|ids| ids.iter().find(|id| id["system"].is_string()));
+        let request = match identifier {
+            Some(id) if kind != "Observation" => json!({ "method": "PUT", "url": format!("{}?identifier={}", kind, search_token(id)) }),
+            _ => json!({ "method": "POST", "url": kind }),
+        };
+        self.entries[index] = json!({ "fullUrl": full_url, "resource": resource, "request": request });
+        full_url
+    }
+
+    fn add(&mut self, resource: Map<String, Value>) -> String {
+        let slot = self.reserve();
+        self.fill(slot, resource)
+    }
+
+    fn adt(&mut self, trigger: &str) -> anyhow::Result<()> {
+        let patient = self.patient()?;
+        if let Some(pv1) = self.message.segment("PV1") {
+            self.encounter(pv1, trigger, &patient);
+        }
+        Ok(())
+    }
+
+    fn oru(&mut self) -> anyhow::Result<()> {
+        let patient = self.patient()?;
+        let message = self.message;
+        let mut report: Option<Report> = None;
+        for segment in &message.segments {
+            match segment.name.as_str() {
+                "OBR" => {
+                    if let Some(done) = report.take() {
+                        self.report(done, &patient);
+                    }
+                    report = Some(Report { obr: segment, slot: self.reserve(), results: Vec::new(), statuses: Vec::new() });
+                },
+                "OBX" => {
+                    let obr = report.as_ref().map(|r| r.obr);
+                    let (reference, status) = self.observation(segment, obr, &patient);
+                    if let Some(report) = &mut report {
+                        report.results.push(reference);
+                        report.statuses.push(status);
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(done) = report {
+            self.report(done, &patient);
+        }
+        Ok(())
+    }
+
+    fn patient(&mut self) -> anyhow::Result<String> {
+        let pid = self.message.segment("PID")
+            .ok_or_else(|| anyhow::anyhow!("FHIR conversion needs a PID segment"))?;
+        let mut patient = resource("Patient");
+
+        let identifiers: Vec<Value> = (1..=Self::repetitions(pid, 3))
+            .filter_map(|r| self.identifier(pid, 3, r))
+            .collect();
+        insert_list(&mut patient, "identifier", identifiers);
+
+        let names: Vec<Value> = (1..=Self::repetitions(pid, 5))
+            .filter_map(|r| {
+                let mut name = Map::new();
+                insert_opt(&mut name, "family", self.get(pid, 5, r, 1).map(Value::from));
+                insert_list(&mut name, "given", [2, 3].iter().filter_map(|&c| self.get(pid, 5, r, c)).map(Value::from).collect());
+                insert_list(&mut name, "prefix", self.get(pid, 5, r, 5).into_iter().map(Value::from).collect());
+                insert_list(&mut name, "suffix", self.get(pid, 5, r, 4).into_iter().map(Value::from).collect());
+                (!name.is_empty()).then_some(Value::Object(name))
+            })
+            .collect();
+        insert_list(&mut patient, "name", names);
+
+        let telecom: Vec<Value> = [(13, "home"), (14, "work")].iter()
+            .flat_map(|&(field, usage)| (1..=Self::repetitions(pid, field)).map(move |r| (field, r, usage)))
+            .filter_map(|(field, r, usage)| self.telecom(pid, field, r, usage))
+            .collect();
+        insert_list(&mut patient, "telecom", telecom);
+
+        insert_opt(&mut patient, "gender", self.get(pid, 8, 1, 1).and_then(|g| self.mapper.gender.get(&g)).map(|g| json!(g)));
+        insert_opt(&mut patient, "birthDate", self.get(pid, 7, 1, 1).and_then(|ts| date_time(&ts, None)).map(Value::from));
+
+        let addresses: Vec<Value> = (1..=Self::repetitions(pid, 11))
+            .filter_map(|r| {
+                let mut address = Map::new();
+                insert_list(&mut address, "line", [1, 2].iter().filter_map(|&c| self.get(pid, 11, r, c)).map(Value::from).collect());
+                for (component, key) in [(3, "city"), (4, "state"), (5, "postalCode"), (6, "country")] {
+                    insert_opt(&mut address, key, self.get(pid, 11, r, component).map(Value::from));
+                }
+                (!address.is_empty()).then_some(Value::Object(address))
+            })
+            .collect();
+        insert_list(&mut patient, "address", addresses);
+
+        Ok(self.add(patient))
+    }
+
+    fn encounter(&mut self, pv1: &Segment, trigger: &str, patient: &str) {
+        let mut encounter = resource("Encounter");
+        insert_list(&mut encounter, "identifier", self.identifier(pv1, 19, 1).into_iter().collect());
+
+        let discharge = self.get(pv1, 45, 1, 1);
+        let status = match (&discharge, trigger) {
+            (Some(_), _) => "finished",
+            (None, "A04") => "arrived",
+            _ => "in-progress",
+        };
+        encounter.insert("status".into(), json!(status));
+
+        let patient_class = self.get(pv1, 2, 1, 1);
+        let class = match patient_class.as_ref().and_then(|c| self.mapper.encounter_class.get(c)) {
+            Some(code) => coding(ACT_CODE, code, display(code, &[])),
+            None => match patient_class {
+                Some(raw) => coding(V2_0004, &raw, None),
+                None => coding(NULL_FLAVOR, "UNK", Some("unknown")),
+            },
+        };
+        encounter.insert("class".into(), class);
+
+        if let Some(service) = self.get(pv1, 10, 1, 1) {
+            encounter.insert("serviceType".into(), json!({ "coding": [coding(V2_0069, &service, None)] }));
+        }
+        encounter.insert("subject".into(), json!({ "reference": patient }));
+
+        let participants: Vec<Value> = (1..=Self::repetitions(pv1, 7))
+            .filter_map(|r| {
+                let name = [6, 3, 4, 2, 5].iter().filter_map(|&c| self.get(pv1, 7, r, c)).collect::<Vec<_>>().join(" ");
+                let mut individual = Map::new();
+                insert_opt(&mut individual, "identifier", self.get(pv1, 7, r, 1).map(|id| json!({ "value": id })));
+                insert_opt(&mut individual, "display", (!name.is_empty()).then(|| json!(name)));
+                (!individual.is_empty()).then(|| json!({
+                    "type": [{ "coding": [coding(PARTICIPATION_TYPE, "ATND", Some("attender"))] }],
+                    "individual": individual,
+                }))
+            })
+            .collect();
+        insert_list(&mut encounter, "participant", participants);
+
+        let mut period = Map::new();
+        insert_opt(&mut period, "start", self.get(pv1, 44, 1, 1).and_then(|ts| self.date_time(&ts)).map(Value::from));
+        insert_opt(&mut period, "end", discharge.and_then(|ts| self.date_time(&ts)).map(Value::from));
+        if !period.is_empty() {
+            encounter.insert("period".into(), Value::Object(period));
+        }
+
+        let location = [1, 2, 3].iter().filter_map(|&c| self.get(pv1, 3, 1, c)).collect::<Vec<_>>().join(" ");
+        if !location.is_empty() {
+            encounter.insert("location".into(), json!([{ "location": { "display": location } }]));
+        }
+
+        self.add(encounter);
+    }
+
+    /// Returns the Observation's reference and status
+    fn observation(&mut self, obx: &Segment, obr: Option<&Segment>, patient: &str) -> (String, String) {
+        let mut observation = resource("Observation");
+        let status = self.get(obx, 11, 1, 1)
+            .and_then(|s| self.mapper.observation_status.get(&s).cloned())
+            .unwrap_or_else(|| "unknown".to_string());
+        observation.insert("status".into(), json!(status));
+        observation.insert("category".into(), json!([{ "coding": [coding(OBSERVATION_CATEGORY, "laboratory", Some("Laboratory"))] }]));
+        insert_opt(&mut observation, "code", self.codeable(obx, 3, 1));
+        observation.insert("subject".into(), json!({ "reference": patient }));
+
+        let effective = self.get(obx, 14, 1, 1).or_else(|| obr.and_then(|obr| self.get(obr, 7, 1, 1)));
+        insert_opt(&mut observation, "effectiveDateTime", effective.and_then(|ts| self.date_time(&ts)).map(Value::from));
+
+        let value_type = self.get(obx, 2, 1, 1).unwrap_or_default();
+        if let Some((key, value)) = self.observation_value(obx, &value_type) {
+            observation.insert(key.into(), value);
+        }
+
+        let interpretations: Vec<Value> = (1..=Self::repetitions(obx, 8))
+            .filter_map(|r| self.get(obx, 8, r, 1))
+            .map(|flag| match self.mapper.interpretation.get(&flag) {
+                Some(code) => json!({ "coding": [coding(INTERPRETATION, code, display(code, INTERPRETATION_DISPLAYS))] }),
+                None => json!({ "text": flag }),
+            })
+            .collect();
+        insert_list(&mut observation, "interpretation", interpretations);
+
+        if let Some(range) = self.get(obx, 7, 1, 1) {
+            let unit = self.get(obx, 6, 1, 1);
+            observation.insert("referenceRange".into(), json!([reference_range(&range, unit.as_deref())]));
+        }
+
+        (self.add(observation), status)
+    }
+
+    fn observation_value(&self, obx: &Segment, value_type: &str) -> Option<(&'static str, Value)> {
+        let first = self.get(obx, 5, 1, 1);
+        match value_type {
+            "NM" => {
+                let text = first?;
+                let Some(number) = number(&text) else {
+                    return Some(("valueString", json!(text)));
+                };
+                let mut quantity = Map::new();
+                quantity.insert("value".into(), number);
+                if let Some(unit) = self.get(obx, 6, 1, 1) {
+                    quantity.insert("unit".into(), json!(unit));
+                    if let Some(system) = self.get(obx, 6, 1, 3).and_then(|s| self.mapper.coding_systems.get(&s)) {
+                        quantity.insert("system".into(), json!(system));
+                        quantity.insert("code".into(), json!(unit));
+                    }
+                }
+                Some(("valueQuantity", Value::Object(quantity)))
+            },
+            "CE" | "CWE" => self.codeable(obx, 5, 1).map(|c| ("valueCodeableConcept", c)),
+            _ => {
+                let lines: Vec<String> = (1..=Self::repetitions(obx, 5))
+                    .filter_map(|r| self.get(obx, 5, r, 1))
+                    .collect();
+                (!lines.is_empty()).then(|| ("valueString", json!(lines.join("\n"))))
+            },
+        }
+    }
+
+    fn report(&mut self, report: Report<'a>, patient: &str) {
+        let obr = report.obr;
+        let mut resource = resource("DiagnosticReport");
+        let identifiers: Vec<Value> = [(3, "FILL"), (2, "PLAC")].iter()
+            .filter_map(|&(field, kind)| {
+                let mut identifier = self.identifier(obr, field, 1)?;
+                identifier["type"] = json!({ "coding": [coding(V2_0203, kind, None)] });
+                Some(identifier)
+            })
+            .collect();
+        insert_list(&mut resource, "identifier", identifiers);
+
+        let status = self.get(obr, 25, 1, 1)
+            .and_then(|s| self.mapper.report_status.get(&s).cloned())
+            .unwrap_or_else(|| {
+                let status = if report.statuses.is_empty() {
+                    "registered"
+                } else if report.statuses.iter().all(|s| s == "final" || s == "corrected") {
+                    "final"
+                } else {
+                    "partial"
+                };
+                status.to_string()
+            });
+        resource.insert("status".into(), json!(status));
+
+        let section = self.get(obr, 24, 1, 1).unwrap_or_else(|| "LAB".to_string());
+        resource.insert("category".into(), json!([{ "coding": [coding(V2_0074, &section, None)] }]));
+        insert_opt(&mut resource, "code", self.codeable(obr, 4, 1));
+        resource.insert("subject".into(), json!({ "reference": patient }));
+        insert_opt(&mut resource, "effectiveDateTime", self.get(obr, 7, 1, 1).and_then(|ts| self.date_time(&ts)).map(Value::from));
+        insert_list(&mut resource, "result", report.results.into_iter().map(|r| json!({ "reference": r })).collect());
+
+        self.fill(report.slot, resource);
+    }
+
+    /// CX / EI: value, system from the assigning authority, CX-5 identifier type
+    fn identifier(&self, segment: &Segment, field: usize, repetition: usize) -> Option<Value> {
+        let value = self.get(segment, field, repetition, 1)?;
+        let authority_component = if segment.name == "OBR" { 2 } else { 4 };
+        let authority = self.get(segment, field, repetition, authority_component);
+
+        let mut identifier = Map::new();
+        if let Some(kind) = self.get(segment, field, repetition, 5).filter(|_| segment.name != "OBR") {
+            identifier.insert("type".into(), json!({ "coding": [coding(V2_0203, &kind, None)] }));
+        }
+        let system = authority.as_ref().and_then(|a| self.mapper.identifier_systems.get(a));
+        insert_opt(&mut identifier, "system", system.map(|s| json!(s)));
+        identifier.insert("value".into(), json!(value));
+        if let (None, Some(authority)) = (system, authority) {
+            identifier.insert("assigner".into(), json!({ "display": authority }));
+        }
+        Some(Value::Object(identifier))
+    }
+
+    /// CE / CWE: the primary and alternate codings
+    fn codeable(&self, segment: &Segment, field: usize, repetition: usize) -> Option<Value> {
+        let codings: Vec<Value> = [(1, 2, 3), (4, 5, 6)].iter()
+            .filter_map(|&(code, text, system)| {
+                let code = self.get(segment, field, repetition, code)?;
+                let system = self.get(segment, field, repetition, system).and_then(|s| self.mapper.coding_systems.get(&s));
+                let mut coding = Map::new();
+                insert_opt(&mut coding, "system", system.map(|s| json!(s)));
+                coding.insert("code".into(), json!(code));
+                insert_opt(&mut coding, "display", self.get(segment, field, repetition, text).map(Value::from));
+                Some(Value::Object(coding))
+            })
+            .collect();
+        let text = self.get(segment, field, repetition, 2).or_else(|| self.get(segment, field, repetition, 5));
+
+        let mut concept = Map::new();
+        insert_list(&mut concept, "coding", codings);
+        insert_opt(&mut concept, "text", text.map(Value::from));
+        (!concept.is_empty()).then_some(Value::Object(concept))
+    }
+
+    /// XTN: the unformatted number (XTN-1), or area code and number, or the e-mail address
+    fn telecom(&self, segment: &Segment, field: usize, repetition: usize, usage: &str) -> Option<Value> {
+        if self.get(segment, field, repetition, 3).as_deref() == Some("Internet") {
+            let email = self.get(segment, field, repetition, 4).or_else(|| self.get(segment, field, repetition, 1))?;
+            return Some(json!({ "system": "email", "value": email, "use": usage }));
+        }
+        let number = self.get(segment, field, repetition, 1).or_else(|| {
+            let local = self.get(segment, field, repetition, 7)?;
+            Some(match self.get(segment, field, repetition, 6) {
+                Some(area) => format!("({}) {}", area, local),
+                None => local,
+            })
+        })?;
+        Some(json!({ "system": "phone", "value": number, "use": usage }))
+    }
+
+    fn date_time(&self, ts: &str) -> Option<String> {
+        date_time(ts, Some(self.mapper.timezone.as_deref()))
+    }
+}
+
+struct Report<'a> {
+    obr: &'a Segment,
+    slot: (usize, String),
+    results: Vec<String>,
+    statuses: Vec<String>,
+}
+
+fn resource(kind: &str) -> Map<String, Value> {
+    let mut resource = Map::new();
+    resource.insert("resourceType".into(), json!(kind));
+    resource
+}
+
+fn coding(system: &str, code: &str, display: Option<&str>) -> Value {
+    match display {
+        Some(display) => json!({ "system": system, "code": code, "display": display }),
+        None => json!({ "system": system, "code": code }),
+    }
+}
+
+fn insert_opt(map: &mut Map<String, Value>, key: &str, value: Option<Value>) {
+    if let Some(value) = value {
+        map.insert(key.into(), value);
+    }
+}
+
+fn insert_list(map: &mut Map<String, Value>, key: &str, values: Vec<Value>) {
+    if !values.is_empty() {
+        map.insert(key.into(), Value::Array(values));
+    }
+}
+
+/// Percent-encoded `system|value` for an identifier search
+fn search_token(identifier: &Value) -> String {
+    // Search parameter escaping, so a `|` inside either part is not read as the separator
+    let escape = |part: &str| part.replace('\\', "\\\\").replace('|', "\\|").replace(',', "\\,").replace('$', "\\$");
+    let token = format!(
+        "{}|{}",
+        escape(identifier["system"].as_str().unwrap_or_default()),
+        escape(identifier["value"].as_str().unwrap_or_default())
+    );
+    url::form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>().replace('+', "%20")
+}
+
+/// FHIR decimal from an NM value; integers stay integers
+fn number(text: &str) -> Option<Value> {
+    let text = text.trim();
+    if text.is_empty() || !text.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+')) {
+        return None;
+    }
+    if let Ok(n) = text.parse::<i64>() {
+        return Some(json!(n));
+    }
+    text.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number)
+}
+
+/// `low-high` (e.g. `3.5-5.0`) as a range; anything else as text
+fn reference_range(range: &str, unit: Option<&str>) -> Value {
+    let quantity = |n: Value| match unit {
+        Some(unit) => json!({ "value": n, "unit": unit }),
+        None => json!({ "value": n }),
+    };
+    let bounds = range.char_indices()
+        .filter(|(i, c)| *c == '-' && *i > 0)
+        .find_map(|(i, _)| Some((number(&range[..i])?, number(&range[i + 1..])?)));
+    match bounds {
+        Some((low, high)) => json!({ "low": quantity(low), "high": quantity(high), "text": range }),
+        None => json!({ "text": range }),
+    }
+}
+
+/// HL7 TS (`YYYY[MM[DD[HH[MM[SS[.S]]]]]][+/-ZZZZ]`) to a FHIR date or dateTime. `timezone` is
+/// `None` for date-only values and otherwise the offset used when the value has none; a time
+/// without any offset is dropped, as FHIR requires one.
+fn date_time(ts: &str, timezone: Option<Option<&str>>) -> Option<String> {
+    let ts = ts.trim();
+    let (stamp, offset) = match ts.find(['+', '-']) {
+        Some(i) => (&ts[..i], Some(&ts[i..])),
+        None => (ts, None),
+    };
+    let (digits, fraction) = match stamp.split_once('.') {
+        Some((digits, fraction)) => (digits, Some(fraction)),
+        None => (stamp, None),
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit()) || ![4, 6, 8, 10, 12, 14].contains(&digits.len())
+        || !fraction.is_none_or(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+
+    let mut date = digits[..4].to_string();
+    for range in [4..6, 6..8] {
+        if let Some(part) = digits.get(range) {
+            date.push('-');
+            date.push_str(part);
+        }
+    }
+    let offset = match offset {
+        Some(o) if o.len() == 5 && o[1..].chars().all(|c| c.is_ascii_digit()) => Some(format!("{}:{}", &o[..3], &o[3..])),
+        Some(_) => return None,
+        None => timezone.flatten().map(str::to_string),
+    };
+    let (Some(offset), true) = (offset.filter(|_| timezone.is_some()), digits.len() > 8) else {
+        return Some(date);
+    };
+
+    let mut time = format!("{}T{}:{}:{}", date, &digits[8..10], digits.get(10..12).unwrap_or("00"), digits.get(12..14).unwrap_or("00"));
+    if let Some(fraction) = fraction.filter(|_| digits.len() == 14) {
+        time.push('.');
+        time.push_str(fraction);
+    }
+    time.push_str(&offset);
+    Some(time)
+}
+
+fn is_offset(tz: &str) -> bool {
+    let bytes = tz.as_bytes();
+    tz == "Z" || (bytes.len() == 6 && matches!(bytes[0], b'+' | b'-') && bytes[3] == b':'
+        && [1, 2, 4, 5].iter().all(|&i| bytes[i].is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(message: &str, mappings: &FhirMappings) -> Value {
+        Mapper::new(mappings).unwrap().bundle(&Message::parse(message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_date_time() {
+        assert_eq!(date_time("19850315", None).as_deref(), Some("1985-03-15"));
+        assert_eq!(date_time("198503", None).as_deref(), Some("1985-03"));
+        assert_eq!(date_time("202312140800", Some(None)).as_deref(), Some("2023-12-14"));
+        assert_eq!(date_time("202312140800", Some(Some("-03:00"))).as_deref(), Some("2023-12-14T08:00:00-03:00"));
+        assert_eq!(date_time("20231214080512.25+0100", Some(None)).as_deref(), Some("2023-12-14T08:05:12.25+01:00"));
+        assert_eq!(date_time("20231214080512+0100", None).as_deref(), Some("2023-12-14"));
+        assert_eq!(date_time("2023121", None), None);
+        assert_eq!(date_time("20231214+01", Some(None)), None);
+    }
+
+    #[test]
+    fn test_mapping_overrides() {
+        let mappings = FhirMappings {
+            gender: HashMap::from([("F".to_string(), "other".to_string())]),
+            encounter_class: HashMap::from([("I".to_string(), "ACUTE".to_string())]),
+            identifier_systems: HashMap::from([("HOSP".to_string(), "urn:oid:1.2.3".to_string())]),
+            timezone: Some("-03:00".to_string()),
+            ..Default::default()
+        };
+        // PV1-2, PV1-19 and PV1-44
+        let adt = format!(
+            "MSH|^~\\&|HIS|HOSP|||202312140800||ADT^A04|M1|P|2.5\rPID|||123^^^HOSP^MR||DOE^JANE||19850315|F\rPV1||I{}V9^^^HOSP{}202312140800",
+            "|".repeat(17), "|".repeat(25)
+        );
+        let bundle = bundle(&adt, &mappings);
+        let patient = &bundle["entry"][0];
+        assert_eq!(patient["resource"]["gender"], "other");
+        assert_eq!(patient["resource"]["identifier"][0]["system"], "urn:oid:1.2.3");
+        assert_eq!(patient["request"]["url"], "Patient?identifier=urn%3Aoid%3A1.2.3%7C123");
+
+        let encounter = &bundle["entry"][1]["resource"];
+        assert_eq!(encounter["status"], "arrived");
+        assert_eq!(encounter["class"], json!({ "system": ACT_CODE, "code": "ACUTE" }));
+        assert_eq!(encounter["period"]["start"], "2023-12-14T08:00:00-03:00");
+        assert_eq!(encounter["subject"]["reference"], patient["fullUrl"]);
+        assert_eq!(bundle["entry"][1]["request"]["url"], "Encounter?identifier=urn%3Aoid%3A1.2.3%7CV9");
+    }
+
+    #[test]
+    fn test_conditional_update_needs_identifier_system() {
+        // PID-3 without a mapped assigning authority: the value alone is not a safe match
+        let adt = "MSH|^~\\&|HIS|HOSP|||1||ADT^A01|M1|P|2.5\rPID|||123^^^HOSP^MR";
+        let unmapped = bundle(adt, &FhirMappings::default());
+        assert_eq!(unmapped["entry"][0]["request"], json!({ "method": "POST", "url": "Patient" }));
+
+        let mappings = FhirMappings {
+            identifier_systems: HashMap::from([("HOSP".to_string(), "http://hosp.example/mrn".to_string())]),
+            ..Default::default()
+        };
+        let adt = "MSH|^~\\&|HIS|HOSP|||1||ADT^A01|M1|P|2.5\rPID|||A\\T\\B #\\F\\1^^^HOSP^MR";
+        let mapped = bundle(adt, &mappings);
+        assert_eq!(
+            mapped["entry"][0]["request"]["url"],
+            "Patient?identifier=http%3A%2F%2Fhosp.example%2Fmrn%7CA%26B%20%23%5C%7C1"
+        );
+    }
+
+    #[test]
+    fn test_invalid_mappings_and_messages() {
+        let invalid = FhirMappings { gender: HashMap::from([("F".to_string(), "woman".to_string())]), ..Default::default() };
+        assert!(Mapper::new(&invalid).is_err());
+        let invalid = FhirMappings { timezone: Some("BRT".to_string()), ..Default::default() };
+        assert!(Mapper::new(&invalid).is_err());
+
+        let mapper = Mapper::new(&FhirMappings::default()).unwrap();
+        let err = mapper.bundle(&Message::parse("MSH|^~\\&|A|B|||1||ADT^A03|1|P|2.3\rPID|1").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not ADT^A03"), "{}", err);
+        assert!(mapper.bundle(&Message::parse("MSH|^~\\&|A|B|||1||ORU^R01|1|P|2.3").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_observation_values() {
+        // OBR-25 is P
+        let oru = format!(
+            "MSH|^~\\&|LIS|LAB|||1||ORU^R01|M2|P|2.5\rPID|||1\rOBR|1||F1^LAB|GLU^Glucose^LN{}P\r\
+OBX|1|NM|2345-7^Glucose^LN||5.4|mmol/L^^UCUM|3.9-6.1|N|||F\rOBX|2|ST|NOTE||line 1~line \\T\\ 2||||||P\rOBX|3|CE|ORG||STA^Staph^LOCAL||||||C",
+            "|".repeat(21)
+        );
+        let bundle = bundle(&oru, &FhirMappings::default());
+        let entries = bundle["entry"].as_array().unwrap();
+        let kinds: Vec<&str> = entries.iter().map(|e| e["resource"]["resourceType"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["Patient", "DiagnosticReport", "Observation", "Observation", "Observation"]);
+
+        let report = &entries[1]["resource"];
+        assert_eq!(report["status"], "preliminary");
+        assert_eq!(report["identifier"][0]["assigner"]["display"], "LAB");
+        assert_eq!(report["result"].as_array().unwrap().len(), 3);
+        assert_eq!(report["result"][2]["reference"], entries[4]["fullUrl"]);
+
+        let glucose = &entries[2]["resource"];
+        assert_eq!(glucose["code"]["coding"][0]["system"], "http://loinc.org");
+        assert_eq!(glucose["valueQuantity"], json!({ "value": 5.4, "unit": "mmol/L", "system": "http://unitsofmeasure.org", "code": "mmol/L" }));
+        assert_eq!(glucose["referenceRange"][0]["low"]["value"], 3.9);
+        assert_eq!(glucose["interpretation"][0]["coding"][0]["code"], "N");
+        assert_eq!(entries[3]["resource"]["valueString"], "line 1\nline & 2");
+        assert_eq!(entries[3]["resource"]["status"], "preliminary");
+        assert_eq!(entries[4]["resource"]["valueCodeableConcept"]["coding"][0]["code"], "STA");
+        assert_eq!(entries[4]["resource"]["status"], "corrected");
+    }
+}
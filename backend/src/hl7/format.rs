@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::storage::models::FhirMappings;
+use super::{fhir, Message};
+
+/// Encodings the `hl7_parser` processor converts between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Pipe-delimited HL7 v2 (`hl7v2`, `er7`)
+    Er7,
+    /// v2.xml-style XML, see `xml`
+    Xml,
+    /// Ordered, component-level JSON, see `json`
+    Json,
+    /// Segments keyed by name with raw field strings (`Message::to_json_map`); output only.
+    /// This is what `json` meant before the structured model existed, so it keeps that name.
+    JsonMap,
+    /// FHIR R4 transaction Bundle, see `fhir`; output only
+    FhirR4,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hl7v2" | "hl7" | "er7" => Ok(Self::Er7),
+            "xml" => Ok(Self::Xml),
+            "hl7_json" => Ok(Self::Json),
+            // `hl7v3` was offered by older editors but never converted; those channels got the segment map
+            "json" | "json_map" | "hl7v3" => Ok(Self::JsonMap),
+            "fhir_r4" | "fhir" => Ok(Self::FhirR4),
+            _ => Err(anyhow::anyhow!("Unknown HL7 format '{}' (expected hl7v2, xml, hl7_json, json or fhir_r4)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Er7 => "hl7v2",
+            Self::Xml => "xml",
+            Self::Json => "hl7_json",
+            Self::JsonMap => "json",
+            Self::FhirR4 => "fhir_r4",
+        })
+    }
+}
+
+/// A validated input/output format pair
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub input: Format,
+    pub output: Format,
+    fhir: Box<fhir::Mapper>,
+}
+
+impl Conversion {
+    pub fn new(input: &str, output: &str) -> anyhow::Result<Self> {
+        let input: Format = input.parse().map_err(|e| anyhow::anyhow!("inputFormat: {}", e))?;
+        let output: Format = output.parse().map_err(|e| anyhow::anyhow!("outputFormat: {}", e))?;
+        if matches!(input, Format::JsonMap | Format::FhirR4) {
+            return Err(anyhow::anyhow!("{} can only be an outputFormat", input));
+        }
+        if input == output {
+            return Err(anyhow::anyhow!("inputFormat and outputFormat are both {}; nothing to convert", input));
+        }
+        Ok(Self { input, output, fhir: Box::new(fhir::Mapper::new(&FhirMappings::default())?) })
+    }
+
+    /// Code tables of the `fhir_r4` output
+    pub fn with_fhir_mappings(mut self, mappings: &FhirMappings) -> anyhow::Result<Self> {
+        self.fhir = Box::new(fhir::Mapper::new(mappings)?);
+        Ok(self)
+    }
+
+    pub fn convert(&self, content: &str) -> anyhow::Result<String> {
+        let message = match self.input {
+            Format::Er7 => Message::parse(content)?,
+            Format::Xml => super::xml::from_xml(content)?,
+            Format::Json => {
+                let value: serde_json::Value = serde_json::from_str(content)
+                    .map_err(|e| anyhow::anyhow!("Invalid HL7 JSON: {}", e))?;
+                super::json::from_json(&value)?
+            }
+            Format::JsonMap | Format::FhirR4 => return Err(anyhow::anyhow!("{} is not an input format", self.input)),
+        };
+        Ok(match self.output {
+            Format::Er7 => message.encode(),
+            Format::Xml => super::xml::to_xml(&message),
+            Format::Json => super::json::to_json(&message).to_string(),
+            Format::JsonMap => message.to_json_map().to_string(),
+            Format::FhirR4 => self.fhir.bundle(&message)?.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADT: &str = "MSH|^~\\&|HIS|HOSP|||202401011200||ADT^A01|MSG1|P|2.5\rPID|1||123^^^HOSP^MR~456||DOE^JOHN";
+
+    #[test]
+    fn test_conversions_round_trip() {
+        let to_xml = Conversion::new("hl7v2", "xml").unwrap().convert(ADT).unwrap();
+        let xml_to_json = Conversion::new("xml", "hl7_json").unwrap().convert(&to_xml).unwrap();
+        let json_to_er7 = Conversion::new("hl7_json", "er7").unwrap().convert(&xml_to_json).unwrap();
+        assert_eq!(json_to_er7, ADT);
+
+        let map = Conversion::new("xml", "json_map").unwrap().convert(&to_xml).unwrap();
+        let map: serde_json::Value = serde_json::from_str(&map).unwrap();
+        assert_eq!(map["PID"][4], "DOE^JOHN");
+    }
+
+    #[test]
+    fn test_formats_saved_by_older_editors() {
+        // `json` keeps producing the segment map that Lua scripts read as `data.PID[5]`
+        for output in ["json", "hl7v3"] {
+            let map = Conversion::new("hl7v2", output).unwrap().convert(ADT).unwrap();
+            let map: serde_json::Value = serde_json::from_str(&map).unwrap();
+            assert_eq!(map["PID"][4], "DOE^JOHN", "{}", output);
+        }
+        assert_eq!(Conversion::new("hl7v2", "fhir").unwrap().output, Format::FhirR4);
+    }
+
+    #[test]
+    fn test_invalid_combinations() {
+        for (input, output) in [("hl7v2", "hl7"), ("hl7_json", "HL7_JSON"), ("json", "hl7v2"), ("fhir_r4", "json"), ("csv", "json"), ("xml", "")] {
+            assert!(Conversion::new(input, output).is_err(), "{} -> {}", input, output);
+        }
+        assert!(Conversion::new("hl7v2", "json").unwrap().convert("<HL7Message/>").is_err());
+        assert!(Conversion::new("hl7_json", "xml").unwrap().convert("not json").is_err());
+    }
+}
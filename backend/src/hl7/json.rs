@@ -0,0 +1,137 @@
+//! Structured JSON encoding that keeps segment order and the full field structure:
+//!
+//! ```json
+//! {"segments": [
+//!   {"name": "PID", "fields": [[["1"]], [[""]], [["123", "", "", "HOSP"], ["456"]]]}
+//! ]}
+//! ```
+//!
+//! A field is an array of repetitions, a repetition an array of components and a component
+//! either a string or, when it has subcomponents, an array of strings. Values keep their HL7
+//! escape sequences, so ER7 -> JSON -> ER7 is lossless.
+
+use serde_json::{json, Value};
+use super::{Component, Delimiters, Field, Message, Repetition, Segment};
+
+pub fn to_json(message: &Message) -> Value {
+    let segments: Vec<Value> = message.segments.iter()
+        .map(|segment| json!({
+            "name": segment.name,
+            "fields": segment.fields.iter().map(field_to_json).collect::<Vec<_>>(),
+        }))
+        .collect();
+    json!({ "segments": segments })
+}
+
+fn field_to_json(field: &Field) -> Value {
+    Value::Array(field.repetitions.iter()
+        .map(|repetition| Value::Array(repetition.components.iter()
+            .map(|component| match component.subcomponents.as_slice() {
+                [value] => Value::String(value.clone()),
+                subcomponents => Value::Array(subcomponents.iter().cloned().map(Value::String).collect()),
+            })
+            .collect()))
+        .collect())
+}
+
+pub fn from_json(value: &Value) -> anyhow::Result<Message> {
+    let invalid = |what: &str| anyhow::anyhow!("Invalid HL7 JSON: {}", what);
+    let items = value.get("segments")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("expected an object with a \"segments\" array"))?;
+    if items.len() > super::MAX_SEGMENTS {
+        return Err(anyhow::anyhow!("HL7 message has more than {} segments", super::MAX_SEGMENTS));
+    }
+
+    let mut segments = Vec::with_capacity(items.len());
+    for item in items {
+        let name = item.get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("every segment needs a \"name\""))?;
+        let fields = match item.get("fields") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(fields)) => fields.iter()
+                .map(|f| field_from_json(f).ok_or_else(|| invalid(&format!("malformed field in segment {}", name))))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            Some(_) => return Err(invalid("\"fields\" must be an array")),
+        };
+        if fields.len() > super::MAX_FIELDS {
+            return Err(anyhow::anyhow!("HL7 segment {} has more than {} fields", name, super::MAX_FIELDS));
+        }
+        segments.push(Segment { name: name.to_string(), fields });
+    }
+
+    let header = segments.first()
+        .filter(|s| s.is_header())
+        .ok_or_else(|| invalid("the first segment must be MSH"))?;
+    let field = header.field(1).and_then(|f| f.get(1, 1, 1)).and_then(|s| s.chars().next()).unwrap_or('|');
+    let encoding = header.field(2).and_then(|f| f.get(1, 1, 1)).map(str::to_string)
+        .unwrap_or_else(|| Delimiters::default().encoding_characters());
+    let delimiters = Delimiters::from_header(field, &encoding)?;
+    for segment in segments.iter_mut().filter(|s| s.is_header()) {
+        segment.set_header(&delimiters);
+    }
+
+    Ok(Message { delimiters, segments, line_ending: "\r".to_string(), trailing_line_ending: false })
+}
+
+/// A plain string is accepted as a field with a single value
+fn field_from_json(value: &Value) -> Option<Field> {
+    let repetitions = match value {
+        Value::String(s) => vec![Value::Array(vec![Value::String(s.clone())])],
+        Value::Array(repetitions) if !repetitions.is_empty() => repetitions.clone(),
+        _ => return None,
+    };
+    let repetitions = repetitions.iter()
+        .map(|repetition| {
+            let components = repetition.as_array().filter(|c| !c.is_empty())?;
+            let components = components.iter()
+                .map(|component| match component {
+                    Value::String(s) => Some(Component { subcomponents: vec![s.clone()] }),
+                    Value::Array(subs) if !subs.is_empty() => Some(Component {
+                        subcomponents: subs.iter().map(|s| s.as_str().map(str::to_string)).collect::<Option<_>>()?,
+                    }),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(Repetition { components })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(Field { repetitions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORU: &str = "MSH|^~\\&|LIS|LAB|||202401011200||ORU^R01|MSG1|P|2.5\rPID|1||123^^^HOSP~456&X||DOE^JOHN\rOBX|1|ST|NOTE||a\\F\\b\rNTE\rOBX|2";
+
+    #[test]
+    fn test_json_round_trip() {
+        let message = Message::parse(ORU).unwrap();
+        let json = to_json(&message);
+        let segments = json["segments"].as_array().unwrap();
+        let names: Vec<&str> = segments.iter().map(|s| s["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["MSH", "PID", "OBX", "NTE", "OBX"]);
+        assert_eq!(segments[0]["fields"][1], json!([["^~\\&"]]));
+        assert_eq!(segments[1]["fields"][2], json!([["123", "", "", "HOSP"], [["456", "X"]]]));
+        assert_eq!(segments[2]["fields"][4], json!([["a\\F\\b"]]));
+        assert_eq!(segments[3]["fields"], json!([]));
+
+        assert_eq!(from_json(&json).unwrap().encode(), ORU);
+    }
+
+    #[test]
+    fn test_from_json_shorthand_and_errors() {
+        let json = json!({"segments": [
+            {"name": "MSH", "fields": ["|", "^~\\&", "A"]},
+            {"name": "PID", "fields": ["1", [[""]], [["ID", ["S1", "S2"]]]]},
+        ]});
+        assert_eq!(from_json(&json).unwrap().encode(), "MSH|^~\\&|A\rPID|1||ID^S1&S2");
+
+        assert!(from_json(&json!({"MSH": []})).is_err());
+        assert!(from_json(&json!({"segments": [{"name": "PID"}]})).is_err());
+        assert!(from_json(&json!({"segments": [{"name": "MSH", "fields": [1]}]})).is_err());
+        assert!(from_json(&json!({"segments": [{"name": "MSH", "fields": [[[]]]}]})).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+//! HL7 v2 messages in ER7 (pipe) encoding, with conversions to and from XML (`xml`) and
+//! structured JSON (`json`), and to FHIR R4 (`fhir`).
+//!
+//! Values are kept exactly as they appear in the message (escape sequences included), so
+//! `Message::parse(text)?.encode() == text` for any well-formed message. Decoded text is
+//! available through `Delimiters::unescape`.
+
+pub mod escape;
+pub mod fhir;
+pub mod format;
+pub mod json;
+pub mod path;
+pub mod xml;
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+pub use format::{Conversion, Format};
+pub use path::{FieldPath, Path};
+
+/// Upper bounds on what a single message may contain
+const MAX_SEGMENTS: usize = 1000;
+const MAX_FIELDS: usize = 100;
+
+/// Segments that carry the encoding characters in their first two fields
+const HEADER_SEGMENTS: [&str; 3] = ["MSH", "BHS", "FHS"];
+
+/// Separators declared by MSH-1 (field) and MSH-2 (component, repetition, escape,
+/// subcomponent and, since v2.7, truncation)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: Option<char>,
+    pub subcomponent: Option<char>,
+    pub truncation: Option<char>,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self {
+            field: '|',
+            component: '^',
+            repetition: '~',
+            escape: Some('\\'),
+            subcomponent: Some('&'),
+            truncation: None,
+        }
+    }
+}
+
+impl Delimiters {
+    fn from_header(field: char, encoding: &str) -> anyhow::Result<Self> {
+        let chars: Vec<char> = encoding.chars().collect();
+        if chars.len() < 2 || chars.len() > 5 {
+            return Err(anyhow::anyhow!("Invalid HL7 encoding characters '{}'", encoding));
+        }
+        let delimiters = Self {
+            field,
+            component: chars[0],
+            repetition: chars[1],
+            escape: chars.get(2).copied(),
+            subcomponent: chars.get(3).copied(),
+            truncation: chars.get(4).copied(),
+        };
+
+        let mut all = vec![field];
+        all.extend(&chars);
+        for (i, c) in all.iter().enumerate() {
+            if c.is_alphanumeric() || c.is_whitespace() || all[..i].contains(c) {
+                return Err(anyhow::anyhow!("Invalid HL7 delimiters '{}{}'", field, encoding));
+            }
+        }
+        Ok(delimiters)
+    }
+
+    /// The MSH-2 value for these delimiters
+    pub fn encoding_characters(&self) -> String {
+        let mut encoding = String::from_iter([self.component, self.repetition]);
+        encoding.extend(self.escape);
+        encoding.extend(self.subcomponent);
+        encoding.extend(self.truncation);
+        encoding
+    }
+
+    /// Decode escape sequences in a raw value
+    pub fn unescape(&self, raw: &str) -> String {
+        escape::unescape(raw, self)
+    }
+
+    /// Escape plain text so it can be stored as a single value
+    pub fn escape(&self, text: &str) -> String {
+        escape::escape(text, self)
+    }
+}
+
+/// A parsed HL7 v2 message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub delimiters: Delimiters,
+    pub segments: Vec<Segment>,
+    /// Segment terminator found in the source (`\r`, `\n` or `\r\n`)
+    pub line_ending: String,
+    /// Whether the source ended with a segment terminator
+    pub trailing_line_ending: bool,
+}
+
+/// One segment. `fields[0]` is field 1 (for MSH: MSH-1, the field separator, and MSH-2, the
+/// encoding characters, are stored as-is).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+/// A field, split into repetitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub repetitions: Vec<Repetition>,
+}
+
+/// One repetition of a field, split into components
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repetition {
+    pub components: Vec<Component>,
+}
+
+/// A component, split into raw (still escaped) subcomponents
+#[derive(Debug, Clone, PartialEq)]
+pub struct Component {
+    pub subcomponents: Vec<String>,
+}
+
+impl Message {
+    /// Parse an ER7 message. Segments may be separated by `\r`, `\n` or `\r\n`; blank lines are
+    /// ignored.
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let content = content.trim_start_matches(['\r', '\n']);
+        let header = content.get(..3).unwrap_or_default();
+        if !HEADER_SEGMENTS.contains(&header) {
+            return Err(anyhow::anyhow!("Not an HL7 v2 message: expected an MSH segment"));
+        }
+        let field_separator = content[3..].chars().next()
+            .ok_or_else(|| anyhow::anyhow!("HL7 message has no field separator (MSH-1)"))?;
+        let encoding = content[3 + field_separator.len_utf8()..]
+            .split([field_separator, '\r', '\n'])
+            .next()
+            .unwrap_or_default();
+        let delimiters = Delimiters::from_header(field_separator, encoding)?;
+
+        let line_ending = match content.find(['\r', '\n']) {
+            Some(i) if content[i..].starts_with("\r\n") => "\r\n",
+            Some(i) if content[i..].starts_with('\n') => "\n",
+            _ => "\r",
+        };
+        let trailing_line_ending = content.ends_with(['\r', '\n']);
+
+        let mut segments = Vec::new();
+        for line in content.split(['\r', '\n']).filter(|l| !l.is_empty()) {
+            if segments.len() == MAX_SEGMENTS {
+                return Err(anyhow::anyhow!("HL7 message has more than {} segments", MAX_SEGMENTS));
+            }
+            segments.push(Segment::parse(line, &delimiters)?);
+        }
+
+        Ok(Self {
+            delimiters,
+            segments,
+            line_ending: line_ending.to_string(),
+            trailing_line_ending,
+        })
+    }
+
+    /// Serialize back to ER7
+    pub fn encode(&self) -> String {
+        let mut out = self.segments.iter()
+            .map(|s| s.encode(&self.delimiters))
+            .collect::<Vec<_>>()
+            .join(&self.line_ending);
+        if self.trailing_line_ending {
+            out.push_str(&self.line_ending);
+        }
+        out
+    }
+
+    /// All segments with this name, in message order
+    pub fn segments_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Segment> + 'a {
+        self.segments.iter().filter(move |s| s.name == name)
+    }
+
+    /// First segment with this name
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.name == name)
+    }
+
+    /// Decoded value at a terser-style path (`PID-5-1`, `OBX(2)-5`); `None` when the message has
+    /// no such value
+    pub fn get(&self, path: &str) -> anyhow::Result<Option<String>> {
+        let path = Path::parse(path)?;
+        let value = self.segments_named(&path.segment)
+            .nth(path.occurrence - 1)
+            .and_then(|segment| segment.get(&path.field, &self.delimiters));
+        Ok(value)
+    }
+
+    /// Set the value at a terser-style path, escaping it and adding empty fields, repetitions or
+    /// components as needed. The segment itself must exist.
+    pub fn set(&mut self, path: &str, value: &str) -> anyhow::Result<()> {
+        let parsed = Path::parse(path)?;
+        let delimiters = self.delimiters.clone();
+        let segment = self.segments.iter_mut()
+            .filter(|s| s.name == parsed.segment)
+            .nth(parsed.occurrence - 1)
+            .ok_or_else(|| anyhow::anyhow!("Cannot set {}: segment {}({}) not found", path, parsed.segment, parsed.occurrence))?;
+        segment.set(&parsed.field, value, &delimiters)
+    }
+
+    /// Number of segments with this name
+    pub fn count(&self, name: &str) -> usize {
+        self.segments_named(name).count()
+    }
+
+    /// Parse one ER7 segment (e.g. `NTE|1||text`) with this message's delimiters and insert it at
+    /// `position` (1-based, among all segments) or append it
+    pub fn add_segment(&mut self, text: &str, position: Option<usize>) -> anyhow::Result<()> {
+        if text.contains(['\r', '\n']) {
+            return Err(anyhow::anyhow!("A segment cannot contain line breaks"));
+        }
+        let segment = Segment::parse(text, &self.delimiters)?;
+        if segment.name.len() != 3 || segment.is_header() {
+            return Err(anyhow::anyhow!("Invalid segment '{}'", segment.name));
+        }
+        if self.segments.len() == MAX_SEGMENTS {
+            return Err(anyhow::anyhow!("HL7 message has more than {} segments", MAX_SEGMENTS));
+        }
+
+        match position {
+            Some(p) if p == 0 || p > self.segments.len() + 1 => {
+                Err(anyhow::anyhow!("Segment position {} is out of range (1-{})", p, self.segments.len() + 1))
+            },
+            Some(p) => {
+                self.segments.insert(p - 1, segment);
+                Ok(())
+            },
+            None => {
+                self.segments.push(segment);
+                Ok(())
+            },
+        }
+    }
+
+    /// Remove the `occurrence`-th (1-based) segment with this name. Returns whether one was removed.
+    pub fn remove_segment(&mut self, name: &str, occurrence: usize) -> bool {
+        let index = self.segments.iter()
+            .enumerate()
+            .filter(|(_, s)| s.name == name)
+            .nth(occurrence.saturating_sub(1))
+            .map(|(i, _)| i);
+        match index {
+            Some(i) if occurrence > 0 => {
+                self.segments.remove(i);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Segments keyed by name, each an array of raw field values where index 0 is field 1.
+    /// A segment that occurs more than once becomes an array of those arrays, in message order.
+    pub fn to_json_map(&self) -> Value {
+        let mut occurrences: Vec<(&str, Vec<Value>)> = Vec::new();
+        for segment in &self.segments {
+            let fields = Value::Array(segment.fields.iter()
+                .map(|f| Value::String(f.encode(&self.delimiters)))
+                .collect());
+            match occurrences.iter_mut().find(|(name, _)| *name == segment.name) {
+                Some((_, list)) => list.push(fields),
+                None => occurrences.push((&segment.name, vec![fields])),
+            }
+        }
+
+        let mut map = Map::new();
+        for (name, mut list) in occurrences {
+            let value = if list.len() == 1 { list.remove(0) } else { Value::Array(list) };
+            map.insert(name.to_string(), value);
+        }
+        Value::Object(map)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+impl Segment {
+    fn parse(line: &str, delimiters: &Delimiters) -> anyhow::Result<Self> {
+        let mut parts = line.split(delimiters.field);
+        let name = parts.next().unwrap_or_default().to_string();
+
+        let mut fields = Vec::new();
+        if HEADER_SEGMENTS.contains(&name.as_str()) {
+            // MSH-1 is the separator itself and MSH-2 holds the (unsplittable) encoding characters
+            fields.push(Field::raw(delimiters.field.to_string()));
+            fields.push(Field::raw(parts.next().unwrap_or_default().to_string()));
+        }
+        for part in parts {
+            if fields.len() == MAX_FIELDS {
+                return Err(anyhow::anyhow!("HL7 segment {} has more than {} fields", name, MAX_FIELDS));
+            }
+            fields.push(Field::parse(part, delimiters));
+        }
+        Ok(Self { name, fields })
+    }
+
+    fn is_header(&self) -> bool {
+        HEADER_SEGMENTS.contains(&self.name.as_str())
+    }
+
+    /// Make fields 1 and 2 of a header segment hold exactly these delimiters
+    fn set_header(&mut self, delimiters: &Delimiters) {
+        if self.fields.len() < 2 {
+            self.fields.resize_with(2, Field::empty);
+        }
+        self.fields[0] = Field::raw(delimiters.field.to_string());
+        self.fields[1] = Field::raw(delimiters.encoding_characters());
+    }
+
+    /// Field `n` (1-based, as in `PID-3`)
+    pub fn field(&self, n: usize) -> Option<&Field> {
+        self.fields.get(n.checked_sub(1)?)
+    }
+
+    /// Decoded value at `path`. MSH-1 and MSH-2 are returned as they are.
+    pub fn get(&self, path: &FieldPath, delimiters: &Delimiters) -> Option<String> {
+        let raw = self.field(path.field)?.get(path.repetition, path.component, path.subcomponent)?;
+        if self.is_header() && path.field <= 2 {
+            Some(raw.to_string())
+        } else {
+            Some(delimiters.unescape(raw))
+        }
+    }
+
+    /// Store `value` (escaped) at `path`, growing the segment as needed
+    pub fn set(&mut self, path: &FieldPath, value: &str, delimiters: &Delimiters) -> anyhow::Result<()> {
+        if self.is_header() && path.field <= 2 {
+            return Err(anyhow::anyhow!("{}-1 and {}-2 hold the delimiters and cannot be set", self.name, self.name));
+        }
+        if path.field > MAX_FIELDS {
+            return Err(anyhow::anyhow!("HL7 segment {} has more than {} fields", self.name, MAX_FIELDS));
+        }
+
+        if self.fields.len() < path.field {
+            self.fields.resize_with(path.field, Field::empty);
+        }
+        let field = &mut self.fields[path.field - 1];
+        if field.repetitions.len() < path.repetition {
+            field.repetitions.resize_with(path.repetition, Repetition::empty);
+        }
+        let repetition = &mut field.repetitions[path.repetition - 1];
+        if repetition.components.len() < path.component {
+            repetition.components.resize_with(path.component, Component::empty);
+        }
+        let component = &mut repetition.components[path.component - 1];
+        if path.subcomponent > 1 && delimiters.subcomponent.is_none() {
+            return Err(anyhow::anyhow!("The message declares no subcomponent separator"));
+        }
+        if component.subcomponents.len() < path.subcomponent {
+            component.subcomponents.resize(path.subcomponent, String::new());
+        }
+        component.subcomponents[path.subcomponent - 1] = delimiters.escape(value);
+        Ok(())
+    }
+
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        let mut out = self.name.clone();
+        // MSH-1 is written by the separator that follows the name
+        let skip = if self.is_header() { 1 } else { 0 };
+        for field in self.fields.iter().skip(skip) {
+            out.push(delimiters.field);
+            out.push_str(&field.encode(delimiters));
+        }
+        out
+    }
+}
+
+impl Field {
+    fn empty() -> Self {
+        Self { repetitions: vec![Repetition::empty()] }
+    }
+
+    /// A field holding one raw value that is never split (MSH-1, MSH-2)
+    fn raw(value: String) -> Self {
+        Self { repetitions: vec![Repetition { components: vec![Component { subcomponents: vec![value] }] }] }
+    }
+
+    fn parse(text: &str, delimiters: &Delimiters) -> Self {
+        let repetitions = text.split(delimiters.repetition)
+            .map(|rep| Repetition {
+                components: rep.split(delimiters.component)
+                    .map(|comp| Component {
+                        subcomponents: match delimiters.subcomponent {
+                            Some(sub) => comp.split(sub).map(str::to_string).collect(),
+                            None => vec![comp.to_string()],
+                        },
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { repetitions }
+    }
+
+    /// Raw subcomponent value; positions are 1-based and missing positions are `None`
+    pub fn get(&self, repetition: usize, component: usize, subcomponent: usize) -> Option<&str> {
+        self.repetitions.get(repetition.checked_sub(1)?)?
+            .components.get(component.checked_sub(1)?)?
+            .subcomponents.get(subcomponent.checked_sub(1)?)
+            .map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.repetitions.iter().all(|r| r.components.iter().all(|c| c.subcomponents.iter().all(String::is_empty)))
+    }
+
+    pub fn encode(&self, delimiters: &Delimiters) -> String {
+        let sub = delimiters.subcomponent.map(String::from).unwrap_or_default();
+        self.repetitions.iter()
+            .map(|rep| rep.components.iter()
+                .map(|comp| comp.subcomponents.join(&sub))
+                .collect::<Vec<_>>()
+                .join(&delimiters.component.to_string()))
+            .collect::<Vec<_>>()
+            .join(&delimiters.repetition.to_string())
+    }
+}
+
+impl Repetition {
+    fn empty() -> Self {
+        Self { components: vec![Component::empty()] }
+    }
+}
+
+impl Component {
+    fn empty() -> Self {
+        Self { subcomponents: vec![String::new()] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORU: &str = "MSH|^~\\&|LIS|LAB|EHR|HOSP|202401011200||ORU^R01|MSG00001|P|2.5.1\r\
+PID|1||12345^^^HOSP^MR~67890^^^NATL^NI||DOE^JOHN^Q||19800101|M\r\
+OBR|1|ORD1||CBC^Complete Blood Count^L\r\
+OBX|1|NM|WBC^Leukocytes^L||7.5|10*3/uL^^UCUM|4.5-11.0|N|||F\r\
+NTE|1||First note with \\F\\ and \\S\\ escaped\r\
+OBX|2|ST|COMMENT||Result\\T\\value&sub|||||F\r\
+NTE|1||Second note\r";
+
+    #[test]
+    fn test_round_trip_is_lossless() {
+        for text in [
+            ORU,
+            "MSH|^~\\&|A|B\nPID|1||X\n",
+            "MSH|^~\\&|A|B\r\nPID|1||X",
+            "MSH|^~\\&|||||||||||||||\rZZZ|||\rNTE",
+            "MSH#*$!@%#A#B#C\rPID#1##ID*1*2$R2@S1",
+        ] {
+            let message = Message::parse(text).unwrap();
+            assert_eq!(message.encode(), text);
+        }
+    }
+
+    #[test]
+    fn test_segment_order_and_repeats() {
+        let message = Message::parse(ORU).unwrap();
+        let names: Vec<&str> = message.segments.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["MSH", "PID", "OBR", "OBX", "NTE", "OBX", "NTE"]);
+
+        let obx: Vec<&str> = message.segments_named("OBX")
+            .map(|s| s.field(3).unwrap().get(1, 1, 1).unwrap())
+            .collect();
+        assert_eq!(obx, ["WBC", "COMMENT"]);
+    }
+
+    #[test]
+    fn test_fields_components_and_repetitions() {
+        let message = Message::parse(ORU).unwrap();
+        let msh = message.segment("MSH").unwrap();
+        assert_eq!(msh.field(1).unwrap().encode(&message.delimiters), "|");
+        assert_eq!(msh.field(2).unwrap().encode(&message.delimiters), "^~\\&");
+        assert_eq!(msh.field(9).unwrap().get(1, 2, 1), Some("R01"));
+        assert_eq!(msh.field(10).unwrap().get(1, 1, 1), Some("MSG00001"));
+
+        let pid = message.segment("PID").unwrap();
+        let ids = pid.field(3).unwrap();
+        assert_eq!(ids.repetitions.len(), 2);
+        assert_eq!(ids.get(2, 1, 1), Some("67890"));
+        assert_eq!(ids.get(2, 5, 1), Some("NI"));
+        assert_eq!(pid.field(5).unwrap().get(1, 2, 1), Some("JOHN"));
+        assert!(pid.field(2).unwrap().is_empty());
+        assert!(pid.field(40).is_none());
+
+        let obx = message.segments_named("OBX").nth(1).unwrap();
+        assert_eq!(obx.field(5).unwrap().get(1, 1, 2), Some("sub"));
+        assert_eq!(message.delimiters.unescape(obx.field(5).unwrap().get(1, 1, 1).unwrap()), "Result&value");
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        let message = Message::parse("MSH#*$!@%#A#B#C\rPID#1##ID*1*2$R2@S1").unwrap();
+        assert_eq!(message.delimiters.field, '#');
+        assert_eq!(message.delimiters.component, '*');
+        assert_eq!(message.delimiters.repetition, '$');
+        assert_eq!(message.delimiters.escape, Some('!'));
+        assert_eq!(message.delimiters.subcomponent, Some('@'));
+        assert_eq!(message.delimiters.truncation, Some('%'));
+        assert_eq!(message.delimiters.encoding_characters(), "*$!@%");
+
+        let id = message.segment("PID").unwrap().field(3).unwrap();
+        assert_eq!(id.get(1, 3, 1), Some("2"));
+        assert_eq!(id.get(2, 1, 2), Some("S1"));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        assert!(Message::parse("PID|1||X").is_err());
+        assert!(Message::parse("MSH").is_err());
+        assert!(Message::parse("MSH|^^\\&|A").is_err());
+        assert!(Message::parse("MSHA^~\\&|A").is_err());
+    }
+
+    #[test]
+    fn test_get_by_path() {
+        let message = Message::parse(ORU).unwrap();
+        assert_eq!(message.get("PID-5-1").unwrap().as_deref(), Some("DOE"));
+        assert_eq!(message.get("PID-5").unwrap().as_deref(), Some("DOE"));
+        assert_eq!(message.get("PID-3(2)-1").unwrap().as_deref(), Some("67890"));
+        assert_eq!(message.get("OBX(2)-3").unwrap().as_deref(), Some("COMMENT"));
+        assert_eq!(message.get("OBX(2)-5").unwrap().as_deref(), Some("Result&value"));
+        assert_eq!(message.get("NTE-3").unwrap().as_deref(), Some("First note with | and ^ escaped"));
+        assert_eq!(message.get("MSH-2").unwrap().as_deref(), Some("^~\\&"));
+        assert_eq!(message.get("MSH-9-2").unwrap().as_deref(), Some("R01"));
+        assert_eq!(message.get("OBX(3)-5").unwrap(), None);
+        assert_eq!(message.get("PID-40").unwrap(), None);
+        assert!(message.get("PID5").is_err());
+    }
+
+    #[test]
+    fn test_set_by_path() {
+        let mut message = Message::parse("MSH|^~\\&|A|B\rPID|1||123^^^HOSP\rOBX|1\rOBX|2").unwrap();
+        message.set("PID-3-1", "456").unwrap();
+        message.set("PID-5-2", "JOHN").unwrap();
+        message.set("PID-3(2)-1", "789").unwrap();
+        message.set("OBX(2)-5", "a|b").unwrap();
+        message.set("OBX-3-1-2", "sub").unwrap();
+        assert_eq!(
+            message.encode(),
+            "MSH|^~\\&|A|B\rPID|1||456^^^HOSP~789||^JOHN\rOBX|1||&sub\rOBX|2||||a\\F\\b"
+        );
+        assert_eq!(message.get("OBX(2)-5").unwrap().as_deref(), Some("a|b"));
+
+        assert!(message.set("NTE-1", "x").is_err());
+        assert!(message.set("MSH-2", "x").is_err());
+    }
+
+    #[test]
+    fn test_add_and_remove_segments() {
+        let mut message = Message::parse(ORU).unwrap();
+        message.add_segment("NTE|2||Added", None).unwrap();
+        message.add_segment("ZPI|1|custom", Some(3)).unwrap();
+        assert_eq!(message.segments[2].name, "ZPI");
+        assert_eq!(message.segments.last().unwrap().name, "NTE");
+        assert_eq!(message.count("NTE"), 3);
+
+        assert!(message.remove_segment("OBX", 1));
+        assert!(!message.remove_segment("OBX", 2));
+        assert_eq!(message.get("OBX-3").unwrap().as_deref(), Some("COMMENT"));
+
+        assert!(message.add_segment("MSH|^~\\&|X", None).is_err());
+        assert!(message.add_segment("NTE|1\rPID|2", None).is_err());
+        assert!(message.add_segment("NTE|1", Some(100)).is_err());
+    }
+
+    #[test]
+    fn test_to_json_map_keeps_repeats() {
+        let json = Message::parse(ORU).unwrap().to_json_map();
+        assert_eq!(json["MSH"][9], "MSG00001");
+        assert_eq!(json["PID"][2], "12345^^^HOSP^MR~67890^^^NATL^NI");
+        assert_eq!(json["OBX"][0][2], "WBC^Leukocytes^L");
+        assert_eq!(json["OBX"][1][2], "COMMENT");
+        assert_eq!(json["NTE"].as_array().unwrap().len(), 2);
+    }
+}
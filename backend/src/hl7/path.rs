@@ -0,0 +1,102 @@
+use std::fmt;
+
+/// Location of a value inside a segment: `field[(repetition)][-component[-subcomponent]]`.
+/// Every position is 1-based; omitted positions default to 1, so `5` addresses the same value as
+/// `5(1)-1-1` (terser convention: `PID-5` is the family name).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldPath {
+    pub field: usize,
+    pub repetition: usize,
+    pub component: usize,
+    pub subcomponent: usize,
+}
+
+/// Terser-style path to a value in a message: `SEG[(occurrence)]-<FieldPath>`,
+/// e.g. `PID-5-1`, `OBX(2)-5`, `PID-3(2)-1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    pub segment: String,
+    pub occurrence: usize,
+    pub field: FieldPath,
+}
+
+impl Path {
+    pub fn parse(path: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid HL7 path '{}': expected e.g. PID-5-1 or OBX(2)-5", path);
+        let (segment, field) = path.trim().split_once('-').ok_or_else(invalid)?;
+        let (segment, occurrence) = split_index(segment).ok_or_else(invalid)?;
+        if segment.len() != 3 || !segment.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let field = FieldPath::parse(field).map_err(|_| invalid())?;
+        Ok(Self { segment: segment.to_string(), occurrence, field })
+    }
+}
+
+impl FieldPath {
+    pub fn parse(path: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid HL7 field path '{}': expected e.g. 5, 5-1 or 3(2)-1", path);
+        let mut parts = path.trim().split('-');
+        let (field, repetition) = parts.next().and_then(split_index).ok_or_else(invalid)?;
+        let field = parse_position(field).ok_or_else(invalid)?;
+        let mut next = || -> anyhow::Result<usize> {
+            match parts.next() {
+                Some(part) => parse_position(part).ok_or_else(invalid),
+                None => Ok(1),
+            }
+        };
+        let component = next()?;
+        let subcomponent = next()?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self { field, repetition, component, subcomponent })
+    }
+}
+
+/// `NAME(3)` -> (`NAME`, 3); `NAME` -> (`NAME`, 1)
+fn split_index(part: &str) -> Option<(&str, usize)> {
+    match part.split_once('(') {
+        Some((name, index)) => Some((name, parse_position(index.strip_suffix(')')?)?)),
+        None => Some((part, 1)),
+    }
+}
+
+fn parse_position(text: &str) -> Option<usize> {
+    text.parse().ok().filter(|n| *n > 0)
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})-{}", self.segment, self.occurrence, self.field)
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})-{}-{}", self.field, self.repetition, self.component, self.subcomponent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_paths() {
+        let path = Path::parse("PID-5-1").unwrap();
+        assert_eq!((path.segment.as_str(), path.occurrence), ("PID", 1));
+        assert_eq!(path.field, FieldPath { field: 5, repetition: 1, component: 1, subcomponent: 1 });
+
+        let path = Path::parse("OBX(2)-5").unwrap();
+        assert_eq!(path.occurrence, 2);
+        assert_eq!(path.field.field, 5);
+
+        let path = Path::parse("PID-3(2)-4-2").unwrap();
+        assert_eq!(path.field, FieldPath { field: 3, repetition: 2, component: 4, subcomponent: 2 });
+
+        for invalid in ["PID", "PID-", "PID-0", "pid-5", "PID(0)-5", "PID-5-1-1-1", "PID(2-5", "PID-x"] {
+            assert!(Path::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
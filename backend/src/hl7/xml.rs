@@ -0,0 +1,342 @@
+//! XML encoding in the style of the HL7 v2.xml schema, without datatype names:
+//!
+//! ```xml
+//! <HL7Message>
+//!   <PID>
+//!     <PID.1>1</PID.1>
+//!     <PID.5><PID.5.1>DOE</PID.5.1><PID.5.2>JOHN</PID.5.2></PID.5>
+//!   </PID>
+//! </HL7Message>
+//! ```
+//!
+//! A repeating field is written as several elements with the same name, subcomponents as
+//! `PID.5.1.1`. Values keep their HL7 escape sequences, so ER7 -> XML -> ER7 is lossless.
+
+use super::{Component, Delimiters, Field, Message, Repetition, Segment, HEADER_SEGMENTS};
+
+const ROOT: &str = "HL7Message";
+
+pub fn to_xml(message: &Message) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<");
+    out.push_str(ROOT);
+    out.push_str(">\n");
+    for segment in &message.segments {
+        write_segment(&mut out, segment);
+    }
+    out.push_str("</");
+    out.push_str(ROOT);
+    out.push_str(">\n");
+    out
+}
+
+fn write_segment(out: &mut String, segment: &Segment) {
+    if segment.fields.is_empty() {
+        out.push_str(&format!("  <{}/>\n", segment.name));
+        return;
+    }
+    out.push_str(&format!("  <{}>\n", segment.name));
+    for (i, field) in segment.fields.iter().enumerate() {
+        let name = format!("{}.{}", segment.name, i + 1);
+        for repetition in &field.repetitions {
+            out.push_str("    ");
+            write_repetition(out, &name, repetition);
+            out.push('\n');
+        }
+    }
+    out.push_str(&format!("  </{}>\n", segment.name));
+}
+
+fn write_repetition(out: &mut String, name: &str, repetition: &Repetition) {
+    match repetition.components.as_slice() {
+        [component] if component.subcomponents.len() == 1 => write_leaf(out, name, &component.subcomponents[0]),
+        components => {
+            out.push_str(&format!("<{}>", name));
+            for (i, component) in components.iter().enumerate() {
+                let name = format!("{}.{}", name, i + 1);
+                match component.subcomponents.as_slice() {
+                    [value] => write_leaf(out, &name, value),
+                    subcomponents => {
+                        out.push_str(&format!("<{}>", name));
+                        for (j, value) in subcomponents.iter().enumerate() {
+                            write_leaf(out, &format!("{}.{}", name, j + 1), value);
+                        }
+                        out.push_str(&format!("</{}>", name));
+                    }
+                }
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+    }
+}
+
+fn write_leaf(out: &mut String, name: &str, value: &str) {
+    if value.is_empty() {
+        out.push_str(&format!("<{}/>", name));
+    } else {
+        out.push_str(&format!("<{}>{}</{}>", name, escape_text(value), name));
+    }
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Parse the XML produced by `to_xml` (element positions are taken from their names, so
+/// omitted empty fields or components are fine)
+pub fn from_xml(xml: &str) -> anyhow::Result<Message> {
+    let root = Reader::new(xml).document()?;
+    if root.name != ROOT {
+        return Err(anyhow::anyhow!("Expected <{}> as the XML root element, found <{}>", ROOT, root.name));
+    }
+
+    let msh = root.children.first()
+        .filter(|s| HEADER_SEGMENTS.contains(&s.name.as_str()))
+        .ok_or_else(|| anyhow::anyhow!("HL7 XML message must start with an MSH segment"))?;
+    let text_of = |n: usize| -> Option<String> {
+        msh.children.iter().find(|c| c.name == format!("{}.{}", msh.name, n)).map(|c| c.text.clone())
+    };
+    let field = text_of(1).and_then(|s| s.chars().next()).unwrap_or('|');
+    let encoding = text_of(2).unwrap_or_else(|| Delimiters::default().encoding_characters());
+    let delimiters = Delimiters::from_header(field, &encoding)?;
+
+    let mut segments = Vec::new();
+    for element in &root.children {
+        let mut segment = Segment { name: element.name.clone(), fields: Vec::new() };
+        let mut last_index = 0;
+        for child in &element.children {
+            let index = position(&child.name, &element.name)?;
+            if index > super::MAX_FIELDS {
+                return Err(anyhow::anyhow!("HL7 segment {} has more than {} fields", segment.name, super::MAX_FIELDS));
+            }
+            if segment.fields.len() < index {
+                segment.fields.resize_with(index, || Field { repetitions: Vec::new() });
+            }
+            let field = &mut segment.fields[index - 1];
+            // Elements repeating the previous field name are repetitions
+            if index != last_index {
+                field.repetitions.clear();
+            }
+            field.repetitions.push(read_repetition(child)?);
+            last_index = index;
+        }
+        for field in &mut segment.fields {
+            if field.repetitions.is_empty() {
+                *field = Field::empty();
+            }
+        }
+        if segment.is_header() {
+            segment.set_header(&delimiters);
+        }
+        segments.push(segment);
+    }
+    if segments.len() > super::MAX_SEGMENTS {
+        return Err(anyhow::anyhow!("HL7 message has more than {} segments", super::MAX_SEGMENTS));
+    }
+
+    Ok(Message { delimiters, segments, line_ending: "\r".to_string(), trailing_line_ending: false })
+}
+
+fn read_repetition(element: &Element) -> anyhow::Result<Repetition> {
+    if element.children.is_empty() {
+        return Ok(Repetition { components: vec![Component { subcomponents: vec![element.text.clone()] }] });
+    }
+    let mut components = Vec::new();
+    for child in &element.children {
+        let index = position(&child.name, &element.name)?;
+        if components.len() < index {
+            components.resize_with(index, Component::empty);
+        }
+        let mut subcomponents = Vec::new();
+        if child.children.is_empty() {
+            subcomponents.push(child.text.clone());
+        }
+        for sub in &child.children {
+            let index = position(&sub.name, &child.name)?;
+            if subcomponents.len() < index {
+                subcomponents.resize(index, String::new());
+            }
+            subcomponents[index - 1] = sub.text.clone();
+        }
+        components[index - 1] = Component { subcomponents };
+    }
+    Ok(Repetition { components })
+}
+
+/// `PID.5` inside `PID` -> 5
+fn position(name: &str, parent: &str) -> anyhow::Result<usize> {
+    name.strip_prefix(parent)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|n| n.parse().ok())
+        .filter(|n| *n > 0)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected element <{}> inside <{}>", name, parent))
+}
+
+/// The subset of XML used by this encoding: elements, text, entities, comments and the
+/// XML declaration. Attributes are ignored.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+struct Reader<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn document(mut self) -> anyhow::Result<Element> {
+        self.skip_misc()?;
+        let root = self.element()?;
+        self.skip_misc()?;
+        if !self.rest().is_empty() {
+            return Err(anyhow::anyhow!("Unexpected content after the XML root element"));
+        }
+        Ok(root)
+    }
+
+    /// Whitespace, comments and processing instructions between elements
+    fn skip_misc(&mut self) -> anyhow::Result<()> {
+        loop {
+            let trimmed = self.rest().trim_start();
+            self.pos = self.input.len() - trimmed.len();
+            if trimmed.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if trimmed.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_past(&mut self, end: &str) -> anyhow::Result<()> {
+        let i = self.rest().find(end).ok_or_else(|| anyhow::anyhow!("Unterminated XML markup"))?;
+        self.pos += i + end.len();
+        Ok(())
+    }
+
+    fn element(&mut self) -> anyhow::Result<Element> {
+        if !self.rest().starts_with('<') {
+            return Err(anyhow::anyhow!("Expected an XML element"));
+        }
+        let end = self.rest().find('>').ok_or_else(|| anyhow::anyhow!("Unterminated XML tag"))?;
+        let tag = &self.rest()[1..end];
+        self.pos += end + 1;
+
+        let self_closing = tag.ends_with('/');
+        let name = tag.trim_end_matches('/').split_whitespace().next().unwrap_or_default().to_string();
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Empty XML tag name"));
+        }
+        let mut element = Element { name, text: String::new(), children: Vec::new() };
+        if self_closing {
+            return Ok(element);
+        }
+
+        let mut text = String::new();
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                let end = rest.find('>').ok_or_else(|| anyhow::anyhow!("Unterminated XML tag"))?;
+                let closing = rest[2..end].trim();
+                if closing != element.name {
+                    return Err(anyhow::anyhow!("Mismatched XML tags <{}> and </{}>", element.name, closing));
+                }
+                self.pos += end + 1;
+                break;
+            } else if rest.starts_with("<!--") || rest.starts_with("<?") {
+                self.skip_misc()?;
+            } else if rest.starts_with('<') {
+                element.children.push(self.element()?);
+            } else if rest.is_empty() {
+                return Err(anyhow::anyhow!("Unclosed XML element <{}>", element.name));
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                text.push_str(&decode_entities(&rest[..end])?);
+                self.pos += end;
+            }
+        }
+        if element.children.is_empty() {
+            element.text = text;
+        }
+        Ok(element)
+    }
+}
+
+fn decode_entities(text: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or_else(|| anyhow::anyhow!("Unterminated XML entity"))? + start;
+        let entity = &rest[start + 1..end];
+        let decoded = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|d| d.parse().ok()),
+                };
+                code.and_then(char::from_u32).ok_or_else(|| anyhow::anyhow!("Unknown XML entity &{};", entity))?
+            }
+        };
+        out.push(decoded);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADT: &str = "MSH|^~\\&|HIS|HOSP|||202401011200||ADT^A01|MSG1|P|2.5\rPID|1||123^^^HOSP^MR~456&X^^^NATL||DOE^JOHN||<b>\rNTE\rZZZ|||";
+
+    #[test]
+    fn test_xml_round_trip() {
+        let message = Message::parse(ADT).unwrap();
+        let xml = to_xml(&message);
+        assert!(xml.contains("<PID.3><PID.3.1>123</PID.3.1><PID.3.2/><PID.3.3/><PID.3.4>HOSP</PID.3.4><PID.3.5>MR</PID.3.5></PID.3>"), "{}", xml);
+        assert!(xml.contains("<PID.3.1><PID.3.1.1>456</PID.3.1.1><PID.3.1.2>X</PID.3.1.2></PID.3.1>"), "{}", xml);
+        assert!(xml.contains("<MSH.2>^~\\&amp;</MSH.2>"), "{}", xml);
+        assert!(xml.contains("<PID.7>&lt;b&gt;</PID.7>"), "{}", xml);
+        assert!(xml.contains("<NTE/>"), "{}", xml);
+
+        assert_eq!(from_xml(&xml).unwrap().encode(), ADT);
+    }
+
+    #[test]
+    fn test_sparse_xml() {
+        let xml = r#"<?xml version="1.0"?>
+            <!-- empty fields may be left out -->
+            <HL7Message>
+              <MSH><MSH.1>|</MSH.1><MSH.2>^~\&amp;</MSH.2><MSH.9><MSH.9.1>ADT</MSH.9.1><MSH.9.2>A01</MSH.9.2></MSH.9></MSH>
+              <PID><PID.3>1</PID.3><PID.3>2</PID.3><PID.5><PID.5.2>JOHN</PID.5.2></PID.5></PID>
+            </HL7Message>"#;
+        let message = from_xml(xml).unwrap();
+        assert_eq!(message.encode(), "MSH|^~\\&|||||||ADT^A01\rPID|||1~2||^JOHN");
+    }
+
+    #[test]
+    fn test_invalid_xml() {
+        assert!(from_xml("<Other/>").is_err());
+        assert!(from_xml("<HL7Message><MSH></PID></HL7Message>").is_err());
+        assert!(from_xml("<HL7Message><MSH><PID.1/></MSH></HL7Message>").is_err());
+        assert!(from_xml("<HL7Message>").is_err());
+    }
+}
@@ -1,6 +1,7 @@
 pub mod api;
 pub mod config;
 pub mod engine;
+pub mod hl7;
 pub mod storage;
 pub mod lua_helpers;
 pub mod logging;
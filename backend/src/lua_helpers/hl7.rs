@@ -1,71 +1,197 @@
-use mlua::{Lua, Result, Table};
+use std::sync::{Arc, Mutex, MutexGuard};
+use mlua::{Lua, LuaSerdeExt, MetaMethod, Result, UserData, UserDataMethods};
+use crate::hl7::{FieldPath, Message};
 
 pub fn register_hl7(lua: &Lua) -> Result<()> {
     let hl7_table = lua.create_table()?;
 
     // Function: parse(hl7_string) -> Table
-    // Returns a table where keys are segment names (e.g., "MSH") and values are arrays of segments 
-    // (since segments can repeat). Each segment is an array of fields.
+    // Returns a table keyed by segment name (e.g. "MSH"). Each segment is an array of raw field
+    // values (`parsed.PID[3]` is PID-3); a segment that repeats (OBX, NTE, ...) becomes an array
+    // of those arrays, in message order.
     hl7_table.set("parse", lua.create_function(|lua, content: String| {
-        let parsed = lua.create_table()?;
-        
-        let segments: Vec<&str> = content.split('\r').collect();
-        for segment in segments {
-            if segment.trim().is_empty() { continue; }
-            
-            let fields: Vec<&str> = segment.split('|').collect();
-            let segment_name = fields[0]; // e.g., MSH, PID
-            
-            // Create a table for this segment's fields
-            let segment_data = lua.create_table()?;
-            for (i, field) in fields.iter().enumerate() {
-                // Handle repetition/components if needed, for MVP just raw strings
-                segment_data.set(i + 1, *field)?;
-            }
-
-            // Check if we already have this segment type (e.g. multiple NTEs or OBXs)
-            // Simplified: We'll overwrite for single segments, or append for multi?
-            // For MVP: Let's store 'MSH': { fields... } 
-            
-            // Actually, HL7 is ordered. For true utility, we might want:
-            // hl7[1] = { name="MSH", fields={...} }
-            // But usually accessing by name is easier: msg["PID"][1][4]
-            
-            // Let's implement: table[SegmentName] = { fields... } (Last wins for simplicity in MVP)
-            parsed.set(segment_name, segment_data)?;
-        }
-        
-        Ok(parsed)
+        let message = Message::parse(&content).map_err(mlua::Error::external)?;
+        lua.to_value(&message.to_json_map())
     })?)?;
 
-    // Function: to_json(hl7_string) -> String (JSON)
+    // Function: to_json(hl7_string) -> String (JSON), same structure as parse
     hl7_table.set("to_json", lua.create_function(|_, content: String| {
-        // Very basic conversion: 
-        // { "MSH": ["|", "^~\\&", ...], "PID": [...] }
-        use std::collections::HashMap;
-        let mut map: HashMap<String, Vec<String>> = HashMap::new();
-        
-        let segments: Vec<&str> = content.split('\r').collect();
-        for segment in segments {
-            if segment.trim().is_empty() { continue; }
-            let fields: Vec<&str> = segment.split('|').collect();
-            let segment_name = fields[0].to_string();
-            let field_values: Vec<String> = fields.iter().map(|s| s.to_string()).collect();
-            
-            map.insert(segment_name, field_values);
-        }
-        
-        let json = serde_json::to_string(&map).unwrap_or("{}".to_string());
-        Ok(json)
+        let message = Message::parse(&content).map_err(mlua::Error::external)?;
+        Ok(message.to_json_map().to_string())
     })?)?;
 
-    // Register as global 'hl7'
-    lua.globals().set("hl7", hl7_table.clone())?;
+    // Function: message(hl7_string) -> editable message, see `LuaMessage`
+    hl7_table.set("message", lua.create_function(|_, content: String| {
+        let message = Message::parse(&content).map_err(mlua::Error::external)?;
+        Ok(LuaMessage(Arc::new(Mutex::new(message))))
+    })?)?;
 
-    // Also register in package.loaded['hl7'] to make require('hl7') work
-    let package: Table = lua.globals().get("package")?;
-    let loaded: Table = package.get("loaded")?;
-    loaded.set("hl7", hl7_table)?;
+    // Register as global 'hl7' (the sandbox's `require('hl7')` resolves to it too)
+    lua.globals().set("hl7", hl7_table)?;
 
     Ok(())
 }
+
+/// HL7 message edited in place from Lua:
+///
+/// ```lua
+/// local m = hl7.message(msg.content)
+/// m:set("PID-5-1", m:get("PID-5-1"):upper())
+/// for seg in m:segments("OBX") do log(seg:get("5")) end
+/// m:remove_segment("NTE", 1)
+/// return m:encode()
+/// ```
+#[derive(Clone)]
+struct LuaMessage(Arc<Mutex<Message>>);
+
+impl LuaMessage {
+    fn lock(&self) -> Result<MutexGuard<'_, Message>> {
+        self.0.lock().map_err(|_| mlua::Error::RuntimeError("HL7 message is poisoned".to_string()))
+    }
+}
+
+impl UserData for LuaMessage {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // msg:get("PID-5-1") -> string or nil
+        methods.add_method("get", |_, this, path: String| {
+            this.lock()?.get(&path).map_err(mlua::Error::external)
+        });
+
+        // msg:set("PID-3-1", value)
+        methods.add_method("set", |_, this, (path, value): (String, Option<String>)| {
+            this.lock()?.set(&path, value.as_deref().unwrap_or_default()).map_err(mlua::Error::external)
+        });
+
+        // msg:count("OBX") -> number of OBX segments
+        methods.add_method("count", |_, this, name: String| Ok(this.lock()?.count(&name)));
+
+        // msg:add_segment("NTE|1||text" [, position])
+        methods.add_method("add_segment", |_, this, (text, position): (String, Option<usize>)| {
+            this.lock()?.add_segment(&text, position).map_err(mlua::Error::external)
+        });
+
+        // msg:remove_segment("OBX" [, occurrence]) -> whether a segment was removed
+        methods.add_method("remove_segment", |_, this, (name, occurrence): (String, Option<usize>)| {
+            Ok(this.lock()?.remove_segment(&name, occurrence.unwrap_or(1)))
+        });
+
+        // for seg in msg:segments(["OBX"]) do ... end
+        // Iterates over the segments present when the loop starts
+        methods.add_method("segments", |lua, this, name: Option<String>| {
+            let positions: Vec<usize> = this.lock()?.segments.iter()
+                .enumerate()
+                .filter(|(_, s)| name.as_deref().is_none_or(|n| s.name == n))
+                .map(|(i, _)| i)
+                .collect();
+            let message = this.0.clone();
+            let mut next = positions.into_iter();
+            lua.create_function_mut(move |_, ()| {
+                Ok(next.next().map(|position| LuaSegment { message: message.clone(), position }))
+            })
+        });
+
+        methods.add_method("encode", |_, this, ()| Ok(this.lock()?.encode()));
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| Ok(this.lock()?.encode()));
+    }
+}
+
+/// One segment of a `LuaMessage`; paths are relative to the segment (`seg:get("5-1")`)
+struct LuaSegment {
+    message: Arc<Mutex<Message>>,
+    position: usize,
+}
+
+impl LuaSegment {
+    fn with<R>(&self, f: impl FnOnce(&mut Message, usize) -> anyhow::Result<R>) -> Result<R> {
+        let mut message = self.message.lock()
+            .map_err(|_| mlua::Error::RuntimeError("HL7 message is poisoned".to_string()))?;
+        if self.position >= message.segments.len() {
+            return Err(mlua::Error::RuntimeError("Segment was removed from the message".to_string()));
+        }
+        f(&mut message, self.position).map_err(mlua::Error::external)
+    }
+}
+
+impl UserData for LuaSegment {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("name", |_, this, ()| {
+            this.with(|message, i| Ok(message.segments[i].name.clone()))
+        });
+
+        methods.add_method("get", |_, this, path: String| {
+            this.with(|message, i| {
+                let path = FieldPath::parse(&path)?;
+                Ok(message.segments[i].get(&path, &message.delimiters))
+            })
+        });
+
+        methods.add_method("set", |_, this, (path, value): (String, Option<String>)| {
+            this.with(|message, i| {
+                let path = FieldPath::parse(&path)?;
+                let delimiters = message.delimiters.clone();
+                message.segments[i].set(&path, value.as_deref().unwrap_or_default(), &delimiters)
+            })
+        });
+
+        methods.add_method("encode", |_, this, ()| {
+            this.with(|message, i| Ok(message.segments[i].encode(&message.delimiters)))
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+            this.with(|message, i| Ok(message.segments[i].encode(&message.delimiters)))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADT: &str = "MSH|^~\\&|HIS|HOSP|LAB|HOSP|202401011200||ADT^A01|MSG1|P|2.5\rPID|1||123^^^HOSP^MR||DOE^JOHN\rNTE|1||first\rNTE|2||second";
+
+    fn lua() -> Lua {
+        let lua = Lua::new();
+        register_hl7(&lua).unwrap();
+        lua.globals().set("content", ADT).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_get_set_encode() {
+        let result: String = lua().load(r#"
+            local m = hl7.message(content)
+            assert(m:get("PID-5-1") == "DOE")
+            assert(m:get("NTE(2)-3") == "second")
+            assert(m:get("PV1-19") == nil)
+            m:set("PID-5-1", m:get("PID-5-1") .. "^X")
+            m:set("PID-8", "M")
+            return m:encode()
+        "#).eval().unwrap();
+        assert_eq!(result, "MSH|^~\\&|HIS|HOSP|LAB|HOSP|202401011200||ADT^A01|MSG1|P|2.5\rPID|1||123^^^HOSP^MR||DOE\\S\\X^JOHN|||M\rNTE|1||first\rNTE|2||second");
+    }
+
+    #[test]
+    fn test_segment_iteration_and_editing() {
+        let result: String = lua().load(r#"
+            local m = hl7.message(content)
+            local notes = {}
+            for seg in m:segments("NTE") do
+                table.insert(notes, seg:get("3"))
+                seg:set("3", seg:get("3"):upper())
+            end
+            assert(table.concat(notes, ",") == "first,second")
+            assert(m:remove_segment("NTE", 1))
+            m:add_segment("ZPI|1|extra")
+            m:add_segment("EVN|A01", 2)
+            local names = {}
+            for seg in m:segments() do table.insert(names, seg:name()) end
+            return table.concat(names, ",") .. " " .. m:get("NTE-3") .. " " .. m:count("NTE")
+        "#).eval().unwrap();
+        assert_eq!(result, "MSH,EVN,PID,NTE,ZPI SECOND 1");
+    }
+
+    #[test]
+    fn test_invalid_path_errors() {
+        let err = lua().load(r#"hl7.message(content):get("PID5")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("Invalid HL7 path"), "{}", err);
+    }
+}
@@ -17,17 +17,8 @@ pub fn register_json(lua: &Lua) -> Result<()> {
         Ok(lua_val)
     })?)?;
 
-    // Create package.loaded if not exists (to mimic require behavior if needed, 
-    // but globally setting 'json' is usually enough for simple scripts)
-    // However, if the user does 'local json = require("json")', we should support that.
-    // Ideally we inject into package.loaded.
-    
-    let globals = lua.globals();
-    globals.set("json", json_table.clone())?; // Global access
-
-    let package: mlua::Table = globals.get("package")?;
-    let loaded: mlua::Table = package.get("loaded")?;
-    loaded.set("json", json_table)?;
+    // Global access; `require("json")` is served by the sandbox's require
+    lua.globals().set("json", json_table)?;
 
     Ok(())
 }
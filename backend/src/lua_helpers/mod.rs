@@ -1,3 +1,4 @@
 pub mod logging;
 pub mod hl7;
 pub mod json;
+pub mod sandbox;
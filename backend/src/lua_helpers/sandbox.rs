@@ -0,0 +1,268 @@
+use mlua::{Lua, StdLib, Table, Value};
+use crate::engine::lua_pool::LuaPool;
+use crate::storage::models::LuaLimits;
+
+/// Maximum memory usage in bytes (10MB)
+pub const MAX_MEMORY_BYTES: usize = 10 * 1024 * 1024;
+/// Maximum code size in bytes (64KB)
+pub const MAX_CODE_SIZE: usize = 64 * 1024;
+
+/// Base library functions scripts may use. Everything else (`load`, `dofile`, `rawset`,
+/// `setmetatable`, `collectgarbage`, `_G`, ...) is left out of the environment.
+const BASE_FUNCTIONS: [&str; 12] = [
+    "assert", "error", "ipairs", "next", "pairs", "pcall",
+    "select", "tonumber", "tostring", "type", "xpcall", "_VERSION",
+];
+/// Standard libraries exposed to scripts
+const LIBRARIES: [&str; 5] = ["string", "table", "math", "utf8", "os"];
+/// Library functions removed even though their library is exposed
+const REMOVED_LIBRARY_FUNCTIONS: [(&str, &str); 1] = [
+    ("string", "dump"), // Bytecode of any function; loading bytecode can corrupt the VM
+];
+/// Helper modules, available as globals and through `require`
+const HELPER_MODULES: [&str; 3] = ["json", "hl7", "log"];
+/// Registry slot holding the modules `require` can resolve
+const MODULES_REGISTRY_KEY: &str = "sandbox_modules";
+
+/// Validate `code` and compile it into a pool of sandboxed states. Every Lua entry point
+/// (processor, filter, router condition, destination) goes through here, so they all share one
+/// policy. With `wrap`, the code runs as the body of a function so it may `return` its result.
+pub fn build_pool(name: &str, code: &str, wrap: bool, limits: LuaLimits) -> anyhow::Result<LuaPool> {
+    validate_code(code)?;
+    let script = if wrap {
+        format!("local function run(msg)\n{}\nend\nreturn run(msg)", code)
+    } else {
+        code.to_string()
+    };
+    Ok(LuaPool::new(name, script, init_state)?.with_limits(limits))
+}
+
+/// Validate Lua code before compiling it. What a script can reach is decided by its
+/// environment (see `init_state`), not by scanning its text.
+pub fn validate_code(code: &str) -> anyhow::Result<()> {
+    if code.len() > MAX_CODE_SIZE {
+        return Err(anyhow::anyhow!(
+            "Lua code exceeds maximum size of {} bytes (got {} bytes)",
+            MAX_CODE_SIZE,
+            code.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Sandboxed state: the globals table (every script's `_ENV`) only holds whitelisted base
+/// functions, safe libraries, the `os` mock, our helpers and a `require` limited to them
+pub fn init_state(lua: &Lua) -> mlua::Result<()> {
+    // io, debug, package, coroutine (the execution limit hook does not follow scripts into
+    // coroutines) and ffi are never loaded
+    lua.load_from_std_lib(StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8)?;
+
+    // Set memory limit to prevent DoS
+    lua.set_memory_limit(MAX_MEMORY_BYTES)?;
+
+    register_os_mock(lua)?;
+
+    // Register safe helpers
+    super::logging::register_logging(lua)?;
+    super::hl7::register_hl7(lua)?;
+    super::json::register_json(lua)?;
+
+    let globals = lua.globals();
+    let mut removed = Vec::new();
+    for pair in globals.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        let allowed = match &key {
+            Value::String(name) => {
+                let name = name.to_str()?;
+                BASE_FUNCTIONS.contains(&name) || LIBRARIES.contains(&name) || HELPER_MODULES.contains(&name)
+            },
+            _ => false,
+        };
+        if !allowed {
+            removed.push(key);
+        }
+    }
+    for key in removed {
+        globals.raw_set(key, Value::Nil)?;
+    }
+
+    for (library, function) in REMOVED_LIBRARY_FUNCTIONS {
+        let library: Table = globals.get(library)?;
+        library.raw_set(function, Value::Nil)?;
+    }
+
+    register_require(lua)
+}
+
+/// `require(name)` resolving only to the helper modules; there is no file or native loading
+fn register_require(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let modules = lua.create_table()?;
+    for name in HELPER_MODULES {
+        modules.raw_set(name, globals.get::<_, Value>(name)?)?;
+    }
+    lua.set_named_registry_value(MODULES_REGISTRY_KEY, modules)?;
+
+    let require = lua.create_function(|lua, name: String| {
+        let modules: Table = lua.named_registry_value(MODULES_REGISTRY_KEY)?;
+        match modules.raw_get::<_, Value>(name.as_str())? {
+            Value::Nil => Err(mlua::Error::RuntimeError(format!(
+                "module '{}' is not available (available: {})", name, HELPER_MODULES.join(", ")
+            ))),
+            module => Ok(module),
+        }
+    })?;
+    globals.set("require", require)
+}
+
+/// We only allow date/time functions, mocking the 'os' table
+fn register_os_mock(lua: &Lua) -> mlua::Result<()> {
+    let os_table = lua.create_table()?;
+    os_table.set("date", lua.create_function(|_, (format, time): (Option<String>, Option<i64>)| {
+        let now = chrono::Utc::now();
+        let ts = if let Some(t) = time {
+            chrono::DateTime::from_timestamp(t, 0).unwrap_or(now)
+        } else {
+            now
+        };
+
+        let fmt = format.unwrap_or("%c".to_string());
+        // chrono format is similar to strftime but likely sufficient for Lua scripts
+        Ok(ts.format(&fmt).to_string())
+    })?)?;
+
+    os_table.set("time", lua.create_function(|_, _table: Option<mlua::Table>| {
+        // Basic time implementation (current timestamp)
+        // Ignoring table arg for full compatibility for now, just returning current ts
+        Ok(chrono::Utc::now().timestamp())
+    })?)?;
+
+    os_table.set("difftime", lua.create_function(|_, (t2, t1): (i64, i64)| {
+        Ok(t2 - t1)
+    })?)?;
+
+    os_table.set("clock", lua.create_function(|_, ()| {
+        Ok(0.0) // Mock
+    })?)?;
+
+    lua.globals().set("os", os_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::destinations::lua::LuaDestination;
+    use crate::engine::message::Message;
+    use crate::engine::processors::filter::FilterProcessor;
+    use crate::engine::processors::lua::LuaProcessor;
+    use crate::engine::processors::router::RouterProcessor;
+    use crate::storage::models::Route;
+    use uuid::Uuid;
+
+    /// Lua expression that is true when neither `io.open` nor `os.execute` can be reached.
+    /// Names are built at runtime, so no scan of the script text could catch them.
+    const UNREACHABLE: &str = r#"(function()
+        local env = _ENV
+        local io_lib = env["i" .. "o"]
+        local os_lib = env["o" .. "s"]
+        local ok = pcall(require, "i" .. "o")
+        return io_lib == nil and not ok and os_lib["exe" .. "cute"] == nil
+    end)()"#;
+
+    fn message() -> Message {
+        Message::new(Uuid::new_v4(), "MSH|^~\\&|TEST".to_string(), "test".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_processor_sandbox() {
+        let code = format!("return tostring({})", UNREACHABLE);
+        let processor = LuaProcessor::new(code, LuaLimits::default()).unwrap();
+        assert_eq!(processor.process(message()).await.unwrap().content, "true");
+    }
+
+    #[tokio::test]
+    async fn test_filter_sandbox() {
+        let filter = FilterProcessor::new(format!("return {}", UNREACHABLE), LuaLimits::default()).unwrap();
+        assert!(filter.process(message()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_router_sandbox() {
+        let routes = vec![Route { name: "sandboxed".to_string(), condition: format!("return {}", UNREACHABLE) }];
+        let router = RouterProcessor::new(routes, None, LuaLimits::default()).unwrap();
+        assert_eq!(router.process(&message()).await.unwrap(), vec!["sandboxed".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_destination_sandbox() {
+        let code = format!("if not {} then error('sandbox escaped') end", UNREACHABLE);
+        let destination = LuaDestination::new(code, LuaLimits::default()).unwrap();
+        destination.send(&message()).await.unwrap();
+    }
+
+    /// Each attempt returns the capability it tried to reach; it must fail or come back `nil`
+    const ESCAPE_ATTEMPTS: &[&str] = &[
+        "return io.open('/etc/passwd')",
+        "return os.execute('id')",
+        "return os.getenv('HOME')",
+        "return os.remove('/tmp/x')",
+        "return os.exit",
+        "return require('io')",
+        "return require('os').execute",
+        "return require('debug')",
+        "return require('package')",
+        "return package.loadlib",
+        "return _G.io",
+        "return _ENV['i' .. 'o']",
+        "return _ENV['deb' .. 'ug']",
+        "return _ENV['pack' .. 'age']",
+        "return _ENV['coro' .. 'utine']",
+        "return load('return 1')",
+        "return loadstring",
+        "return dofile('/etc/passwd')",
+        "return loadfile('/etc/passwd')",
+        "return string.dump(function() end)",
+        "return ('').dump",
+        "return getmetatable('').__index.dump",
+        "return rawget(_ENV, 'io')",
+        "return setmetatable({}, { __gc = function() end })",
+        "return collectgarbage('count')",
+        "return debug.getinfo(1)",
+        "return coroutine.wrap(function() end)",
+        "return print",
+    ];
+
+    #[tokio::test]
+    async fn test_escape_attempts() {
+        for attempt in ESCAPE_ATTEMPTS {
+            let code = format!(
+                "local ok, cap = pcall(function() {} end)\nif ok and cap ~= nil then return 'reachable' end\nreturn 'contained'",
+                attempt
+            );
+            let processor = LuaProcessor::new(code, LuaLimits::default()).unwrap();
+            let outcome = processor.process(message()).await.unwrap().content;
+            assert_eq!(outcome, "contained", "{}", attempt);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legitimate_code_allowed() {
+        // Identifiers that the old substring blocklist rejected ("io.", "load(")
+        let code = r#"
+            local ratio = { value = 2 }
+            local function download(x) return x * 10 end
+            local json = require('json')
+            return json.encode({ result = download(ratio.value), date = os.date('%Y') ~= nil })
+        "#;
+        let processor = LuaProcessor::new(code.to_string(), LuaLimits::default()).unwrap();
+        let content = processor.process(message()).await.unwrap().content;
+        assert_eq!(content, r#"{"date":true,"result":20}"#);
+    }
+
+    #[tokio::test]
+    async fn test_require_unknown_module() {
+        let processor = LuaProcessor::new("return require('socket')".to_string(), LuaLimits::default()).unwrap();
+        let err = processor.process(message()).await.unwrap_err();
+        assert!(err.to_string().contains("module 'socket' is not available"), "{}", err);
+    }
+}
@@ -89,6 +89,9 @@ async fn auth_middleware(
 
 #[tokio::main]
 async fn main() {
+    // For the TLS configs built by libraries (HTTPS admin API)
+    let _ = mirthbr_backend::config::tls_provider().as_ref().clone().install_default();
+
     // Shared Log Buffer
     let log_buffer = Arc::new(Mutex::new(VecDeque::with_capacity(100)));
     
@@ -111,6 +114,12 @@ async fn main() {
 
     tracing::info!("Starting MirthBR Backend...");
 
+    // Where destinations may connect (EGRESS_ALLOW / EGRESS_DENY / EGRESS_DEFAULT)
+    let egress_policy = engine::egress::EgressPolicy::from_env()
+        .unwrap_or_else(|e| panic!("FATAL: invalid egress policy: {}", e));
+    tracing::info!("Egress policy: {:?}", egress_policy);
+    let _ = engine::egress::install(egress_policy);
+
     let database_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:mirth.db".to_string());
     let db = match storage::db::Database::new(&database_url).await {
         Ok(db) => {
@@ -158,7 +167,17 @@ async fn main() {
     if let Some(ref database) = db {
         let retry_pool = database.pool.clone();
         let retry_senders = channel_manager.get_senders();
-        let worker = engine::retry_worker::RetryWorker::new(retry_pool, retry_senders);
+        let retry_compiled = channel_manager.get_compiled_destinations();
+        let retry_interval: u64 = std::env::var("RETRY_INTERVAL_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .expect("Invalid RETRY_INTERVAL_SECS format");
+        let worker = engine::retry_worker::RetryWorker::with_interval(
+            retry_pool,
+            retry_senders,
+            retry_compiled,
+            std::time::Duration::from_secs(retry_interval.max(1)),
+        );
         tokio::spawn(async move {
             worker.start().await;
         });
@@ -198,6 +217,7 @@ async fn main() {
         .route("/test/tcp", post(api::handlers::test::test_tcp_dispatch))
         .route("/logs", get(api::handlers::logs::get_logs))
         .route("/messages", get(api::messages::list_messages))
+        .route("/messages/:id", get(api::messages::get_message))
         .route("/messages/:id/retry", post(api::messages::retry_message))
         .route("/ws/metrics", get(api::handlers::websocket::ws_handler))
         .layer(middleware::from_fn_with_state(api_key, auth_middleware));
@@ -53,11 +53,66 @@ impl Database {
         .execute(&self.pool)
         .await?;
         
+        // Columns added after the initial schema (existing databases are migrated in place)
+        self.add_column_if_missing("messages", "dlq_status", "TEXT").await?;
+        self.add_column_if_missing("messages", "dlq_error", "TEXT").await?;
+        self.add_column_if_missing("messages", "metadata", "TEXT").await?;
+
         // Create indexes
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_messages_channel_status ON messages(channel_id, status)")
             .execute(&self.pool)
             .await?;
 
+        // Create message_destinations table (per-destination delivery status)
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS message_destinations (
+                message_id TEXT NOT NULL,
+                destination_id TEXT NOT NULL,
+                destination_name TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER DEFAULT 0,
+                last_error TEXT,
+                response TEXT,
+                content TEXT,
+                created_at DATETIME NOT NULL,
+                updated_at DATETIME NOT NULL,
+                PRIMARY KEY (message_id, destination_id)
+            )"
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.add_column_if_missing("message_destinations", "content", "TEXT").await?;
+        self.add_column_if_missing("message_destinations", "metadata", "TEXT").await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_message_destinations_status ON message_destinations(status)")
+            .execute(&self.pool)
+            .await?;
+
+        // Create destination_queue table (durable queue for destinations in queue mode)
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS destination_queue (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                channel_id TEXT NOT NULL,
+                destination_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER DEFAULT 0,
+                last_error TEXT,
+                next_attempt_at DATETIME NOT NULL,
+                created_at DATETIME NOT NULL
+            )"
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.add_column_if_missing("destination_queue", "metadata", "TEXT").await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_destination_queue_dest ON destination_queue(channel_id, destination_id, status, seq)")
+            .execute(&self.pool)
+            .await?;
+
         // Create processed_ids table for deduplication
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS processed_ids (
@@ -80,6 +135,22 @@ impl Database {
         Ok(())
     }
 
+    /// SQLite has no `ADD COLUMN IF NOT EXISTS`, so check the table info first
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
+            .await?;
+
+        let exists = rows.iter().any(|row| row.get::<String, _>("name") == column);
+        if !exists {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn save_channel(&self, id: &str, name: &str, config: serde_json::Value, frontend_schema: Option<serde_json::Value>) -> Result<(), sqlx::Error> {
         sqlx::query(
             "INSERT INTO channels (id, name, config, frontend_schema) 
@@ -125,3 +196,22 @@ impl Database {
         Ok(channels)
     }
 }
+
+/// Deletes the file of a `temp_database` when dropped
+#[cfg(test)]
+pub struct TempDatabaseFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl Drop for TempDatabaseFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A fresh database in a temporary file for tests; keep the guard alive as long as the database
+#[cfg(test)]
+pub async fn temp_database() -> (Database, TempDatabaseFile) {
+    let path = std::env::temp_dir().join(format!("mirthbr_test_{}.db", uuid::Uuid::new_v4()));
+    let db = Database::new(&format!("sqlite:{}", path.display())).await.unwrap();
+    (db, TempDatabaseFile(path))
+}
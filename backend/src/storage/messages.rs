@@ -1,7 +1,9 @@
 use sqlx::{sqlite::SqlitePool, Row};
+use std::collections::HashMap;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use crate::storage::models::DestinationConfig;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum MessageStatus {
@@ -10,17 +12,27 @@ pub enum MessageStatus {
     SENT,
     ERROR,
     FILTERED,
+    /// Some destinations succeeded and at least one failed
+    PARTIAL,
+    /// Retries exhausted (terminal)
+    FAILED,
+    /// Waiting in a destination queue
+    QUEUED,
 }
 
-impl ToString for MessageStatus {
-    fn to_string(&self) -> String {
-        match self {
-            MessageStatus::PENDING => "PENDING".to_string(),
-            MessageStatus::PROCESSING => "PROCESSING".to_string(),
-            MessageStatus::SENT => "SENT".to_string(),
-            MessageStatus::ERROR => "ERROR".to_string(),
-            MessageStatus::FILTERED => "FILTERED".to_string(),
-        }
+impl std::fmt::Display for MessageStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MessageStatus::PENDING => "PENDING",
+            MessageStatus::PROCESSING => "PROCESSING",
+            MessageStatus::SENT => "SENT",
+            MessageStatus::ERROR => "ERROR",
+            MessageStatus::FILTERED => "FILTERED",
+            MessageStatus::PARTIAL => "PARTIAL",
+            MessageStatus::FAILED => "FAILED",
+            MessageStatus::QUEUED => "QUEUED",
+        };
+        write!(f, "{}", s)
     }
 }
 
@@ -32,6 +44,9 @@ impl From<String> for MessageStatus {
             "SENT" => MessageStatus::SENT,
             "ERROR" => MessageStatus::ERROR,
             "FILTERED" => MessageStatus::FILTERED,
+            "PARTIAL" => MessageStatus::PARTIAL,
+            "FAILED" => MessageStatus::FAILED,
+            "QUEUED" => MessageStatus::QUEUED,
             _ => MessageStatus::ERROR, // Default to error if unknown
         }
     }
@@ -47,6 +62,29 @@ pub struct MessageRecord {
     pub retry_count: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Outcome of the error destination (DLQ) delivery, if the message was sent there
+    pub dlq_status: Option<String>,
+    pub dlq_error: Option<String>,
+    /// Source metadata (`http.*`, `client_cert.*`), restored when the message is re-run
+    pub metadata: HashMap<String, String>,
+}
+
+/// Delivery state of a message for one destination (connector-level record)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageDestinationRecord {
+    pub message_id: String,
+    pub destination_id: String,
+    pub destination_name: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub response: Option<String>,
+    /// Transformed payload delivered to this destination (resent as-is on retry)
+    pub content: Option<String>,
+    /// Metadata the payload was delivered with (template variables), restored on retry
+    pub metadata: HashMap<String, String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Clone)]
@@ -80,6 +118,16 @@ impl MessageStore {
         Ok(id)
     }
 
+    /// Keep the metadata the message arrived with, so retries and recovery see the same variables
+    pub async fn save_metadata(&self, id: &str, metadata: &HashMap<String, String>) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE messages SET metadata = ? WHERE id = ?")
+            .bind(encode_metadata(metadata))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn update_status(&self, id: &str, status: MessageStatus, error_message: Option<String>) -> Result<(), sqlx::Error> {
         let now = Utc::now();
         let status_str = status.to_string();
@@ -104,6 +152,17 @@ impl MessageStore {
         Ok(())
     }
 
+    pub async fn update_dlq_status(&self, id: &str, status: MessageStatus, error: Option<String>) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE messages SET dlq_status = ?, dlq_error = ?, updated_at = ? WHERE id = ?")
+            .bind(status.to_string())
+            .bind(error)
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn increment_retry(&self, id: &str) -> Result<(), sqlx::Error> {
         sqlx::query("UPDATE messages SET retry_count = retry_count + 1 WHERE id = ?")
             .bind(id)
@@ -180,9 +239,154 @@ impl MessageStore {
             retry_count: row.get("retry_count"),
             created_at: row.get("created_at"),
             updated_at: row.get("updated_at"),
+            dlq_status: row.get("dlq_status"),
+            dlq_error: row.get("dlq_error"),
+            metadata: decode_metadata(row.get("metadata")),
         }
     }
     
+    /// Record the outcome of a delivery attempt to a destination (increments the attempt count)
+    #[allow(clippy::too_many_arguments)]
+    pub async fn record_destination_attempt(
+        &self,
+        message_id: &str,
+        destination: &DestinationConfig,
+        content: &str,
+        metadata: &HashMap<String, String>,
+        status: MessageStatus,
+        error: Option<String>,
+        response: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+
+        sqlx::query(
+            "INSERT INTO message_destinations (message_id, destination_id, destination_name, status, attempts, last_error, response, content, metadata, created_at, updated_at)
+             VALUES (?, ?, ?, ?, 1, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(message_id, destination_id) DO UPDATE SET
+                destination_name = excluded.destination_name,
+                status = excluded.status,
+                attempts = message_destinations.attempts + 1,
+                last_error = excluded.last_error,
+                response = excluded.response,
+                content = excluded.content,
+                metadata = excluded.metadata,
+                updated_at = excluded.updated_at"
+        )
+        .bind(message_id)
+        .bind(&destination.id)
+        .bind(&destination.name)
+        .bind(status.to_string())
+        .bind(error)
+        .bind(response)
+        .bind(content)
+        .bind(encode_metadata(metadata))
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Set a destination status without counting a delivery attempt (e.g. not selected by the router)
+    pub async fn set_destination_status(&self, message_id: &str, destination_id: &str, destination_name: &str, status: MessageStatus) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+
+        sqlx::query(
+            "INSERT INTO message_destinations (message_id, destination_id, destination_name, status, attempts, created_at, updated_at)
+             VALUES (?, ?, ?, ?, 0, ?, ?)
+             ON CONFLICT(message_id, destination_id) DO UPDATE SET
+                status = excluded.status,
+                updated_at = excluded.updated_at"
+        )
+        .bind(message_id)
+        .bind(destination_id)
+        .bind(destination_name)
+        .bind(status.to_string())
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_message_destinations(&self, message_id: &str) -> Result<Vec<MessageDestinationRecord>, sqlx::Error> {
+        let rows = sqlx::query("SELECT * FROM message_destinations WHERE message_id = ? ORDER BY created_at ASC")
+            .bind(message_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| self.row_to_destination(row)).collect())
+    }
+
+    /// Destinations of a message whose last delivery attempt failed, whether retries remain or not
+    pub async fn get_failed_destinations(&self, message_id: &str) -> Result<Vec<MessageDestinationRecord>, sqlx::Error> {
+        let rows = sqlx::query("SELECT * FROM message_destinations WHERE message_id = ? AND status IN ('ERROR', 'FAILED')")
+            .bind(message_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| self.row_to_destination(row)).collect())
+    }
+
+    /// Recompute the overall message status from its destination records
+    pub async fn refresh_status_from_destinations(&self, message_id: &str) -> Result<MessageStatus, sqlx::Error> {
+        let destinations = self.get_message_destinations(message_id).await?;
+        let attempted: Vec<&MessageDestinationRecord> = destinations.iter()
+            .filter(|d| d.status != MessageStatus::FILTERED.to_string())
+            .collect();
+
+        let failed = MessageStatus::FAILED.to_string();
+        let queued = attempted.iter().any(|d| d.status == MessageStatus::QUEUED.to_string());
+        let retrying = queued || attempted.iter().any(|d| d.status == MessageStatus::ERROR.to_string());
+        let errors: Vec<String> = attempted.iter()
+            .filter(|d| d.status == MessageStatus::ERROR.to_string() || d.status == failed)
+            .map(|d| format!("Destination {} failed: {}", d.destination_name, d.last_error.as_deref().unwrap_or("unknown error")))
+            .collect();
+
+        // FAILED destinations are terminal; once every destination failed and nothing is left to
+        // retry the message is FAILED too. Queued destinations are still in flight.
+        let status = if errors.is_empty() && queued {
+            MessageStatus::QUEUED
+        } else if errors.is_empty() {
+            MessageStatus::SENT
+        } else if errors.len() < attempted.len() {
+            MessageStatus::PARTIAL
+        } else if !retrying {
+            MessageStatus::FAILED
+        } else {
+            MessageStatus::ERROR
+        };
+
+        let error_message = if errors.is_empty() { None } else { Some(errors.join("; ")) };
+        sqlx::query("UPDATE messages SET status = ?, error_message = ?, updated_at = ? WHERE id = ?")
+            .bind(status.to_string())
+            .bind(error_message)
+            .bind(Utc::now())
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(status)
+    }
+
+    fn row_to_destination(&self, row: sqlx::sqlite::SqliteRow) -> MessageDestinationRecord {
+        MessageDestinationRecord {
+            message_id: row.get("message_id"),
+            destination_id: row.get("destination_id"),
+            destination_name: row.get("destination_name"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+            response: row.get("response"),
+            content: row.get("content"),
+            metadata: decode_metadata(row.get("metadata")),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
     // Pruning
     pub async fn prune_messages(&self, days: i64) -> Result<u64, sqlx::Error> {
         // Delete messages older than X days
@@ -190,7 +394,25 @@ impl MessageStore {
             .bind(format!("-{} days", days))
             .execute(&self.pool)
             .await?;
+
+        sqlx::query("DELETE FROM message_destinations WHERE message_id NOT IN (SELECT id FROM messages)")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM destination_queue WHERE message_id NOT IN (SELECT id FROM messages)")
+            .execute(&self.pool)
+            .await?;
             
         Ok(result.rows_affected())
     }
 }
+
+/// Metadata is stored as a JSON object
+pub(crate) fn encode_metadata(metadata: &HashMap<String, String>) -> String {
+    serde_json::to_string(metadata).unwrap_or_else(|_| "{}".to_string())
+}
+
+/// Rows written before metadata was stored have none
+pub(crate) fn decode_metadata(json: Option<String>) -> HashMap<String, String> {
+    json.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default()
+}
@@ -3,6 +3,7 @@ pub mod logs;
 pub mod db;
 pub mod messages;
 pub mod deduplication;
+pub mod queue;
 
 pub fn init() {
     println!("Storage init");
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -7,9 +8,61 @@ pub struct Mapping {
     pub target: String,
 }
 
+/// Execution limits of a Lua script, checked by a VM hook while the script runs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LuaLimits {
+    /// Lua VM instructions a single run may execute
+    #[serde(default = "default_max_instructions")]
+    pub max_instructions: u64,
+    /// Wall-clock time a single run may take
+    #[serde(default = "default_lua_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_max_instructions() -> u64 {
+    10_000_000
+}
+
+fn default_lua_timeout_ms() -> u64 {
+    5_000
+}
+
+impl Default for LuaLimits {
+    fn default() -> Self {
+        Self {
+            max_instructions: default_max_instructions(),
+            timeout_ms: default_lua_timeout_ms(),
+        }
+    }
+}
+
+/// Per-channel overrides for the `fhir_r4` output of an `hl7_parser` processor. Each table maps an
+/// HL7 v2 code to a FHIR code and is layered over the built-in table.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FhirMappings {
+    /// PID-8 administrative sex -> Patient.gender
+    pub gender: HashMap<String, String>,
+    /// PV1-2 patient class -> Encounter.class (v3 ActCode)
+    pub encounter_class: HashMap<String, String>,
+    /// OBX-11 result status -> Observation.status
+    pub observation_status: HashMap<String, String>,
+    /// OBR-25 result status -> DiagnosticReport.status
+    pub report_status: HashMap<String, String>,
+    /// OBX-8 abnormal flag -> Observation.interpretation (v3 ObservationInterpretation)
+    pub interpretation: HashMap<String, String>,
+    /// Assigning authority or namespace (CX-4, EI-2) -> Identifier.system
+    pub identifier_systems: HashMap<String, String>,
+    /// Coding system (CE-3) -> Coding.system
+    pub coding_systems: HashMap<String, String>,
+    /// Offset such as `-03:00` for timestamps that carry none; without it they are cut to dates
+    pub timezone: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Route {
     pub name: String,
+    /// Lua condition, evaluated the same way as a filter (`return true` to match)
     pub condition: String,
 }
 
@@ -24,14 +77,166 @@ pub struct Channel {
     pub destinations: Vec<DestinationConfig>,
     #[serde(default)]
     pub error_destination: Option<DestinationConfig>,
+    /// Maximum number of retries after the first attempt (`null` = retry forever)
     #[serde(default = "default_max_retries")]
     pub max_retries: Option<i32>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub destination_mode: DestinationMode,
+    /// Messages processed concurrently by this channel
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Messages with the same key (JSON path like `patient.id` or HL7 field like `PID-3`) are
+    /// processed in arrival order; different keys run in parallel
+    #[serde(default)]
+    pub ordering_key: Option<String>,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+/// When an MLLP (`tcp_listener`) source acknowledges a message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    /// AA as soon as the message is persisted, before it is processed
+    #[default]
+    Immediate,
+    /// Once the pipeline has finished: AA when processed, AR when filtered, AE when it failed
+    AfterProcessing,
+    /// No ACK at all
+    None,
+}
+
+/// How messages are delimited on a TCP connection
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Framing {
+    /// `<VT>message<FS><CR>`
+    #[default]
+    Mllp,
+    /// Custom start (may be empty) and end byte sequences, e.g. `"end": [13, 10]`
+    Delimited {
+        #[serde(default)]
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    /// Big-endian length of the message, in 2 or 4 bytes, before each message
+    LengthPrefixed {
+        #[serde(default = "default_length_width")]
+        width: u8,
+    },
+    /// ASTM E1381 low-level protocol (ENQ, numbered frames with checksums, EOT)
+    Astm,
+    /// Records of exactly `size` bytes, right-padded with `padding`
+    Fixed {
+        size: usize,
+        #[serde(default = "default_padding")]
+        padding: u8,
+    },
+}
+
+fn default_length_width() -> u8 {
+    4
+}
+
+fn default_padding() -> u8 {
+    b' '
+}
+
+/// How a message is handed to the channel's destinations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DestinationMode {
+    /// One destination after the other, in order
+    #[default]
+    Sequential,
+    /// All destinations at once; respond when every one has finished
+    WaitAll,
+    /// All destinations at once; respond as soon as one succeeds (or all failed)
+    FirstSuccess,
+    /// All destinations at once; respond immediately, outcomes are only recorded
+    FireAndForget,
 }
 
 fn default_max_retries() -> Option<i32> {
     Some(3)
 }
 
+/// Backoff between retries: `initial_delay_ms * multiplier^retry`, capped at `max_delay_ms`,
+/// then spread by +/- `jitter` (fraction of the delay). The attempt cap is `Channel.max_retries`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryPolicy {
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    #[serde(default)]
+    pub jitter: f64,
+}
+
+fn default_initial_delay_ms() -> u64 {
+    60_000
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_max_delay_ms() -> u64 {
+    3_600_000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: default_initial_delay_ms(),
+            multiplier: default_multiplier(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (0-based). `seed` keeps the jitter stable for a given
+    /// message and attempt, so repeated scans of the retry worker agree on when it is due.
+    pub fn delay(&self, retry: u32, seed: &str) -> std::time::Duration {
+        use std::hash::{Hash, Hasher};
+
+        let base = self.initial_delay_ms as f64 * self.multiplier.max(1.0).powi(retry.min(64) as i32);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            (seed, retry).hash(&mut hasher);
+            // Map the hash to [-1.0, 1.0]
+            let unit = (hasher.finish() % 10_001) as f64 / 5_000.0 - 1.0;
+            1.0 + jitter * unit
+        } else {
+            1.0
+        };
+
+        // Cap after jitter so no delay exceeds `max_delay_ms`
+        let delay = (base * factor).min(self.max_delay_ms as f64);
+        std::time::Duration::from_millis(delay.max(0.0) as u64)
+    }
+}
+
+impl Channel {
+    /// Whether another retry is allowed after `retries_done` retries
+    pub fn can_retry(&self, retries_done: i32) -> bool {
+        match self.max_retries {
+            Some(max) => retries_done < max,
+            None => true,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MetricUpdate {
     pub channel_id: String,
@@ -40,13 +245,139 @@ pub struct MetricUpdate {
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
+fn default_http_methods() -> Vec<String> {
+    vec!["POST".to_string()]
+}
+
+/// How an HTTP listener turns the request body into message content
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyEncoding {
+    /// UTF-8 text; other bodies are rejected
+    #[default]
+    Text,
+    /// Always base64, for binary payloads (PDF, images, DICOM)
+    Base64,
+    /// Text when the body is UTF-8, base64 otherwise
+    Auto,
+}
+
+/// How an HTTP listener answers requests
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpResponseConfig {
+    #[serde(default)]
+    pub mode: HttpResponseMode,
+    /// How long a sync reply waits for the pipeline
+    #[serde(default = "default_http_response_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Status of a reply to a filtered message
+    #[serde(default = "default_filtered_status")]
+    pub filtered_status: u16,
+    /// Reply with the response body of this destination (by name), when it has one
+    #[serde(default)]
+    pub destination: Option<String>,
+    /// Content-Type of a reply taken from `destination`
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// Lua that sees `msg` and `response` and may return a replacement reply
+    #[serde(default)]
+    pub script: Option<String>,
+}
+
+fn default_http_response_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_filtered_status() -> u16 {
+    204
+}
+
+impl Default for HttpResponseConfig {
+    fn default() -> Self {
+        Self {
+            mode: HttpResponseMode::default(),
+            timeout_ms: default_http_response_timeout_ms(),
+            filtered_status: default_filtered_status(),
+            destination: None,
+            content_type: None,
+            script: None,
+        }
+    }
+}
+
+/// When an HTTP listener replies
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpResponseMode {
+    /// Once the pipeline is done with the message
+    #[default]
+    Sync,
+    /// `202 Accepted` with the message ID as soon as it is persisted
+    Async,
+}
+
+/// Client certificate authentication of a TLS listener
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientAuth {
+    /// PEM CA bundle client certificates must chain to
+    pub ca_path: String,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// Glob patterns (`*` wildcard, case-insensitive) of accepted peers, matched against the
+    /// subject (`O=Acme, CN=hl7-gw`), each subject attribute (`CN=hl7-*`) and each SAN
+    /// (`DNS:*.acme.org`, `email:...`, `URI:...`, `IP:...`). Empty accepts any trusted certificate.
+    #[serde(default)]
+    pub allowed_subjects: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Connections without a trusted client certificate are refused
+    #[default]
+    Required,
+    /// Clients may connect without a certificate; one that is presented must be trusted
+    Optional,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", content = "config")]
 pub enum SourceConfig {
     #[serde(rename = "http_listener")]
-    Http { port: u16, path: Option<String>, cert_path: Option<String>, key_path: Option<String> },
+    Http {
+        port: u16,
+        /// May contain `:name` parameters and end with a `*name` wildcard
+        path: Option<String>,
+        /// HTTP methods accepted on `path`
+        #[serde(default = "default_http_methods")]
+        methods: Vec<String>,
+        #[serde(default)]
+        body_encoding: BodyEncoding,
+        #[serde(default)]
+        response: HttpResponseConfig,
+        cert_path: Option<String>,
+        key_path: Option<String>,
+        /// Mutual TLS (with `cert_path`/`key_path`)
+        #[serde(default)]
+        client_auth: Option<ClientAuth>,
+    },
     #[serde(rename = "tcp_listener")]
-    Tcp { port: u16, cert_path: Option<String>, key_path: Option<String> },
+    Tcp {
+        port: u16,
+        cert_path: Option<String>,
+        key_path: Option<String>,
+        /// Mutual TLS (with `cert_path`/`key_path`)
+        #[serde(default)]
+        client_auth: Option<ClientAuth>,
+        /// When the MLLP ACK is sent
+        #[serde(default)]
+        ack_mode: AckMode,
+        /// Lua run before each ACK is sent; sees `msg` and `ack` and may return a replacement ACK
+        #[serde(default)]
+        ack_script: Option<String>,
+        #[serde(default)]
+        framing: Framing,
+    },
     #[serde(rename = "file_reader")]
     File { path: String, pattern: Option<String> },
     #[serde(rename = "database_poller")]
@@ -67,38 +398,272 @@ pub struct ProcessorConfig {
 #[serde(tag = "type", content = "config")]
 pub enum ProcessorType {
     #[serde(rename = "lua_script")]
-    Lua { code: String },
+    Lua {
+        code: String,
+        #[serde(default)]
+        limits: LuaLimits,
+    },
     #[serde(rename = "mapper")]
     Mapper { mappings: Vec<Mapping> },
     #[serde(rename = "filter")]
-    Filter { condition: String },
+    Filter {
+        condition: String,
+        #[serde(default)]
+        limits: LuaLimits,
+    },
     #[serde(rename = "router")]
-    Router { routes: Vec<Route> },
+    Router {
+        routes: Vec<Route>,
+        /// Route used when no condition matches. Without it, unmatched messages are filtered.
+        #[serde(default)]
+        default_route: Option<String>,
+        /// Limits applied to each route condition
+        #[serde(default)]
+        limits: LuaLimits,
+    },
+    /// Converts between `hl7v2` (ER7), `xml`, `hl7_json` and the output-only `json` and `fhir_r4`
     #[serde(rename = "hl7_parser")]
-    Hl7 { inputFormat: String, outputFormat: String },
+    Hl7 {
+        #[serde(rename = "inputFormat")]
+        input_format: String,
+        #[serde(rename = "outputFormat")]
+        output_format: String,
+        #[serde(rename = "fhirMappings", default)]
+        fhir_mappings: Box<FhirMappings>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DestinationConfig {
     pub id: String,
     pub name: String,
+    /// Router route names this destination listens to. Empty means it receives every message.
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Deliver through a durable queue drained by background workers instead of inline
+    #[serde(default)]
+    pub queue: Option<QueueConfig>,
+    /// Give up on a delivery attempt after this long (counts as a failure)
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
     #[serde(flatten)]
     pub kind: DestinationType,
 }
 
+/// Destination queue settings. Failed deliveries stay queued and are retried by the queue
+/// workers with the channel's `retry_policy` (or the override below) up to `max_retries`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueConfig {
+    /// Number of worker tasks draining the queue
+    #[serde(default = "default_queue_concurrency")]
+    pub concurrency: usize,
+    /// Deliver strictly in arrival order: a failing message blocks the ones behind it.
+    /// Ordered queues always use a single worker.
+    #[serde(default = "default_queue_ordered")]
+    pub ordered: bool,
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
+}
+
+fn default_queue_concurrency() -> usize {
+    1
+}
+
+fn default_queue_ordered() -> bool {
+    true
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: default_queue_concurrency(),
+            ordered: default_queue_ordered(),
+            retry_policy: None,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Worker count actually used (ordered queues are drained by a single worker)
+    pub fn workers(&self) -> usize {
+        if self.ordered { 1 } else { self.concurrency.max(1) }
+    }
+}
+
+/// TLS for a TCP sender
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TcpClientTls {
+    /// PEM CA bundle the server certificate must chain to; the public web roots when absent
+    pub ca_path: Option<String>,
+    /// Client certificate (PEM) for mutual TLS, together with `key_path`
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// Name the server certificate must match; the host by default
+    pub server_name: Option<String>,
+}
+
+/// What a TCP sender expects back for each message (ASTM links acknowledge frame by frame
+/// and ignore this)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpResponseMode {
+    /// An HL7 ACK; AE/AR/CE/CR and non-ACK replies fail the delivery
+    #[default]
+    Hl7Ack,
+    /// One framed reply of any content, returned as the destination response
+    Raw,
+    /// Nothing: the message is delivered once written
+    None,
+}
+
+/// Timeouts of a TCP sender, in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TcpTimeouts {
+    /// Connecting, TLS handshake included
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_ms: u64,
+    /// Writing one message
+    #[serde(default = "default_send_timeout_ms")]
+    pub send_ms: u64,
+    /// Waiting for the ACK
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_ms: u64,
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_send_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    10000
+}
+
+impl Default for TcpTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_ms: default_connect_timeout_ms(),
+            send_ms: default_send_timeout_ms(),
+            ack_ms: default_ack_timeout_ms(),
+        }
+    }
+}
+
+/// A header or query parameter of an HTTP sender
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpParam {
+    pub name: String,
+    pub value: String,
+}
+
+/// How an HTTP sender authenticates (secrets are redacted from `Debug` output)
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HttpAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+    /// A key sent as a header or a query parameter named `name`
+    ApiKey {
+        name: String,
+        value: String,
+        #[serde(default)]
+        location: ApiKeyLocation,
+    },
+    /// OAuth2 client credentials grant; tokens are cached until shortly before they expire
+    Oauth2 {
+        token_url: String,
+        client_id: String,
+        client_secret: String,
+        #[serde(default)]
+        scope: Option<String>,
+    },
+}
+
+impl std::fmt::Debug for HttpAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const REDACTED: &str = "<redacted>";
+        match self {
+            Self::Basic { username, .. } => f.debug_struct("Basic")
+                .field("username", username)
+                .field("password", &REDACTED)
+                .finish(),
+            Self::Bearer { .. } => f.debug_struct("Bearer").field("token", &REDACTED).finish(),
+            Self::ApiKey { name, location, .. } => f.debug_struct("ApiKey")
+                .field("name", name)
+                .field("value", &REDACTED)
+                .field("location", location)
+                .finish(),
+            Self::Oauth2 { token_url, client_id, scope, .. } => f.debug_struct("Oauth2")
+                .field("token_url", token_url)
+                .field("client_id", client_id)
+                .field("client_secret", &REDACTED)
+                .field("scope", scope)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyLocation {
+    #[default]
+    Header,
+    Query,
+}
+
+fn default_http_content_type() -> String {
+    "application/json".to_string()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", content = "config")]
 pub enum DestinationType {
     #[serde(rename = "http_sender")]
-    Http { url: String, method: String },
+    Http {
+        /// May contain `${...}` message variables
+        url: String,
+        method: String,
+        /// Sent as-is unless the value contains `${...}` message variables
+        #[serde(default)]
+        headers: Vec<HttpParam>,
+        /// Query parameters added to the URL, templated like headers
+        #[serde(default)]
+        query: Vec<HttpParam>,
+        #[serde(default)]
+        auth: Option<HttpAuth>,
+        #[serde(default = "default_http_content_type")]
+        content_type: String,
+    },
     #[serde(rename = "file_writer")]
     File { path: String, filename: Option<String>, append: Option<bool>, encoding: Option<String> },
     #[serde(rename = "database_writer")]
     Database { url: String, table: Option<String>, mode: String, query: Option<String> },
     #[serde(rename = "tcp_sender")]
-    Tcp { host: String, port: u16 },
+    Tcp {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        framing: Framing,
+        /// Reuse one connection for every message instead of connecting per message
+        #[serde(default)]
+        keep_connection_open: bool,
+        #[serde(default)]
+        tls: Option<TcpClientTls>,
+        #[serde(default)]
+        timeouts: TcpTimeouts,
+        /// What the peer answers to each message
+        #[serde(default)]
+        response: TcpResponseMode,
+    },
     #[serde(rename = "lua_script")]
-    Lua { code: String },
+    Lua {
+        code: String,
+        #[serde(default)]
+        limits: LuaLimits,
+    },
 }
 
 
@@ -0,0 +1,156 @@
+use sqlx::{sqlite::SqlitePool, Row};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use crate::storage::messages::{decode_metadata, encode_metadata};
+
+/// A transformed message waiting in a destination queue
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub seq: i64,
+    pub channel_id: String,
+    pub destination_id: String,
+    pub message_id: String,
+    pub content: String,
+    /// Metadata of the message when it was enqueued (template variables)
+    pub metadata: HashMap<String, String>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+/// Durable per-destination queues (`destination_queue` table), drained in `seq` order
+#[derive(Clone)]
+pub struct QueueStore {
+    pool: SqlitePool,
+}
+
+impl QueueStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn enqueue(&self, channel_id: &str, destination_id: &str, message_id: &str, content: &str, metadata: &HashMap<String, String>) -> Result<i64, sqlx::Error> {
+        let now = Utc::now();
+
+        let result = sqlx::query(
+            "INSERT INTO destination_queue (channel_id, destination_id, message_id, content, metadata, status, attempts, next_attempt_at, created_at)
+             VALUES (?, ?, ?, ?, ?, 'QUEUED', 0, ?, ?)"
+        )
+        .bind(channel_id)
+        .bind(destination_id)
+        .bind(message_id)
+        .bind(content)
+        .bind(encode_metadata(metadata))
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Claim the next entry that is due for delivery. In an ordered queue only the head can be
+    /// claimed, so a failing entry holds back everything behind it until it is delivered or dropped.
+    pub async fn claim_next(&self, channel_id: &str, destination_id: &str, ordered: bool) -> Result<Option<QueueEntry>, sqlx::Error> {
+        let now = Utc::now();
+
+        loop {
+            let row = if ordered {
+                sqlx::query(
+                    "SELECT * FROM destination_queue
+                     WHERE channel_id = ? AND destination_id = ? AND status IN ('QUEUED', 'SENDING')
+                     ORDER BY seq ASC LIMIT 1"
+                )
+                .bind(channel_id)
+                .bind(destination_id)
+                .fetch_optional(&self.pool)
+                .await?
+            } else {
+                sqlx::query(
+                    "SELECT * FROM destination_queue
+                     WHERE channel_id = ? AND destination_id = ? AND status = 'QUEUED' AND next_attempt_at <= ?
+                     ORDER BY seq ASC LIMIT 1"
+                )
+                .bind(channel_id)
+                .bind(destination_id)
+                .bind(now)
+                .fetch_optional(&self.pool)
+                .await?
+            };
+
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let status: String = row.get("status");
+            let entry = Self::row_to_entry(row);
+            if status != "QUEUED" || entry.next_attempt_at > now {
+                return Ok(None);
+            }
+
+            // Another worker may have claimed it between the select and the update
+            let claimed = sqlx::query("UPDATE destination_queue SET status = 'SENDING' WHERE seq = ? AND status = 'QUEUED'")
+                .bind(entry.seq)
+                .execute(&self.pool)
+                .await?;
+            if claimed.rows_affected() == 1 {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    /// Put a claimed entry back after a failed attempt, to be retried at `next_attempt_at`
+    pub async fn reschedule(&self, seq: i64, error: &str, next_attempt_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE destination_queue SET status = 'QUEUED', attempts = attempts + 1, last_error = ?, next_attempt_at = ? WHERE seq = ?"
+        )
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(seq)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Drop an entry once it was delivered or its retries ran out
+    pub async fn remove(&self, seq: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM destination_queue WHERE seq = ?")
+            .bind(seq)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Release entries left in flight by a previous run (e.g. after a crash or redeploy)
+    pub async fn release_in_flight(&self, channel_id: &str, destination_id: &str) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("UPDATE destination_queue SET status = 'QUEUED' WHERE channel_id = ? AND destination_id = ? AND status = 'SENDING'")
+            .bind(channel_id)
+            .bind(destination_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Number of entries waiting in a destination queue
+    pub async fn depth(&self, channel_id: &str, destination_id: &str) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query("SELECT COUNT(*) AS depth FROM destination_queue WHERE channel_id = ? AND destination_id = ?")
+            .bind(channel_id)
+            .bind(destination_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get("depth"))
+    }
+
+    fn row_to_entry(row: sqlx::sqlite::SqliteRow) -> QueueEntry {
+        QueueEntry {
+            seq: row.get("seq"),
+            channel_id: row.get("channel_id"),
+            destination_id: row.get("destination_id"),
+            message_id: row.get("message_id"),
+            content: row.get("content"),
+            metadata: decode_metadata(row.get("metadata")),
+            attempts: row.get("attempts"),
+            last_error: row.get("last_error"),
+            next_attempt_at: row.get("next_attempt_at"),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use mirthbr_backend::hl7::Conversion;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+// Golden files for the `fhir_r4` output, one per HL7 payload in samples/test_node_examples.json.
+// Run with UPDATE_GOLDEN=1 to rewrite them after an intended mapping change.
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn sample_messages() -> Vec<(String, String)> {
+    let path = manifest_dir().join("../samples/test_node_examples.json");
+    let samples: Value = serde_json::from_str(&std::fs::read_to_string(&path).expect("Failed to read samples")).unwrap();
+    samples["testPayloads"].as_object().unwrap()
+        .iter()
+        .filter(|(_, payload)| payload["format"] == "hl7")
+        .map(|(name, payload)| (name.clone(), payload["content"].as_str().unwrap().to_string()))
+        .collect()
+}
+
+fn check_golden(path: &Path, actual: &Value) {
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, serde_json::to_string_pretty(actual).unwrap() + "\n").unwrap();
+        return;
+    }
+    let expected: Value = serde_json::from_str(
+        &std::fs::read_to_string(path).unwrap_or_else(|_| panic!("Missing golden file {}", path.display()))
+    ).unwrap();
+    assert_eq!(actual, &expected, "{} differs, rerun with UPDATE_GOLDEN=1 if the change is intended", path.display());
+}
+
+#[test]
+fn test_sample_messages_match_fhir_golden_files() {
+    let conversion = Conversion::new("hl7v2", "fhir_r4").unwrap();
+    let messages = sample_messages();
+    assert!(messages.len() >= 3, "Expected HL7 payloads in the samples");
+
+    let mut converted = 0;
+    for (name, content) in messages {
+        let message_type = content.split(['\r', '\n']).next().unwrap().split('|').nth(8).unwrap().to_string();
+        match conversion.convert(&content) {
+            Ok(bundle) => {
+                let bundle: Value = serde_json::from_str(&bundle).unwrap();
+                check_golden(&manifest_dir().join("tests/golden/fhir").join(format!("{}.json", name)), &bundle);
+                converted += 1;
+            },
+            // Only ADT^A01/A04/A08 and ORU^R01 are mapped
+            Err(e) => assert!(
+                !["ADT^A01", "ADT^A04", "ADT^A08", "ORU^R01"].contains(&message_type.as_str()),
+                "{} ({}) failed: {}", name, message_type, e
+            ),
+        }
+    }
+    assert!(converted >= 3);
+}
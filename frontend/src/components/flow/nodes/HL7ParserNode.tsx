@@ -12,10 +12,10 @@ interface HL7ParserData {
 
 const formatOptions = [
     { value: 'hl7v2', label: 'HL7 v2.x' },
-    { value: 'hl7v3', label: 'HL7 v3' },
-    { value: 'fhir', label: 'FHIR JSON' },
-    { value: 'json', label: 'JSON' },
-    { value: 'xml', label: 'XML' },
+    { value: 'xml', label: 'HL7 XML' },
+    { value: 'hl7_json', label: 'JSON (structured)' },
+    { value: 'json', label: 'JSON (by segment)' },
+    { value: 'fhir_r4', label: 'FHIR R4 Bundle' },
 ];
 
 /**
@@ -77,7 +77,7 @@ const HL7ParserNode = ({ data }: NodeProps<HL7ParserData>) => {
                 <div className="p-2 rounded-lg bg-[var(--background)]/50 border border-[var(--glass-border)]">
                     <div className="text-xs text-[var(--foreground-muted)] mb-1">Output Format</div>
                     <InlineEdit
-                        value={data.outputFormat || 'fhir'}
+                        value={data.outputFormat || 'json'}
                         onChange={(v) => handleChange('outputFormat', v)}
                         type="select"
                         options={formatOptions}
@@ -10,7 +10,7 @@ export const nodeDefaults: Record<string, NodeData> = {
     mapper: { label: 'Field Mapper', mappings: [{ source: 'field1', target: 'newField1' }] },
     filter: { label: 'Message Filter', condition: 'msg.type == "HL7"' },
     router: { label: 'Content Router', routes: [{ name: 'Route A', condition: '' }] },
-    hl7Parser: { label: 'HL7 Parser', inputFormat: 'hl7v2', outputFormat: 'fhir' },
+    hl7Parser: { label: 'HL7 Parser', inputFormat: 'hl7v2', outputFormat: 'json' },
     // Destinations
     fileWriter: { label: 'File Writer', path: './output', filename: '${timestamp}.txt' },
     httpSender: { label: 'HTTP Sender', url: 'https://api.example.com', method: 'POST' },
@@ -12,9 +12,35 @@ interface BackChannel {
     max_retries?: number;
 }
 
+type ClientAuth = {
+    ca_path: string;
+    mode?: 'required' | 'optional';
+    allowed_subjects?: string[];
+};
+
+type HttpResponseConfig = {
+    mode?: 'sync' | 'async';
+    timeout_ms?: number;
+    filtered_status?: number;
+    destination?: string;
+    content_type?: string;
+    script?: string;
+};
+
+type TcpFraming =
+    | { mode: 'mllp' }
+    | { mode: 'delimited'; start?: number[]; end: number[] }
+    | { mode: 'length_prefixed'; width?: 2 | 4 }
+    | { mode: 'astm' }
+    | { mode: 'fixed'; size: number; padding?: number };
+
 type SourceConfig =
-    | { type: 'http_listener'; config: { port: number; path?: string; cert_path?: string; key_path?: string } }
-    | { type: 'tcp_listener'; config: { port: number; cert_path?: string; key_path?: string } }
+    | { type: 'http_listener'; config: {
+        port: number; path?: string; methods?: string[]; body_encoding?: 'text' | 'base64' | 'auto';
+        response?: HttpResponseConfig;
+        cert_path?: string; key_path?: string; client_auth?: ClientAuth;
+    } }
+    | { type: 'tcp_listener'; config: { port: number; cert_path?: string; key_path?: string; client_auth?: ClientAuth; ack_mode?: 'immediate' | 'after_processing' | 'none'; ack_script?: string; framing?: TcpFraming } }
     | { type: 'file_reader'; config: { path: string; pattern?: string } }
     | { type: 'database_poller'; config: { query: string; interval: number } }
     | { type: 'test_source'; config: { payload_type: string; payload: string } };
@@ -27,17 +53,33 @@ type ProcessorConfig = {
         | { type: 'mapper'; config: { mappings: { source: string; target: string }[] } }
         | { type: 'filter'; config: { condition: string } }
         | { type: 'router'; config: { routes: { name: string; condition: string }[] } }
-        | { type: 'hl7_parser'; config: { inputFormat: string; outputFormat: string } }
+        | { type: 'hl7_parser'; config: { inputFormat: string; outputFormat: string; fhirMappings?: Record<string, unknown> } }
     );
 
+type HttpParam = { name: string; value: string };
+
+type HttpAuth =
+    | { type: 'basic'; username: string; password: string }
+    | { type: 'bearer'; token: string }
+    | { type: 'api_key'; name: string; value: string; location?: 'header' | 'query' }
+    | { type: 'oauth2'; token_url: string; client_id: string; client_secret: string; scope?: string };
+
 type DestinationConfig = {
     id: string;
     name: string;
 } & (
-        | { type: 'http_sender'; config: { url: string; method: string } }
+        | { type: 'http_sender'; config: {
+            url: string; method: string; headers?: HttpParam[]; query?: HttpParam[];
+            auth?: HttpAuth; content_type?: string;
+        } }
         | { type: 'file_writer'; config: { path: string; filename?: string; append?: boolean; encoding?: string } }
         | { type: 'database_writer'; config: { table?: string; mode: string; query?: string } }
-        | { type: 'tcp_sender'; config: { host: string; port: number } }
+        | { type: 'tcp_sender'; config: {
+            host: string; port: number; framing?: TcpFraming; keep_connection_open?: boolean;
+            tls?: { ca_path?: string; cert_path?: string; key_path?: string; server_name?: string };
+            timeouts?: { connect_ms?: number; send_ms?: number; ack_ms?: number };
+            response?: 'hl7_ack' | 'raw' | 'none';
+        } }
         | { type: 'lua_script'; config: { code: string } }
     );
 
@@ -82,8 +124,12 @@ function buildSourceConfig(node: Node, nodes: Node[], edges: Edge[]): SourceConf
                 config: {
                     port: Number(resolveConfigValue(node, 'port', 'config-port', nodes, edges)) || 1234,
                     path: resolveConfigValue(node, 'path', 'config-path', nodes, edges),
+                    methods: data.methods || undefined,
+                    body_encoding: data.body_encoding || undefined,
+                    response: data.response || undefined,
                     cert_path: data.cert_path || undefined,
-                    key_path: data.key_path || undefined
+                    key_path: data.key_path || undefined,
+                    client_auth: data.client_auth || undefined
                 }
             };
         case 'tcpListener':
@@ -92,7 +138,11 @@ function buildSourceConfig(node: Node, nodes: Node[], edges: Edge[]): SourceConf
                 config: {
                     port: Number(resolveConfigValue(node, 'port', 'config-port', nodes, edges)) || 9090,
                     cert_path: data.cert_path || undefined,
-                    key_path: data.key_path || undefined
+                    key_path: data.key_path || undefined,
+                    client_auth: data.client_auth || undefined,
+                    ack_mode: data.ack_mode || undefined,
+                    ack_script: data.ack_script || undefined,
+                    framing: data.framing || undefined
                 }
             };
         case 'fileReader':
@@ -135,7 +185,11 @@ function buildProcessorConfig(node: Node, nodes: Node[], edges: Edge[]): Process
             return {
                 ...base,
                 type: 'hl7_parser',
-                config: { inputFormat: data.inputFormat || 'hl7v2', outputFormat: data.outputFormat || 'fhir' }
+                config: {
+                    inputFormat: data.inputFormat || 'hl7v2',
+                    outputFormat: data.outputFormat || 'json',
+                    ...(data.fhirMappings ? { fhirMappings: data.fhirMappings } : {})
+                }
             };
         default:
             return { ...base, type: 'lua_script', config: { code: 'return msg' } };
@@ -159,7 +213,14 @@ function buildDestinationConfig(node: Node, nodes: Node[], edges: Edge[]): Desti
                 }
             };
         case 'httpSender':
-            return { ...base, type: 'http_sender', config: { url: data.url || '', method: data.method || 'POST' } };
+            return { ...base, type: 'http_sender', config: {
+                url: data.url || '',
+                method: data.method || 'POST',
+                headers: data.headers || undefined,
+                query: data.query || undefined,
+                auth: data.auth || undefined,
+                content_type: data.content_type || undefined
+            } };
         case 'databaseWriter':
             return {
                 ...base,
@@ -167,7 +228,15 @@ function buildDestinationConfig(node: Node, nodes: Node[], edges: Edge[]): Desti
                 config: { table: data.table, mode: data.mode || 'insert', query: data.query }
             };
         case 'tcpSender':
-            return { ...base, type: 'tcp_sender', config: { host: data.host || '127.0.0.1', port: Number(data.port) || 9000 } };
+            return { ...base, type: 'tcp_sender', config: {
+                host: data.host || '127.0.0.1',
+                port: Number(data.port) || 9000,
+                framing: data.framing || undefined,
+                keep_connection_open: data.keep_connection_open || undefined,
+                tls: data.tls || undefined,
+                timeouts: data.timeouts || undefined,
+                response: data.response || undefined
+            } };
         case 'luaDestination':
             return { ...base, type: 'lua_script', config: { code: data.code || 'return msg' } };
         default:
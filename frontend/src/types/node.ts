@@ -46,6 +46,8 @@ export interface RouterData extends BaseNodeData {
 export interface HL7ParserData extends BaseNodeData {
     inputFormat: string;
     outputFormat: string;
+    /** Code table overrides for the fhir_r4 output */
+    fhirMappings?: Record<string, unknown>;
 }
 
 export interface FileWriterData extends BaseNodeData {